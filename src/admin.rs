@@ -0,0 +1,163 @@
+// Read-only sync status for debugging, served on admin_bind
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::health::{unix_time, HEALTH};
+use crate::metrics::METRICS;
+use crate::pause::PAUSE;
+
+pub struct Status {
+    // Unix time in seconds when the last full sync started, 0 if no full sync started yet
+    pub full_sync_started: AtomicU64,
+    // Number of source secrets listed by the last full sync
+    pub full_sync_secrets: AtomicU64,
+    // Whether the audit device exists in the source Vault, checked on start and on reconnect
+    pub audit_device: AtomicBool,
+    // Unix time in seconds of the last parsed audit log entry, 0 if no entry is received yet
+    pub last_audit_entry: AtomicU64,
+    // For the duration of the full sync, set with full_sync_started
+    pub full_sync_instant: Mutex<Option<time::Instant>>,
+    // Counters of the last finished full sync
+    pub full_sync_summary: Mutex<Option<FullSyncSummary>>,
+}
+
+// Secrets read from the source by the full sync, and the results of the ops in all destinations
+// since the previous full sync, including the live sync in the meantime. The duration includes
+// waiting for the ops.
+#[derive(Serialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct FullSyncSummary {
+    pub read: u64,
+    pub written: u64,
+    pub skipped: u64,
+    pub deleted: u64,
+    pub errors: u64,
+    pub duration_ms: u64,
+}
+
+pub static STATUS: Status = Status::new();
+
+impl Status {
+    const fn new() -> Status {
+        Status {
+            full_sync_started: AtomicU64::new(0),
+            full_sync_secrets: AtomicU64::new(0),
+            audit_device: AtomicBool::new(false),
+            last_audit_entry: AtomicU64::new(0),
+            full_sync_instant: Mutex::new(None),
+            full_sync_summary: Mutex::new(None),
+        }
+    }
+
+    pub fn start_full_sync(&self) {
+        self.full_sync_started.store(unix_time(), Ordering::Relaxed);
+        *self.full_sync_instant.lock().unwrap() = Some(time::Instant::now());
+    }
+
+    // Milliseconds since the last full sync started, 0 if no full sync started yet
+    pub fn full_sync_duration_ms(&self) -> u64 {
+        self.full_sync_instant.lock().unwrap().map_or(0, |started| started.elapsed().as_millis() as u64)
+    }
+
+    // Tokens are Vault URLs and Unix times when the tokens expire, 0 if a token does not expire
+    // Standby are URLs of the destinations that are standby nodes
+    fn render(&self, now: u64, full_sync_finished: u64, queue_depth: i64, tokens: &[(String, u64)], paused: bool, standby: &[String]) -> Value {
+        let time = |value: u64| if value == 0 { None } else { Some(value) };
+        let tokens: Vec<Value> = tokens.iter().map(|(url, expiry)| json!({
+            "url": url,
+            "ttl": time(*expiry).map(|expiry| expiry.saturating_sub(now)),
+        })).collect();
+        json!({
+            "full_sync": {
+                "started": time(self.full_sync_started.load(Ordering::Relaxed)),
+                "finished": time(full_sync_finished),
+                "secrets": self.full_sync_secrets.load(Ordering::Relaxed),
+                "summary": *self.full_sync_summary.lock().unwrap(),
+            },
+            "queue_depth": queue_depth,
+            "tokens": tokens,
+            "audit_device": self.audit_device.load(Ordering::Relaxed),
+            "paused": paused,
+            "standby": standby,
+        })
+    }
+}
+
+// Handler for http::serve
+pub fn handle(method: &str, path: &str) -> (u16, String) {
+    match (method, path) {
+        ("GET", "/status") => {
+            let status = STATUS.render(
+                unix_time(),
+                METRICS.last_full_sync.load(Ordering::Relaxed),
+                METRICS.queue_depth.load(Ordering::Relaxed),
+                &HEALTH.tokens(),
+                PAUSE.is_paused(),
+                &HEALTH.standby(),
+            );
+            (200, format!("{}\n", status))
+        },
+        (_, "/status") => (405, "Method not allowed\n".to_string()),
+        _ => (404, "Not found\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use serde_json::json;
+
+    use crate::admin::{handle, FullSyncSummary, Status};
+
+    #[test]
+    fn test_render() {
+        let status = Status::new();
+        assert_eq!(status.render(1000, 0, 0, &[], false, &[]), json!({
+            "full_sync": {"started": null, "finished": null, "secrets": 0, "summary": null},
+            "queue_depth": 0,
+            "tokens": [],
+            "audit_device": false,
+            "paused": false,
+            "standby": [],
+        }));
+
+        status.full_sync_started.store(900, Ordering::Relaxed);
+        status.full_sync_secrets.store(42, Ordering::Relaxed);
+        status.audit_device.store(true, Ordering::Relaxed);
+        *status.full_sync_summary.lock().unwrap() = Some(FullSyncSummary { read: 42, written: 3, skipped: 38, deleted: 1, errors: 1, duration_ms: 1500 });
+        let tokens = vec![
+            ("http://127.0.0.1:8200/".to_string(), 4600),
+            ("http://127.0.0.1:8201/".to_string(), 0),
+        ];
+        assert_eq!(status.render(1000, 950, 3, &tokens, true, &["http://127.0.0.1:8201/".to_string()]), json!({
+            "full_sync": {
+                "started": 900,
+                "finished": 950,
+                "secrets": 42,
+                "summary": {"read": 42, "written": 3, "skipped": 38, "deleted": 1, "errors": 1, "duration_ms": 1500},
+            },
+            "queue_depth": 3,
+            "tokens": [
+                {"url": "http://127.0.0.1:8200/", "ttl": 3600},
+                {"url": "http://127.0.0.1:8201/", "ttl": null},
+            ],
+            "audit_device": true,
+            "paused": true,
+            "standby": ["http://127.0.0.1:8201/"],
+        }));
+    }
+
+    #[test]
+    fn test_handle() {
+        let (status, body) = handle("GET", "/status");
+        assert_eq!(status, 200);
+        assert!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["full_sync"].is_object());
+        assert_eq!(handle("POST", "/status").0, 405);
+        assert_eq!(handle("GET", "/").0, 404);
+    }
+}
@@ -1,21 +1,125 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+// Fields that are not needed for the sync are optional, so entries of other requests (for example
+// auth requests) are parsed and ignored without errors
 #[derive(Deserialize, Debug)]
 pub struct AuditLog {
     #[allow(dead_code)]
+    #[serde(default)]
     pub time: String,
     #[serde(rename = "type")]
     pub log_type: String,
+    pub auth: Option<Auth>,
+    #[serde(default)]
     pub request: Request,
 }
 
 #[derive(Deserialize, Debug)]
+pub struct Auth {
+    // HMAC of the token accessor, unless the audit device has hmac_accessor=false
+    pub accessor: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
 pub struct Request {
+    #[serde(default)]
     pub operation: String,
+    // "kv" for KV secrets engines. Values hashed by the audit device start with "hmac-", such
+    // entries are not recognized as KV requests.
     pub mount_type: Option<String>,
+    #[serde(default)]
     pub path: String,
 }
 
+// Entries larger than this are dropped, so garbage without a newline does not grow the buffer.
+// The rest of a dropped entry is skipped until the newline without buffering it.
+const MAX_ENTRY_SIZE: usize = 1024 * 1024;
+
+// Splits the audit log stream into entries. An entry can be split across several reads, and
+// several entries can be in one read. Entries that cannot be parsed are returned as errors, and
+// reading continues with the next entry.
+#[derive(Default)]
+pub struct EntryReader {
+    pending: Vec<u8>,
+    // The rest of a dropped entry is still to come, up to the next newline
+    discarding: bool,
+}
+
+impl EntryReader {
+    // Adds the bytes read from the stream, returns the complete entries
+    pub fn push(&mut self, mut bytes: &[u8]) -> Vec<Result<AuditLog, String>> {
+        if self.discarding {
+            match bytes.iter().position(|b| *b == b'\n') {
+                Some(end) => {
+                    self.discarding = false;
+                    bytes = &bytes[end + 1..];
+                },
+                None => return Vec::new(),
+            }
+        }
+        self.pending.extend_from_slice(bytes);
+        let mut entries = Vec::new();
+        loop {
+            let start = self.pending.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(self.pending.len());
+            self.pending.drain(..start);
+            if self.pending.is_empty() {
+                break;
+            }
+            if self.pending[0] != b'{' {
+                // Garbage before the next entry
+                let end = self.pending.iter().position(|b| *b == b'{' || *b == b'\n').unwrap_or(self.pending.len());
+                entries.push(Err(format!("unexpected data '{}'", String::from_utf8_lossy(&self.pending[..end]))));
+                self.pending.drain(..end);
+                continue;
+            }
+            let mut stream = serde_json::Deserializer::from_slice(&self.pending).into_iter::<Value>();
+            match stream.next() {
+                Some(Ok(value)) => {
+                    let end = stream.byte_offset();
+                    self.pending.drain(..end);
+                    entries.push(serde_json::from_value(value).map_err(|error| error.to_string()));
+                },
+                Some(Err(error)) if error.is_eof() => {
+                    if self.pending.len() > MAX_ENTRY_SIZE {
+                        entries.push(Err(format!("entry is larger than {} bytes", MAX_ENTRY_SIZE)));
+                        self.pending.clear();
+                        self.discarding = true;
+                    }
+                    break;
+                },
+                Some(Err(error)) => {
+                    // Vault writes one entry per line, the broken entry ends at the newline
+                    match self.pending.iter().position(|b| *b == b'\n') {
+                        Some(end) => {
+                            let line = String::from_utf8_lossy(&self.pending[..end]).into_owned();
+                            entries.push(Err(format!("{}, entry '{}'", error, line)));
+                            self.pending.drain(..=end);
+                        },
+                        None => {
+                            if self.pending.len() > MAX_ENTRY_SIZE {
+                                entries.push(Err(format!("{}, entry is larger than {} bytes", error, MAX_ENTRY_SIZE)));
+                                self.pending.clear();
+                                self.discarding = true;
+                            }
+                            break;
+                        },
+                    }
+                },
+                None => break,
+            }
+        }
+        entries
+    }
+
+    // Drops the incomplete entry, for example when the file is truncated
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.discarding = false;
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Serialize, Debug)]
 pub struct CreateAuditDeviceRequest {
     #[serde(rename = "type")]
@@ -23,8 +127,78 @@ pub struct CreateAuditDeviceRequest {
     pub options: AuditDeviceOptions,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Debug)]
 pub struct AuditDeviceOptions {
     pub address: String,
     pub socket_type: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::{EntryReader, MAX_ENTRY_SIZE};
+
+    const ENTRY: &str = r#"{"time":"","type":"response","request":{"operation":"update","mount_type":"kv","path":"secret/data/foo"}}"#;
+
+    fn paths(entries: Vec<Result<crate::audit::AuditLog, String>>) -> Vec<Result<String, ()>> {
+        entries.into_iter().map(|entry| entry.map(|log| log.request.path).map_err(|_| ())).collect()
+    }
+
+    #[test]
+    fn test_fragmented() {
+        let mut reader = EntryReader::default();
+        let (first, second) = ENTRY.split_at(40);
+        assert!(reader.push(first.as_bytes()).is_empty());
+        assert_eq!(paths(reader.push(format!("{}\n{}", second, first).as_bytes())), vec![Ok("secret/data/foo".to_string())]);
+        assert_eq!(paths(reader.push(format!("{}{}\n", second, ENTRY).as_bytes())), vec![
+            Ok("secret/data/foo".to_string()),
+            Ok("secret/data/foo".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_garbage() {
+        let mut reader = EntryReader::default();
+        let (truncated, _) = ENTRY.split_at(40);
+        let input = format!(
+            "garbage\n{}\n{}\n{{\"type\":\"response\"\n{}\n[1,2]\n{{\"no_type\":1}}\n{}\n",
+            truncated, ENTRY, ENTRY, ENTRY,
+        );
+        let mut input = input.into_bytes();
+        input.extend_from_slice(b"\xff\xfe\n");
+        input.extend_from_slice(ENTRY.as_bytes());
+        input.push(b'\n');
+        let entries = paths(reader.push(&input));
+        let ok: Vec<&Result<String, ()>> = entries.iter().filter(|entry| entry.is_ok()).collect();
+        assert_eq!(ok.len(), 4);
+        assert!(entries.iter().filter(|entry| entry.is_err()).count() >= 4);
+        assert!(reader.push(b"").is_empty());
+    }
+
+    #[test]
+    fn test_oversized() {
+        let mut reader = EntryReader::default();
+        // The value has objects that would parse as entries if the rest was not skipped
+        let chunk = r#"{"type":"response"},"#.repeat(1000);
+        assert!(reader.push(br#"{"time":"","type":"response","request":{"data":["#).is_empty());
+        let mut errors = 0;
+        for _ in 0..(3 * MAX_ENTRY_SIZE / chunk.len()) {
+            let entries = reader.push(chunk.as_bytes());
+            assert!(entries.iter().all(|entry| entry.is_err()));
+            errors += entries.len();
+            assert!(reader.pending.len() <= MAX_ENTRY_SIZE + chunk.len());
+        }
+        assert_eq!(errors, 1);
+        assert!(reader.push(b"{}]}}").is_empty());
+        assert_eq!(paths(reader.push(format!("\n{}\n", ENTRY).as_bytes())), vec![Ok("secret/data/foo".to_string())]);
+    }
+
+    #[test]
+    fn test_other_requests() {
+        let mut reader = EntryReader::default();
+        let entries = reader.push(br#"{"time":"","type":"request","auth":{"accessor":"hmac-sha256:1"},"request":{"operation":"update","mount_type":"hmac-sha256:2"}}"#);
+        let log = entries.into_iter().next().unwrap().unwrap();
+        assert_eq!(log.request.path, "");
+        assert_eq!(log.request.mount_type.unwrap(), "hmac-sha256:2");
+    }
+}
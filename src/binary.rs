@@ -0,0 +1,219 @@
+// Secret values that are not valid UTF-8, with binary_safe. JSON strings cannot hold them, so such
+// a value is written to the destinations base64-encoded, and MARKER_KEY of the secret lists the
+// encoded keys. A secret with MARKER_KEY that is synced back to the source Vault in bidirectional
+// mode is decoded again, so the source keeps the raw bytes.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::{Map, Value};
+
+pub const MARKER_KEY: &str = "vault-sync-base64";
+
+// Prefix of the encoded values between parse and encode, in the JSON text escaped as \u0000
+const SENTINEL: &str = "\u{0}vault-sync-base64:";
+
+// Parses a response body like serde_json. A string that is not valid UTF-8 is replaced with the
+// base64 of its bytes after SENTINEL, encode moves it into the marker convention.
+pub fn parse(body: &[u8]) -> serde_json::Result<Value> {
+    if std::str::from_utf8(body).is_ok() {
+        return serde_json::from_slice(body);
+    }
+    let mut text = Vec::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(start) = rest.iter().position(|&byte| byte == b'"') {
+        text.extend_from_slice(&rest[..start]);
+        let literal = &rest[start + 1..];
+        let mut end = 0;
+        while end < literal.len() && literal[end] != b'"' {
+            end += if literal[end] == b'\\' { 2 } else { 1 };
+        }
+        let end = end.min(literal.len());
+        let content = &literal[..end];
+        text.push(b'"');
+        if std::str::from_utf8(content).is_ok() {
+            text.extend_from_slice(content);
+        } else {
+            text.extend_from_slice(b"\\u0000");
+            text.extend_from_slice(&SENTINEL.as_bytes()[1..]);
+            text.extend_from_slice(STANDARD.encode(unescape(content)).as_bytes());
+        }
+        text.push(b'"');
+        rest = literal.get(end + 1..).unwrap_or_default();
+    }
+    text.extend_from_slice(rest);
+    serde_json::from_slice(&text)
+}
+
+// The top-level values of the secret data from parse are kept base64-encoded and listed in
+// MARKER_KEY. Encoded values elsewhere, for example in nested objects, are an error.
+pub fn encode(data: &mut Value) -> Result<(), String> {
+    let Value::Object(map) = data else {
+        return Ok(());
+    };
+    let mut keys = Vec::new();
+    for (key, value) in map.iter_mut() {
+        if let Some(encoded) = value.as_str().and_then(|value| value.strip_prefix(SENTINEL)) {
+            *value = Value::String(encoded.to_string());
+            keys.push(Value::String(key.clone()));
+        }
+    }
+    if map.values().any(has_sentinel) {
+        return Err("secret has values that are not valid UTF-8 and not top-level strings".to_string());
+    }
+    if keys.is_empty() {
+        return Ok(());
+    }
+    if map.contains_key(MARKER_KEY) {
+        return Err(format!("secret has values that are not valid UTF-8 and the key {}", MARKER_KEY));
+    }
+    map.insert(MARKER_KEY.to_string(), Value::Array(keys));
+    Ok(())
+}
+
+fn has_sentinel(value: &Value) -> bool {
+    match value {
+        Value::String(value) => value.starts_with(SENTINEL),
+        Value::Array(values) => values.iter().any(has_sentinel),
+        Value::Object(map) => map.values().any(has_sentinel),
+        _ => false,
+    }
+}
+
+// The secret data as JSON with the keys of MARKER_KEY decoded to their bytes and without
+// MARKER_KEY, None if the secret has no MARKER_KEY
+pub fn decode(data: &Value) -> Option<Result<Vec<u8>, String>> {
+    let map = data.as_object()?;
+    let keys = map.get(MARKER_KEY)?;
+    Some(decode_map(map, keys))
+}
+
+fn decode_map(map: &Map<String, Value>, keys: &Value) -> Result<Vec<u8>, String> {
+    let keys: Vec<&str> = keys.as_array()
+        .and_then(|keys| keys.iter().map(Value::as_str).collect())
+        .ok_or_else(|| format!("{} is not a list of keys", MARKER_KEY))?;
+    let mut body = b"{".to_vec();
+    for (key, value) in map.iter().filter(|(key, _)| *key != MARKER_KEY) {
+        if body.len() > 1 {
+            body.push(b',');
+        }
+        body.extend_from_slice(serde_json::to_string(key).unwrap().as_bytes());
+        body.push(b':');
+        if !keys.contains(&key.as_str()) {
+            body.extend_from_slice(value.to_string().as_bytes());
+            continue;
+        }
+        let bytes = value.as_str()
+            .and_then(|value| STANDARD.decode(value).ok())
+            .ok_or_else(|| format!("value of {} listed in {} is not base64", key, MARKER_KEY))?;
+        body.push(b'"');
+        for byte in bytes {
+            match byte {
+                b'"' => body.extend_from_slice(b"\\\""),
+                b'\\' => body.extend_from_slice(b"\\\\"),
+                0..=0x1f => body.extend_from_slice(format!("\\u{:04x}", byte).as_bytes()),
+                byte => body.push(byte),
+            }
+        }
+        body.push(b'"');
+    }
+    body.push(b'}');
+    Ok(body)
+}
+
+// Bytes of the string literal content, escapes that are not valid are kept as they are
+fn unescape(content: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(content.len());
+    let mut i = 0;
+    while i < content.len() {
+        if content[i] != b'\\' || i + 1 == content.len() {
+            bytes.push(content[i]);
+            i += 1;
+            continue;
+        }
+        let escaped = match content[i + 1] {
+            b'b' => Some(0x08),
+            b'f' => Some(0x0c),
+            b'n' => Some(b'\n'),
+            b'r' => Some(b'\r'),
+            b't' => Some(b'\t'),
+            b'u' => None,
+            byte => Some(byte),
+        };
+        if let Some(byte) = escaped {
+            bytes.push(byte);
+            i += 2;
+            continue;
+        }
+        let (code_point, len) = unescape_unicode(&content[i..]);
+        match code_point.and_then(char::from_u32) {
+            Some(char) => bytes.extend_from_slice(char.encode_utf8(&mut [0; 4]).as_bytes()),
+            None => bytes.extend_from_slice(&content[i..i + len]),
+        }
+        i += len;
+    }
+    bytes
+}
+
+// \uXXXX, or a surrogate pair \uXXXX\uXXXX, with the number of bytes of the escape
+fn unescape_unicode(escape: &[u8]) -> (Option<u32>, usize) {
+    let hex = |bytes: &[u8]| std::str::from_utf8(bytes).ok().and_then(|hex| u32::from_str_radix(hex, 16).ok());
+    let Some(high) = escape.get(2..6).and_then(hex) else {
+        return (None, 2);
+    };
+    if !(0xd800..0xdc00).contains(&high) || escape.get(6..8) != Some(b"\\u") {
+        return (Some(high), 6);
+    }
+    match escape.get(8..12).and_then(hex) {
+        Some(low) if (0xdc00..0xe000).contains(&low) => (Some(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)), 12),
+        _ => (None, 6),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::binary::{decode, encode, parse, MARKER_KEY};
+
+    #[test]
+    fn test_round_trip() {
+        let mut body = br#"{"data":{"text":"a\"b","key":""#.to_vec();
+        body.extend_from_slice(&[0xff, 0xfe, b'\\', b'n', 0x80, b'\\', b'"']);
+        body.extend_from_slice(br#""}}"#);
+        let mut data = parse(&body).unwrap()["data"].take();
+        encode(&mut data).unwrap();
+        assert_eq!(data, json!({"text": "a\"b", "key": "//4KgCI=", MARKER_KEY: ["key"]}));
+
+        let decoded = decode(&data).unwrap().unwrap();
+        let mut expected = br#"{"key":""#.to_vec();
+        expected.extend_from_slice(&[0xff, 0xfe, b'\\', b'u', b'0', b'0', b'0', b'a', 0x80, b'\\', b'"']);
+        expected.extend_from_slice(br#"","text":"a\"b"}"#);
+        assert_eq!(decoded, expected);
+        // Read again, the bytes are the same
+        let mut again = parse(&decoded).unwrap();
+        encode(&mut again).unwrap();
+        assert_eq!(again, data);
+    }
+
+    #[test]
+    fn test_valid_utf8() {
+        let mut data = parse(r#"{"key":"é😀"}"#.as_bytes()).unwrap();
+        encode(&mut data).unwrap();
+        assert_eq!(data, json!({"key": "é😀"}));
+        assert!(decode(&data).is_none());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut body = br#"{"nested":{"key":""#.to_vec();
+        body.push(0xff);
+        body.extend_from_slice(br#""}}"#);
+        let mut data = parse(&body).unwrap();
+        assert!(encode(&mut data).is_err());
+
+        let data = json!({"key": "not base64!", MARKER_KEY: ["key"]});
+        assert!(decode(&data).unwrap().is_err());
+        assert!(decode(&json!({MARKER_KEY: "key"})).unwrap().is_err());
+        assert!(parse(b"{\"key\":\"\xff").is_err());
+    }
+}
@@ -0,0 +1,66 @@
+// Keyed checksums of secret data, HMAC-SHA-256 of the JSON with the object keys sorted, so a
+// checksum that is printed or saved does not reveal the value without the key.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use hmac::{Hmac, KeyInit, Mac};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+
+pub struct ChecksumKey(Vec<u8>);
+
+impl ChecksumKey {
+    // Checksums with a key of one run cannot be compared with those of another run
+    pub fn random() -> io::Result<ChecksumKey> {
+        let mut key = vec![0; 32];
+        File::open("/dev/urandom")?.read_exact(&mut key)?;
+        Ok(ChecksumKey(key))
+    }
+
+    // For checksums that are compared across runs, the secret must not be kept with them
+    pub fn from_secret(secret: &str) -> ChecksumKey {
+        ChecksumKey(secret.as_bytes().to_vec())
+    }
+
+    // Hex of the HMAC of the JSON with the object keys sorted at every level
+    pub fn checksum(&self, value: &Value) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(sorted(value).to_string().as_bytes());
+        mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+// Objects are rebuilt in key order, which does not depend on the order in the response
+fn sorted(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            Value::Object(keys.into_iter().map(|key| (key.clone(), sorted(&map[key]))).collect::<Map<String, Value>>())
+        },
+        Value::Array(values) => Value::Array(values.iter().map(sorted).collect()),
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::checksum::ChecksumKey;
+
+    #[test]
+    fn test_checksum() {
+        let key = ChecksumKey::from_secret("key");
+        let a = json!({"b": {"d": 1, "c": [{"f": 2, "e": 3}]}, "a": "1"});
+        let b = json!({"a": "1", "b": {"c": [{"e": 3, "f": 2}], "d": 1}});
+        assert_eq!(key.checksum(&a), key.checksum(&b));
+        assert_eq!(key.checksum(&a).len(), 64);
+        assert_ne!(key.checksum(&a), key.checksum(&json!({"a": "2"})));
+        // Without the key, the checksum cannot be computed for a guessed value
+        assert_ne!(key.checksum(&a), ChecksumKey::from_secret("other").checksum(&a));
+        let random = ChecksumKey::random().unwrap();
+        assert_ne!(random.checksum(&a), ChecksumKey::random().unwrap().checksum(&a));
+    }
+}
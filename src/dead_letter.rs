@@ -0,0 +1,143 @@
+// Secret operations that failed after retries, appended to dead_letter_path as JSON lines and
+// synced again with --replay
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+use std::time;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::sync::{SecretOp, SecretPath};
+
+// One line of the file. New fields must be optional, so older files can still be replayed.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DeadLetter {
+    // Unix time of the failure
+    pub timestamp: u64,
+    pub op: Operation,
+    // Source secret, for example "secret" and "src/path/to/secret"
+    pub mount: String,
+    pub path: String,
+    // URL of the destination Vault where the op failed
+    pub destination: String,
+    // Source secret data when the op failed, if it could be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Create,
+    Update,
+    Delete,
+    Destroy,
+}
+
+impl DeadLetter {
+    // Returns None for the ops without a secret
+    pub fn new(op: &SecretOp, destination: &str, data: Option<Value>, error: String) -> Option<DeadLetter> {
+        let (op, secret) = match op {
+            SecretOp::Create(secret) => (Operation::Create, secret),
+            SecretOp::Update(secret) => (Operation::Update, secret),
+            SecretOp::Delete(secret) => (Operation::Delete, secret),
+            SecretOp::Destroy(secret) => (Operation::Destroy, secret),
+            SecretOp::Counted(op, _) => return DeadLetter::new(op, destination, data, error),
+            SecretOp::FullSyncFinished | SecretOp::Shutdown => return None,
+        };
+        let timestamp = time::SystemTime::now().duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Some(DeadLetter {
+            timestamp,
+            op,
+            mount: secret.mount.clone(),
+            path: secret.path.clone(),
+            destination: destination.to_string(),
+            data,
+            error,
+        })
+    }
+
+    pub fn op(&self) -> SecretOp {
+        let secret = SecretPath { mount: self.mount.clone(), path: self.path.clone() };
+        match self.op {
+            Operation::Create => SecretOp::Create(secret),
+            Operation::Update => SecretOp::Update(secret),
+            Operation::Delete => SecretOp::Delete(secret),
+            Operation::Destroy => SecretOp::Destroy(secret),
+        }
+    }
+}
+
+// Lines written by several sync threads are not mixed
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn append(file_name: &str, letter: &DeadLetter) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(letter)?;
+    line.push('\n');
+    let _lock = FILE_LOCK.lock().unwrap();
+    // The lines have the secret data, only the owner can read a new file
+    let mut file = OpenOptions::new().create(true).append(true).mode(0o600).open(file_name)?;
+    file.write_all(line.as_bytes())
+}
+
+pub fn read(file_name: &str) -> Result<Vec<DeadLetter>, Box<dyn Error>> {
+    let file = File::open(file_name).map_err(|error| format!("{}: {}", file_name, error))?;
+    let mut letters = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let letter = serde_json::from_str(&line)
+            .map_err(|error| format!("{}, line {}: {}", file_name, index + 1, error))?;
+        letters.push(letter);
+    }
+    Ok(letters)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use serde_json::json;
+
+    use crate::dead_letter::{append, read, DeadLetter, Operation};
+    use crate::sync::{SecretOp, SecretPath};
+
+    #[test]
+    fn test_round_trip() {
+        let file_name = std::env::temp_dir().join(format!("vault-sync-dead-letters-{}.jsonl", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+        let _ = fs::remove_file(file_name);
+
+        let secret = || SecretPath { mount: "secret".to_string(), path: "src/foo".to_string() };
+        let create = DeadLetter::new(&SecretOp::Create(secret()), "http://dst/", Some(json!({"a": "1"})), "error".to_string()).unwrap();
+        let destroy = DeadLetter::new(&SecretOp::Destroy(secret()), "http://dst/", None, "error".to_string()).unwrap();
+        assert!(DeadLetter::new(&SecretOp::Shutdown, "http://dst/", None, "error".to_string()).is_none());
+        append(file_name, &create).unwrap();
+        append(file_name, &destroy).unwrap();
+        assert_eq!(fs::metadata(file_name).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let letters = read(file_name).unwrap();
+        fs::remove_file(file_name).unwrap();
+        assert_eq!(letters, vec![create, destroy]);
+        assert_eq!(letters[0].op(), SecretOp::Create(secret()));
+        assert_eq!(letters[1].op, Operation::Destroy);
+        assert_eq!(letters[1].op(), SecretOp::Destroy(secret()));
+    }
+
+    #[test]
+    fn test_format() {
+        let line = r#"{"timestamp":1700000000,"op":"update","mount":"secret","path":"src/foo","destination":"http://dst/","error":"permission denied"}"#;
+        let letter: DeadLetter = serde_json::from_str(line).unwrap();
+        assert_eq!(letter.op, Operation::Update);
+        assert_eq!(letter.data, None);
+        assert_eq!(serde_json::to_string(&letter).unwrap(), line);
+    }
+}
@@ -0,0 +1,292 @@
+// Differences between the source and the destinations, reported with --diff. Both Vaults are only
+// listed and read, so the report can be checked before the first sync.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use serde_json::Value;
+
+use crate::config::{get_prefixes, VaultDestination, VaultSyncConfig};
+use crate::sync::{list_all, resolve_dst_path, secret_dst_to_src_path, PathMap, SecretPath, SyncError};
+use crate::table;
+use crate::vault::{self, VaultClient};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DiffKind {
+    // The source secret is not in the destination
+    Missing,
+    // The destination secret is not in the source
+    Extra,
+    // The values are different
+    Mismatch,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Difference {
+    pub kind: DiffKind,
+    // URL of the destination Vault
+    pub destination: String,
+    // Destination secret
+    pub mount: String,
+    pub path: String,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Summary {
+    pub destination: String,
+    pub missing: u64,
+    pub extra: u64,
+    pub mismatch: u64,
+    pub same: u64,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Diff {
+    // In the order of the destinations
+    pub summaries: Vec<Summary>,
+    // Sorted by destination and path
+    pub differences: Vec<Difference>,
+    // False if some of the secrets could not be listed or read
+    pub complete: bool,
+}
+
+// Compares the secrets that the full sync would write, filtered and without blacklisted keys like
+// in the sync. Secrets that could not be read are logged and skipped.
+pub fn diff(config: &VaultSyncConfig, src_client: &Arc<Mutex<VaultClient>>, dst_clients: &[Arc<Mutex<VaultClient>>]) -> Diff {
+    let mut diff = Diff { complete: true, ..Default::default() };
+    let src_prefixes = get_prefixes(&config.src.prefix);
+    let mut src_secrets = HashMap::new();
+    for backend in config.src.synced_backends() {
+        let mut secrets = Vec::new();
+        for src_prefix in &src_prefixes {
+            diff.complete &= list_all(src_prefix, &backend, &config.src.host.url, src_client.clone(), |secret| secrets.push(secret));
+        }
+        src_secrets.insert(backend, secrets);
+    }
+
+    for (dst, dst_client) in config.dst.iter().zip(dst_clients) {
+        let mut summary = Summary { destination: dst.host.url.clone(), ..Default::default() };
+        let paths = PathMap::new(&config.src, dst);
+        for (backend, secrets) in &src_secrets {
+            let mount = &paths.mounts[backend];
+            let mut dst_secrets = HashSet::new();
+            for (_, dst_prefix) in &paths.prefixes {
+                diff.complete &= list_all(dst_prefix, mount, &dst.host.url, dst_client.clone(), |secret| {
+                    dst_secrets.insert(secret);
+                });
+            }
+            let mut expected = HashSet::new();
+            for secret in secrets {
+                let src = SecretPath { mount: backend.clone(), path: secret.clone() };
+                let dst_path = match resolve_dst_path(config, &paths, src_client, &src) {
+                    Ok(Some(dst_path)) => dst_path,
+                    Ok(None) => continue,
+                    Err(error) => {
+                        warn!(secret = error.secret.path.as_str(), host = error.host.as_str(); "Failed to read metadata of secret {}", error);
+                        diff.complete = false;
+                        continue;
+                    },
+                };
+                let dst_secret = SecretPath { mount: mount.clone(), path: dst_path };
+                expected.insert(dst_secret.path.clone());
+                if !config.src.filter.matches(secret) || !dst.in_canary(secret) {
+                    continue;
+                }
+                let listed = dst_secrets.contains(&dst_secret.path).then_some(&dst_secret);
+                let kind = match compare(config, src_client, &src, dst_client, dst, listed) {
+                    Ok(Compared::Same) => None,
+                    Ok(Compared::Different(kind)) => Some(kind),
+                    Ok(Compared::Skipped) => continue,
+                    Err(error) => {
+                        warn!(secret = error.secret.path.as_str(), host = error.host.as_str(); "Failed to read secret {}", error);
+                        diff.complete = false;
+                        continue;
+                    },
+                };
+                summary.add(kind);
+                if let Some(kind) = kind {
+                    diff.differences.push(Difference { kind, destination: dst.host.url.clone(), mount: mount.clone(), path: dst_secret.path });
+                }
+            }
+            for secret in dst_secrets {
+                if expected.contains(&secret) {
+                    continue;
+                }
+                // Destination paths with rewrites or path_template cannot be mapped back to check
+                // the filters
+                if dst.rewriter.is_empty() && !paths.has_template() {
+                    let (src_prefix, dst_prefix) = paths.prefixes.iter()
+                        .find(|(_, dst_prefix)| secret.starts_with(dst_prefix.as_str()))
+                        .unwrap_or(&paths.prefixes[0]);
+                    if !config.src.filter.matches(&secret_dst_to_src_path(src_prefix, dst_prefix, &secret)) {
+                        continue;
+                    }
+                }
+                summary.add(Some(DiffKind::Extra));
+                diff.differences.push(Difference { kind: DiffKind::Extra, destination: dst.host.url.clone(), mount: mount.clone(), path: secret });
+            }
+        }
+        diff.summaries.push(summary);
+    }
+    diff.differences.sort_by(|a, b| (&a.destination, &a.mount, &a.path).cmp(&(&b.destination, &b.mount, &b.path)));
+    diff
+}
+
+impl Summary {
+    fn add(&mut self, kind: Option<DiffKind>) {
+        match kind {
+            Some(DiffKind::Missing) => self.missing += 1,
+            Some(DiffKind::Extra) => self.extra += 1,
+            Some(DiffKind::Mismatch) => self.mismatch += 1,
+            None => self.same += 1,
+        }
+    }
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+enum Compared {
+    // Deleted in the source, or only blacklisted keys, the sync skips it
+    Skipped,
+    Same,
+    Different(DiffKind),
+}
+
+// The source secret as it is written to the destination: without blacklisted keys and
+// transformed. None if the sync skips it, when it is deleted or has only blacklisted keys.
+pub fn expected_secret(
+    config: &VaultSyncConfig,
+    src_client: &Arc<Mutex<VaultClient>>,
+    src: &SecretPath,
+    dst_config: &VaultDestination,
+) -> Result<Option<Value>, SyncError> {
+    let Some(mut src_secret) = vault::read_secret(src_client, &src.mount, &src.path).map_err(|error| SyncError::new(src, &config.src.host.url, error))? else {
+        return Ok(None);
+    };
+    if config.src.blacklist.strip(&src.path, &mut src_secret) && src_secret.as_object().is_some_and(|data| data.is_empty()) {
+        return Ok(None);
+    }
+    dst_config.transformer.apply(&src.path, &mut src_secret);
+    Ok(Some(src_secret))
+}
+
+// The destination secret is None if it is not listed. Blacklisted keys are removed from both
+// secrets, the destination keeps its own values of them.
+fn compare(
+    config: &VaultSyncConfig,
+    src_client: &Arc<Mutex<VaultClient>>,
+    src: &SecretPath,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    dst_config: &VaultDestination,
+    dst: Option<&SecretPath>,
+) -> Result<Compared, SyncError> {
+    let Some(src_secret) = expected_secret(config, src_client, src, dst_config)? else {
+        return Ok(Compared::Skipped);
+    };
+    let blacklist = &config.src.blacklist;
+    let Some(dst) = dst else {
+        return Ok(Compared::Different(DiffKind::Missing));
+    };
+    let Some(mut dst_secret) = vault::read_secret(dst_client, &dst.mount, &dst.path).map_err(|error| SyncError::new(dst, &dst_config.host.url, error))? else {
+        return Ok(Compared::Different(DiffKind::Missing));
+    };
+    blacklist.strip(&src.path, &mut dst_secret);
+    // JSON objects are compared regardless of the key order
+    Ok(if src_secret == dst_secret { Compared::Same } else { Compared::Different(DiffKind::Mismatch) })
+}
+
+// Renders the summaries, and the differences with details, as tables with aligned columns
+pub fn render(diff: &Diff, details: bool) -> String {
+    let mut lines = vec![["DESTINATION", "MISSING", "EXTRA", "MISMATCH", "SAME"].map(String::from).to_vec()];
+    for summary in &diff.summaries {
+        lines.push(vec![
+            summary.destination.clone(),
+            summary.missing.to_string(),
+            summary.extra.to_string(),
+            summary.mismatch.to_string(),
+            summary.same.to_string(),
+        ]);
+    }
+    let mut text = table::render(&lines);
+    if details && !diff.is_empty() {
+        let mut lines = vec![["DIFFERENCE", "DESTINATION", "SECRET"].map(String::from).to_vec()];
+        for difference in &diff.differences {
+            let kind = match difference.kind {
+                DiffKind::Missing => "missing",
+                DiffKind::Extra => "extra",
+                DiffKind::Mismatch => "mismatch",
+            };
+            lines.push(vec![kind.to_string(), difference.destination.clone(), format!("{}/{}", &difference.mount, &difference.path)]);
+        }
+        text.push('\n');
+        text.push_str(&table::render(&lines));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Backend;
+    use crate::diff::{diff, render, DiffKind, Difference};
+    use crate::filter::{KeyBlacklist, KeyRule, PathFilter};
+    use crate::mock::{self, MockServer};
+
+    #[test]
+    fn test_diff() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["same","changed","missing","excluded","deleted","secret"]}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/same") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","b":"2"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/changed") => (200, r#"{"request_id":"1","data":{"data":{"a":"2"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/secret") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","password":"src"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/missing") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/dst/") => (200, r#"{"request_id":"1","data":{"keys":["same","changed","extra","excluded","secret"]}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/same") => (200, r#"{"request_id":"1","data":{"data":{"b":"2","a":"1"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/changed") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/secret") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","password":"dst"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = mock::config(&src.url, &dst.url);
+        config.src.filter = PathFilter::new(&[], &["^src/excluded".to_string()]).unwrap();
+        config.src.blacklist = KeyBlacklist::new(&[KeyRule { path: None, keys: vec!["password".to_string()] }]).unwrap();
+
+        let diff = diff(&config, &mock::client(&src), &[mock::client(&dst)]);
+        assert!(diff.complete);
+        let summary = &diff.summaries[0];
+        assert_eq!((summary.missing, summary.extra, summary.mismatch, summary.same), (1, 1, 1, 2));
+        let difference = |kind, path: &str| Difference { kind, destination: dst.url.clone(), mount: "secret".to_string(), path: path.to_string() };
+        assert_eq!(diff.differences, vec![
+            difference(DiffKind::Mismatch, "dst/changed"),
+            difference(DiffKind::Extra, "dst/extra"),
+            difference(DiffKind::Missing, "dst/missing"),
+        ]);
+        // Nothing is written
+        assert!(dst.requests().iter().all(|request| request.method == "GET" || request.method == "LIST"));
+
+        let text = render(&diff, true);
+        assert!(text.starts_with("DESTINATION"));
+        assert!(text.contains(&format!("mismatch    {}  secret/dst/changed\n", dst.url)));
+        assert!(!render(&diff, false).contains("DIFFERENCE"));
+
+        // Backends that are not in mounts are not compared
+        config.src.backend = Some(Backend::Backends(vec!["secret".to_string(), "other".to_string()]));
+        config.dst[0].backend = Some(Backend::Backends(vec!["secret".to_string(), "other".to_string()]));
+        config.src.mounts = vec!["secret".to_string()];
+        assert_eq!(crate::diff::diff(&config, &mock::client(&src), &[mock::client(&dst)]).summaries[0].same, 2);
+        assert_eq!(src.count("LIST", "/v1/other/metadata/src/"), 0);
+        assert_eq!(dst.count("LIST", "/v1/other/metadata/dst/"), 0);
+    }
+}
@@ -0,0 +1,99 @@
+// Sync actions written to stdout as one JSON object per line with --events-ndjson, for other tools
+// to process. The format is independent of the log format, logs go to stderr in this mode.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+use crate::health::unix_time;
+use crate::report::ActionKind;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Event<'a> {
+    // Unix time in seconds
+    pub timestamp: u64,
+    // Creates and updates are writes, the sync does not read the destination to tell them apart
+    pub action: ActionKind,
+    // Source secret with the mount, for example "secret/src/a"
+    pub path: String,
+    // URL of the source Vault
+    pub source: &'a str,
+    // URL of the destination Vault
+    pub destination: &'a str,
+    // Destination secret with the mount
+    pub destination_path: String,
+    pub result: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Ok,
+    Error,
+}
+
+pub fn init(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+impl Event<'_> {
+    pub fn new<'a>(
+        action: ActionKind,
+        path: String,
+        source: &'a str,
+        destination: &'a str,
+        destination_path: String,
+        error: Option<String>,
+    ) -> Event<'a> {
+        let result = if error.is_some() { Outcome::Error } else { Outcome::Ok };
+        Event { timestamp: unix_time(), action, path, source, destination, destination_path, result, error }
+    }
+
+    fn line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+// A single write per line, so events from different sync threads are not mixed
+pub fn emit(event: &Event) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(event.line().as_bytes());
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::{Event, Outcome};
+    use crate::report::ActionKind;
+
+    #[test]
+    fn test_event_line() {
+        let mut event = Event::new(
+            ActionKind::Write,
+            "secret/src/a".to_string(),
+            "http://src:8200/",
+            "http://dst:8200/",
+            "secret/dst/a".to_string(),
+            None,
+        );
+        event.timestamp = 1;
+        assert_eq!(event.result, Outcome::Ok);
+        assert_eq!(event.line(), "{\"timestamp\":1,\"action\":\"write\",\"path\":\"secret/src/a\",\"source\":\"http://src:8200/\",\
+            \"destination\":\"http://dst:8200/\",\"destination_path\":\"secret/dst/a\",\"result\":\"ok\"}\n");
+
+        let event = Event::new(ActionKind::Destroy, "secret/src/b".to_string(), "", "", "secret/dst/b".to_string(), Some("permission denied".to_string()));
+        assert_eq!(event.result, Outcome::Error);
+        assert!(event.line().contains(r#""action":"destroy""#));
+        assert!(event.line().ends_with("\"result\":\"error\",\"error\":\"permission denied\"}\n"));
+    }
+}
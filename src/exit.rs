@@ -0,0 +1,144 @@
+// Categories of the errors vault-sync exits with on start, each with its own exit status, so an
+// orchestrator can retry connection errors and alert on the others.
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+
+use hashicorp_vault::client::error::Error as VaultError;
+use reqwest::StatusCode;
+
+use crate::config::ConfigError;
+use crate::vault;
+
+#[derive(Debug)]
+pub enum StartupError {
+    // Invalid configuration file or command line options, or a configuration that does not match
+    // the Vaults, for example the secrets engine version
+    Config(Box<dyn Error>),
+    // Vault is unreachable, times out, is sealed or rate limits, also after startup_retry_secs
+    Connection(Box<dyn Error>),
+    // Login or token lookup is denied
+    Auth(Box<dyn Error>),
+    // The token is valid but misses capabilities
+    Permission(Box<dyn Error>),
+    // Everything else, for example a failed --sync-once
+    Other(Box<dyn Error>),
+}
+
+impl StartupError {
+    // Errors of vault_client: transient errors are connection errors, others are a denied login
+    pub fn login(error: VaultError) -> StartupError {
+        if vault::is_transient(&error) {
+            StartupError::Connection(error.into())
+        } else {
+            StartupError::Auth(error.into())
+        }
+    }
+
+    // Errors that are not already categorized by the type of the error. Vault errors after the
+    // login are connection errors if transient and permission errors on 403.
+    pub fn classify(error: Box<dyn Error>) -> StartupError {
+        let error = match error.downcast::<StartupError>() {
+            Ok(error) => return *error,
+            Err(error) => error,
+        };
+        if error.is::<ConfigError>() || error.is::<serde_yaml::Error>() {
+            return StartupError::Config(error);
+        }
+        match error.downcast_ref::<VaultError>() {
+            Some(vault_error) if vault::is_transient(vault_error) => StartupError::Connection(error),
+            Some(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::FORBIDDEN => StartupError::Permission(error),
+            _ => StartupError::Other(error),
+        }
+    }
+
+    pub fn category(&self) -> &'static str {
+        match self {
+            StartupError::Config(_) => "config",
+            StartupError::Connection(_) => "connection",
+            StartupError::Auth(_) => "auth",
+            StartupError::Permission(_) => "permission",
+            StartupError::Other(_) => "other",
+        }
+    }
+
+    // 3 is the exit status after --max-runtime
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::Other(_) => 1,
+            StartupError::Config(_) => 2,
+            StartupError::Connection(_) => 4,
+            StartupError::Auth(_) => 5,
+            StartupError::Permission(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::Config(error)
+            | StartupError::Connection(error)
+            | StartupError::Auth(error)
+            | StartupError::Permission(error)
+            | StartupError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for StartupError {
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::config::{ConfigError, EngineVersion, VaultAuthMethod, VaultHost};
+    use crate::exit::StartupError;
+    use crate::mock::{self, MockServer};
+    use crate::vault;
+
+    fn host(server: &MockServer) -> VaultHost {
+        VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::TokenAuth { token: "token".to_string() }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let error = StartupError::classify(ConfigError::InvalidHeartbeatSecs.into());
+        assert_eq!((error.category(), error.exit_code()), ("config", 2));
+        assert_eq!(error.to_string(), ConfigError::InvalidHeartbeatSecs.to_string());
+
+        let error: Box<dyn Error> = StartupError::Permission("missing capabilities".into()).into();
+        assert_eq!(StartupError::classify(error).category(), "permission");
+        assert_eq!(StartupError::classify("full sync failed".into()).exit_code(), 1);
+
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/sys/mounts") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                _ => (503, r#"{"errors":["Vault is sealed"]}"#.to_string()),
+            }
+        });
+        let mut client = vault::vault_client(&host(&server), &EngineVersion::V2).unwrap();
+        let error = vault::mount_types(&client).unwrap_err();
+        assert_eq!(StartupError::classify(error.into()).exit_code(), 6);
+        let error = vault::check_list(&mut client, "secret", "src/").unwrap_err();
+        assert_eq!(StartupError::classify(error.into()).exit_code(), 4);
+    }
+
+    #[test]
+    fn test_login() {
+        let server = MockServer::start(|_| (403, r#"{"errors":["permission denied"]}"#.to_string()));
+        let error = vault::vault_client(&host(&server), &EngineVersion::V2).unwrap_err();
+        assert_eq!(StartupError::login(error).category(), "auth");
+
+        server.stop();
+        let error = vault::vault_client(&host(&server), &EngineVersion::V2).unwrap_err();
+        assert_eq!(StartupError::login(error).category(), "connection");
+    }
+}
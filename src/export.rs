@@ -0,0 +1,294 @@
+// Snapshots of the source secrets in a local directory, written with --export and synced to the
+// destinations with --import, for example to move secrets between air-gapped Vaults. Every secret
+// is a JSON file <dir>/<mount>/<path>.json with its data, KV v2 secrets also have a sibling
+// <path>.metadata.json with the metadata settings.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{error, info, warn};
+use serde_json::Value;
+
+use crate::config::{get_prefixes, EngineVersion, VaultSyncConfig};
+use crate::sync::{list_all, with_retries, write_secret, PathMap, SecretPath};
+use crate::vault::{self, MetadataSettings, VaultClient};
+
+const DATA_EXTENSION: &str = ".json";
+const METADATA_EXTENSION: &str = ".metadata.json";
+
+// Writes the secrets that the full sync would write, filtered and without blacklisted keys, to the
+// directory, which must be empty or not exist. Files are readable by the owner only. Returns the
+// number of secrets, or an error if some secrets could not be listed or read.
+pub fn export(config: &VaultSyncConfig, src_client: &Arc<Mutex<VaultClient>>, dir: &str) -> Result<u64, Box<dyn Error>> {
+    if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(format!("Export directory {} is not empty", dir).into());
+    }
+    let mut complete = true;
+    let mut exported = 0;
+    for backend in config.src.synced_backends() {
+        let mut secrets = Vec::new();
+        for prefix in get_prefixes(&config.src.prefix) {
+            complete &= list_all(&prefix, &backend, &config.src.host.url, src_client.clone(), |secret| secrets.push(secret));
+        }
+        for secret in secrets {
+            if !config.src.filter.matches(&secret) {
+                continue;
+            }
+            let secret = SecretPath { mount: backend.clone(), path: secret };
+            // A secret named like the metadata of another one would be imported as its metadata
+            if config.src.version == EngineVersion::V2 && secret.path.ends_with(".metadata") {
+                warn!(secret = secret.path.as_str(); "Secret {}/{} cannot be exported next to metadata files, skipping", &secret.mount, &secret.path);
+                complete = false;
+                continue;
+            }
+            match export_secret(config, src_client, &secret, dir) {
+                Ok(true) => exported += 1,
+                Ok(false) => {},
+                Err(error) => {
+                    warn!(secret = secret.path.as_str(); "Failed to export secret {}/{}: {}", &secret.mount, &secret.path, error);
+                    complete = false;
+                },
+            }
+        }
+    }
+    info!("Exported {} secrets to {}", exported, dir);
+    if !complete {
+        return Err("not all secrets could be listed or exported".into());
+    }
+    Ok(exported)
+}
+
+// Returns false if the secret is deleted or has only blacklisted keys, like in the sync
+fn export_secret(config: &VaultSyncConfig, src_client: &Arc<Mutex<VaultClient>>, secret: &SecretPath, dir: &str) -> Result<bool, Box<dyn Error>> {
+    let Some(mut data) = vault::read_secret(src_client, &secret.mount, &secret.path)? else {
+        return Ok(false);
+    };
+    if config.src.blacklist.strip(&secret.path, &mut data) && data.as_object().is_some_and(|data| data.is_empty()) {
+        return Ok(false);
+    }
+    let base = file_path(dir, secret)?;
+    write_file(&base, DATA_EXTENSION, &data)?;
+    if config.src.version == EngineVersion::V2 {
+        if let Some(metadata) = vault::read_metadata(&src_client.lock().unwrap(), &secret.mount, &secret.path)? {
+            write_file(&base, METADATA_EXTENSION, &serde_json::to_value(MetadataSettings::from(&metadata))?)?;
+        }
+    }
+    Ok(true)
+}
+
+// <dir>/<mount>/<path> without the extension. Secret paths come from Vault, but they must not
+// point outside of the directory.
+fn file_path(dir: &str, secret: &SecretPath) -> Result<PathBuf, Box<dyn Error>> {
+    let relative = format!("{}/{}", &secret.mount, &secret.path);
+    if relative.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+        return Err(format!("{} is not a valid file path", relative).into());
+    }
+    Ok(Path::new(dir).join(relative))
+}
+
+fn write_file(base: &Path, extension: &str, value: &Value) -> Result<(), Box<dyn Error>> {
+    let mut file_name = base.as_os_str().to_owned();
+    file_name.push(extension);
+    if let Some(parent) = base.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+    }
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&file_name)?;
+    file.write_all(serde_json::to_string_pretty(value)?.as_bytes())?;
+    file.write_all(b"\n")?;
+    Ok(())
+}
+
+// Writes the secrets from the export directory to all destinations, mapped with the prefixes and
+// rewrites like in the sync. The destination secrets keep their own values of the blacklisted keys.
+// Metadata is written to KV v2 destinations. Returns the number of written secrets, or an error if
+// some could not be read or written.
+pub fn import(config: &VaultSyncConfig, dst_clients: &[Arc<Mutex<VaultClient>>], dir: &str) -> Result<u64, Box<dyn Error>> {
+    let mut files = Vec::new();
+    walk(Path::new(dir), &mut files)?;
+    files.sort();
+    let names: HashSet<&PathBuf> = files.iter().collect();
+    let backends = config.src.synced_backends();
+    let mut complete = true;
+    let mut imported = 0;
+    for file in &files {
+        let relative = file.strip_prefix(dir)?.to_string_lossy().replace('\\', "/");
+        let Some(base) = relative.strip_suffix(DATA_EXTENSION) else {
+            continue;
+        };
+        // Metadata files have a data file next to them
+        if let Some(data_base) = base.strip_suffix(".metadata") {
+            if names.contains(&Path::new(dir).join(format!("{}{}", data_base, DATA_EXTENSION))) {
+                continue;
+            }
+        }
+        // Mounts can have slashes, the longest matching one is used
+        let Some(mount) = backends.iter().filter(|backend| base.starts_with(&format!("{}/", backend))).max_by_key(|backend| backend.len()) else {
+            warn!("{} is not in one of the synced source backends {:?}, skipping", file.display(), &backends);
+            continue;
+        };
+        let secret = SecretPath { mount: mount.clone(), path: base[mount.len() + 1..].to_string() };
+        if !config.src.filter.matches(&secret.path) {
+            continue;
+        }
+        let metadata_file = Path::new(dir).join(format!("{}{}", base, METADATA_EXTENSION));
+        match import_secret(config, dst_clients, &secret, file, &metadata_file) {
+            Ok(()) => imported += 1,
+            Err(error) => {
+                warn!(secret = secret.path.as_str(); "Failed to import secret {}/{}: {}", &secret.mount, &secret.path, error);
+                complete = false;
+            },
+        }
+    }
+    info!("Imported {} secrets from {}", imported, dir);
+    if !complete {
+        return Err("not all secrets could be imported".into());
+    }
+    Ok(imported)
+}
+
+fn import_secret(
+    config: &VaultSyncConfig,
+    dst_clients: &[Arc<Mutex<VaultClient>>],
+    secret: &SecretPath,
+    file: &Path,
+    metadata_file: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut data: Value = serde_json::from_str(&fs::read_to_string(file)?)?;
+    // For an export written with other key_blacklist rules
+    config.src.blacklist.strip(&secret.path, &mut data);
+    let keys = config.src.blacklist.keys(&secret.path);
+    let metadata: Option<MetadataSettings> = match fs::read_to_string(metadata_file) {
+        Ok(text) => Some(serde_json::from_str(&text)?),
+        // KV v1 secrets have no metadata
+        Err(error) if error.kind() == ErrorKind::NotFound => None,
+        Err(error) => return Err(format!("Failed to read {}: {}", metadata_file.display(), error).into()),
+    };
+    for (dst_config, dst_client) in config.dst.iter().zip(dst_clients) {
+        if !dst_config.in_canary(&secret.path) {
+            continue;
+        }
+        let paths = PathMap::new(&config.src, dst_config);
+        let custom_metadata = metadata.as_ref().map(|metadata| metadata.custom_metadata.clone()).unwrap_or_default();
+        let Some(dst_path) = paths.dst_path_with(&secret.path, &custom_metadata) else {
+            info!(secret = secret.path.as_str(); "Secret {} has no custom_metadata for path_template of {}, skipping", &secret.path, &dst_config.host.url);
+            continue;
+        };
+        let dst = SecretPath { mount: paths.mounts[&secret.mount].clone(), path: dst_path };
+        if !dst_config.is_allowed(&dst.path) {
+            error!(secret = dst.path.as_str(), host = dst_config.host.url.as_str();
+                "Secret {}/{} is not under allowed_dst_prefixes of {}, rejecting", &dst.mount, &dst.path, &dst_config.host.url);
+            return Err("rejected by allowed_dst_prefixes".into());
+        }
+        let mut data = data.clone();
+        dst_config.transformer.apply(&secret.path, &mut data);
+        if !keys.is_empty() {
+            let dst_secret = with_retries(config.max_retries, &dst, &dst_config.host.url, || {
+                vault::read_secret(dst_client, &dst.mount, &dst.path)
+            })?;
+            if let (Value::Object(data), Some(Value::Object(dst_data))) = (&mut data, &dst_secret) {
+                for key in &keys {
+                    if let Some(value) = dst_data.get(*key) {
+                        data.insert(key.to_string(), value.clone());
+                    }
+                }
+            }
+        }
+        info!(secret = dst.path.as_str(); "Creating/updating secret {}", &dst.path);
+        with_retries(config.max_retries, &dst, &dst_config.host.url, || {
+            let mut client = dst_client.lock().unwrap().clone_without_data();
+            client.secret_backend(&dst.mount);
+            write_secret(&client, &dst, &data, dst_config)
+        })?;
+        if let (Some(metadata), EngineVersion::V2) = (&metadata, &dst_config.version) {
+            with_retries(config.max_retries, &dst, &dst_config.host.url, || {
+                vault::write_metadata(&dst_client.lock().unwrap(), &dst.mount, &dst.path, metadata)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir).map_err(|error| format!("Failed to read {}: {}", dir.display(), error))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use serde_json::Value;
+
+    use crate::export::{export, import};
+    use crate::filter::{KeyBlacklist, KeyRule};
+    use crate::mock::{self, MockServer};
+
+    #[test]
+    fn test_export_import() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a","team/","deleted"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/src/team/") => (200, r#"{"request_id":"1","data":{"keys":["b"]}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/a") => (200, r#"{"request_id":"1","data":{"data":{"key":"a"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/team/b") => (200, r#"{"request_id":"1","data":{"data":{"key":"b"}}}"#.to_string()),
+                ("GET", "/v1/secret/metadata/src/a") | ("GET", "/v1/secret/metadata/src/team/b") => (200, r#"{"request_id":"1","data":{
+                    "current_version":1,"max_versions":5,"delete_version_after":"0s","custom_metadata":{"owner":"team"},"versions":{}
+                }}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/dst/a") => (200, r#"{"request_id":"1","data":{"data":{"key":"old","token":"dst"}}}"#.to_string()),
+                ("PUT", _) | ("POST", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = mock::config(&src.url, &dst.url);
+        config.src.prefix = vec!["src/".to_string()];
+        config.dst[0].prefix = vec!["dst/".to_string()];
+        config.src.blacklist = KeyBlacklist::new(&[KeyRule { path: None, keys: vec!["token".to_string()] }]).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("vault-sync-export-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir_name = dir.to_str().unwrap();
+        assert_eq!(export(&config, &mock::client(&src), dir_name).unwrap(), 2);
+        let data: Value = serde_json::from_str(&fs::read_to_string(dir.join("secret/src/team/b.json")).unwrap()).unwrap();
+        assert_eq!(data, serde_json::json!({"key": "b"}));
+        let metadata: Value = serde_json::from_str(&fs::read_to_string(dir.join("secret/src/a.metadata.json")).unwrap()).unwrap();
+        assert_eq!(metadata["custom_metadata"]["owner"], "team");
+        assert_eq!(fs::metadata(dir.join("secret/src/a.json")).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!dir.join("secret/src/deleted.json").exists());
+        // Secrets removed in the source would stay in an old export
+        assert!(export(&config, &mock::client(&src), dir_name).is_err());
+
+        assert_eq!(import(&config, &[mock::client(&dst)], dir_name).unwrap(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+        let request = dst.requests().into_iter().find(|r| r.method == "PUT" && r.path == "/v1/secret/data/dst/team/b").unwrap();
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["data"], serde_json::json!({"key": "b"}));
+        // The destination keeps its own value of the blacklisted key
+        let request = dst.requests().into_iter().find(|r| r.method == "PUT" && r.path == "/v1/secret/data/dst/a").unwrap();
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["data"], serde_json::json!({"key": "a", "token": "dst"}));
+        let request = dst.requests().into_iter().find(|r| r.method == "POST" && r.path == "/v1/secret/metadata/dst/a").unwrap();
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["max_versions"], 5);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/a.metadata"), 0);
+    }
+}
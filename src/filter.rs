@@ -0,0 +1,276 @@
+use std::fmt;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::{ConfigError, WatchedOperation};
+
+// Entry of the include list of the source, a pattern, or a pattern with the audit log operations
+// that are synced for the matching secrets
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum IncludeRule {
+    Pattern(String),
+    Rule {
+        pattern: String,
+        operations: Vec<WatchedOperation>,
+    },
+}
+
+impl IncludeRule {
+    pub fn pattern(&self) -> &str {
+        match self {
+            IncludeRule::Pattern(pattern) | IncludeRule::Rule { pattern, .. } => pattern,
+        }
+    }
+
+    // None if the rule does not limit the operations
+    pub fn operations(&self) -> Option<&[WatchedOperation]> {
+        match self {
+            IncludeRule::Pattern(_) => None,
+            IncludeRule::Rule { operations, .. } => Some(operations),
+        }
+    }
+}
+
+// Include and exclude filters for secret paths. A secret is synced if its path matches at least
+// one include pattern (or there are no include patterns) and does not match any exclude pattern.
+// Exclude wins over include.
+#[derive(Clone, Default)]
+pub struct PathFilter {
+    include: Vec<Regex>,
+    // Operations of the include patterns, in the same order, None for all operations
+    operations: Vec<Option<Vec<WatchedOperation>>>,
+    exclude: Vec<Regex>,
+    // Prefix of the matched paths and the prefix the patterns are written for, see with_prefix
+    prefix: Option<(String, String)>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter, ConfigError> {
+        Ok(PathFilter {
+            include: compile(include)?,
+            operations: vec![None; include.len()],
+            exclude: compile(exclude)?,
+            prefix: None,
+        })
+    }
+
+    pub fn from_rules(include: &[IncludeRule], exclude: &[String]) -> Result<PathFilter, ConfigError> {
+        let patterns: Vec<String> = include.iter().map(|rule| rule.pattern().to_string()).collect();
+        Ok(PathFilter {
+            operations: include.iter().map(|rule| rule.operations().map(<[WatchedOperation]>::to_vec)).collect(),
+            ..PathFilter::new(&patterns, exclude)?
+        })
+    }
+
+    // Returns the same filter for paths with another prefix, for example destination paths in
+    // bidirectional mode: "dst/secret" is matched as "src/secret". Prefixes must be normalized!
+    pub fn with_prefix(&self, prefix: &str, original_prefix: &str) -> PathFilter {
+        PathFilter {
+            prefix: Some((prefix.to_string(), original_prefix.to_string())),
+            ..self.clone()
+        }
+    }
+
+    // Path is the secret path in the secrets engine, for example "src/path/to/secret"
+    pub fn matches(&self, path: &str) -> bool {
+        let path = self.original_path(path);
+        if self.exclude.iter().any(|re| re.is_match(&path)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|re| re.is_match(&path))
+    }
+
+    // Same as matches, and at least one of the matching include patterns syncs the operation
+    pub fn watches(&self, path: &str, operation: &WatchedOperation) -> bool {
+        if !self.matches(path) {
+            return false;
+        }
+        let path = self.original_path(path);
+        self.include.is_empty() || self.include.iter().zip(&self.operations)
+            .any(|(re, operations)| re.is_match(&path) && operations.as_ref().is_none_or(|operations| operations.contains(operation)))
+    }
+
+    fn original_path(&self, path: &str) -> String {
+        match &self.prefix {
+            Some((prefix, original_prefix)) => match path.strip_prefix(prefix.as_str()) {
+                Some(path) => format!("{}{}", original_prefix, path),
+                None => path.to_string(),
+            },
+            None => path.to_string(),
+        }
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Regex>, ConfigError> {
+    patterns.iter()
+        .map(|pattern| Regex::new(pattern).map_err(|error| ConfigError::InvalidPattern(pattern.clone(), error.to_string())))
+        .collect()
+}
+
+impl PartialEq for PathFilter {
+    fn eq(&self, other: &Self) -> bool {
+        let patterns = |regexes: &[Regex]| regexes.iter().map(|re| re.as_str().to_string()).collect::<Vec<_>>();
+        patterns(&self.include) == patterns(&other.include)
+            && self.operations == other.operations
+            && patterns(&self.exclude) == patterns(&other.exclude)
+            && self.prefix == other.prefix
+    }
+}
+
+impl fmt::Debug for PathFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PathFilter")
+            .field("include", &self.include.iter().map(|re| re.as_str()).collect::<Vec<_>>())
+            .field("operations", &self.operations)
+            .field("exclude", &self.exclude.iter().map(|re| re.as_str()).collect::<Vec<_>>())
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+// Rule of the key_blacklist list of the source
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KeyRule {
+    // Regex matched against the secret path like include, all secrets if not set
+    pub path: Option<String>,
+    // Top-level keys of the secret data
+    pub keys: Vec<String>,
+}
+
+// Keys of the secret data that are not synced, for example local-only tokens. Every rule with a
+// matching path adds its keys.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct KeyBlacklist {
+    rules: Vec<(PathFilter, Vec<String>)>,
+}
+
+impl KeyBlacklist {
+    pub fn new(rules: &[KeyRule]) -> Result<KeyBlacklist, ConfigError> {
+        let rules = rules.iter()
+            .map(|rule| {
+                let include: Vec<String> = rule.path.iter().cloned().collect();
+                Ok((PathFilter::new(&include, &[])?, rule.keys.clone()))
+            })
+            .collect::<Result<_, ConfigError>>()?;
+        Ok(KeyBlacklist { rules })
+    }
+
+    // Same as PathFilter::with_prefix
+    pub fn with_prefix(&self, prefix: &str, original_prefix: &str) -> KeyBlacklist {
+        KeyBlacklist {
+            rules: self.rules.iter().map(|(filter, keys)| (filter.with_prefix(prefix, original_prefix), keys.clone())).collect(),
+        }
+    }
+
+    // Blacklisted keys for the secret path, for example "src/path/to/secret"
+    pub fn keys(&self, path: &str) -> Vec<&str> {
+        self.rules.iter()
+            .filter(|(filter, _)| filter.matches(path))
+            .flat_map(|(_, keys)| keys.iter().map(String::as_str))
+            .collect()
+    }
+
+    // Removes the blacklisted keys from the secret data, returns true if some were removed
+    pub fn strip(&self, path: &str, data: &mut Value) -> bool {
+        let Value::Object(map) = data else {
+            return false;
+        };
+        let mut stripped = false;
+        for key in self.keys(path) {
+            stripped |= map.remove(key).is_some();
+        }
+        stripped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::config::WatchedOperation;
+    use crate::filter::{IncludeRule, KeyBlacklist, KeyRule, PathFilter};
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let include: Vec<String> = include.iter().map(|s| s.to_string()).collect();
+        let exclude: Vec<String> = exclude.iter().map(|s| s.to_string()).collect();
+        PathFilter::new(&include, &exclude).unwrap()
+    }
+
+    #[test]
+    fn test_no_patterns() {
+        assert!(filter(&[], &[]).matches("src/secret"));
+    }
+
+    #[test]
+    fn test_include() {
+        let filter = filter(&["^src/team-a/", "^src/team-b/"], &[]);
+        assert!(filter.matches("src/team-a/secret"));
+        assert!(filter.matches("src/team-b/secret"));
+        assert!(!filter.matches("src/team-c/secret"));
+    }
+
+    #[test]
+    fn test_exclude_wins() {
+        let filter = filter(&["^src/team-a/"], &["/local$"]);
+        assert!(filter.matches("src/team-a/secret"));
+        assert!(!filter.matches("src/team-a/local"));
+        assert!(!filter.matches("src/team-b/secret"));
+    }
+
+    #[test]
+    fn test_include_operations() {
+        let filter = PathFilter::from_rules(&[
+            IncludeRule::Pattern("^src/team-a/".to_string()),
+            IncludeRule::Rule { pattern: "^src/team-b/".to_string(), operations: vec![WatchedOperation::Create, WatchedOperation::Update] },
+            IncludeRule::Rule { pattern: "^src/team-b/shared/".to_string(), operations: vec![WatchedOperation::Delete] },
+        ], &["/local$".to_string()]).unwrap();
+        assert!(filter.watches("src/team-a/secret", &WatchedOperation::Delete));
+        assert!(filter.watches("src/team-b/secret", &WatchedOperation::Update));
+        assert!(!filter.watches("src/team-b/secret", &WatchedOperation::Delete));
+        // Any of the matching rules
+        assert!(filter.watches("src/team-b/shared/secret", &WatchedOperation::Delete));
+        assert!(!filter.watches("src/team-b/local", &WatchedOperation::Create));
+        assert!(!filter.watches("src/team-c/secret", &WatchedOperation::Create));
+        assert!(filter.matches("src/team-b/secret"));
+
+        let filter = filter.with_prefix("dst/", "src/");
+        assert!(!filter.watches("dst/team-b/secret", &WatchedOperation::Delete));
+        assert!(filter.watches("dst/team-a/secret", &WatchedOperation::Delete));
+    }
+
+    #[test]
+    fn test_with_prefix() {
+        let filter = filter(&["^src/team-a/"], &[]).with_prefix("dst/", "src/");
+        assert!(filter.matches("dst/team-a/secret"));
+        assert!(!filter.matches("dst/team-b/secret"));
+        assert!(!filter.matches("other/team-a/secret"));
+    }
+
+    #[test]
+    fn test_key_blacklist() {
+        let blacklist = KeyBlacklist::new(&[
+            KeyRule { path: None, keys: vec!["bootstrap_token".to_string()] },
+            KeyRule { path: Some("^src/team-a/".to_string()), keys: vec!["local".to_string()] },
+        ]).unwrap();
+        assert_eq!(blacklist.keys("src/team-a/secret"), vec!["bootstrap_token", "local"]);
+        assert_eq!(blacklist.keys("src/team-b/secret"), vec!["bootstrap_token"]);
+
+        let mut data = json!({"bootstrap_token": "t", "local": "l", "password": "p"});
+        assert!(blacklist.strip("src/team-b/secret", &mut data));
+        assert_eq!(data, json!({"local": "l", "password": "p"}));
+        assert!(!blacklist.strip("src/team-b/secret", &mut data));
+
+        let blacklist = blacklist.with_prefix("dst/", "src/");
+        assert!(blacklist.strip("dst/team-a/secret", &mut data));
+        assert_eq!(data, json!({"password": "p"}));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let result = PathFilter::new(&["src/(".to_string()], &[]);
+        assert!(result.unwrap_err().to_string().contains("src/("));
+    }
+}
@@ -0,0 +1,189 @@
+// Liveness and readiness state, served on health_bind
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time;
+
+use crate::metrics::METRICS;
+use crate::pause::PAUSE;
+
+pub struct Health {
+    // Vault URL and Unix time in seconds when its token expires, 0 if the token does not expire
+    tokens: Mutex<Vec<(String, Arc<AtomicU64>)>>,
+    // Set when the audit log listener stops accepting connections
+    pub log_sync_stopped: AtomicBool,
+    // URLs of the destinations that are standby nodes, the writes wait until they are active
+    standby: Mutex<Vec<String>>,
+    // URLs of the Vaults with token renewals failing for longer than token_renew_grace
+    renewal_failing: Mutex<Vec<String>>,
+}
+
+pub static HEALTH: Health = Health::new();
+
+impl Health {
+    const fn new() -> Health {
+        Health {
+            tokens: Mutex::new(Vec::new()),
+            log_sync_stopped: AtomicBool::new(false),
+            standby: Mutex::new(Vec::new()),
+            renewal_failing: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Returns the token expiry for the token worker of the Vault host
+    pub fn register_token(&self, url: &str) -> Arc<AtomicU64> {
+        let expiry = Arc::new(AtomicU64::new(0));
+        self.tokens.lock().unwrap().push((url.to_string(), expiry.clone()));
+        expiry
+    }
+
+    // Vault URLs and Unix times when the tokens expire
+    pub fn tokens(&self) -> Vec<(String, u64)> {
+        self.tokens.lock().unwrap().iter()
+            .map(|(url, expiry)| (url.clone(), expiry.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    // Returns true if the standby state of the destination is changed
+    pub fn set_standby(&self, url: &str, standby: bool) -> bool {
+        set_url(&self.standby, url, standby)
+    }
+
+    // Returns true if the renewal state of the Vault is changed
+    pub fn set_renewal_failing(&self, url: &str, failing: bool) -> bool {
+        set_url(&self.renewal_failing, url, failing)
+    }
+
+    pub fn renewal_failing(&self) -> Vec<String> {
+        self.renewal_failing.lock().unwrap().clone()
+    }
+
+    pub fn standby(&self) -> Vec<String> {
+        self.standby.lock().unwrap().clone()
+    }
+
+    // Vault URLs of the expired tokens
+    pub fn expired_tokens(&self, now: u64) -> Vec<String> {
+        self.tokens.lock().unwrap().iter()
+            .filter(|(_, expiry)| expired(expiry, now))
+            .map(|(url, _)| url.clone())
+            .collect()
+    }
+
+    // Returns the reason if vault-sync is not alive
+    fn live(&self, now: u64) -> Result<(), String> {
+        if let Some(url) = self.expired_tokens(now).first() {
+            return Err(format!("token for {} expired", url));
+        }
+        if self.log_sync_stopped.load(Ordering::Relaxed) {
+            return Err("audit log listener stopped".to_string());
+        }
+        if let Some(url) = self.renewal_failing().first() {
+            return Err(format!("token renewal for {} failing", url));
+        }
+        Ok(())
+    }
+
+    // Ready also requires at least one finished full sync
+    fn ready(&self, now: u64, full_sync_runs: u64) -> Result<(), String> {
+        self.live(now)?;
+        if full_sync_runs == 0 {
+            return Err("full sync not finished".to_string());
+        }
+        if let Some(url) = self.standby.lock().unwrap().first() {
+            return Err(format!("destination {} is a standby node", url));
+        }
+        Ok(())
+    }
+}
+
+// Adds or removes the URL, returns true if the list is changed
+fn set_url(urls: &Mutex<Vec<String>>, url: &str, present: bool) -> bool {
+    let mut urls = urls.lock().unwrap();
+    let index = urls.iter().position(|other| other == url);
+    match (index, present) {
+        (None, true) => urls.push(url.to_string()),
+        (Some(index), false) => {
+            urls.remove(index);
+        },
+        _ => return false,
+    }
+    true
+}
+
+fn expired(expiry: &AtomicU64, now: u64) -> bool {
+    let expiry = expiry.load(Ordering::Relaxed);
+    expiry != 0 && expiry <= now
+}
+
+// Unix time of the token expiry, for the token with the given TTL obtained or renewed now
+pub fn token_expiry(ttl: Option<time::Duration>) -> u64 {
+    match ttl {
+        Some(ttl) => unix_time() + ttl.as_secs(),
+        None => 0,
+    }
+}
+
+pub fn unix_time() -> u64 {
+    time::SystemTime::now().duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// Handler for http::serve
+pub fn handle(method: &str, path: &str) -> (u16, String) {
+    let now = unix_time();
+    let result = match (method, path) {
+        ("GET", "/healthz") | ("GET", "/livez") => HEALTH.live(now),
+        ("GET", "/readyz") => HEALTH.ready(now, METRICS.full_sync_runs.load(Ordering::Relaxed)),
+        _ => return (404, "Not found\n".to_string()),
+    };
+    match result {
+        // Paused is still alive and ready, the status tells why nothing is written
+        Ok(_) if PAUSE.is_paused() => (200, "ok, sync paused\n".to_string()),
+        Ok(_) => (200, "ok\n".to_string()),
+        Err(reason) => (503, format!("{}\n", reason)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use crate::health::Health;
+
+    #[test]
+    fn test_live_and_ready() {
+        let health = Health::new();
+        assert!(health.live(1000).is_ok());
+        assert_eq!(health.ready(1000, 0), Err("full sync not finished".to_string()));
+        assert!(health.ready(1000, 1).is_ok());
+
+        let src_token = health.register_token("http://127.0.0.1:8200/");
+        let dst_token = health.register_token("http://127.0.0.1:8201/");
+        src_token.store(3000, Ordering::Relaxed);
+        dst_token.store(2000, Ordering::Relaxed);
+        assert!(health.live(1000).is_ok());
+        assert_eq!(health.live(2000), Err("token for http://127.0.0.1:8201/ expired".to_string()));
+        assert_eq!(health.expired_tokens(2000), ["http://127.0.0.1:8201/"]);
+        assert_eq!(health.expired_tokens(3000).len(), 2);
+        assert_eq!(health.ready(2000, 1), Err("token for http://127.0.0.1:8201/ expired".to_string()));
+
+        src_token.store(0, Ordering::Relaxed);
+        dst_token.store(0, Ordering::Relaxed);
+        health.log_sync_stopped.store(true, Ordering::Relaxed);
+        assert_eq!(health.live(1000), Err("audit log listener stopped".to_string()));
+        health.log_sync_stopped.store(false, Ordering::Relaxed);
+
+        assert!(health.set_standby("http://127.0.0.1:8201/", true));
+        assert!(!health.set_standby("http://127.0.0.1:8201/", true));
+        assert!(health.live(1000).is_ok());
+        assert_eq!(health.ready(1000, 1), Err("destination http://127.0.0.1:8201/ is a standby node".to_string()));
+        assert!(health.set_standby("http://127.0.0.1:8201/", false));
+        assert!(!health.set_standby("http://127.0.0.1:8201/", false));
+        assert!(health.ready(1000, 1).is_ok());
+
+        assert!(health.set_renewal_failing("http://127.0.0.1:8200/", true));
+        assert_eq!(health.live(1000), Err("token renewal for http://127.0.0.1:8200/ failing".to_string()));
+        assert!(health.set_renewal_failing("http://127.0.0.1:8200/", false));
+        assert!(health.live(1000).is_ok());
+    }
+}
@@ -0,0 +1,60 @@
+// Periodic log that vault-sync is alive, for deployments without the metrics and health endpoints
+
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time;
+
+use log::{info, warn};
+
+use crate::admin::STATUS;
+use crate::config::SharedConfig;
+use crate::health::{unix_time, HEALTH};
+use crate::metrics::METRICS;
+
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// Logs every heartbeat_secs, and warns for every expired token. The interval can be reloaded, the
+// log is off if it is not set.
+pub fn heartbeat_worker(config: &SharedConfig) {
+    let mut last = unix_time();
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+        let Some(interval) = config.read().unwrap().heartbeat_secs else {
+            continue;
+        };
+        let now = unix_time();
+        if now.saturating_sub(last) < interval {
+            continue;
+        }
+        last = now;
+        info!("{}", message(
+            now,
+            STATUS.last_audit_entry.load(Ordering::Relaxed),
+            METRICS.last_full_sync.load(Ordering::Relaxed),
+            METRICS.queue_depth.load(Ordering::Relaxed),
+        ));
+        for url in HEALTH.expired_tokens(now) {
+            warn!(host = url.as_str(); "Token for {} has expired", &url);
+        }
+    }
+}
+
+// Times are Unix times in seconds, 0 if it has not happened yet
+fn message(now: u64, last_audit_entry: u64, last_full_sync: u64, queue_depth: i64) -> String {
+    let ago = |time: u64| if time == 0 { "never".to_string() } else { format!("{}s ago", now.saturating_sub(time)) };
+    format!(
+        "Alive, last audit log entry {}, last full sync finished {}, queue depth {}",
+        ago(last_audit_entry), ago(last_full_sync), queue_depth,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heartbeat::message;
+
+    #[test]
+    fn test_message() {
+        assert_eq!(message(1000, 0, 0, 0), "Alive, last audit log entry never, last full sync finished never, queue depth 0");
+        assert_eq!(message(1000, 990, 700, 3), "Alive, last audit log entry 10s ago, last full sync finished 300s ago, queue depth 3");
+    }
+}
@@ -0,0 +1,127 @@
+// Minimal HTTP server for the metrics, health and admin endpoints
+
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{thread, time};
+
+use log::{debug, info, warn};
+
+// Serves requests until stop is set. For every request, the handler gets the method and the path
+// and returns an HTTP status and a response body. The listener is polled, so the server notices
+// stop without a new connection.
+pub fn serve<F>(name: &str, listener: TcpListener, stop: Arc<AtomicBool>, handler: F)
+    where
+        F: Fn(&str, &str) -> (u16, String),
+{
+    if let Err(error) = listener.set_nonblocking(true) {
+        warn!("Failed to configure {} listener: {}", name, error);
+        return;
+    }
+    info!("{} worker started", name);
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => handle(stream, &handler),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(time::Duration::from_millis(100));
+            },
+            Err(error) => {
+                warn!("{} connection failed: {}", name, error);
+            }
+        }
+    }
+    info!("{} worker stopped", name);
+}
+
+fn handle<F: Fn(&str, &str) -> (u16, String)>(stream: TcpStream, handler: &F) {
+    if stream.set_nonblocking(false).is_err() {
+        return;
+    }
+    let _ = stream.set_read_timeout(Some(time::Duration::from_secs(5)));
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    // Skip headers
+    loop {
+        let mut header = String::new();
+        match reader.read_line(&mut header) {
+            Ok(0) => break,
+            Ok(_) if header.trim().is_empty() => break,
+            Ok(_) => {},
+            Err(_) => return,
+        }
+    }
+    debug!("HTTP request: {}", line.trim());
+    let mut parts = line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => handler(method, path),
+        _ => (400, "Bad request\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body,
+    );
+    let _ = (&stream).write_all(response.as_bytes());
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use crate::http::serve;
+
+    fn get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            serve("Test", listener, worker_stop, |method, path| {
+                match (method, path) {
+                    ("GET", "/ok") => (200, "ok\n".to_string()),
+                    ("GET", "/down") => (503, "down\n".to_string()),
+                    _ => (404, "Not found\n".to_string()),
+                }
+            })
+        });
+
+        let response = get(&addr, "/ok");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok\n"));
+        assert!(get(&addr, "/down").starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(get(&addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+}
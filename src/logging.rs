@@ -0,0 +1,173 @@
+// Logger that writes one JSON object per line, for --log-format json, and the log levels of
+// --log-level or RUST_LOG for both log formats
+
+use std::io::Write;
+use std::str::FromStr;
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use log::kv::{Error as KvError, Key, Value as KvValue, VisitSource};
+use serde_json::{Map, Value};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+// Levels in the RUST_LOG format: a default level and levels for targets, for example
+// "warn,vault_sync::sync=debug". Targets are module paths, "sync" is the same as "vault_sync::sync".
+#[derive(Debug, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    pub fn parse(spec: &str) -> Result<LogFilter, String> {
+        let mut filter = LogFilter { default: LevelFilter::Info, targets: Vec::new() };
+        for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let level = |level: &str| LevelFilter::from_str(level).map_err(|_| format!("Invalid log level {:?}", level));
+            match directive.split_once('=') {
+                Some((target, value)) => filter.targets.push((target.trim().to_string(), level(value.trim())?)),
+                None => filter.default = level(directive)?,
+            }
+        }
+        // The longest matching target wins
+        filter.targets.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        Ok(filter)
+    }
+
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        let matches = |prefix: &str| {
+            target == prefix || target.strip_prefix(prefix).is_some_and(|rest| rest.starts_with("::"))
+        };
+        self.targets.iter()
+            .find(|(prefix, _)| matches(prefix) || matches(&format!("vault_sync::{}", prefix)))
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+// Applies the filter to the text or JSON logger
+struct FilteredLogger {
+    filter: LogFilter,
+    logger: Box<dyn Log>,
+}
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+pub fn init(logger: Box<dyn Log>, filter: LogFilter) -> Result<(), SetLoggerError> {
+    log::set_max_level(filter.max_level());
+    log::set_boxed_logger(Box::new(FilteredLogger { filter, logger }))
+}
+
+pub struct JsonLogger {
+    // All lines go to stderr, for example when stdout has the events of --events-ndjson
+    pub stderr: bool,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default();
+        let line = format_record(record, &timestamp);
+        // A single write per line, so lines from different threads are not mixed
+        if self.stderr || record.level() == Level::Error {
+            let _ = std::io::stderr().lock().write_all(line.as_bytes());
+        } else {
+            let _ = std::io::stdout().lock().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+// Formats the record as a JSON object followed by a newline. Newlines in the message are escaped
+// by the JSON encoding, so a multi-line message stays on one line.
+fn format_record(record: &Record, timestamp: &str) -> String {
+    let mut object = Map::new();
+    object.insert("timestamp".into(), timestamp.into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut Fields(&mut object));
+    format!("{}\n", Value::Object(object))
+}
+
+// Adds key-values of the record, for example `secret` and `host`, as JSON fields
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), KvError> {
+        self.0.insert(key.to_string(), value.to_string().into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, LevelFilter, Record};
+    use serde_json::Value;
+
+    use crate::logging::{format_record, LogFilter};
+
+    #[test]
+    fn test_log_filter() {
+        let filter = LogFilter::parse("").unwrap();
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+        assert_eq!(filter.level("vault_sync::sync"), LevelFilter::Info);
+
+        let filter = LogFilter::parse("warn, sync=debug,vault_sync::sync::audit=trace,reqwest=off").unwrap();
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+        assert_eq!(filter.level("vault_sync"), LevelFilter::Warn);
+        assert_eq!(filter.level("vault_sync::sync"), LevelFilter::Debug);
+        assert_eq!(filter.level("vault_sync::sync::audit"), LevelFilter::Trace);
+        assert_eq!(filter.level("vault_sync::synchronize"), LevelFilter::Warn);
+        assert_eq!(filter.level("reqwest::connect"), LevelFilter::Off);
+
+        assert_eq!(LogFilter::parse("loud").unwrap_err(), "Invalid log level \"loud\"");
+        assert!(LogFilter::parse("sync=").is_err());
+    }
+
+    #[test]
+    fn test_format_record() {
+        let kvs = [("secret", "src/foo"), ("host", "http://127.0.0.1:8200/")];
+        let line = format_record(
+            &Record::builder()
+                .args(format_args!("Configuration:\n{}", "{\n  \"id\": \"vault-sync\"\n}"))
+                .level(Level::Info)
+                .target("vault_sync::sync")
+                .key_values(&kvs)
+                .build(),
+            "2024-01-01T00:00:00Z",
+        );
+        assert!(line.ends_with('\n'));
+        assert_eq!(line.matches('\n').count(), 1);
+
+        let object: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(object["timestamp"], "2024-01-01T00:00:00Z");
+        assert_eq!(object["level"], "INFO");
+        assert_eq!(object["target"], "vault_sync::sync");
+        assert_eq!(object["message"], "Configuration:\n{\n  \"id\": \"vault-sync\"\n}");
+        assert_eq!(object["secret"], "src/foo");
+        assert_eq!(object["host"], "http://127.0.0.1:8200/");
+    }
+}
@@ -1,26 +1,92 @@
+// hashicorp_vault::Error keeps the whole HTTP response, so every VaultResult is large.
+#![allow(clippy::result_large_err)]
+
 use std::{thread};
 use std::error::Error;
-use std::net::TcpListener;
-use std::sync::{Arc, Mutex};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use clap::{crate_authors, crate_version, Arg, App};
-use log::{error, info};
+use log::{error, info, warn};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use signal_hook::iterator::Signals;
 use simplelog::*;
+use time::OffsetDateTime;
 
 use config::{VaultHost, VaultSyncConfig};
-use vault::VaultClient;
-use crate::config::{EngineVersion, get_backends};
+use serde_json::Value;
+use vault::{OnReconnect, VaultClient};
+use crate::checksum::ChecksumKey;
+use crate::health::HEALTH;
+use crate::logging::{JsonLogger, LogFilter};
+use crate::report::DryRun;
+use crate::sync::AuditListener;
+use crate::exit::StartupError;
+use crate::config::{AuditMode, AuditSelfTest, Backend, EngineVersion, get_backends, get_prefixes, SecretEngine, SharedConfig, SyncMode, VaultDestination};
 
+mod admin;
 mod audit;
+mod binary;
+mod checksum;
 mod config;
+mod dead_letter;
+mod diff;
+mod events;
+mod exit;
+mod export;
+mod filter;
+mod health;
+mod heartbeat;
+mod http;
+mod logging;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod pause;
+mod permissions;
+mod rate_limit;
+mod report;
+mod rewrite;
+mod state;
 mod sync;
+mod table;
+mod transform;
+mod transit;
 mod vault;
+mod verify;
+mod webhook;
+
+// Time to deliver the queued webhook notifications before exit
+const WEBHOOK_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Exit status after --max-runtime, errors on start exit with the status of their category, see
+// StartupError
+const MAX_RUNTIME_EXIT_CODE: i32 = 3;
 
+static MAX_RUNTIME_REACHED: AtomicBool = AtomicBool::new(false);
+
+// Errors are logged with their category and exit with its status instead of 1. The queued webhook
+// notifications are sent first, also when a --once run fails before its cleanup.
 fn main() -> Result<(), Box<dyn Error>> {
-    TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Mixed, ColorChoice::Auto)?;
+    if let Err(error) = run() {
+        let error = StartupError::classify(error);
+        // Before the logger is initialized, for example with an invalid --log-level
+        if log::log_enabled!(log::Level::Error) {
+            error!(category = error.category(); "Exiting with status {} ({} error): {}", error.exit_code(), error.category(), error);
+        } else {
+            eprintln!("Error: {}", error);
+        }
+        webhook::flush(WEBHOOK_FLUSH_TIMEOUT);
+        std::process::exit(error.exit_code());
+    }
+    Ok(())
+}
 
+fn run() -> Result<(), Box<dyn Error>> {
     let matches = App::new("vault-sync")
         .author(crate_authors!())
         .version(crate_version!())
@@ -32,142 +98,1150 @@ fn main() -> Result<(), Box<dyn Error>> {
             .takes_value(true))
         .arg(Arg::with_name("dry-run")
             .long("dry-run")
-            .help("Do not do any changes with the destination Vault"))
+            .value_name("LEVEL")
+            .help("Do not do any changes with the destination Vault. With verify (default), destination secrets are read \
+                to classify the changes, with plan they are not read either")
+            .possible_values(&["verify", "plan"])
+            .min_values(0)
+            .max_values(1)
+            .require_equals(true))
+        .arg(Arg::with_name("dry-run-output")
+            .long("dry-run-output")
+            .value_name("FILE")
+            .help("Write the dry run report to the file instead of stdout")
+            .requires("dry-run")
+            .takes_value(true))
         .arg(Arg::with_name("once")
             .long("once")
             .help("Run the full sync once, then exit"))
+        .arg(Arg::with_name("sync-once")
+            .long("sync-once")
+            .help("Run the full sync once without the audit log, then exit with an error if it fails")
+            .conflicts_with_all(&["once", "replay"]))
+        .arg(Arg::with_name("paths-file")
+            .long("paths-file")
+            .value_name("FILE")
+            .help("With --sync-once, sync only the secrets in the file, one path relative to the source prefix per line")
+            .requires("sync-once")
+            .takes_value(true))
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .value_name("FILE")
+            .help("Sync the secrets from the dead letter file, then exit")
+            .takes_value(true))
+        .arg(Arg::with_name("max-runtime")
+            .long("max-runtime")
+            .value_name("SECS")
+            .help("Shut down after the number of seconds like on SIGTERM, then exit with status 3")
+            .takes_value(true))
+        .arg(Arg::with_name("since")
+            .long("since")
+            .value_name("DURATION")
+            .help("Sync only the KV v2 secrets updated within the duration, for example 6h, in the first full sync. \
+                Units are s, m, h and d")
+            .conflicts_with_all(&["replay", "paths-file"])
+            .takes_value(true))
+        .arg(Arg::with_name("validate")
+            .long("validate")
+            .help("Check the configuration and access to both Vaults, then exit"))
+        .arg(Arg::with_name("once-validate-permissions")
+            .long("once-validate-permissions")
+            .help("Print the required and granted capabilities of the tokens, then exit with an error if some are missing"))
+        .arg(Arg::with_name("diff")
+            .long("diff")
+            .help("Print the number of secrets missing in the destinations, extra in the destinations and with different \
+                values, then exit with an error if there are differences. Nothing is written")
+            .conflicts_with_all(&["dry-run", "once", "sync-once", "replay", "validate", "once-validate-permissions"]))
+        .arg(Arg::with_name("verify")
+            .long("verify")
+            .help("Compare checksums of the source secrets and the destination secrets, print the mismatches and the number \
+                of matched secrets, then exit with an error if some do not match. Nothing is written")
+            .conflicts_with_all(&["dry-run", "once", "sync-once", "replay", "validate", "once-validate-permissions", "diff"]))
+        .arg(Arg::with_name("diff-details")
+            .long("diff-details")
+            .help("With --diff, also print every differing secret")
+            .requires("diff"))
+        .arg(Arg::with_name("export")
+            .long("export")
+            .value_name("DIR")
+            .help("Write the source secrets to JSON files in the directory, then exit")
+            .conflicts_with_all(&["dry-run", "once", "sync-once", "replay", "validate", "once-validate-permissions", "diff", "import"])
+            .takes_value(true))
+        .arg(Arg::with_name("import")
+            .long("import")
+            .value_name("DIR")
+            .help("Write the secrets from a directory written by --export to the destinations, then exit")
+            .conflicts_with_all(&["dry-run", "once", "sync-once", "replay", "validate", "once-validate-permissions", "diff"])
+            .takes_value(true))
+        .arg(Arg::with_name("events-ndjson")
+            .long("events-ndjson")
+            .help("Write every applied sync action to stdout as one JSON object per line, logs go to stderr")
+            .conflicts_with_all(&["dry-run", "validate", "once-validate-permissions", "diff"]))
+        .arg(Arg::with_name("print-schema")
+            .long("print-schema")
+            .help("Print all configuration options with their default values as YAML, then exit"))
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .value_name("FORMAT")
+            .help("Log format")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .takes_value(true))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .value_name("LEVEL")
+            .help("Log level, optionally with levels for modules, for example warn,sync=debug [default: RUST_LOG or info]")
+            .takes_value(true))
         .get_matches();
 
-    let config = load_config(matches.value_of("config").unwrap())?;
-    let (tx, rx): (mpsc::Sender<sync::SecretOp>, mpsc::Receiver<sync::SecretOp>) = mpsc::channel();
+    // The flag wins over RUST_LOG
+    let log_level = match matches.value_of("log-level") {
+        Some(level) => level.to_string(),
+        None => std::env::var("RUST_LOG").unwrap_or_default(),
+    };
+    let filter = LogFilter::parse(&log_level).map_err(|error| StartupError::Config(error.into()))?;
+    // Stdout has only the events with --events-ndjson
+    let events_ndjson = matches.is_present("events-ndjson");
+    let terminal_mode = if events_ndjson { TerminalMode::Stderr } else { TerminalMode::Mixed };
+    let logger: Box<dyn log::Log> = match matches.value_of("log-format") {
+        Some("json") => Box::new(JsonLogger { stderr: events_ndjson }),
+        _ => TermLogger::new(LevelFilter::Trace, Config::default(), terminal_mode, ColorChoice::Auto),
+    };
+    logging::init(logger, filter)?;
+    events::init(events_ndjson);
 
-    let log_sync = match &config.bind {
-        Some(_) => Some(log_sync_worker(&config, tx.clone())?),
+    // Does not need a configuration file
+    if matches.is_present("print-schema") {
+        print!("{}", config::schema()?);
+        return Ok(());
+    }
+
+    let config_file = matches.value_of("config").unwrap();
+    let mut config = load_config(config_file)?;
+    let max_runtime = match matches.value_of("max-runtime") {
+        Some(secs) => Some(secs.parse::<u64>().ok().filter(|secs| *secs > 0).ok_or_else(|| config_error(format!("Invalid --max-runtime {:?}", secs)))?),
+        None => None,
+    };
+    let since = match matches.value_of("since") {
+        Some(value) => Some(OffsetDateTime::now_utc() - sync::parse_duration(value).ok_or_else(|| config_error(format!("Invalid --since {:?}", value)))?),
         None => None,
     };
 
-    info!("Connecting to {}", &config.src.host.url);
-    let src_client = vault_client(&config.src.host, &config.src.version)?;
-    let shared_src_client = Arc::new(Mutex::new(src_client));
-    let src_token = token_worker(&config.src.host, &config.src.version, shared_src_client.clone());
-
-    info!("Connecting to {}", &config.dst.host.url);
-    let dst_client = vault_client(&config.dst.host, &config.dst.version)?;
-    let shared_dst_client = Arc::new(Mutex::new(dst_client));
-    let dst_token = token_worker(&config.dst.host, &config.dst.version,shared_dst_client.clone());
-
-    info!(
-        "Audit device {} exists: {}",
-        &config.id,
-        sync::audit_device_exists(&config.id, shared_src_client.clone()),
-    );
-
-    let sync = sync_worker(
-        rx,
-        &config,
-        shared_src_client.clone(),
-        shared_dst_client.clone(),
-        matches.is_present("dry-run"),
-        matches.is_present("once"),
-    );
-
-    let mut join_handlers = vec![sync];
-
-    if !matches.is_present("once") {
-        let full_sync = full_sync_worker(&config, shared_src_client.clone(), tx.clone());
-        join_handlers.push(full_sync);
-        join_handlers.push(src_token);
-        join_handlers.push(dst_token);
-        if log_sync.is_some() {
-            join_handlers.push(log_sync.unwrap());
+    if matches.is_present("validate") {
+        return validate(&mut config);
+    }
+    if matches.is_present("once-validate-permissions") {
+        return validate_permissions(&mut config);
+    }
+    if matches.is_present("diff") {
+        return diff(&mut config, matches.is_present("diff-details"));
+    }
+    if matches.is_present("verify") {
+        return verify(&mut config);
+    }
+    if let Some(dir) = matches.value_of("export") {
+        return export(&mut config, dir);
+    }
+    if let Some(dir) = matches.value_of("import") {
+        return import(&mut config, dir);
+    }
+    webhook::init(&config);
+    if let Some(file_name) = matches.value_of("replay") {
+        return replay(&mut config, file_name, dry_run(&matches), matches.value_of("dry-run-output"), max_runtime);
+    }
+    if matches.is_present("sync-once") {
+        return sync_once(&mut config, matches.value_of("paths-file"), dry_run(&matches), matches.value_of("dry-run-output"), max_runtime, since);
+    }
+
+    let shared_config: SharedConfig = Arc::new(RwLock::new(config.clone()));
+    let (tx, rx): (mpsc::SyncSender<sync::SecretOp>, mpsc::Receiver<sync::SecretOp>) = mpsc::sync_channel(config.queue_capacity);
+    let dry_run = dry_run(&matches);
+    let dry_run_output = matches.value_of("dry-run-output").map(String::from);
+    let once = matches.is_present("once");
+
+    let mut http_workers = Vec::new();
+    if let Some(addr) = &config.metrics_bind {
+        http_workers.push(http_worker("Metrics", addr, metrics::handle)?);
+    }
+    if let Some(addr) = &config.health_bind {
+        http_workers.push(http_worker("Health", addr, health::handle)?);
+    }
+    if let Some(addr) = &config.admin_bind {
+        if !is_loopback(addr) {
+            warn!("Admin endpoint on {} is reachable from other hosts, consider binding to 127.0.0.1", addr);
         }
+        http_workers.push(http_worker("Admin", addr, admin::handle)?);
+    }
+    {
+        let config = shared_config.clone();
+        thread::spawn(move || heartbeat::heartbeat_worker(&config));
+    }
+    // Before the sync workers start, SIGUSR1 would terminate the process otherwise
+    if !once {
+        pause_worker()?;
+    }
+
+    // Without live sync, the audit device is neither checked nor restored
+    let audit_device = Arc::new(Mutex::new(None));
+    let admin_host = config.src.admin_host();
+    let restore = restore_audit_device(config.audit_path(), &audit_device, admin_host.clone(), &config.src.version);
+    let src = connect(&config.src.host, &config.src.backend, &config.src.configured_version, config.startup_retry_secs, restore)?;
+    config.src.version = src.version.clone();
+    let mut dsts = Vec::new();
+    for dst in config.dst.iter_mut() {
+        let client = connect(&dst.host, &dst.backend, &dst.configured_version, config.startup_retry_secs, Arc::new(|_| {}))?;
+        dst.version = client.version.clone();
+        dsts.push(client);
+    }
+    config.validate()?;
+    // The workers that are already running read the detected versions too
+    *shared_config.write().unwrap() = config.clone();
+    let dst_clients: Vec<Arc<Mutex<VaultClient>>> = dsts.iter().map(|dst| dst.client.clone()).collect();
+    let src_client = src.client.clone();
+
+    check_mounts(&src.client.lock().unwrap(), &config.src.host, &config.src.mounts)?;
+    for (dst, dst_config) in dsts.iter().zip(&config.dst) {
+        // Nothing is written in dry run
+        if dry_run.is_none() {
+            check_writable(&dst.client.lock().unwrap(), dst_config, &config.src.secret_engine, config.require_write_permissions)?;
+        }
+    }
+
+    if config.live_sync {
+        let admin_client = audit_client(admin_host.as_ref(), &config.src.version, config.startup_retry_secs, &src.client)?;
+        *audit_device.lock().unwrap() = sync::audit_device(config.audit_path(), admin_client);
+        if let Some(device) = audit_device.lock().unwrap().as_ref() {
+            let warnings = sync::check_audit_device_options(device, config.mode == SyncMode::Bidirectional, config.bind.as_deref()).map_err(|error| {
+                error!("{}", error);
+                config_error(error)
+            })?;
+            for warning in warnings {
+                warn!("{}", warning);
+            }
+        }
+        let audit_device_exists = audit_device.lock().unwrap().is_some();
+        admin::STATUS.audit_device.store(audit_device_exists, Ordering::Relaxed);
+        info!("Audit device {} exists: {}", config.audit_path(), audit_device_exists);
     } else {
-        let backends = get_backends(&config.src.backend);
-        sync::full_sync(&config.src.prefix, &backends, shared_src_client.clone(), tx.clone());
-    };
+        info!("Live sync is disabled, only full syncs run");
+    }
 
-    // Join all threads
-    for handler in join_handlers {
-        let _ = handler.join();
+    // Other workers run until the process exits, sync workers stop on shutdown
+    let mut socket_files = Vec::new();
+    match config.audit_mode {
+        _ if !config.live_sync => {},
+        AuditMode::Socket if config.bind.is_some() => {
+            socket_files.extend(log_sync_worker(&shared_config, tx.clone(), src.client.clone())?);
+        },
+        AuditMode::Socket => {},
+        AuditMode::File => {
+            log_file_sync_worker(&shared_config, tx.clone(), src.client.clone());
+        },
+    }
+    // Before the full sync, which would sync the sentinel secret
+    let audit_log_received = config.audit_mode == AuditMode::File || config.bind.is_some();
+    if config.live_sync && audit_log_received && config.audit_self_test != AuditSelfTest::Off {
+        if dry_run.is_some() || once {
+            info!("Audit self-test writes to the source Vault, skipping it with --dry-run and --once");
+        } else if let Err(error) = sync::audit_self_test(&config, &src.client) {
+            if config.audit_self_test == AuditSelfTest::Fail {
+                error!("Audit self-test failed: {}", error);
+                return Err(error.into());
+            }
+            warn!("Audit self-test failed, changes in the source Vault may not be synced until the next full sync: {}", error);
+        }
+    }
+    let mut sync_workers = vec![sync_worker(rx, &shared_config, src.client.clone(), dst_clients.clone(), dry_run, dry_run_output, once)];
+    let mut queues = vec![tx.clone()];
+
+    // The reverse direction has its own audit log listener and sync worker, but no full sync
+    let mut reverse_config = None;
+    if config.mode == SyncMode::Bidirectional && !once {
+        info!("Bidirectional mode, syncing secrets from {} to {}", &config.dst[0].host.url, &config.src.host.url);
+        let reversed: SharedConfig = Arc::new(RwLock::new(config.reversed()));
+        let (reverse_tx, reverse_rx) = mpsc::sync_channel(config.queue_capacity);
+        socket_files.extend(log_sync_worker(&reversed, reverse_tx.clone(), dst_clients[0].clone())?);
+        sync_workers.push(sync_worker(reverse_rx, &reversed, dst_clients[0].clone(), vec![src.client.clone()], dry_run, None, false));
+        queues.push(reverse_tx);
+        reverse_config = Some(reversed);
     }
 
+    max_runtime_worker(queues.clone(), max_runtime, config.shutdown_timeout);
+    shutdown_worker(queues, config.shutdown_timeout)?;
+
+    if !once {
+        full_sync_worker(&shared_config, src.client.clone(), dst_clients.clone(), tx.clone(), dry_run, since);
+        reload_worker(config_file, shared_config.clone(), reverse_config, src, dsts)?;
+    } else {
+        sync::full_sync(&config, src.client.clone(), &dst_clients, tx.clone(), &mut sync::DeleteBreaker::load(config.delete_breaker_path().as_deref()), &mut state::FullSyncCursor::load(config.full_sync_cursor_path.as_deref()), dry_run, since);
+    };
+
+    // Sync workers stop after the full sync with --once, or after the queued ops are applied on
+    // shutdown
+    for worker in sync_workers {
+        let _ = worker.join();
+    }
+    let shutdown = Shutdown { http_workers, socket_files, src_client, dst_clients };
+    shutdown.run(&shared_config.read().unwrap());
+    info!("Stopped");
+    exit_after_max_runtime();
     Ok(())
 }
 
 fn load_config(file_name: &str) -> Result<VaultSyncConfig, Box<dyn Error>> {
     match VaultSyncConfig::from_file(file_name) {
         Ok(config) => {
-            info!("Configuration from {}:\n{}", file_name, serde_json::to_string_pretty(&config).unwrap());
+            info!("Configuration from {}:\n{}", file_name, config.to_log_string());
             Ok(config)
         },
         Err(error) => {
             error!("Failed to load configuration file {}: {}", file_name, error);
-            Err(error)
+            Err(StartupError::Config(error).into())
+        }
+    }
+}
+
+// Connects to all Vaults (which also looks up the tokens) and checks that secrets can be listed,
+// does not start any workers
+fn validate(config: &mut VaultSyncConfig) -> Result<(), Box<dyn Error>> {
+    let (src_client, dst_clients) = connect_all(config)?;
+    let mut src_client = src_client.lock().unwrap();
+    check_mounts(&src_client, &config.src.host, &config.src.mounts)?;
+    for backend in config.src.synced_backends() {
+        for prefix in get_prefixes(&config.src.prefix) {
+            check_list(&mut src_client, &config.src.host, &config.src.secret_engine, &backend, &prefix)?;
         }
     }
+    for (dst, dst_client) in config.dst.iter().zip(&dst_clients) {
+        let mut dst_client = dst_client.lock().unwrap();
+        for backend in get_backends(&dst.backend) {
+            for prefix in get_prefixes(&dst.prefix) {
+                check_list(&mut dst_client, &dst.host, &config.src.secret_engine, &backend, &prefix)?;
+            }
+        }
+    }
+    info!("Configuration is valid");
+    Ok(())
+}
+
+// Checks the capabilities of the tokens for the prefixes and the audit devices with
+// sys/capabilities-self, prints them as a table
+fn validate_permissions(config: &mut VaultSyncConfig) -> Result<(), Box<dyn Error>> {
+    // The required paths depend on the versions
+    let src_client = connect_src(config)?;
+    let src_client = src_client.lock().unwrap();
+    let mut requirements = permissions::src_requirements(&config.src);
+    // The admin token has only the audit device requirement
+    let mut rows = match config.src.admin_host() {
+        Some(admin_host) => {
+            let admin_client = vault_client(&admin_host, &config.src.version, config.startup_retry_secs)?;
+            let mut rows = permissions::check(&config.src.host.url, &src_client, requirements)?;
+            rows.extend(permissions::check(&config.src.host.url, &admin_client, permissions::audit_requirements(config.audit_path()))?);
+            rows
+        },
+        None => {
+            requirements.extend(permissions::audit_requirements(config.audit_path()));
+            permissions::check(&config.src.host.url, &src_client, requirements)?
+        },
+    };
+    let dst_clients = connect_dsts(config)?;
+    for (dst, dst_client) in config.dst.iter().zip(&dst_clients) {
+        rows.extend(permissions::check(&dst.host.url, &dst_client.lock().unwrap(), permissions::dst_requirements(dst, &config.src.secret_engine))?);
+    }
+    print!("{}", permissions::render(&rows));
+    let missing = rows.iter().filter(|row| !row.missing().is_empty()).count();
+    if missing > 0 {
+        return Err(StartupError::Permission(format!("missing capabilities for {} paths", missing).into()).into());
+    }
+    Ok(())
+}
+
+// Lists and reads the secrets in both Vaults, prints the differences as tables
+fn diff(config: &mut VaultSyncConfig, details: bool) -> Result<(), Box<dyn Error>> {
+    if config.src.secret_engine == SecretEngine::Transit {
+        return Err(config_error("--diff supports only the KV secrets engine".to_string()));
+    }
+    let (src_client, dst_clients) = connect_all(config)?;
+    let diff = diff::diff(config, &src_client, &dst_clients);
+    print!("{}", diff::render(&diff, details));
+    if !diff.complete {
+        return Err("not all secrets could be listed or read, the differences are incomplete".into());
+    }
+    if !diff.is_empty() {
+        return Err(format!("{} secrets are different", diff.differences.len()).into());
+    }
+    Ok(())
+}
+
+// Like diff, but reports checksums of the secrets for a record that the sync succeeded
+fn verify(config: &mut VaultSyncConfig) -> Result<(), Box<dyn Error>> {
+    if config.src.secret_engine == SecretEngine::Transit {
+        return Err(config_error("--verify supports only the KV secrets engine".to_string()));
+    }
+    let (src_client, dst_clients) = connect_all(config)?;
+    check_mounts(&src_client.lock().unwrap(), &config.src.host, &config.src.mounts)?;
+    let key = ChecksumKey::random()?;
+    let verification = verify::verify(config, &key, &src_client, &dst_clients);
+    print!("{}", verify::render(&verification));
+    info!("Verified {} matching secrets, {} mismatches", verification.matched(), verification.mismatches.len());
+    if !verification.complete {
+        return Err("not all secrets could be listed or read, the verification is incomplete".into());
+    }
+    if !verification.mismatches.is_empty() {
+        return Err(format!("{} secrets do not match", verification.mismatches.len()).into());
+    }
+    Ok(())
+}
+
+// Connects only to the source Vault
+fn export(config: &mut VaultSyncConfig, dir: &str) -> Result<(), Box<dyn Error>> {
+    if config.src.secret_engine == SecretEngine::Transit {
+        return Err(config_error("--export supports only the KV secrets engine".to_string()));
+    }
+    let src_client = connect_src(config)?;
+    config.validate()?;
+    export::export(config, &src_client, dir)?;
+    Ok(())
+}
+
+// Connects only to the destination Vaults
+fn import(config: &mut VaultSyncConfig, dir: &str) -> Result<(), Box<dyn Error>> {
+    if config.src.secret_engine == SecretEngine::Transit {
+        return Err(config_error("--import supports only the KV secrets engine".to_string()));
+    }
+    let dst_clients = connect_dsts(config)?;
+    for (dst, dst_client) in config.dst.iter().zip(&dst_clients) {
+        check_writable(&dst_client.lock().unwrap(), dst, &config.src.secret_engine, config.require_write_permissions)?;
+    }
+    config.validate()?;
+    export::import(config, &dst_clients, dir)?;
+    Ok(())
+}
+
+// Sends the ops from the dead letter file through the sync worker, which syncs every secret to
+// all destinations. Ops that fail again are appended to dead_letter_path.
+fn replay(
+    config: &mut VaultSyncConfig,
+    file_name: &str,
+    dry_run: Option<DryRun>,
+    dry_run_output: Option<&str>,
+    max_runtime: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    let letters = dead_letter::read(file_name)?;
+    info!("Replaying {} secret operations from {}", letters.len(), file_name);
+    let (src_client, dst_clients) = connect_all(config)?;
+    let (tx, rx) = mpsc::sync_channel(config.queue_capacity);
+    max_runtime_worker(vec![tx.clone()], max_runtime, config.shutdown_timeout);
+    // The queue is bounded, so the ops are sent while the sync worker runs
+    let ops: Vec<sync::SecretOp> = letters.iter().map(|letter| letter.op()).collect();
+    let sender = thread::spawn(move || {
+        for op in ops {
+            sync::send_op(&tx, op);
+        }
+        sync::send_op(&tx, sync::SecretOp::Shutdown);
+    });
+    let shared_config: SharedConfig = Arc::new(RwLock::new(config.clone()));
+    sync::sync_worker(rx, &shared_config, src_client.clone(), dst_clients.clone(), dry_run, dry_run_output, false);
+    let _ = sender.join();
+    Shutdown { http_workers: Vec::new(), socket_files: Vec::new(), src_client, dst_clients }.run(config);
+    exit_after_max_runtime();
+    Ok(())
+}
+
+// Full sync for cron-style runs: no audit device, listeners or reloads. Fails if some secrets
+// could not be listed or synced. With paths_file, only the secrets in the file are synced.
+fn sync_once(
+    config: &mut VaultSyncConfig,
+    paths_file: Option<&str>,
+    dry_run: Option<DryRun>,
+    dry_run_output: Option<&str>,
+    max_runtime: Option<u64>,
+    since: Option<OffsetDateTime>,
+) -> Result<(), Box<dyn Error>> {
+    let paths = match paths_file {
+        Some(_) if config.src.secret_engine == SecretEngine::Transit => return Err(config_error("--paths-file is not supported for transit keys".to_string())),
+        Some(_) if config.src.prefix.len() != 1 => return Err(config_error("--paths-file requires a single source prefix".to_string())),
+        Some(file_name) => Some(sync::read_paths(file_name)?),
+        None => None,
+    };
+    let (src_client, dst_clients) = connect_all(config)?;
+    check_mounts(&src_client.lock().unwrap(), &config.src.host, &config.src.mounts)?;
+    let (tx, rx) = mpsc::sync_channel(config.queue_capacity);
+    max_runtime_worker(vec![tx.clone()], max_runtime, config.shutdown_timeout);
+    let shared_config: SharedConfig = Arc::new(RwLock::new(config.clone()));
+    let worker = sync_worker(rx, &shared_config, src_client.clone(), dst_clients.clone(), dry_run, dry_run_output.map(String::from), true);
+    // Listed in another thread, so the sync can stop after --max-runtime while the listing runs
+    let lister = {
+        let (config, src_client, dst_clients, paths) = (config.clone(), src_client.clone(), dst_clients.clone(), paths.clone());
+        thread::spawn(move || match &paths {
+            Some(paths) => sync::paths_sync(&config, src_client, paths, tx),
+            None => sync::full_sync(&config, src_client, &dst_clients, tx, &mut sync::DeleteBreaker::load(config.delete_breaker_path().as_deref()), &mut state::FullSyncCursor::load(config.full_sync_cursor_path.as_deref()), dry_run, since),
+        })
+    };
+    let _ = worker.join();
+    Shutdown { http_workers: Vec::new(), socket_files: Vec::new(), src_client, dst_clients }.run(config);
+    exit_after_max_runtime();
+    let listed = lister.join().unwrap_or(false);
+    let errors = metrics::METRICS.sync_errors.load(Ordering::Relaxed);
+    if !listed {
+        let reason = if paths.is_some() { "not all secrets could be read" } else { "not all secrets are listed" };
+        return Err(format!("full sync failed, {}", reason).into());
+    }
+    if errors > 0 {
+        return Err(format!("full sync failed, {} secret operations failed", errors).into());
+    }
+    Ok(())
+}
+
+fn check_list(client: &mut VaultClient, host: &VaultHost, engine: &SecretEngine, backend: &str, prefix: &str) -> Result<(), Box<dyn Error>> {
+    let result = match engine {
+        SecretEngine::Kv => vault::check_list(client, backend, prefix),
+        SecretEngine::Transit => transit::list_keys(client, backend).map(|_| ()),
+    };
+    match result {
+        Ok(_) => {
+            info!("Listing {}/{} in {}: ok", backend, prefix, &host.url);
+            Ok(())
+        },
+        Err(error) => {
+            error!("Failed to list {}/{} in {}: {}", backend, prefix, &host.url, error);
+            Err(error.into())
+        }
+    }
+}
+
+// Without a configured version, the version of the secrets engines is detected from sys/mounts,
+// or from sys/internal/ui/mounts if the token cannot read sys/mounts, and KV v2 is used if neither
+// can be read. A configured version must be the version of the secrets engines, otherwise secrets
+// would be read from or written to the wrong paths.
+fn engine_version(
+    client: &VaultClient,
+    host: &VaultHost,
+    backend: &Option<Backend>,
+    configured: &Option<EngineVersion>,
+) -> Result<EngineVersion, Box<dyn Error>> {
+    let mut detected: Option<(String, EngineVersion)> = None;
+    let mut unknown = false;
+    for backend in get_backends(backend) {
+        let actual = match vault::engine_version(client, &backend).or_else(|error| vault::ui_engine_version(client, &backend).map_err(|_| error)) {
+            Ok(actual) => actual,
+            Err(error) => {
+                warn!("Failed to check version of secrets engine {} in {}: {}", backend, &host.url, error);
+                unknown = true;
+                continue;
+            }
+        };
+        // Not mounted or not a KV secrets engine
+        let Some(actual) = actual else {
+            continue;
+        };
+        info!(host = host.url.as_str(); "Secrets engine {} in {} is KV v{}", backend, &host.url, actual.clone() as u8);
+        if let Some(version) = configured.as_ref().filter(|version| **version != actual) {
+            let error = format!(
+                "Secrets engine {} in {} is KV v{}, but version {} is configured",
+                backend, &host.url, actual as u8, version.clone() as u8,
+            );
+            error!("{}", error);
+            return Err(config_error(error));
+        }
+        if let Some((other, version)) = &detected {
+            if *version != actual {
+                let error = format!(
+                    "Secrets engines {} and {} in {} have different KV versions, only one version is supported",
+                    other, backend, &host.url,
+                );
+                error!("{}", error);
+                return Err(config_error(error));
+            }
+        }
+        detected = Some((backend, actual));
+    }
+    match (configured, detected) {
+        (Some(version), _) => Ok(version.clone()),
+        (None, Some((_, version))) => Ok(version),
+        (None, None) => {
+            if unknown {
+                warn!(host = host.url.as_str(); "Using KV v2 for {}, set version if the secrets engines are KV v1", &host.url);
+            }
+            Ok(EngineVersion::V2)
+        },
+    }
+}
+
+// Same as engine_version, and switches the client to the version
+fn detect_version(
+    client: &mut VaultClient,
+    host: &VaultHost,
+    backend: &Option<Backend>,
+    configured: &Option<EngineVersion>,
+) -> Result<EngineVersion, Box<dyn Error>> {
+    let version = engine_version(client, host, backend, configured)?;
+    vault::set_engine_version(client, &version);
+    Ok(version)
 }
 
-fn vault_client(host: &VaultHost, version: &EngineVersion) -> Result<VaultClient, Box<dyn Error>> {
-    match vault::vault_client(host, version) {
+// The mounts allowlist of the source must name existing secrets engines. Like for the engine
+// versions, not being able to read sys/mounts is not an error.
+fn check_mounts(client: &VaultClient, host: &VaultHost, mounts: &[String]) -> Result<(), Box<dyn Error>> {
+    if mounts.is_empty() {
+        return Ok(());
+    }
+    match vault::mount_types(client) {
+        Ok(types) => match mounts.iter().find(|mount| !types.contains_key(mount.as_str())) {
+            Some(mount) => {
+                let error = format!("Mount {} does not exist in {}", mount, &host.url);
+                error!("{}", error);
+                Err(config_error(error))
+            },
+            None => Ok(()),
+        },
+        Err(error) => {
+            warn!("Failed to check mounts in {}: {}", &host.url, error);
+            Ok(())
+        }
+    }
+}
+
+// Warns before the live sync starts if the token cannot write to the destination prefixes, or with
+// require_write_permissions exits instead of failing every write with 403. Not being able to check
+// the capabilities is not an error.
+fn check_writable(client: &VaultClient, dst: &VaultDestination, engine: &SecretEngine, required: bool) -> Result<(), Box<dyn Error>> {
+    let rows = match permissions::check(&dst.host.url, client, permissions::dst_requirements(dst, engine)) {
+        Ok(rows) => rows,
+        Err(error) => {
+            warn!(host = dst.host.url.as_str(); "Failed to check capabilities in {}: {}", &dst.host.url, error);
+            return Ok(());
+        }
+    };
+    for row in rows {
+        let missing = row.missing_write();
+        if !missing.is_empty() {
+            let error = format!(
+                "Token for {} cannot write secrets to {}, missing capabilities: {}",
+                &dst.host.url, &row.path, missing.join(","),
+            );
+            if !required {
+                warn!(host = dst.host.url.as_str(); "{}", error);
+                continue;
+            }
+            error!(host = dst.host.url.as_str(); "{}", error);
+            return Err(StartupError::Permission(error.into()).into());
+        }
+    }
+    Ok(())
+}
+
+fn config_error(message: String) -> Box<dyn Error> {
+    StartupError::Config(message.into()).into()
+}
+
+// Transient errors are retried for retry_secs, see startup_retry_secs
+fn vault_client(host: &VaultHost, version: &EngineVersion, retry_secs: u64) -> Result<VaultClient, Box<dyn Error>> {
+    match vault::vault_client_with_retry(host, version, Duration::from_secs(retry_secs)) {
         Ok(client) => {
             Ok(client)
         },
         Err(error) => {
-            error!("Failed to connect to {}: {}", &host.url, error);
-            Err(error.into())
+            error!(host = host.url.as_str(); "Failed to connect to {}: {}", &host.url, error);
+            Err(StartupError::login(error).into())
         }
     }
 }
 
-fn token_worker(host: &VaultHost, version: &EngineVersion, client: Arc<Mutex<VaultClient>>) -> JoinHandle<()> {
+type SharedClient = Arc<Mutex<VaultClient>>;
+
+// Connects to the source Vault and detects the version of its secrets engine, for the runs that
+// exit without a token worker
+fn connect_src(config: &mut VaultSyncConfig) -> Result<SharedClient, Box<dyn Error>> {
+    info!(host = config.src.host.url.as_str(); "Connecting to {}", &config.src.host.url);
+    let mut src_client = vault_client(&config.src.host, &config.src.version, config.startup_retry_secs)?;
+    config.src.version = detect_version(&mut src_client, &config.src.host, &config.src.backend, &config.src.configured_version)?;
+    Ok(Arc::new(Mutex::new(src_client)))
+}
+
+// Like connect_src, for every destination in order
+fn connect_dsts(config: &mut VaultSyncConfig) -> Result<Vec<SharedClient>, Box<dyn Error>> {
+    let mut dst_clients = Vec::new();
+    for dst in config.dst.iter_mut() {
+        info!(host = dst.host.url.as_str(); "Connecting to {}", &dst.host.url);
+        let mut dst_client = vault_client(&dst.host, &dst.version, config.startup_retry_secs)?;
+        dst.version = detect_version(&mut dst_client, &dst.host, &dst.backend, &dst.configured_version)?;
+        dst_clients.push(Arc::new(Mutex::new(dst_client)));
+    }
+    Ok(dst_clients)
+}
+
+// Connects to all Vaults, then validates the configuration, which depends on the detected versions
+fn connect_all(config: &mut VaultSyncConfig) -> Result<(SharedClient, Vec<SharedClient>), Box<dyn Error>> {
+    let src_client = connect_src(config)?;
+    let dst_clients = connect_dsts(config)?;
+    config.validate()?;
+    Ok((src_client, dst_clients))
+}
+
+// Connects to the Vault host, detects the version of its secrets engines (see engine_version) and
+// starts its token worker
+fn connect(
+    host: &VaultHost,
+    backend: &Option<Backend>,
+    version: &Option<EngineVersion>,
+    retry_secs: u64,
+    on_reconnect: OnReconnect,
+) -> Result<HostClient, Box<dyn Error>> {
+    info!(host = host.url.as_str(); "Connecting to {}", &host.url);
+    let mut client = vault_client(host, &version.clone().unwrap_or_default(), retry_secs)?;
+    let version = detect_version(&mut client, host, backend, version)?;
+    let client = Arc::new(Mutex::new(client));
+    let token_worker_stop = Arc::new(AtomicBool::new(false));
+    let token_expiry = HEALTH.register_token(&host.url);
+    token_worker(host, &version, client.clone(), token_worker_stop.clone(), token_expiry.clone(), on_reconnect.clone());
+    Ok(HostClient { client, version, token_worker_stop, token_expiry, on_reconnect })
+}
+
+fn token_worker(
+    host: &VaultHost,
+    version: &EngineVersion,
+    client: Arc<Mutex<VaultClient>>,
+    stop: Arc<AtomicBool>,
+    token_expiry: Arc<AtomicU64>,
+    on_reconnect: OnReconnect,
+) -> JoinHandle<()> {
     let host = host.clone();
     let version = version.clone();
     thread::spawn(move || {
-        vault::token_worker(&host, &version, client);
+        vault::token_worker(&host, &version, client, stop, &token_expiry, &on_reconnect);
+    })
+}
+
+// Client for the audit device. With admin_auth, it logs in again every time, so a short-lived
+// admin token is used only when needed and is not renewed. Otherwise it is the source client.
+fn audit_client(
+    admin_host: Option<&VaultHost>,
+    version: &EngineVersion,
+    retry_secs: u64,
+    src_client: &Arc<Mutex<VaultClient>>,
+) -> Result<Arc<Mutex<VaultClient>>, Box<dyn Error>> {
+    match admin_host {
+        Some(host) => Ok(Arc::new(Mutex::new(vault_client(host, version, retry_secs)?))),
+        None => Ok(src_client.clone()),
+    }
+}
+
+// Enables the audit device again if it is missing after reconnecting to the source Vault, for
+// example after a restart of a dev server. The device is enabled with the options it had on start.
+// A device enabled by someone else at the same path in the meantime is left as is.
+fn restore_audit_device(path: &str, device: &Arc<Mutex<Option<Value>>>, admin_host: Option<VaultHost>, version: &EngineVersion) -> OnReconnect {
+    let path = path.to_string();
+    let device = device.clone();
+    let version = version.clone();
+    Arc::new(move |client| {
+        let Some(device) = device.lock().unwrap().clone() else {
+            return;
+        };
+        // Login errors are logged by vault_client
+        let Ok(client) = audit_client(admin_host.as_ref(), &version, 0, client) else {
+            admin::STATUS.audit_device.store(false, Ordering::Relaxed);
+            return;
+        };
+        let exists = match sync::audit_device(&path, client.clone()) {
+            Some(current) if sync::same_audit_device(&current, &device) => true,
+            Some(_) => {
+                warn!("Audit device {} has been replaced by another device, not restoring it", &path);
+                false
+            },
+            None => sync::enable_audit_device(&path, &device, client.clone()),
+        };
+        admin::STATUS.audit_device.store(exists, Ordering::Relaxed);
     })
 }
 
 fn sync_worker(
     rx: mpsc::Receiver<sync::SecretOp>,
-    config: &VaultSyncConfig,
+    config: &SharedConfig,
     src_client: Arc<Mutex<VaultClient>>,
-    dst_client: Arc<Mutex<VaultClient>>,
-    dry_run: bool,
+    dst_clients: Vec<Arc<Mutex<VaultClient>>>,
+    dry_run: Option<DryRun>,
+    dry_run_output: Option<String>,
     run_once: bool,
 ) -> thread::JoinHandle<()> {
-    info!("Dry run: {}", dry_run);
+    info!("Dry run: {:?}", dry_run);
     let config = config.clone();
     thread::spawn(move || {
-        sync::sync_worker(rx, &config, src_client, dst_client, dry_run, run_once);
+        sync::sync_worker(rx, &config, src_client, dst_clients, dry_run, dry_run_output.as_deref(), run_once);
     })
 }
 
-fn log_sync_worker(config: &VaultSyncConfig, tx: mpsc::Sender<sync::SecretOp>) -> Result<JoinHandle<()>, std::io::Error> {
-    let addr = &config.bind.clone().unwrap();
+// Returns the socket file to remove on shutdown if bind is a Unix socket
+fn log_sync_worker(
+    config: &SharedConfig,
+    tx: mpsc::SyncSender<sync::SecretOp>,
+    client: Arc<Mutex<VaultClient>>,
+) -> Result<Option<String>, std::io::Error> {
+    let addr = &config.read().unwrap().bind.clone().unwrap();
     let config = config.clone();
-    info!("Listening on {}", addr);
-    let listener = TcpListener::bind(addr)?;
-    let handle = thread::spawn(move || {
-        for stream in listener.incoming() {
-            if let Ok(stream) = stream {
-                let tx = tx.clone();
-                let config = config.clone();
-                thread::spawn(move || {
-                    sync::log_sync(&config, stream, tx);
-                });
-            }
-        }
+    let listener = AuditListener::bind(addr)?;
+    info!("Listening on {} for the audit log", listener.addr());
+    let socket_file = listener.socket_file().map(String::from);
+    thread::spawn(move || {
+        sync::log_listener(&config, listener, tx, client);
+        HEALTH.log_sync_stopped.store(true, Ordering::Relaxed);
     });
-    Ok(handle)
+    Ok(socket_file)
 }
 
-fn full_sync_worker(
-    config: &VaultSyncConfig,
+fn log_file_sync_worker(
+    config: &SharedConfig,
+    tx: mpsc::SyncSender<sync::SecretOp>,
     client: Arc<Mutex<VaultClient>>,
-    tx: mpsc::Sender<sync::SecretOp>
+) -> JoinHandle<()> {
+    // Changes of audit_file require restart
+    let file_name = config.read().unwrap().audit_file.clone().unwrap();
+    let config = config.clone();
+    thread::spawn(move || {
+        sync::log_file_sync(&config, &file_name, tx, client);
+    })
+}
+
+fn full_sync_worker(
+    config: &SharedConfig,
+    src_client: Arc<Mutex<VaultClient>>,
+    dst_clients: Vec<Arc<Mutex<VaultClient>>>,
+    tx: mpsc::SyncSender<sync::SecretOp>,
+    dry_run: Option<DryRun>,
+    since: Option<OffsetDateTime>,
 ) -> thread::JoinHandle<()>{
     let config = config.clone();
     thread::spawn(move || {
-        sync::full_sync_worker(&config, client, tx);
+        sync::full_sync_worker(&config, src_client, dst_clients, tx, dry_run, since);
     })
 }
+
+// --dry-run without a level is --dry-run=verify
+fn dry_run(matches: &clap::ArgMatches) -> Option<DryRun> {
+    if !matches.is_present("dry-run") {
+        return None;
+    }
+    match matches.value_of("dry-run") {
+        Some("plan") => Some(DryRun::Plan),
+        _ => Some(DryRun::Verify),
+    }
+}
+
+// HTTP server thread and its stop flag
+struct HttpWorker {
+    handle: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+}
+
+// Addresses that cannot be parsed are left for TcpListener::bind to report
+fn is_loopback(addr: &str) -> bool {
+    match addr.to_socket_addrs() {
+        Ok(mut addrs) => addrs.all(|addr| addr.ip().is_loopback()),
+        Err(_) => true,
+    }
+}
+
+fn http_worker(name: &'static str, addr: &str, handler: fn(&str, &str) -> (u16, String)) -> Result<HttpWorker, std::io::Error> {
+    info!("{} listening on {}", name, addr);
+    let listener = TcpListener::bind(addr)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let worker_stop = stop.clone();
+    let handle = thread::spawn(move || {
+        http::serve(name, listener, worker_stop, handler);
+    });
+    Ok(HttpWorker { handle, stop })
+}
+
+// On SIGINT or SIGTERM, asks the sync workers to apply the queued ops (none while paused) and stop, then main returns.
+// Exits if they do not stop in shutdown_timeout seconds, or on the second signal.
+fn shutdown_worker(queues: Vec<mpsc::SyncSender<sync::SecretOp>>, timeout: u64) -> Result<JoinHandle<()>, std::io::Error> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let handle = thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!(
+                "Received signal {}, shutting down after {} queued secret operations",
+                signal,
+                metrics::METRICS.queue_depth.load(Ordering::Relaxed),
+            );
+            // The queued ops are not applied to a standby destination, and not at all while paused
+            pause::PAUSE.stop();
+            sync::SHUTDOWN.store(true, Ordering::Relaxed);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(timeout));
+                warn!("Sync workers did not stop in {}s, exiting", timeout);
+                std::process::exit(1);
+            });
+            // Sending blocks while a queue is full, the second signal still exits
+            thread::spawn(move || {
+                for queue in &queues {
+                    sync::send_op(queue, sync::SecretOp::Shutdown);
+                }
+            });
+        }
+        if let Some(signal) = signals.next() {
+            warn!("Received signal {} again, exiting", signal);
+            std::process::exit(1);
+        }
+    });
+    Ok(handle)
+}
+
+// After max_runtime seconds, asks the sync workers to stop like shutdown_worker does. The ops that
+// are not queued yet, for example of a running full sync, are not applied.
+fn max_runtime_worker(queues: Vec<mpsc::SyncSender<sync::SecretOp>>, max_runtime: Option<u64>, timeout: u64) {
+    let Some(max_runtime) = max_runtime else {
+        return;
+    };
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(max_runtime));
+        info!(
+            "Reached --max-runtime of {}s, shutting down after {} queued secret operations",
+            max_runtime,
+            metrics::METRICS.queue_depth.load(Ordering::Relaxed),
+        );
+        MAX_RUNTIME_REACHED.store(true, Ordering::Relaxed);
+        pause::PAUSE.stop();
+        sync::SHUTDOWN.store(true, Ordering::Relaxed);
+        // Started first, sending blocks while the queue is full
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(timeout));
+            warn!("Sync workers did not stop in {}s, exiting", timeout);
+            std::process::exit(MAX_RUNTIME_EXIT_CODE);
+        });
+        for queue in &queues {
+            sync::send_op(queue, sync::SecretOp::Shutdown);
+        }
+    });
+}
+
+// Exits with MAX_RUNTIME_EXIT_CODE after the cleanup, if the sync stopped because of --max-runtime
+fn exit_after_max_runtime() {
+    if MAX_RUNTIME_REACHED.load(Ordering::Relaxed) {
+        std::process::exit(MAX_RUNTIME_EXIT_CODE);
+    }
+}
+
+// Cleanup when vault-sync exits, after the sync workers stop. The steps run in order: the HTTP
+// servers stop, the queued webhook notifications are sent, then with revoke_on_exit the tokens of
+// the source and the destinations are revoked. The audit device is not deleted.
+struct Shutdown {
+    http_workers: Vec<HttpWorker>,
+    // Unix sockets of the audit log listeners
+    socket_files: Vec<String>,
+    src_client: Arc<Mutex<VaultClient>>,
+    dst_clients: Vec<Arc<Mutex<VaultClient>>>,
+}
+
+impl Shutdown {
+    // The configuration is the current one, revoke_on_exit and the hosts can be reloaded
+    fn run(self, config: &VaultSyncConfig) {
+        for worker in &self.http_workers {
+            worker.stop.store(true, Ordering::Relaxed);
+        }
+        for worker in self.http_workers {
+            let _ = worker.handle.join();
+        }
+        for file_name in &self.socket_files {
+            if let Err(error) = std::fs::remove_file(file_name) {
+                warn!("Failed to remove socket {}: {}", file_name, error);
+            }
+        }
+        webhook::flush(WEBHOOK_FLUSH_TIMEOUT);
+        if !config.revoke_on_exit {
+            return;
+        }
+        let hosts = std::iter::once(&config.src.host).chain(config.dst.iter().map(|dst| &dst.host));
+        let clients = std::iter::once(&self.src_client).chain(&self.dst_clients);
+        for (host, client) in hosts.zip(clients) {
+            match vault::revoke_token(host, &client.lock().unwrap()) {
+                Ok(true) => info!(host = host.url.as_str(); "Revoked token for {}", &host.url),
+                Ok(false) => info!(host = host.url.as_str(); "Token for {} is from the configuration, not revoking it", &host.url),
+                Err(error) => warn!(host = host.url.as_str(); "Failed to revoke token for {}: {}", &host.url, error),
+            }
+        }
+    }
+}
+
+// Shared client for a Vault host, the stop flag, the token expiry and the reconnect callback for
+// its token worker
+struct HostClient {
+    client: Arc<Mutex<VaultClient>>,
+    // Configured or detected version, kept on reload for the same host and backends
+    version: EngineVersion,
+    token_worker_stop: Arc<AtomicBool>,
+    token_expiry: Arc<AtomicU64>,
+    on_reconnect: OnReconnect,
+}
+
+impl HostClient {
+    // Replaces the client with a new one and restarts the token worker
+    fn replace(&mut self, client: VaultClient, host: &VaultHost, version: &EngineVersion) {
+        *self.client.lock().unwrap() = client;
+        self.version = version.clone();
+        self.token_worker_stop.store(true, Ordering::Relaxed);
+        self.token_worker_stop = Arc::new(AtomicBool::new(false));
+        token_worker(host, version, self.client.clone(), self.token_worker_stop.clone(), self.token_expiry.clone(), self.on_reconnect.clone());
+    }
+}
+
+// Reloads the configuration on SIGHUP
+fn reload_worker(
+    file_name: &str,
+    config: SharedConfig,
+    reverse_config: Option<SharedConfig>,
+    mut src: HostClient,
+    mut dsts: Vec<HostClient>,
+) -> Result<JoinHandle<()>, std::io::Error> {
+    let file_name = file_name.to_string();
+    let mut signals = Signals::new([SIGHUP])?;
+    let handle = thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("Reloading configuration from {}", &file_name);
+            reload_config(&file_name, &config, &reverse_config, &mut src, &mut dsts);
+        }
+    });
+    Ok(handle)
+}
+
+// Pauses the sync on SIGUSR1, resumes it on SIGUSR2
+fn pause_worker() -> Result<JoinHandle<()>, std::io::Error> {
+    let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+    let handle = thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGUSR1 {
+                pause::PAUSE.pause();
+            } else {
+                pause::PAUSE.resume();
+            }
+        }
+    });
+    Ok(handle)
+}
+
+fn reload_config(
+    file_name: &str,
+    config: &SharedConfig,
+    reverse_config: &Option<SharedConfig>,
+    src: &mut HostClient,
+    dsts: &mut [HostClient],
+) {
+    let Ok(mut new_config) = load_config(file_name) else {
+        warn!("Keeping the current configuration");
+        return;
+    };
+    let current = config.read().unwrap().clone();
+    if new_config.id != current.id
+        || new_config.bind != current.bind
+        || new_config.metrics_bind != current.metrics_bind
+        || new_config.health_bind != current.health_bind
+        || new_config.admin_bind != current.admin_bind
+        || new_config.state_path != current.state_path
+        || new_config.content_cache != current.content_cache
+        || new_config.content_cache_path != current.content_cache_path
+        || new_config.content_cache_key != current.content_cache_key
+        || new_config.full_sync_cursor_path != current.full_sync_cursor_path
+        || new_config.full_sync_concurrency != current.full_sync_concurrency
+        || new_config.pool_size != current.pool_size
+        || new_config.coalesce_ms != current.coalesce_ms
+        || new_config.queue_capacity != current.queue_capacity
+        || new_config.mode != current.mode
+        || new_config.live_sync != current.live_sync
+        || new_config.audit_mode != current.audit_mode
+        || new_config.audit_file != current.audit_file
+        || new_config.audit_path != current.audit_path
+        || new_config.webhook_url != current.webhook_url
+        || new_config.webhook_events != current.webhook_events {
+        warn!("Changes of id, bind, metrics_bind, health_bind, admin_bind, mode, live_sync, audit_mode, audit_file, audit_path, state_path, content_cache options, full_sync_cursor_path, full_sync_concurrency, pool_size, coalesce_ms, queue_capacity and webhook options require restart, ignoring");
+        new_config.id = current.id.clone();
+        new_config.bind = current.bind.clone();
+        new_config.metrics_bind = current.metrics_bind.clone();
+        new_config.health_bind = current.health_bind.clone();
+        new_config.admin_bind = current.admin_bind.clone();
+        new_config.state_path = current.state_path.clone();
+        new_config.content_cache = current.content_cache;
+        new_config.content_cache_path = current.content_cache_path.clone();
+        new_config.content_cache_key = current.content_cache_key.clone();
+        new_config.full_sync_cursor_path = current.full_sync_cursor_path.clone();
+        new_config.full_sync_concurrency = current.full_sync_concurrency;
+        new_config.pool_size = current.pool_size;
+        new_config.coalesce_ms = current.coalesce_ms;
+        new_config.queue_capacity = current.queue_capacity;
+        new_config.mode = current.mode.clone();
+        new_config.live_sync = current.live_sync;
+        new_config.audit_mode = current.audit_mode.clone();
+        new_config.audit_file = current.audit_file.clone();
+        new_config.audit_path = current.audit_path.clone();
+        new_config.webhook_url = current.webhook_url.clone();
+        new_config.webhook_events = current.webhook_events.clone();
+    }
+    // Each destination has its own client and token worker, started only once
+    if new_config.dst.len() != current.dst.len() {
+        warn!("Changing the number of destinations requires restart, keeping the current configuration");
+        return;
+    }
+
+    // Connect first, so a failure on any host keeps the current configuration for all of them
+    let Ok((src_client, src_version)) = reconnect(&new_config.src.host, &new_config.src.backend, &new_config.src.configured_version, &current.src.host, &current.src.backend, src) else {
+        warn!("Keeping the current configuration");
+        return;
+    };
+    new_config.src.version = src_version;
+    let mut dst_clients = Vec::new();
+    for ((new_dst, current_dst), dst) in new_config.dst.iter_mut().zip(&current.dst).zip(dsts.iter()) {
+        let Ok((dst_client, dst_version)) = reconnect(&new_dst.host, &new_dst.backend, &new_dst.configured_version, &current_dst.host, &current_dst.backend, dst) else {
+            warn!("Keeping the current configuration");
+            return;
+        };
+        new_dst.version = dst_version;
+        dst_clients.push(dst_client);
+    }
+    if let Err(error) = new_config.validate() {
+        warn!("Invalid configuration with the versions of the secrets engines: {}, keeping the current configuration", error);
+        return;
+    }
+    if let Some(client) = src_client {
+        src.replace(client, &new_config.src.host, &new_config.src.version);
+    }
+    for ((dst, new_dst), dst_client) in dsts.iter_mut().zip(&new_config.dst).zip(dst_clients) {
+        if let Some(client) = dst_client {
+            dst.replace(client, &new_dst.host, &new_dst.version);
+        }
+    }
+
+    if let Some(reverse_config) = reverse_config {
+        *reverse_config.write().unwrap() = new_config.reversed();
+    }
+    *config.write().unwrap() = new_config;
+    info!("Configuration reloaded");
+}
+
+// Returns a new client if the host or the secrets engine version has changed, and the version.
+// Without a configured version, the version of the current client is kept for the same host and
+// backends, otherwise it is detected again.
+fn reconnect(
+    host: &VaultHost,
+    backend: &Option<Backend>,
+    version: &Option<EngineVersion>,
+    current_host: &VaultHost,
+    current_backend: &Option<Backend>,
+    current: &HostClient,
+) -> Result<(Option<VaultClient>, EngineVersion), Box<dyn Error>> {
+    // A reload is not retried, the current client is kept if it fails
+    if host == current_host {
+        let version = match version {
+            Some(version) => version.clone(),
+            None if backend == current_backend => current.version.clone(),
+            None => engine_version(&current.client.lock().unwrap(), host, backend, &None)?,
+        };
+        if version == current.version {
+            return Ok((None, version));
+        }
+        info!(host = host.url.as_str(); "Connecting to {}", &host.url);
+        return Ok((Some(vault_client(host, &version, 0)?), version));
+    }
+    info!(host = host.url.as_str(); "Connecting to {}", &host.url);
+    let mut client = vault_client(host, &version.clone().unwrap_or_default(), 0)?;
+    let version = detect_version(&mut client, host, backend, version)?;
+    Ok((Some(client), version))
+}
@@ -0,0 +1,270 @@
+// Prometheus metrics in the text exposition format, served on metrics_bind
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time;
+
+use crate::health::{unix_time, HEALTH};
+
+pub struct Metrics {
+    pub secrets_synced: AtomicU64,
+    pub secrets_deleted: AtomicU64,
+    pub sync_errors: AtomicU64,
+    pub sync_retries: AtomicU64,
+    // Destination paths outside of allowed_dst_prefixes
+    pub secrets_rejected: AtomicU64,
+    // Secret operations that waited for a full queue
+    pub queue_blocked: AtomicU64,
+    // Paths that could not be listed, after retries
+    pub list_errors: AtomicU64,
+    // Secret operations skipped, or not, by content_cache without reading the destination
+    pub content_cache_hits: AtomicU64,
+    pub content_cache_misses: AtomicU64,
+    // Failed renewals of the tokens, including the logins after a failed renewal
+    pub token_renewal_failures: AtomicU64,
+    // Webhook notifications that did not fit in the queue
+    pub webhook_dropped: AtomicU64,
+    pub full_sync_runs: AtomicU64,
+    // Unix time in seconds
+    pub last_full_sync: AtomicU64,
+    // Number of SecretOps sent to the sync worker, but not received yet
+    pub queue_depth: AtomicI64,
+    // Durations of the requests to the destinations, by Request
+    pub destination_requests: [Histogram; 3],
+}
+
+// Kind of a request to a destination Vault, the label of the histogram
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request {
+    Read,
+    Write,
+    Delete,
+}
+
+impl Request {
+    const ALL: [Request; 3] = [Request::Read, Request::Write, Request::Delete];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Request::Read => "read",
+            Request::Write => "write",
+            Request::Delete => "delete",
+        }
+    }
+}
+
+// Upper bounds of the histogram buckets in seconds, from a fast local Vault to a slow remote one
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub struct Histogram {
+    // Not cumulative, every observation is counted in the first bucket it fits in
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: time::Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, text: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(text, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(text, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(text, "{}_sum{{{}}} {}", name, labels, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(text, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            secrets_synced: AtomicU64::new(0),
+            secrets_deleted: AtomicU64::new(0),
+            sync_errors: AtomicU64::new(0),
+            sync_retries: AtomicU64::new(0),
+            secrets_rejected: AtomicU64::new(0),
+            queue_blocked: AtomicU64::new(0),
+            list_errors: AtomicU64::new(0),
+            content_cache_hits: AtomicU64::new(0),
+            content_cache_misses: AtomicU64::new(0),
+            token_renewal_failures: AtomicU64::new(0),
+            webhook_dropped: AtomicU64::new(0),
+            full_sync_runs: AtomicU64::new(0),
+            last_full_sync: AtomicU64::new(0),
+            queue_depth: AtomicI64::new(0),
+            destination_requests: [const { Histogram::new() }; 3],
+        }
+    }
+
+    // Calls f, a request to a destination, and records its duration. The client is locked before,
+    // so the time waiting for other sync threads is not recorded.
+    pub fn time<T, F: FnOnce() -> T>(&self, request: Request, f: F) -> T {
+        let started = time::Instant::now();
+        let result = f();
+        self.destination_requests[request as usize].observe(started.elapsed());
+        result
+    }
+
+    pub fn full_sync_finished(&self) {
+        self.full_sync_runs.fetch_add(1, Ordering::Relaxed);
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.last_full_sync.store(now, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let counters = [
+            ("vault_sync_secrets_synced_total", "Number of secrets created or updated in the destination", &self.secrets_synced),
+            ("vault_sync_secrets_deleted_total", "Number of secrets deleted in the destination", &self.secrets_deleted),
+            ("vault_sync_errors_total", "Number of failed secret operations", &self.sync_errors),
+            ("vault_sync_retries_total", "Number of retried secret operations after transient errors", &self.sync_retries),
+            ("vault_sync_secrets_rejected_total", "Number of secret operations rejected by allowed_dst_prefixes", &self.secrets_rejected),
+            ("vault_sync_queue_blocked_total", "Number of secret operations that waited for a full queue", &self.queue_blocked),
+            ("vault_sync_list_errors_total", "Number of paths that could not be listed", &self.list_errors),
+            ("vault_sync_content_cache_hits_total", "Number of secrets skipped by content_cache without reading the destination", &self.content_cache_hits),
+            ("vault_sync_content_cache_misses_total", "Number of secrets not found unchanged in content_cache", &self.content_cache_misses),
+            ("vault_sync_token_renewal_failures_total", "Number of failed token renewals", &self.token_renewal_failures),
+            ("vault_sync_webhook_dropped_total", "Number of webhook notifications dropped because the queue was full", &self.webhook_dropped),
+            ("vault_sync_full_sync_runs_total", "Number of finished full syncs", &self.full_sync_runs),
+        ];
+        for (name, help, value) in counters {
+            metric(&mut text, name, help, "counter", value.load(Ordering::Relaxed) as i64);
+        }
+        metric(
+            &mut text,
+            "vault_sync_last_full_sync_timestamp_seconds",
+            "Unix time of the last finished full sync",
+            "gauge",
+            self.last_full_sync.load(Ordering::Relaxed) as i64,
+        );
+        metric(
+            &mut text,
+            "vault_sync_queue_depth",
+            "Number of secret operations waiting for the sync worker",
+            "gauge",
+            self.queue_depth.load(Ordering::Relaxed),
+        );
+        let name = "vault_sync_destination_request_duration_seconds";
+        let _ = writeln!(text, "# HELP {} Duration of reads, writes and deletes in the destination Vaults", name);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        for request in Request::ALL {
+            let labels = format!("operation=\"{}\"", request.label());
+            self.destination_requests[request as usize].render(&mut text, name, &labels);
+        }
+        text
+    }
+}
+
+// Tokens are Vault URLs and Unix times when the tokens expire, tokens that do not expire (0) are
+// not included
+fn render_tokens(text: &mut String, tokens: &[(String, u64)], now: u64) {
+    let name = "vault_sync_token_ttl_seconds";
+    let _ = writeln!(text, "# HELP {} Remaining TTL of the Vault token", name);
+    let _ = writeln!(text, "# TYPE {} gauge", name);
+    for (url, expiry) in tokens {
+        if *expiry != 0 {
+            let _ = writeln!(text, "{}{{host=\"{}\"}} {}", name, url.replace('\\', "\\\\").replace('"', "\\\""), expiry.saturating_sub(now));
+        }
+    }
+}
+
+fn metric(text: &mut String, name: &str, help: &str, metric_type: &str, value: i64) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(text, "{} {}", name, value);
+}
+
+// Handler for http::serve
+pub fn handle(method: &str, path: &str) -> (u16, String) {
+    match (method, path) {
+        ("GET", "/metrics") => {
+            let mut text = METRICS.render();
+            render_tokens(&mut text, &HEALTH.tokens(), unix_time());
+            (200, text)
+        },
+        _ => (404, "Not found\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use crate::metrics::{handle, render_tokens, Metrics, Request};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.secrets_synced.fetch_add(3, Ordering::Relaxed);
+        metrics.queue_depth.fetch_add(2, Ordering::Relaxed);
+        let text = metrics.render();
+        assert!(text.contains("# HELP vault_sync_secrets_synced_total "));
+        assert!(text.contains("# TYPE vault_sync_secrets_synced_total counter\nvault_sync_secrets_synced_total 3\n"));
+        assert!(text.contains("# TYPE vault_sync_queue_depth gauge\nvault_sync_queue_depth 2\n"));
+        assert!(text.contains("vault_sync_last_full_sync_timestamp_seconds 0\n"));
+        metrics.full_sync_finished();
+        assert!(metrics.render().contains("vault_sync_full_sync_runs_total 1\n"));
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let metrics = Metrics::new();
+        metrics.destination_requests[Request::Write as usize].observe(Duration::from_millis(20));
+        metrics.destination_requests[Request::Write as usize].observe(Duration::from_millis(300));
+        metrics.destination_requests[Request::Write as usize].observe(Duration::from_secs(20));
+        assert_eq!(metrics.time(Request::Delete, || 42), 42);
+        let text = metrics.render();
+        let name = "vault_sync_destination_request_duration_seconds";
+        assert!(text.contains(&format!("# TYPE {} histogram\n", name)));
+        assert!(text.contains(&format!("{}_bucket{{operation=\"write\",le=\"0.01\"}} 0\n", name)));
+        assert!(text.contains(&format!("{}_bucket{{operation=\"write\",le=\"0.025\"}} 1\n", name)));
+        assert!(text.contains(&format!("{}_bucket{{operation=\"write\",le=\"10\"}} 2\n", name)));
+        assert!(text.contains(&format!("{}_bucket{{operation=\"write\",le=\"+Inf\"}} 3\n", name)));
+        assert!(text.contains(&format!("{}_sum{{operation=\"write\"}} 20.32\n", name)));
+        assert!(text.contains(&format!("{}_count{{operation=\"read\"}} 0\n", name)));
+        assert!(text.contains(&format!("{}_count{{operation=\"delete\"}} 1\n", name)));
+    }
+
+    #[test]
+    fn test_render_tokens() {
+        let mut text = String::new();
+        let tokens = vec![
+            ("http://127.0.0.1:8200/".to_string(), 4600),
+            ("http://127.0.0.1:8201/".to_string(), 0),
+        ];
+        render_tokens(&mut text, &tokens, 1000);
+        assert!(text.contains("# TYPE vault_sync_token_ttl_seconds gauge\n"));
+        assert!(text.contains("vault_sync_token_ttl_seconds{host=\"http://127.0.0.1:8200/\"} 3600\n"));
+        assert!(!text.contains("8201"));
+    }
+
+    #[test]
+    fn test_handle() {
+        let (status, body) = handle("GET", "/metrics");
+        assert_eq!(status, 200);
+        assert!(body.contains("# TYPE vault_sync_errors_total counter"));
+        assert_eq!(handle("GET", "/").0, 404);
+    }
+}
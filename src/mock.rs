@@ -0,0 +1,193 @@
+// Minimal HTTP server that mocks Vault API in unit tests.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::config::{Backend, EngineVersion, VaultAuthMethod, VaultHost, VaultSyncConfig};
+use crate::vault::{vault_client, VaultClient};
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+    // The body as received, body replaces bytes that are not valid UTF-8
+    pub bytes: Vec<u8>,
+}
+
+type Handler = dyn Fn(&Request) -> (u16, Vec<u8>) + Send + Sync;
+
+pub struct MockServer {
+    pub url: String,
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+    handler: Arc<Handler>,
+    stopped: Arc<AtomicBool>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl MockServer {
+    // Starts the server in a background thread. For every request, the handler returns an HTTP
+    // status and a response body.
+    pub fn start<F>(handler: F) -> MockServer
+        where
+            F: Fn(&Request) -> (u16, String) + Send + Sync + 'static,
+    {
+        Self::start_bytes(move |request| {
+            let (status, body) = handler(request);
+            (status, body.into_bytes())
+        })
+    }
+
+    // Same as start, but the response body can be any bytes, for example invalid UTF-8
+    pub fn start_bytes<F>(handler: F) -> MockServer
+        where
+            F: Fn(&Request) -> (u16, Vec<u8>) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = MockServer {
+            url: format!("http://127.0.0.1:{}/", addr.port()),
+            addr,
+            requests: Arc::new(Mutex::new(Vec::new())),
+            handler: Arc::new(handler),
+            stopped: Arc::new(AtomicBool::new(false)),
+            worker: Mutex::new(None),
+        };
+        server.serve(listener);
+        server
+    }
+
+    // Closes the listening socket, so connections are refused like for a Vault that is down
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes up the worker blocked in accept
+        let _ = TcpStream::connect(self.addr);
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            let _ = worker.join();
+        }
+    }
+
+    // Starts the stopped server again on the same port
+    pub fn restart(&self) {
+        self.stopped.store(false, Ordering::SeqCst);
+        self.serve(TcpListener::bind(self.addr).unwrap());
+    }
+
+    fn serve(&self, listener: TcpListener) {
+        let requests = self.requests.clone();
+        let handler = self.handler.clone();
+        let stopped = self.stopped.clone();
+        let worker = thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Some(request) = read_request(&stream) {
+                    requests.lock().unwrap().push(request.clone());
+                    let (status, body) = handler(&request);
+                    write_response(&stream, status, &body);
+                }
+            }
+        });
+        *self.worker.lock().unwrap() = Some(worker);
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // Returns number of received requests for the given method and path
+    pub fn count(&self, method: &str, path: &str) -> usize {
+        self.requests().iter().filter(|r| r.method == method && r.path == path).count()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request { method, path, body: String::from_utf8_lossy(&body).to_string(), bytes: body })
+}
+
+fn write_response(mut stream: &TcpStream, status: u16, body: &[u8]) {
+    let mut response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len(),
+    ).into_bytes();
+    response.extend_from_slice(body);
+    let _ = stream.write_all(&response);
+}
+
+// Response for auth/token/lookup-self
+pub fn lookup_self(renewable: bool, ttl: u64) -> String {
+    format!(
+        r#"{{"request_id":"1","data":{{"accessor":"accessor","creation_time":0,"display_name":"token","id":"token","num_uses":0,"orphan":true,"path":"auth/token/create","policies":["default"],"renewable":{},"ttl":{}}}}}"#,
+        renewable,
+        ttl,
+    )
+}
+
+// Response for auth/<mount>/login
+pub fn login(token: &str, renewable: bool, ttl: u64) -> String {
+    format!(
+        r#"{{"request_id":"1","auth":{{"client_token":"{}","policies":["default"],"lease_duration":{},"renewable":{}}}}}"#,
+        token,
+        ttl,
+        renewable,
+    )
+}
+
+// Client with token auth for the server, KV v2
+pub fn client(server: &MockServer) -> Arc<Mutex<VaultClient>> {
+    let host = VaultHost {
+        url: server.url.clone(),
+        auth: Some(VaultAuthMethod::TokenAuth { token: "token".to_string() }),
+        ..Default::default()
+    };
+    Arc::new(Mutex::new(vault_client(&host, &EngineVersion::V2).unwrap()))
+}
+
+// Configuration syncing the prefix src to dst of the backends secret
+pub fn config(src_url: &str, dst_url: &str) -> VaultSyncConfig {
+    let yaml = format!(r#"
+        id: vault-sync
+        full_sync_interval: 60
+        src:
+          url: {}
+          token: token
+          prefix: src
+        dst:
+          url: {}
+          token: token
+          prefix: dst
+    "#, src_url, dst_url);
+    let mut config: VaultSyncConfig = serde_yaml::from_str(&yaml).unwrap();
+    config.src.backend = Some(Backend::Backend("secret".to_string()));
+    config.dst[0].backend = Some(Backend::Backend("secret".to_string()));
+    config
+}
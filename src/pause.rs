@@ -0,0 +1,113 @@
+// Pause of the writes to the destinations for maintenance, SIGUSR1 pauses and SIGUSR2 resumes.
+// While paused, the sync threads wait before applying the next op, so the ops stay in the queues
+// until they are full (see queue_capacity), then the audit log and full sync wait too. A shutdown
+// while paused stops the sync threads without applying the queued ops.
+
+use std::sync::{Condvar, Mutex};
+
+use log::info;
+
+pub struct Pause {
+    state: Mutex<State>,
+    resumed: Condvar,
+}
+
+struct State {
+    paused: bool,
+    stopped: bool,
+}
+
+pub static PAUSE: Pause = Pause::new();
+
+impl Pause {
+    const fn new() -> Pause {
+        Pause { state: Mutex::new(State { paused: false, stopped: false }), resumed: Condvar::new() }
+    }
+
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.paused {
+            state.paused = true;
+            info!("Sync paused, secret operations are queued until resumed");
+        }
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            state.paused = false;
+            info!("Sync resumed");
+            self.resumed.notify_all();
+        }
+    }
+
+    // On shutdown, wakes the waiting threads without resuming, so the ops are not applied
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        self.resumed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    // Returns true when the sync is not paused, false if it is stopped while paused
+    pub fn wait(&self) -> bool {
+        let state = self.resumed.wait_while(self.state.lock().unwrap(), |state| state.paused && !state.stopped).unwrap();
+        !state.paused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time;
+
+    use crate::pause::Pause;
+
+    #[test]
+    fn test_pause() {
+        let pause: &'static Pause = Box::leak(Box::new(Pause::new()));
+        assert!(!pause.is_paused());
+        assert!(pause.wait());
+
+        pause.pause();
+        pause.pause();
+        assert!(pause.is_paused());
+        let done = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let done = done.clone();
+            thread::spawn(move || {
+                assert!(pause.wait());
+                done.store(true, Ordering::Relaxed);
+            })
+        };
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(!done.load(Ordering::Relaxed));
+
+        pause.resume();
+        waiter.join().unwrap();
+        assert!(done.load(Ordering::Relaxed));
+        assert!(!pause.is_paused());
+    }
+
+    #[test]
+    fn test_stop() {
+        let pause: &'static Pause = Box::leak(Box::new(Pause::new()));
+        pause.pause();
+        let waiter = thread::spawn(move || pause.wait());
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        // Still paused, the waiting and the next ops are not applied
+        pause.stop();
+        assert!(!waiter.join().unwrap());
+        assert!(!pause.wait());
+        assert!(pause.is_paused());
+        pause.resume();
+        assert!(pause.wait());
+    }
+}
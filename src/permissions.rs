@@ -0,0 +1,231 @@
+// Capabilities of the vault-sync tokens, checked with --once-validate-permissions before the sync
+// starts failing with 403 responses
+
+use std::collections::HashMap;
+
+use hashicorp_vault::client::error::Result as VaultResult;
+
+use crate::config::{EngineVersion, get_backends, get_prefixes, SecretEngine, VaultDestination, VaultSource};
+use crate::table;
+use crate::vault::{self, VaultClient};
+
+// Capabilities that vault-sync needs for a path
+#[derive(Debug, PartialEq)]
+pub struct Requirement {
+    pub path: String,
+    pub capabilities: Vec<&'static str>,
+}
+
+// Required and granted capabilities for a path in one of the Vaults
+#[derive(Debug, PartialEq)]
+pub struct Row {
+    pub host: String,
+    pub path: String,
+    pub required: Vec<&'static str>,
+    pub granted: Vec<String>,
+}
+
+impl Row {
+    pub fn missing(&self) -> Vec<&'static str> {
+        if self.granted.iter().any(|capability| capability == "root") {
+            return Vec::new();
+        }
+        self.required.iter()
+            .filter(|required| !self.granted.iter().any(|granted| granted == *required))
+            .copied()
+            .collect()
+    }
+
+    // Missing capabilities that are needed to write secrets to the path
+    pub fn missing_write(&self) -> Vec<&'static str> {
+        self.missing().into_iter().filter(|capability| WRITE_CAPABILITIES.contains(capability)).collect()
+    }
+}
+
+// The other capabilities are needed for listing and removing secrets
+const WRITE_CAPABILITIES: [&str; 2] = ["create", "update"];
+
+// Secrets are read and listed, metadata of KV v2 secrets is read for versions and settings
+pub fn src_requirements(src: &VaultSource) -> Vec<Requirement> {
+    if src.secret_engine == SecretEngine::Transit {
+        return transit_requirements(&src.synced_backends(), &[("keys", &["read", "list"]), ("backup", &["read"])]);
+    }
+    kv_requirements(&src.synced_backends(), &get_prefixes(&src.prefix), &src.version, &["read", "list"], &["read", "list"])
+}
+
+// Secrets are written and deleted, metadata of KV v2 secrets is written with the settings of the
+// source. Secrets missing in the source are listed and removed with propagate_deletes.
+pub fn dst_requirements(dst: &VaultDestination, engine: &SecretEngine) -> Vec<Requirement> {
+    if engine == &SecretEngine::Transit {
+        return transit_requirements(&get_backends(&dst.backend), &[("keys", &["create", "read", "update"]), ("restore", &["update"])]);
+    }
+    let mut data = vec!["create", "update", "delete"];
+    let mut metadata = vec!["read", "update"];
+    if dst.propagate_deletes {
+        data.push("list");
+        metadata.extend(["list", "delete"]);
+    }
+    kv_requirements(&get_backends(&dst.backend), &get_prefixes(&dst.prefix), &dst.version, &data, &metadata)
+}
+
+// Audit devices are listed on start, and the vault-sync device is enabled again if it disappears
+pub fn audit_requirements(audit_path: &str) -> Vec<Requirement> {
+    vec![
+        Requirement { path: "sys/audit".to_string(), capabilities: vec!["read", "sudo"] },
+        Requirement { path: format!("sys/audit/{}", audit_path), capabilities: vec!["sudo", "create", "update", "delete"] },
+    ]
+}
+
+// Paths end with the prefixes, so policies with globs like "secret/data/src/*" also cover them
+fn kv_requirements(
+    backends: &[String],
+    prefixes: &[String],
+    version: &EngineVersion,
+    data: &[&'static str],
+    metadata: &[&'static str],
+) -> Vec<Requirement> {
+    let mut requirements = Vec::new();
+    for backend in backends {
+        for prefix in prefixes {
+            match version {
+                EngineVersion::V1 => {
+                    requirements.push(Requirement { path: format!("{}/{}", backend, prefix), capabilities: data.to_vec() });
+                },
+                // Secrets are listed in the metadata
+                EngineVersion::V2 => {
+                    let capabilities = data.iter().filter(|capability| **capability != "list").copied().collect();
+                    requirements.push(Requirement { path: format!("{}/data/{}", backend, prefix), capabilities });
+                    requirements.push(Requirement { path: format!("{}/metadata/{}", backend, prefix), capabilities: metadata.to_vec() });
+                },
+            }
+        }
+    }
+    requirements
+}
+
+// Paths end with a slash, policies like "transit/keys/*" cover them
+fn transit_requirements(backends: &[String], endpoints: &[(&str, &[&'static str])]) -> Vec<Requirement> {
+    let mut requirements = Vec::new();
+    for backend in backends {
+        for (endpoint, capabilities) in endpoints {
+            requirements.push(Requirement { path: format!("{}/{}/", backend, endpoint), capabilities: capabilities.to_vec() });
+        }
+    }
+    requirements
+}
+
+pub fn check(host: &str, client: &VaultClient, requirements: Vec<Requirement>) -> VaultResult<Vec<Row>> {
+    let paths: Vec<String> = requirements.iter().map(|requirement| requirement.path.clone()).collect();
+    let mut granted: HashMap<String, Vec<String>> = vault::capabilities(client, &paths)?;
+    Ok(requirements.into_iter().map(|requirement| Row {
+        host: host.to_string(),
+        granted: granted.remove(&requirement.path).unwrap_or_default(),
+        path: requirement.path,
+        required: requirement.capabilities,
+    }).collect())
+}
+
+// Renders the rows as a table with aligned columns
+pub fn render(rows: &[Row]) -> String {
+    let mut lines = vec![["HOST", "PATH", "REQUIRED", "GRANTED", "MISSING"].map(String::from).to_vec()];
+    for row in rows {
+        let missing = row.missing();
+        lines.push(vec![
+            row.host.clone(),
+            row.path.clone(),
+            row.required.join(","),
+            if row.granted.is_empty() { "-".to_string() } else { row.granted.join(",") },
+            if missing.is_empty() { "-".to_string() } else { missing.join(",") },
+        ]);
+    }
+    table::render(&lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{EngineVersion, SecretEngine, VaultDestination};
+    use crate::mock::{self, MockServer};
+    use crate::permissions::{audit_requirements, check, dst_requirements, render, src_requirements, Requirement, Row};
+    
+    #[test]
+    fn test_requirements() {
+        let mut config = mock::config("http://127.0.0.1:8200/", "http://127.0.0.1:8200/");
+        assert_eq!(src_requirements(&config.src), vec![
+            Requirement { path: "secret/data/src/".to_string(), capabilities: vec!["read"] },
+            Requirement { path: "secret/metadata/src/".to_string(), capabilities: vec!["read", "list"] },
+        ]);
+        assert_eq!(dst_requirements(&config.dst[0], &SecretEngine::Kv), vec![
+            Requirement { path: "secret/data/dst/".to_string(), capabilities: vec!["create", "update", "delete"] },
+            Requirement { path: "secret/metadata/dst/".to_string(), capabilities: vec!["read", "update"] },
+        ]);
+
+        let dst: &mut VaultDestination = &mut config.dst[0];
+        dst.version = EngineVersion::V1;
+        dst.propagate_deletes = true;
+        assert_eq!(dst_requirements(dst, &SecretEngine::Kv), vec![
+            Requirement { path: "secret/dst/".to_string(), capabilities: vec!["create", "update", "delete", "list"] },
+        ]);
+        assert_eq!(dst_requirements(dst, &SecretEngine::Transit), vec![
+            Requirement { path: "secret/keys/".to_string(), capabilities: vec!["create", "read", "update"] },
+            Requirement { path: "secret/restore/".to_string(), capabilities: vec!["update"] },
+        ]);
+    }
+
+    #[test]
+    fn test_check() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("POST", "/v1/sys/capabilities-self") => (200, r#"{"request_id":"1","data":{
+                    "capabilities":["read"],
+                    "secret/data/src/":["read"],
+                    "secret/metadata/src/":["list"]
+                }}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let client = mock::client(&server);
+        let rows = check("src", &client.lock().unwrap(), src_requirements(&mock::config(&server.url, &server.url).src)).unwrap();
+        assert!(rows[0].missing().is_empty());
+        assert_eq!(rows[1].missing(), vec!["read"]);
+        assert!(rows[1].missing_write().is_empty());
+
+        let request = server.requests().into_iter().find(|r| r.method == "POST").unwrap();
+        assert_eq!(request.body, r#"{"paths":["secret/data/src/","secret/metadata/src/"]}"#);
+    }
+
+    #[test]
+    fn test_audit_requirements() {
+        let requirements = audit_requirements("vault-sync");
+        assert_eq!(requirements[0], Requirement { path: "sys/audit".to_string(), capabilities: vec!["read", "sudo"] });
+        assert_eq!(requirements[1], Requirement {
+            path: "sys/audit/vault-sync".to_string(),
+            capabilities: vec!["sudo", "create", "update", "delete"],
+        });
+    }
+
+    #[test]
+    fn test_missing_write() {
+        let row = Row {
+            host: "dst".to_string(),
+            path: "secret/data/dst/".to_string(),
+            required: vec!["create", "update", "delete", "list"],
+            granted: vec!["update".to_string(), "list".to_string()],
+        };
+        assert_eq!(row.missing(), vec!["create", "delete"]);
+        assert_eq!(row.missing_write(), vec!["create"]);
+    }
+
+    #[test]
+    fn test_render() {
+        let rows = vec![
+            Row { host: "src".to_string(), path: "secret/data/src/".to_string(), required: vec!["read"], granted: vec!["root".to_string()] },
+            Row { host: "dst".to_string(), path: "sys/audit".to_string(), required: vec!["read", "sudo"], granted: vec![] },
+        ];
+        assert_eq!(render(&rows), "\
+HOST  PATH              REQUIRED   GRANTED  MISSING
+src   secret/data/src/  read       root     -
+dst   sys/audit         read,sudo  -        read,sudo
+");
+    }
+}
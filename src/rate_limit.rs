@@ -0,0 +1,82 @@
+// Token bucket limiter for requests to a destination Vault
+
+use std::{thread, time};
+
+use log::debug;
+
+pub struct RateLimiter {
+    // Tokens added per second
+    rate: f64,
+    // Maximum number of tokens, so a short burst after an idle period is allowed
+    capacity: f64,
+    tokens: f64,
+    last: time::Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> RateLimiter {
+        let capacity = rate.max(1.0);
+        RateLimiter { rate, capacity, tokens: capacity, last: time::Instant::now() }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    // Takes a token, waits until it is available
+    pub fn acquire(&mut self) {
+        let delay = self.acquire_at(time::Instant::now());
+        if !delay.is_zero() {
+            debug!("Rate limit reached, waiting {}ms", delay.as_millis());
+            thread::sleep(delay);
+        }
+    }
+
+    // Takes a token at the given time, returns how long to wait for it. The token can be taken in
+    // advance, so the bucket can be negative until the delay is over.
+    fn acquire_at(&mut self, now: time::Instant) -> time::Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity) - 1.0;
+        if self.tokens >= 0.0 {
+            time::Duration::ZERO
+        } else {
+            time::Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::rate_limit::RateLimiter;
+
+    #[test]
+    fn test_acquire_at() {
+        let mut limiter = RateLimiter::new(2.0);
+        let start = limiter.last;
+        // Full bucket
+        assert_eq!(limiter.acquire_at(start), Duration::ZERO);
+        assert_eq!(limiter.acquire_at(start), Duration::ZERO);
+        // Empty bucket, a token every 500ms
+        assert_eq!(limiter.acquire_at(start), Duration::from_millis(500));
+        assert_eq!(limiter.acquire_at(start), Duration::from_millis(1000));
+        // After the delays are over, the bucket is empty again
+        let later = start + Duration::from_millis(1000);
+        assert_eq!(limiter.acquire_at(later), Duration::from_millis(500));
+        // Idle for a long time, but not more than the capacity
+        let idle = later + Duration::from_secs(60);
+        assert_eq!(limiter.acquire_at(idle), Duration::ZERO);
+        assert_eq!(limiter.acquire_at(idle), Duration::ZERO);
+        assert_eq!(limiter.acquire_at(idle), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_slow_rate() {
+        let mut limiter = RateLimiter::new(0.5);
+        let start = limiter.last;
+        assert_eq!(limiter.acquire_at(start), Duration::ZERO);
+        assert_eq!(limiter.acquire_at(start), Duration::from_secs(2));
+    }
+}
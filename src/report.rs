@@ -0,0 +1,124 @@
+// Changes that vault-sync would make in dry run mode, written as JSON at the end of a full sync
+
+use std::fs;
+use std::io::Write;
+use std::time;
+
+use serde::{Deserialize, Serialize};
+
+// Level of --dry-run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DryRun {
+    // Destination secrets are read to classify the actions, read errors like 403 fail the sync
+    Verify,
+    // Destination secrets are neither read nor written, creates and updates are planned as writes
+    Plan,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DryRunReport {
+    // Unix time of the report
+    pub timestamp: u64,
+    pub summary: Summary,
+    // Sorted by destination and path
+    pub actions: Vec<Action>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct Summary {
+    pub create: u64,
+    pub update: u64,
+    // Creates or updates, planned without reading the destination
+    #[serde(default)]
+    pub write: u64,
+    pub delete: u64,
+    pub destroy: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Action {
+    pub action: ActionKind,
+    // URL of the destination Vault
+    pub destination: String,
+    // Destination secret
+    pub mount: String,
+    pub path: String,
+    // For updates, whether the destination value differs from the source, not set if unknown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionKind {
+    Create,
+    Update,
+    Write,
+    Delete,
+    Destroy,
+}
+
+impl DryRunReport {
+    pub fn new(mut actions: Vec<Action>) -> DryRunReport {
+        actions.sort_by(|a, b| (&a.destination, &a.mount, &a.path).cmp(&(&b.destination, &b.mount, &b.path)));
+        let mut summary = Summary::default();
+        for action in &actions {
+            match action.action {
+                ActionKind::Create => summary.create += 1,
+                ActionKind::Update => summary.update += 1,
+                ActionKind::Write => summary.write += 1,
+                ActionKind::Delete => summary.delete += 1,
+                ActionKind::Destroy => summary.destroy += 1,
+            }
+        }
+        let timestamp = time::SystemTime::now().duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        DryRunReport { timestamp, summary, actions }
+    }
+
+    // Writes the report as one line to stdout, or replaces the file with it
+    pub fn write(&self, file_name: Option<&str>) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        match file_name {
+            Some(file_name) => fs::write(file_name, line),
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(line.as_bytes())?;
+                stdout.flush()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::report::{Action, ActionKind, DryRunReport};
+
+    fn action(action: ActionKind, path: &str, changed: Option<bool>) -> Action {
+        Action {
+            action,
+            destination: "http://dst/".to_string(),
+            mount: "secret".to_string(),
+            path: path.to_string(),
+            changed,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let report = DryRunReport::new(vec![
+            action(ActionKind::Update, "dst/b", Some(true)),
+            action(ActionKind::Create, "dst/a", None),
+            action(ActionKind::Update, "dst/c", Some(false)),
+            action(ActionKind::Write, "dst/d", None),
+        ]);
+        assert_eq!(report.summary.create, 1);
+        assert_eq!(report.summary.update, 2);
+        assert_eq!(report.summary.write, 1);
+        let paths: Vec<&str> = report.actions.iter().map(|action| action.path.as_str()).collect();
+        assert_eq!(paths, vec!["dst/a", "dst/b", "dst/c", "dst/d"]);
+
+        let json = serde_json::to_string(&report.actions[0]).unwrap();
+        assert_eq!(json, r#"{"action":"create","destination":"http://dst/","mount":"secret","path":"dst/a"}"#);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+
+// Rule of the rewrites list of a destination
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Rewrite {
+    // Regex matched against the secret path relative to the source prefix, for example "^team-a/"
+    pub pattern: String,
+    // Replaces the first match, can refer to capture groups, for example "imported/team-a/$1"
+    pub replacement: String,
+}
+
+// Rewrite rules for secret paths relative to the prefixes. Rules are tried in order, the first
+// matching rule wins.
+#[derive(Clone, Default)]
+pub struct PathRewriter {
+    rules: Vec<(Regex, String)>,
+}
+
+impl PathRewriter {
+    pub fn new(rewrites: &[Rewrite]) -> Result<PathRewriter, ConfigError> {
+        let rules = rewrites.iter()
+            .map(|rewrite| match Regex::new(&rewrite.pattern) {
+                Ok(re) => Ok((re, rewrite.replacement.clone())),
+                Err(error) => Err(ConfigError::InvalidPattern(rewrite.pattern.clone(), error.to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(PathRewriter { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Returns None if no rule matches the path
+    pub fn rewrite(&self, path: &str) -> Option<String> {
+        self.rules.iter()
+            .find(|(re, _)| re.is_match(path))
+            .map(|(re, replacement)| re.replace(path, replacement.as_str()).into_owned())
+    }
+}
+
+impl PartialEq for PathRewriter {
+    fn eq(&self, other: &Self) -> bool {
+        let rules = |rewriter: &PathRewriter| rewriter.rules.iter()
+            .map(|(re, replacement)| (re.as_str().to_string(), replacement.clone()))
+            .collect::<Vec<_>>();
+        rules(self) == rules(other)
+    }
+}
+
+impl fmt::Debug for PathRewriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.rules.iter().map(|(re, replacement)| (re.as_str(), replacement)))
+            .finish()
+    }
+}
+
+// Destination path with fields of the source secret, for example "{{custom_metadata.team}}/{{path}}".
+// {{path}} is the path relative to the prefixes, after rewrites.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Text(String),
+    Path,
+    CustomMetadata(String),
+}
+
+impl PathTemplate {
+    pub fn new(template: &str) -> Result<PathTemplate, ConfigError> {
+        let error = |reason| ConfigError::InvalidPathTemplate(template.to_string(), reason);
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(TemplatePart::Text(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find("}}") else {
+                return Err(error("unclosed {{"));
+            };
+            let field = rest[start + 2..start + end].trim();
+            parts.push(match field.strip_prefix("custom_metadata.") {
+                None if field == "path" => TemplatePart::Path,
+                Some(key) if !key.is_empty() => TemplatePart::CustomMetadata(key.to_string()),
+                _ => return Err(error("fields are path and custom_metadata.<key>")),
+            });
+            rest = &rest[start + end + 2..];
+        }
+        if rest.contains("}}") {
+            return Err(error("unopened }}"));
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Text(rest.to_string()));
+        }
+        // Otherwise all secrets would be written to the same paths
+        if !parts.contains(&TemplatePart::Path) {
+            return Err(error("{{path}} is required"));
+        }
+        Ok(PathTemplate { parts })
+    }
+
+    // A custom_metadata value that is missing, or not a single path segment, is replaced with the
+    // default. Returns None if there is no default.
+    pub fn render(&self, path: &str, custom_metadata: &HashMap<String, String>, default: Option<&str>) -> Option<String> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Text(text) => rendered.push_str(text),
+                TemplatePart::Path => rendered.push_str(path),
+                TemplatePart::CustomMetadata(key) => {
+                    let value = custom_metadata.get(key).map(String::as_str).filter(|value| is_segment(value));
+                    rendered.push_str(value.or(default)?);
+                },
+            }
+        }
+        Some(rendered)
+    }
+}
+
+// Otherwise a value set by someone who can write the source metadata could move the secret out of
+// the destination prefix, or to paths of other secrets
+pub fn is_segment(value: &str) -> bool {
+    !value.is_empty() && value != "." && value != ".." && !value.contains('/') && !value.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::rewrite::{PathRewriter, PathTemplate, Rewrite};
+
+    fn rewriter(rules: &[(&str, &str)]) -> PathRewriter {
+        let rewrites: Vec<Rewrite> = rules.iter()
+            .map(|(pattern, replacement)| Rewrite { pattern: pattern.to_string(), replacement: replacement.to_string() })
+            .collect();
+        PathRewriter::new(&rewrites).unwrap()
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let rewriter = rewriter(&[
+            ("^team-a/(.*)$", "imported/team-a/$1"),
+            ("^([^/]+)/nested/(.*)$", "$1/$2"),
+            ("^team-a/", "never/"),
+        ]);
+        assert_eq!(rewriter.rewrite("team-a/secret").unwrap(), "imported/team-a/secret");
+        assert_eq!(rewriter.rewrite("team-b/nested/secret").unwrap(), "team-b/secret");
+        assert_eq!(rewriter.rewrite("team-c/secret"), None);
+        assert!(PathRewriter::default().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let rewrites = vec![Rewrite { pattern: "team-(".to_string(), replacement: "".to_string() }];
+        let result = PathRewriter::new(&rewrites);
+        assert!(result.unwrap_err().to_string().contains("team-("));
+    }
+
+    #[test]
+    fn test_path_template() {
+        let template = PathTemplate::new("teams/{{ custom_metadata.team }}/{{path}}").unwrap();
+        let metadata = HashMap::from([("team".to_string(), "a".to_string())]);
+        assert_eq!(template.render("app/secret", &metadata, None).unwrap(), "teams/a/app/secret");
+        assert_eq!(template.render("app/secret", &HashMap::new(), None), None);
+        assert_eq!(template.render("app/secret", &HashMap::new(), Some("shared")).unwrap(), "teams/shared/app/secret");
+        let empty = HashMap::from([("team".to_string(), "".to_string())]);
+        assert_eq!(template.render("app/secret", &empty, Some("shared")).unwrap(), "teams/shared/app/secret");
+        for value in ["..", ".", "a/b", "../../other", "a\nb", "a\u{0}"] {
+            let invalid = HashMap::from([("team".to_string(), value.to_string())]);
+            assert_eq!(template.render("app/secret", &invalid, Some("shared")).unwrap(), "teams/shared/app/secret");
+            assert_eq!(template.render("app/secret", &invalid, None), None);
+        }
+        assert_eq!(PathTemplate::new("{{path}}").unwrap().render("app/secret", &HashMap::new(), None).unwrap(), "app/secret");
+    }
+
+    #[test]
+    fn test_invalid_path_template() {
+        let error = |template| PathTemplate::new(template).unwrap_err().to_string();
+        assert_eq!(error("{{custom_metadata.team}}"), "Invalid path_template \"{{custom_metadata.team}}\": {{path}} is required");
+        assert!(error("{{path").ends_with("unclosed {{"));
+        assert!(error("{{path}}}}").ends_with("unopened }}"));
+        assert!(error("{{version}}/{{path}}").ends_with("fields are path and custom_metadata.<key>"));
+        assert!(error("{{custom_metadata.}}/{{path}}").ends_with("fields are path and custom_metadata.<key>"));
+    }
+}
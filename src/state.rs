@@ -0,0 +1,350 @@
+// Source secrets already synced to the destinations, for the incremental full sync. Saved to
+// state_path as a JSON object. Checksums of the secrets last written, for content_cache.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::checksum::ChecksumKey;
+use crate::sync::SecretPath;
+
+#[derive(Default)]
+pub struct SyncState {
+    file_name: Option<String>,
+    // Destination URL and secret -> updated_time of the source secret metadata when it was synced
+    synced: Mutex<HashMap<String, String>>,
+    changed: AtomicBool,
+}
+
+impl SyncState {
+    // Without the file, nothing is recorded. A file that cannot be read is logged, then all
+    // secrets are synced again.
+    pub fn load(file_name: Option<&str>) -> SyncState {
+        let Some(file_name) = file_name else {
+            return SyncState::default();
+        };
+        let synced = match fs::read_to_string(file_name) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(synced) => synced,
+                Err(error) => {
+                    warn!("Failed to parse sync state {}, syncing all secrets: {}", file_name, error);
+                    HashMap::new()
+                }
+            },
+            Err(error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(error) => {
+                warn!("Failed to read sync state {}, syncing all secrets: {}", file_name, error);
+                HashMap::new()
+            }
+        };
+        info!("Sync state {}: {} secrets", file_name, synced.len());
+        SyncState {
+            file_name: Some(file_name.to_string()),
+            synced: Mutex::new(synced),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file_name.is_some()
+    }
+
+    // True if the secret is synced to the destination since the source secret was updated
+    pub fn is_synced(&self, url: &str, dst: &SecretPath, updated_time: &str) -> bool {
+        self.synced.lock().unwrap().get(&key(url, dst)).is_some_and(|synced| synced == updated_time)
+    }
+
+    pub fn set_synced(&self, url: &str, dst: &SecretPath, updated_time: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.synced.lock().unwrap().insert(key(url, dst), updated_time.to_string());
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    // The secret is synced again on the next full sync
+    pub fn remove(&self, url: &str, dst: &SecretPath) {
+        if self.synced.lock().unwrap().remove(&key(url, dst)).is_some() {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Writes the file if anything changed since the last save. The file is replaced, so it is
+    // never partially written.
+    pub fn save(&self) {
+        let Some(file_name) = &self.file_name else {
+            return;
+        };
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let text = serde_json::to_string(&*self.synced.lock().unwrap()).unwrap();
+        let temp_name = format!("{}.tmp", file_name);
+        if let Err(error) = fs::write(&temp_name, text).and_then(|_| fs::rename(&temp_name, file_name)) {
+            warn!("Failed to write sync state {}: {}", file_name, error);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// Last secret sent by a full sync that stopped at full_sync_batch_limit, the next full sync
+// continues after it. Saved to full_sync_cursor_path as JSON, or kept in memory only.
+#[derive(Default)]
+pub struct FullSyncCursor {
+    file_name: Option<String>,
+    last: Option<SecretPath>,
+}
+
+impl FullSyncCursor {
+    // A file that cannot be read is logged, then the full sync starts from the beginning
+    pub fn load(file_name: Option<&str>) -> FullSyncCursor {
+        let Some(file_name) = file_name else {
+            return FullSyncCursor::default();
+        };
+        let last = match fs::read_to_string(file_name) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|error| {
+                warn!("Failed to parse full sync cursor {}, starting from the beginning: {}", file_name, error);
+                None
+            }),
+            Err(error) if error.kind() == ErrorKind::NotFound => None,
+            Err(error) => {
+                warn!("Failed to read full sync cursor {}, starting from the beginning: {}", file_name, error);
+                None
+            }
+        };
+        FullSyncCursor { file_name: Some(file_name.to_string()), last }
+    }
+
+    pub fn last(&self) -> Option<&SecretPath> {
+        self.last.as_ref()
+    }
+
+    // None when the full sync reached the end, the file is written if the cursor is changed
+    pub fn set(&mut self, last: Option<SecretPath>) {
+        if self.last == last {
+            return;
+        }
+        self.last = last;
+        let Some(file_name) = &self.file_name else {
+            return;
+        };
+        let text = serde_json::to_string(&self.last).unwrap();
+        let temp_name = format!("{}.tmp", file_name);
+        if let Err(error) = fs::write(&temp_name, text).and_then(|_| fs::rename(&temp_name, file_name)) {
+            warn!("Failed to write full sync cursor {}: {}", file_name, error);
+        }
+    }
+}
+
+// Checksum of the data last written to, or found unchanged in, each destination secret, so an op
+// with the same source data skips the destination read and write. Kept in memory, and saved to
+// content_cache_path as a JSON object if it is set. The checksums are keyed with content_cache_key,
+// or a random key for the run, so the file does not reveal the values.
+#[derive(Default)]
+pub struct ContentCache {
+    // Unset if the cache is disabled
+    key: Option<ChecksumKey>,
+    file_name: Option<String>,
+    // Destination URL and secret -> checksum of the data
+    checksums: Mutex<HashMap<String, String>>,
+    changed: AtomicBool,
+}
+
+impl ContentCache {
+    // A file that cannot be read is logged, then every secret is compared with the destination once
+    pub fn load(enabled: bool, file_name: Option<&str>, key: Option<&str>) -> ContentCache {
+        if !enabled {
+            return ContentCache::default();
+        }
+        let key = match key.map(ChecksumKey::from_secret).map_or_else(ChecksumKey::random, Ok) {
+            Ok(key) => Some(key),
+            Err(error) => {
+                warn!("Failed to create the content cache key, the cache is disabled: {}", error);
+                return ContentCache::default();
+            }
+        };
+        let Some(file_name) = file_name else {
+            return ContentCache { key, ..Default::default() };
+        };
+        let checksums = match fs::read_to_string(file_name) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(checksums) => checksums,
+                Err(error) => {
+                    warn!("Failed to parse content cache {}, starting empty: {}", file_name, error);
+                    HashMap::new()
+                }
+            },
+            Err(error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(error) => {
+                warn!("Failed to read content cache {}, starting empty: {}", file_name, error);
+                HashMap::new()
+            }
+        };
+        info!("Content cache {}: {} secrets", file_name, checksums.len());
+        ContentCache {
+            key,
+            file_name: Some(file_name.to_string()),
+            checksums: Mutex::new(checksums),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.key.is_some()
+    }
+
+    // Checksum of the source data, None if the cache is disabled
+    pub fn checksum(&self, value: &Value) -> Option<String> {
+        self.key.as_ref().map(|key| key.checksum(value))
+    }
+
+    // True if the data with the checksum is the data last synced to the destination secret
+    pub fn is_unchanged(&self, url: &str, dst: &SecretPath, checksum: &str) -> bool {
+        self.checksums.lock().unwrap().get(&key(url, dst)).is_some_and(|cached| cached == checksum)
+    }
+
+    pub fn set(&self, url: &str, dst: &SecretPath, checksum: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        let previous = self.checksums.lock().unwrap().insert(key(url, dst), checksum.to_string());
+        if previous.as_deref() != Some(checksum) {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // The next op for the secret reads the destination again
+    pub fn remove(&self, url: &str, dst: &SecretPath) {
+        if self.checksums.lock().unwrap().remove(&key(url, dst)).is_some() {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Writes the file if anything changed since the last save, like SyncState::save
+    pub fn save(&self) {
+        let Some(file_name) = &self.file_name else {
+            return;
+        };
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let text = serde_json::to_string(&*self.checksums.lock().unwrap()).unwrap();
+        let temp_name = format!("{}.tmp", file_name);
+        // Only the owner can read the checksums
+        let write = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp_name)
+            .and_then(|mut file| file.write_all(text.as_bytes()));
+        if let Err(error) = write.and_then(|_| fs::rename(&temp_name, file_name)) {
+            warn!("Failed to write content cache {}: {}", file_name, error);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn key(url: &str, dst: &SecretPath) -> String {
+    format!("{} {}/{}", url, dst.mount, dst.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use serde_json::json;
+
+    use crate::state::{ContentCache, FullSyncCursor, SyncState};
+    use crate::sync::SecretPath;
+
+    #[test]
+    fn test_round_trip() {
+        let file_name = std::env::temp_dir().join(format!("vault-sync-state-{}.json", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+        let _ = fs::remove_file(file_name);
+        let secret = |path: &str| SecretPath { mount: "secret".to_string(), path: path.to_string() };
+
+        let disabled = SyncState::load(None);
+        disabled.set_synced("http://dst/", &secret("dst/a"), "t1");
+        assert!(!disabled.is_synced("http://dst/", &secret("dst/a"), "t1"));
+
+        let state = SyncState::load(Some(file_name));
+        state.set_synced("http://dst/", &secret("dst/a"), "t1");
+        state.set_synced("http://dst/", &secret("dst/b"), "t1");
+        state.remove("http://dst/", &secret("dst/b"));
+        state.save();
+
+        let state = SyncState::load(Some(file_name));
+        fs::remove_file(file_name).unwrap();
+        assert!(state.is_synced("http://dst/", &secret("dst/a"), "t1"));
+        assert!(!state.is_synced("http://dst/", &secret("dst/a"), "t2"));
+        assert!(!state.is_synced("http://other/", &secret("dst/a"), "t1"));
+        assert!(!state.is_synced("http://dst/", &secret("dst/b"), "t1"));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let file_name = std::env::temp_dir().join(format!("vault-sync-cursor-{}.json", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+        let _ = fs::remove_file(file_name);
+        let secret = SecretPath { mount: "secret".to_string(), path: "src/b".to_string() };
+
+        let mut cursor = FullSyncCursor::load(Some(file_name));
+        assert_eq!(cursor.last(), None);
+        cursor.set(Some(secret.clone()));
+        assert_eq!(FullSyncCursor::load(Some(file_name)).last(), Some(&secret));
+        cursor.set(None);
+        assert_eq!(FullSyncCursor::load(Some(file_name)).last(), None);
+
+        fs::write(file_name, "not json").unwrap();
+        assert_eq!(FullSyncCursor::load(Some(file_name)).last(), None);
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_content_cache() {
+        let file_name = std::env::temp_dir().join(format!("vault-sync-content-{}.json", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+        let _ = fs::remove_file(file_name);
+        let secret = |path: &str| SecretPath { mount: "secret".to_string(), path: path.to_string() };
+
+        let disabled = ContentCache::load(false, Some(file_name), Some("key"));
+        disabled.set("http://dst/", &secret("dst/a"), "c1");
+        assert!(!disabled.is_unchanged("http://dst/", &secret("dst/a"), "c1"));
+
+        let memory = ContentCache::load(true, None, None);
+        memory.set("http://dst/", &secret("dst/a"), "c1");
+        assert!(memory.is_unchanged("http://dst/", &secret("dst/a"), "c1"));
+        memory.save();
+
+        let cache = ContentCache::load(true, Some(file_name), Some("key"));
+        cache.set("http://dst/", &secret("dst/a"), "c1");
+        cache.set("http://dst/", &secret("dst/b"), "c1");
+        cache.remove("http://dst/", &secret("dst/b"));
+        cache.save();
+        assert_eq!(fs::metadata(file_name).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let cache = ContentCache::load(true, Some(file_name), Some("key"));
+        fs::remove_file(file_name).unwrap();
+        assert_eq!(cache.checksum(&json!({"a": "1"})), ContentCache::load(true, None, Some("key")).checksum(&json!({"a": "1"})));
+        assert_ne!(cache.checksum(&json!({"a": "1"})), ContentCache::load(true, None, Some("other")).checksum(&json!({"a": "1"})));
+        assert!(cache.is_unchanged("http://dst/", &secret("dst/a"), "c1"));
+        assert!(!cache.is_unchanged("http://dst/", &secret("dst/a"), "c2"));
+        assert!(!cache.is_unchanged("http://other/", &secret("dst/a"), "c1"));
+        assert!(!cache.is_unchanged("http://dst/", &secret("dst/b"), "c1"));
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let file_name = std::env::temp_dir().join(format!("vault-sync-state-invalid-{}.json", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+        fs::write(file_name, "not json").unwrap();
+        let state = SyncState::load(Some(file_name));
+        fs::remove_file(file_name).unwrap();
+        assert!(state.is_enabled());
+        assert!(!state.is_synced("http://dst/", &SecretPath { mount: "secret".to_string(), path: "a".to_string() }, ""));
+    }
+}
@@ -1,31 +1,59 @@
 use std::{thread, time};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader};
-use std::net::TcpStream;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::net::TcpListener;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc;
 
 use hashicorp_vault::client::{EndpointResponse, HttpVerb};
-use log::{debug, info, warn};
+use hashicorp_vault::client::error::{Error as VaultError, Result as VaultResult};
+use log::{debug, error, info, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use socket2::{SockRef, TcpKeepalive};
+use ::time::OffsetDateTime;
+use ::time::format_description::well_known::Rfc3339;
 
+use crate::admin::{FullSyncSummary, STATUS};
 use crate::audit;
-use crate::config::{EngineVersion, get_backends, VaultSyncConfig};
-use crate::vault::VaultClient;
+use crate::binary;
+use crate::dead_letter::{self, DeadLetter};
+use crate::events::{self, Event};
+use crate::filter::{KeyBlacklist, PathFilter};
+use crate::health::{unix_time, HEALTH};
+use crate::metrics::{Request, METRICS};
+use crate::pause::PAUSE;
+use crate::rate_limit::RateLimiter;
+use crate::report::{Action, ActionKind, DryRun, DryRunReport};
+use crate::rewrite::{PathRewriter, PathTemplate};
+use crate::state::{ContentCache, FullSyncCursor, SyncState};
+use crate::transform::ValueTransformer;
+use crate::transit;
+use crate::config::{EngineVersion, get_backends, get_prefixes, unix_socket_path, OnStandby, SecretEngine, SharedConfig, VaultDestination, VaultSource, VaultSyncConfig, WatchedOperation, WebhookEvent};
+use crate::vault;
+use crate::vault::{ClientPool, VaultClient};
+use crate::webhook;
 
-pub fn audit_device_exists(name: &str, client: Arc<Mutex<VaultClient>>) -> bool {
+// Returns the audit device as listed in sys/audit, with its type and options
+pub fn audit_device(name: &str, client: Arc<Mutex<VaultClient>>) -> Option<Value> {
     let client = client.lock().unwrap();
     let name = format!("{}/", name);
     match client.call_endpoint::<Value>(HttpVerb::GET, "sys/audit", None, None) {
         Ok(response) => {
             debug!("GET sys/audit: {:?}", response);
             if let EndpointResponse::VaultResponse(response) = response {
-                if let Some(Value::Object(map)) = response.data {
-                    for (key, _) in &map {
-                        if key == &name {
-                            return true;
-                        }
-                    }
+                if let Some(Value::Object(mut map)) = response.data {
+                    return map.remove(&name);
                 }
             }
         },
@@ -33,21 +61,200 @@ pub fn audit_device_exists(name: &str, client: Arc<Mutex<VaultClient>>) -> bool
             warn!("GET sys/audit: {}", error);
         }
     }
-    false
+    None
+}
+
+// Returns true if both audit devices, as returned by audit_device, have the same type,
+// description and options, so the device at the path is the one vault-sync has seen before.
+pub fn same_audit_device(device: &Value, other: &Value) -> bool {
+    ["type", "description", "options"].iter().all(|key| device[key] == other[key])
+}
+
+// Checks the options of the audit device returned by audit_device. Returns an error if the live
+// sync cannot parse its entries, or warnings for options that work but should be changed. Bind is
+// where vault-sync listens for the socket audit device.
+pub fn check_audit_device_options(device: &Value, bidirectional: bool, bind: Option<&str>) -> Result<Vec<String>, String> {
+    let option = |name: &str| device["options"][name].as_str().map(str::to_string);
+    if option("format").is_some_and(|format| format != "json") {
+        return Err(format!("Audit device format {} cannot be parsed, only json is supported", option("format").unwrap()));
+    }
+    let mut warnings = Vec::new();
+    // Paths, operations and mount types are never hashed, so the default options are enough
+    if option("log_raw").as_deref() == Some("true") {
+        warnings.push("Audit device has log_raw=true, secret values are written to the audit log unhashed, vault-sync does not need them".to_string());
+    }
+    if bidirectional && option("hmac_accessor").as_deref() != Some("false") {
+        warnings.push("Audit device hashes token accessors, enable it with hmac_accessor=false so secrets are not synced back".to_string());
+    }
+    // The TCP address of vault-sync can differ for Vault, for example behind a service, the path
+    // of a Unix socket cannot
+    if let (Some("socket"), Some(bind)) = (device["type"].as_str(), bind) {
+        let socket_type = option("socket_type").unwrap_or_else(|| "tcp".to_string());
+        match unix_socket_path(bind) {
+            Some(path) if socket_type != "unix" || option("address").as_deref() != Some(path) =>
+                warnings.push(format!("Audit device sends the audit log to {} {}, enable it with socket_type=unix address={} for bind {}",
+                    socket_type, option("address").unwrap_or_default(), path, bind)),
+            None if socket_type != "tcp" =>
+                warnings.push(format!("Audit device has socket_type={}, enable it with socket_type=tcp for bind {}", socket_type, bind)),
+            _ => {},
+        }
+    }
+    Ok(warnings)
+}
+
+// Enables the audit device returned by audit_device, requires sudo for sys/audit.
+// Returns true if the audit device is enabled, also if the same device is already enabled.
+pub fn enable_audit_device(name: &str, device: &Value, client: Arc<Mutex<VaultClient>>) -> bool {
+    let endpoint = format!("sys/audit/{}", name);
+    let body = serde_json::json!({
+        "type": device["type"],
+        "description": device["description"],
+        "options": device["options"],
+        "local": device["local"],
+    }).to_string();
+    let result = client.lock().unwrap().call_endpoint::<Value>(HttpVerb::PUT, &endpoint, None, Some(&body));
+    match result {
+        Ok(_) => {
+            info!("Audit device {} enabled", name);
+            true
+        },
+        // Enabled in the meantime, for example by another vault-sync instance after a restart of Vault
+        Err(error) if error.to_string().contains("path already in use")
+            && audit_device(name, client.clone()).is_some_and(|current| same_audit_device(&current, device)) => {
+            info!("Audit device {} is already enabled", name);
+            true
+        },
+        Err(error) => {
+            warn!("Failed to enable audit device {}: {}", name, error);
+            false
+        },
+    }
 }
 
 pub fn full_sync_worker(
-    config: &VaultSyncConfig,
-    client: Arc<Mutex<VaultClient>>,
-    tx: mpsc::Sender<SecretOp>
+    config: &SharedConfig,
+    src_client: Arc<Mutex<VaultClient>>,
+    dst_clients: Vec<Arc<Mutex<VaultClient>>>,
+    tx: mpsc::SyncSender<SecretOp>,
+    dry_run: Option<DryRun>,
+    since: Option<OffsetDateTime>,
 ) {
     info!("FullSync worker started");
-    let interval = time::Duration::from_secs(config.full_sync_interval);
-    let prefix = &config.src.prefix;
-    let backends = get_backends(&config.src.backend);
+    let current = config.read().unwrap().clone();
+    if !current.full_sync_on_start {
+        thread::sleep(full_sync_delay(current.full_sync_interval, current.full_sync_jitter, random()));
+    }
+    let mut breaker = DeleteBreaker::load(current.delete_breaker_path().as_deref());
+    let mut cursor = FullSyncCursor::load(current.full_sync_cursor_path.as_deref());
+    // Only the first full sync catches up with since, the next ones sync all secrets
+    let mut since = since;
     loop {
-        full_sync(prefix, &backends, client.clone(), tx.clone());
-        thread::sleep(interval);
+        // Configuration can be reloaded, changes take effect on the next cycle
+        if PAUSE.is_paused() {
+            info!("FullSync waits until the sync is resumed");
+            if !PAUSE.wait() {
+                info!("FullSync worker stopped while the sync is paused");
+                return;
+            }
+        }
+        let current = config.read().unwrap().clone();
+        // Errors of single secrets do not stop the full sync, the next full sync runs after the
+        // interval
+        full_sync(&current, src_client.clone(), &dst_clients, tx.clone(), &mut breaker, &mut cursor, dry_run, since.take());
+        // An interval set to 0 on reload also stops the full syncs, setting it back requires restart
+        if current.full_sync_interval == 0 {
+            info!("FullSync worker stopped, full_sync_interval is 0");
+            return;
+        }
+        thread::sleep(full_sync_delay(current.full_sync_interval, current.full_sync_jitter, random()));
+    }
+}
+
+// Interval changed by up to jitter percent in either direction, so full syncs of several
+// instances started at the same time spread out. random is in [0, 1).
+fn full_sync_delay(interval: u64, jitter: f64, random: f64) -> time::Duration {
+    let factor = 1.0 + jitter / 100.0 * (2.0 * random - 1.0);
+    time::Duration::from_secs(interval).mul_f64(factor.max(0.0))
+}
+
+// Stops full syncs from removing more than max_delete_ratio of the destination secrets at once,
+// for example when a permission glitch hides most of the source secrets. The secrets are removed
+// only if the next full sync finds them missing again. With state_path, the secrets waiting for it
+// are saved next to the state, so the next run with --once confirms them too.
+#[derive(Default)]
+pub struct DeleteBreaker {
+    file_name: Option<String>,
+    // Secrets waiting for confirmation, by source backend
+    tripped: HashMap<String, HashSet<String>>,
+}
+
+impl DeleteBreaker {
+    // A file that cannot be read is logged, then the removal waits for the next full sync
+    pub fn load(file_name: Option<&str>) -> DeleteBreaker {
+        let Some(file_name) = file_name else {
+            return DeleteBreaker::default();
+        };
+        let tripped = match fs::read_to_string(file_name) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|error| {
+                warn!("Failed to parse pending removals {}, starting empty: {}", file_name, error);
+                HashMap::new()
+            }),
+            Err(error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(error) => {
+                warn!("Failed to read pending removals {}, starting empty: {}", file_name, error);
+                HashMap::new()
+            }
+        };
+        DeleteBreaker { file_name: Some(file_name.to_string()), tripped }
+    }
+
+    // Returns the missing secrets that can be removed. removals is the number of secrets to
+    // remove counted for every destination, total is the number of listed destination secrets.
+    fn check(&mut self, backend: &str, missing: Vec<String>, removals: usize, total: usize, max_ratio: Option<f64>) -> Vec<String> {
+        let before = self.tripped.get(backend).cloned();
+        let removable = self.confirm(backend, missing, removals, total, max_ratio);
+        if self.tripped.get(backend) != before.as_ref() {
+            self.save();
+        }
+        removable
+    }
+
+    fn confirm(&mut self, backend: &str, missing: Vec<String>, removals: usize, total: usize, max_ratio: Option<f64>) -> Vec<String> {
+        let previous = self.tripped.remove(backend);
+        let Some(max_ratio) = max_ratio else {
+            return missing;
+        };
+        if total == 0 || removals as f64 <= max_ratio * total as f64 {
+            return missing;
+        }
+        let Some(previous) = previous else {
+            let message = format!(
+                "Full sync would remove {} of {} destination secrets for {}, more than max_delete_ratio {}, skipping removal until the next full sync confirms it",
+                removals, total, backend, max_ratio,
+            );
+            error!("{}", &message);
+            webhook::notify(WebhookEvent::Error, None, None, Some(message));
+            self.tripped.insert(backend.to_string(), missing.into_iter().collect());
+            return Vec::new();
+        };
+        // Secrets missing for the first time wait for the next full sync
+        let (confirmed, pending): (Vec<String>, Vec<String>) = missing.into_iter().partition(|secret| previous.contains(secret));
+        warn!("Removal of {} secrets for {} is confirmed by the previous full sync", confirmed.len(), backend);
+        if !pending.is_empty() {
+            self.tripped.insert(backend.to_string(), pending.into_iter().collect());
+        }
+        confirmed
+    }
+
+    fn save(&self) {
+        let Some(file_name) = &self.file_name else {
+            return;
+        };
+        let text = serde_json::to_string(&self.tripped).unwrap();
+        let temp_name = format!("{}.tmp", file_name);
+        if let Err(error) = fs::write(&temp_name, text).and_then(|_| fs::rename(&temp_name, file_name)) {
+            warn!("Failed to write pending removals {}: {}", file_name, error);
+        }
     }
 }
 
@@ -57,17 +264,339 @@ struct Item {
     index: usize,
 }
 
-pub fn full_sync(prefix: &str, backends: &Vec<String>, client: Arc<Mutex<VaultClient>>, tx: mpsc::Sender<SecretOp>) {
-    let prefix= normalize_prefix(prefix);
+// Parses a duration like 90s, 30m, 6h or 2d, a number without a unit is seconds
+pub fn parse_duration(value: &str) -> Option<time::Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let number = number.parse::<u64>().ok().filter(|number| *number > 0)?;
+    Some(time::Duration::from_secs(number.checked_mul(secs)?))
+}
+
+// Returns false if some of the secrets could not be listed. With since, KV v2 secrets updated
+// before it are listed but not synced. With full_sync_batch_limit, the secrets before the cursor
+// and after the limit are listed but not synced.
+#[allow(clippy::too_many_arguments)]
+pub fn full_sync(
+    config: &VaultSyncConfig,
+    src_client: Arc<Mutex<VaultClient>>,
+    dst_clients: &[Arc<Mutex<VaultClient>>],
+    tx: mpsc::SyncSender<SecretOp>,
+    breaker: &mut DeleteBreaker,
+    cursor: &mut FullSyncCursor,
+    dry_run: Option<DryRun>,
+    since: Option<OffsetDateTime>,
+) -> bool {
     info!("FullSync started");
+    STATUS.start_full_sync();
     let now = time::Instant::now();
-    for backend in backends {
-        full_sync_internal(&prefix, backend, client.clone(), tx.clone());
-    }
+    let (listed, secrets) = match config.src.secret_engine {
+        SecretEngine::Kv => full_sync_kv(config, src_client, dst_clients, &tx, breaker, cursor, dry_run, since),
+        SecretEngine::Transit => transit::full_sync(config, &src_client, &tx),
+    };
+    STATUS.full_sync_secrets.store(secrets, Ordering::Relaxed);
+    send_op(&tx, SecretOp::FullSyncFinished);
     info!("FullSync finished in {}ms", now.elapsed().as_millis());
+    if !listed {
+        webhook::notify(WebhookEvent::Error, None, None, Some("Full sync could not list all secrets".to_string()));
+    }
+    webhook::notify(WebhookEvent::FullSyncComplete, None, None, Some(format!("{} secrets listed", secrets)));
+    listed
+}
+
+// Returns false if some of the secrets could not be listed, and the number of secrets sent
+#[allow(clippy::too_many_arguments)]
+fn full_sync_kv(
+    config: &VaultSyncConfig,
+    src_client: Arc<Mutex<VaultClient>>,
+    dst_clients: &[Arc<Mutex<VaultClient>>],
+    tx: &mpsc::SyncSender<SecretOp>,
+    breaker: &mut DeleteBreaker,
+    cursor: &mut FullSyncCursor,
+    dry_run: Option<DryRun>,
+    since: Option<OffsetDateTime>,
+) -> (bool, u64) {
+    let mut listed = true;
+    let mut secrets = 0;
+    let mut older = 0;
+    // KV v1 secrets have no updated_time, they are all synced
+    let since = since.filter(|_| config.src.version == EngineVersion::V2);
+    let src_prefixes = get_prefixes(&config.src.prefix);
+    let backends = config.src.synced_backends();
+    let limit = config.full_sync_batch_limit;
+    // A cursor in a backend or prefix that is not synced anymore starts from the beginning
+    let resume = cursor.last().filter(|_| limit.is_some()).and_then(|last| position(&backends, &src_prefixes, last));
+    let mut last = None;
+    let mut before = 0;
+    let mut after = 0;
+    let propagate_deletes = config.dst.iter().any(|dst| dst.propagate_deletes);
+    // With fail_fast, a failed op of this full sync stops the walk, the ops sent before are still
+    // applied. The ops are applied asynchronously, so the walk stops at a later secret.
+    let failures = OpFailures::default();
+    let counted = |op| if config.fail_fast { SecretOp::Counted(Box::new(op), failures.clone()) } else { op };
+    let mut stopped = None;
+    // Not being able to read sys/mounts is not an error, the token may not have the permission,
+    // then all backends are listed
+    let mounts = match vault::mount_types(&src_client.lock().unwrap().clone_without_data()) {
+        Ok(mounts) => mounts,
+        Err(error) => {
+            debug!("Failed to read the secrets engines in {}: {}", &config.src.host.url, error);
+            HashMap::new()
+        },
+    };
+    for (backend_index, backend) in backends.iter().cloned().enumerate() {
+        if let Some(engine) = mounts.get(&backend).filter(|engine| engine.as_str() != "kv") {
+            warn!("Secrets engine {} in {} is {}, not KV, skipping", &backend, &config.src.host.url, engine);
+            continue;
+        }
+        // Other secrets engines mounted under the backend, relative to it, for example "src/pki/"
+        let nested: Vec<String> = mounts.iter()
+            .filter(|(_, engine)| engine.as_str() != "kv")
+            .filter_map(|(path, _)| path.strip_prefix(&format!("{}/", backend)).map(|path| format!("{}/", path)))
+            .collect();
+        let mut src_secrets = HashSet::new();
+        let mut complete = true;
+        for (prefix_index, src_prefix) in src_prefixes.iter().enumerate() {
+            complete &= list_all_except(src_prefix, &backend, &config.src.host.url, src_client.clone(), &nested, config.fail_fast, |secret| {
+                if config.fail_fast && failures.count() > 0 {
+                    stopped = Some(format!("a secret operation failed before {}/{}", &backend, &secret));
+                    return false;
+                }
+                if !config.src.filter.matches(&secret) {
+                    debug!("Secret {}/{} is filtered out, skipping", &backend, &secret);
+                    return true;
+                }
+                if propagate_deletes {
+                    src_secrets.insert(secret.clone());
+                }
+                if resume.as_ref().is_some_and(|resume| (backend_index, prefix_index, secret.as_str()) <= (resume.0, resume.1, resume.2.as_str())) {
+                    before += 1;
+                    return true;
+                }
+                let path = SecretPath { mount: backend.clone(), path: secret };
+                if let Some(since) = since {
+                    if updated_before(config, &src_client, &path, since) {
+                        debug!("Secret {}/{} is updated before --since, skipping", &backend, &path.path);
+                        older += 1;
+                        return true;
+                    }
+                }
+                if limit.is_some_and(|limit| secrets >= limit) {
+                    after += 1;
+                    return true;
+                }
+                secrets += 1;
+                last = Some(path.clone());
+                send_op(tx, counted(SecretOp::Create(path)));
+                true
+            });
+            if config.fail_fast && !complete && stopped.is_none() {
+                stopped = Some(format!("not all secrets in {} are listed", &backend));
+            }
+            if stopped.is_some() {
+                break;
+            }
+        }
+        listed &= complete;
+        // Nothing is removed and the cursor is not moved, the next full sync starts over
+        if let Some(reason) = &stopped {
+            error!("FullSync stopped with fail_fast, {}", reason);
+            listed = false;
+            break;
+        }
+        if propagate_deletes {
+            if dry_run == Some(DryRun::Plan) {
+                info!("Dry run does not list {} in the destinations, skipping removal of secrets missing in the source", &backend);
+                continue;
+            }
+            // Secrets that could not be listed would be removed from the destination
+            if !complete {
+                warn!("Not all secrets in {} are listed, skipping removal of secrets missing in the source", &backend);
+                continue;
+            }
+            // Most likely a wrong prefix or backend, rather than all secrets deleted
+            if src_secrets.is_empty() {
+                warn!("No secrets in {}, skipping removal of secrets missing in the source", &backend);
+                continue;
+            }
+            // A secret missing in several destinations is sent once, the sync worker removes it
+            // from every destination
+            let mut missing = Vec::new();
+            let mut seen = HashSet::new();
+            let mut removals = 0;
+            let mut total = 0;
+            for (dst, dst_client) in config.dst.iter().zip(dst_clients) {
+                if !dst.propagate_deletes {
+                    continue;
+                }
+                // Destination paths cannot be mapped back to the source paths
+                if !dst.rewriter.is_empty() || dst.template.is_some() {
+                    warn!("Destination {} has rewrites or path_template, skipping removal of secrets missing in the source", &dst.host.url);
+                    continue;
+                }
+                let paths = PathMap::new(&config.src, dst);
+                let Some(dst_mount) = paths.mounts.get(&backend) else {
+                    warn!("No destination backend for {} in {}, skipping removal of secrets missing in the source", &backend, &dst.host.url);
+                    continue;
+                };
+                for (src_prefix, dst_prefix) in &paths.prefixes {
+                    listed &= list_all(dst_prefix, dst_mount, &dst.host.url, dst_client.clone(), |secret| {
+                        total += 1;
+                        let src_secret = secret_dst_to_src_path(src_prefix, dst_prefix, &secret);
+                        // Also the secrets with include rules that do not sync deletes are kept
+                        if src_secrets.contains(&src_secret) || !config.src.filter.watches(&src_secret, &WatchedOperation::Delete)
+                            || nested.iter().any(|mount| src_secret.starts_with(mount.as_str())) {
+                            return;
+                        }
+                        removals += 1;
+                        if seen.insert(src_secret.clone()) {
+                            missing.push(src_secret);
+                        }
+                    });
+                }
+            }
+            for secret in breaker.check(&backend, missing, removals, total, config.max_delete_ratio) {
+                send_op(tx, counted(SecretOp::Destroy(SecretPath { mount: backend.clone(), path: secret })));
+            }
+        }
+    }
+    if let Some(since) = since {
+        info!("FullSync skipped {} secrets updated before {}", older, since.format(&Rfc3339).unwrap_or_default());
+    }
+    // Reaching the limit is expected, the live sync keeps the secrets up to date until the next
+    // full syncs reach them. In dry run, the cursor is not moved.
+    if let Some(limit) = limit.filter(|_| stopped.is_none()) {
+        let next = match (after, last) {
+            (0, _) => {
+                info!("FullSync reached the end of the secrets, skipped {} secrets before the cursor, the next full sync starts from the beginning", before);
+                None
+            },
+            (_, Some(last)) => {
+                info!("FullSync reached full_sync_batch_limit {} at {}/{}, the next full sync continues with the {} secrets after it", limit, &last.mount, &last.path, after);
+                Some(last)
+            },
+            (_, None) => cursor.last().cloned(),
+        };
+        if dry_run.is_none() {
+            cursor.set(next);
+        }
+    }
+    (listed, secrets)
+}
+
+// Backend and prefix index and the path of the secret, at which the full sync lists it. Vault
+// lists the keys sorted, so the paths under a prefix are listed in the order of the strings.
+fn position(backends: &[String], prefixes: &[String], secret: &SecretPath) -> Option<(usize, usize, String)> {
+    let backend = backends.iter().position(|backend| backend == &secret.mount)?;
+    let prefix = prefixes.iter().position(|prefix| secret.path.starts_with(prefix.as_str()))?;
+    Some((backend, prefix, secret.path.clone()))
+}
+
+// Reads the secrets for --paths-file, one path relative to the source prefix per line. Empty lines
+// and comments starting with '#' are skipped.
+pub fn read_paths(file_name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let text = fs::read_to_string(file_name).map_err(|error| format!("Failed to read {}: {}", file_name, error))?;
+    let mut paths = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let path = line.trim_start_matches('/');
+        if path.is_empty() || path.ends_with('/') {
+            warn!("{:?} in {} is not a secret path, skipping", line, file_name);
+            continue;
+        }
+        paths.push(path.to_string());
+    }
+    Ok(paths)
+}
+
+// Syncs the listed secrets instead of listing the prefix, in every source backend. A secret that
+// is missing in the source is removed in the destinations with propagate_deletes, otherwise it is
+// skipped. Returns false if some of the secrets could not be checked.
+pub fn paths_sync(config: &VaultSyncConfig, src_client: Arc<Mutex<VaultClient>>, paths: &[String], tx: mpsc::SyncSender<SecretOp>) -> bool {
+    info!("Syncing {} listed secrets", paths.len());
+    STATUS.start_full_sync();
+    let mut checked = true;
+    let mut secrets = 0;
+    let prefix = normalize_prefix(&config.src.prefix[0]);
+    let propagate_deletes = config.dst.iter().any(|dst| dst.propagate_deletes);
+    for backend in config.src.synced_backends() {
+        for path in paths {
+            let secret = SecretPath { mount: backend.clone(), path: format!("{}{}", &prefix, path) };
+            if !config.src.filter.matches(&secret.path) {
+                debug!("Secret {}/{} is filtered out, skipping", &backend, &secret.path);
+                continue;
+            }
+            let exists = secret_exists(&src_client.lock().unwrap(), &secret, &config.src.version);
+            match exists {
+                Ok(true) => {
+                    secrets += 1;
+                    send_op(&tx, SecretOp::Create(secret));
+                },
+                Ok(false) if propagate_deletes && config.src.filter.watches(&secret.path, &WatchedOperation::Delete) => {
+                    info!(secret = secret.path.as_str(); "Secret {}/{} is not found in the source, removing it", &backend, &secret.path);
+                    send_op(&tx, SecretOp::Destroy(secret));
+                },
+                Ok(false) => {
+                    warn!(secret = secret.path.as_str(); "Secret {}/{} is not found in the source, skipping", &backend, &secret.path);
+                },
+                Err(error) => {
+                    let error = SyncError::new(&secret, &config.src.host.url, error);
+                    warn!(secret = secret.path.as_str(), host = error.host.as_str(); "Failed to read secret {}", error);
+                    checked = false;
+                },
+            }
+        }
+    }
+    STATUS.full_sync_secrets.store(secrets, Ordering::Relaxed);
+    send_op(&tx, SecretOp::FullSyncFinished);
+    checked
+}
+
+// True if the source metadata of the secret has an updated_time before since. A secret with
+// metadata that cannot be read is synced.
+fn updated_before(config: &VaultSyncConfig, src_client: &Arc<Mutex<VaultClient>>, path: &SecretPath, since: OffsetDateTime) -> bool {
+    let metadata = with_retries(config.max_retries, path, &config.src.host.url, || {
+        vault::read_metadata(&src_client.lock().unwrap(), &path.mount, &path.path)
+    });
+    match metadata {
+        Ok(Some(metadata)) => OffsetDateTime::parse(&metadata.updated_time, &Rfc3339).is_ok_and(|updated| updated < since),
+        Ok(None) => false,
+        Err(error) => {
+            debug!(secret = path.path.as_str(), host = error.host.as_str(); "Failed to read metadata of secret {}", error);
+            false
+        }
+    }
+}
+
+// Recursively lists secrets with the prefix, calls f for every secret.
+// Returns false if some of the paths could not be listed.
+pub fn list_all<F: FnMut(String)>(prefix: &str, backend: &str, host: &str, client: Arc<Mutex<VaultClient>>, mut f: F) -> bool {
+    list_all_except(prefix, backend, host, client, &[], false, |secret| {
+        f(secret);
+        true
+    })
 }
 
-fn full_sync_internal(prefix: &str, backend: &str, client: Arc<Mutex<VaultClient>>, tx: mpsc::Sender<SecretOp>) {
+// Same as list_all, but the directories in skip, for example "src/pki/", are not listed. The
+// listing stops when f returns false, and with fail_fast at the first path that cannot be listed.
+#[allow(clippy::too_many_arguments)]
+fn list_all_except<F: FnMut(String) -> bool>(
+    prefix: &str,
+    backend: &str,
+    host: &str,
+    client: Arc<Mutex<VaultClient>>,
+    skip: &[String],
+    fail_fast: bool,
+    mut f: F,
+) -> bool {
+    let mut complete = true;
     let mut stack: Vec<Item> = Vec::new();
     let item = Item {
         parent: prefix.to_string(),
@@ -76,12 +605,14 @@ fn full_sync_internal(prefix: &str, backend: &str, client: Arc<Mutex<VaultClient
     };
     stack.push(item);
 
-    'outer: while stack.len() > 0 {
+    'outer: while !stack.is_empty() {
         let len = stack.len();
         let item = stack.get_mut(len - 1).unwrap();
         if item.secrets.is_none() {
+            // A copy of the shared client for every request, so the live sync does not wait for
+            // the listing, and a token renewed in the meantime is used
             let secrets = {
-                let mut client = client.lock().unwrap();
+                let mut client = client.lock().unwrap().clone_without_data();
                 client.secret_backend(backend);
                 client.list_secrets(&item.parent)
             };
@@ -89,8 +620,16 @@ fn full_sync_internal(prefix: &str, backend: &str, client: Arc<Mutex<VaultClient
                 Ok(secrets) => {
                     item.secrets = Some(secrets);
                 },
+                // A missing prefix is not an error, there are just no secrets yet
+                Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => {},
                 Err(error) => {
-                    warn!("Failed to list secrets in {}: {}", &item.parent, error);
+                    let error = SyncError::new(&SecretPath { mount: backend.to_string(), path: item.parent.clone() }, host, error);
+                    warn!(host = host; "Failed to list secrets in {}", error);
+                    METRICS.list_errors.fetch_add(1, Ordering::Relaxed);
+                    complete = false;
+                    if fail_fast {
+                        return false;
+                    }
                 }
             }
         }
@@ -99,229 +638,1782 @@ fn full_sync_internal(prefix: &str, backend: &str, client: Arc<Mutex<VaultClient
                 let secret = &secrets[item.index];
                 item.index += 1;
                 if secret.ends_with("/") {
+                    // item.parent ends with '/'
+                    let parent = format!("{}{}", &item.parent, secret);
+                    if skip.contains(&parent) {
+                        warn!(host = host; "{}/{} is a mount of another secrets engine, skipping", backend, &parent);
+                        continue;
+                    }
                     let item = Item {
-                        // item.parent ends with '/'
-                        parent: format!("{}{}", &item.parent, secret),
+                        parent,
                         secrets: None,
                         index: 0,
                     };
                     stack.push(item);
                     continue 'outer;
-                } else {
-                    let full_name = format!("{}{}", &item.parent, &secret);
-                    let op = SecretOp::Create(SecretPath {mount: backend.to_string(), path: full_name});
-                    if let Err(error) = tx.send(op) {
-                        warn!("Failed to send a secret to a sync thread: {}", error);
-                    }
+                } else if !f(format!("{}{}", &item.parent, &secret)) {
+                    return false;
                 }
             }
         }
         stack.pop();
     }
-    let _ = tx.send(SecretOp::FullSyncFinished);
+    complete
+}
+
+// Idle connections are probed, so a connection that Vault dropped without closing it is noticed
+const KEEPALIVE_TIME: time::Duration = time::Duration::from_secs(60);
+
+const WATCHDOG_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// Where the socket audit device sends the audit log: a TCP address, or a Unix socket with bind
+// unix:<path> when Vault runs on the same host
+pub enum AuditListener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),
+}
+
+impl AuditListener {
+    // A socket file left by a previous run that did not stop cleanly is replaced, a socket that
+    // accepts connections belongs to a running vault-sync and fails the bind, like other files. The
+    // socket is readable and writable by its owner and group only, so Vault must run as the same
+    // user or with the group of vault-sync.
+    pub fn bind(bind: &str) -> io::Result<AuditListener> {
+        let Some(path) = unix_socket_path(bind) else {
+            return Ok(AuditListener::Tcp(TcpListener::bind(bind)?));
+        };
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(ErrorKind::AddrInUse, format!("socket {} is in use", path)));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+        Ok(AuditListener::Unix(listener, path.to_string()))
+    }
+
+    // The bound address, with the actual port if the configured one is 0, or the socket path
+    pub fn addr(&self) -> String {
+        match self {
+            AuditListener::Tcp(listener) => listener.local_addr().map_or_else(|_| "the audit log".to_string(), |addr| addr.to_string()),
+            AuditListener::Unix(_, path) => format!("unix:{}", path),
+        }
+    }
+
+    // The socket file to remove on shutdown
+    pub fn socket_file(&self) -> Option<&str> {
+        match self {
+            AuditListener::Tcp(_) => None,
+            AuditListener::Unix(_, path) => Some(path),
+        }
+    }
 }
 
-pub fn log_sync(config: &VaultSyncConfig, stream: TcpStream, tx: mpsc::Sender<SecretOp>) {
-    match stream.peer_addr() {
-        Ok(peer_addr) => {
-            info!("New connection from {}", peer_addr);
+// Accepts connections from the socket audit device, every connection is read by its own thread.
+// A dropped connection is read to the end, Vault connects again for the next entries. With
+// audit_idle_timeout, a watchdog warns if no entries are received for that long.
+pub fn log_listener(config: &SharedConfig, listener: AuditListener, tx: mpsc::SyncSender<SecretOp>, client: Arc<Mutex<VaultClient>>) {
+    let addr = listener.addr();
+    let received = Arc::new(AtomicU64::new(unix_time()));
+    {
+        let (config, addr, received) = (config.clone(), addr.clone(), received.clone());
+        thread::spawn(move || audit_watchdog(&config, &addr, &received));
+    }
+    let read = |stream: Box<dyn Read + Send>| {
+        let (config, tx, client, received) = (config.clone(), tx.clone(), client.clone(), received.clone());
+        thread::spawn(move || {
+            log_sync(&config, stream, tx, client, &received);
+        });
+    };
+    match listener {
+        AuditListener::Tcp(listener) => {
+            for stream in listener.incoming().flatten() {
+                if let Err(error) = SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(KEEPALIVE_TIME)) {
+                    warn!("Failed to enable TCP keepalive for the audit log connection: {}", error);
+                }
+                match stream.peer_addr() {
+                    Ok(peer_addr) => info!("New connection from {}", peer_addr),
+                    Err(_) => info!("New connection"),
+                }
+                read(Box::new(stream));
+            }
+        },
+        AuditListener::Unix(listener, _) => {
+            for stream in listener.incoming().flatten() {
+                info!("New connection on {}", &addr);
+                read(Box::new(stream));
+            }
         },
-        Err(_) => {
-            info!("New connection");
+    }
+}
+
+// Warns once when the audit log is idle for audit_idle_timeout, and again after entries are
+// received, so a stale connection is visible in the logs. The timeout can be reloaded.
+fn audit_watchdog(config: &SharedConfig, addr: &str, received: &AtomicU64) {
+    let mut watchdog = AuditWatchdog::default();
+    loop {
+        thread::sleep(WATCHDOG_INTERVAL);
+        let timeout = config.read().unwrap().audit_idle_timeout;
+        match watchdog.check(received.load(Ordering::Relaxed), unix_time(), timeout) {
+            Some(Idle::Warning(idle)) => warn!("No audit log entries received on {} for {}s, the connection from Vault may be stale", addr, idle),
+            Some(Idle::Resumed) => info!("Audit log entries received again on {}", addr),
+            None => {},
+        }
+    }
+}
+
+#[derive(Default)]
+struct AuditWatchdog {
+    warned: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum Idle {
+    // Seconds since the last entry
+    Warning(u64),
+    Resumed,
+}
+
+impl AuditWatchdog {
+    // received is the Unix time of the last entry, or when the listener started
+    fn check(&mut self, received: u64, now: u64, timeout: Option<u64>) -> Option<Idle> {
+        let idle = now.saturating_sub(received);
+        let is_idle = timeout.is_some_and(|timeout| idle >= timeout);
+        match (is_idle, self.warned) {
+            (true, false) => {
+                self.warned = true;
+                Some(Idle::Warning(idle))
+            },
+            (false, true) => {
+                self.warned = false;
+                Some(Idle::Resumed)
+            },
+            _ => None,
         }
     }
-    let backends = get_backends(&config.src.backend);
-    let prefix = &config.src.prefix;
-    let version = &config.src.version;
+}
 
-    let mut reader = BufReader::new(stream);
+// Reads the audit log entries from the connection until it is closed, over TCP or a Unix socket.
+// received is updated with the Unix time of the last read.
+// Client is for the Vault that sends the audit log, requests made with its token are not synced
+pub fn log_sync<R: Read>(
+    config: &SharedConfig,
+    mut stream: R,
+    tx: mpsc::SyncSender<SecretOp>,
+    client: Arc<Mutex<VaultClient>>,
+    received: &AtomicU64,
+) {
+    let mut entries = audit::EntryReader::default();
+    let mut buf = [0; 64 * 1024];
     loop {
-        let mut line = String::new();
-        match reader.read_line(&mut line) {
+        match stream.read(&mut buf) {
             Ok(0) => {
                 // EOF
                 break;
             },
-            Ok(_) => {
-                debug!("Log: '{}'", line.trim());
-                let audit_log: Result<audit::AuditLog, _> = serde_json::from_str(&line);
-                match audit_log {
-                    Ok(audit_log) => {
-                        if let Some(op) = audit_log_op(&backends, &prefix, &version, &audit_log) {
-                            if let Err(error) = tx.send(op) {
-                                warn!("Failed to send a secret to a sync thread: {}", error);
-                            }
-                        }
-                    },
-                    Err(error) => {
-                        warn!("Failed to deserialize: {}, response: {}", error, &line);
-                    }
-                }
+            Ok(n) => {
+                received.store(unix_time(), Ordering::Relaxed);
+                audit_log_entries(config, entries.push(&buf[..n]), &tx, &client);
             },
+            Err(error) if error.kind() == ErrorKind::Interrupted => {},
             Err(error) => {
                 warn!("Error: {}", error);
                 break;
             }
         }
     }
+    audit_log_entries(config, entries.push(b"\n"), &tx, &client);
     debug!("Closed connection");
 }
 
-#[derive(Debug)]
-pub struct SecretPath{
-    mount: String,
-    path: String,
-}
-
-#[derive(Debug)]
-pub enum SecretOp {
-    Create(SecretPath),
-    Update(SecretPath),
-    Delete(SecretPath),
-    FullSyncFinished,
-}
-
-struct SyncStats {
-    updated: u64,
-    deleted: u64,
-}
-
-impl SyncStats {
-    fn new() -> SyncStats {
-        SyncStats { updated: 0, deleted: 0 }
-    }
-    fn reset(&mut self) {
-        self.updated = 0;
-        self.deleted = 0;
+// Reads the audit log written by the file audit device, like "tail -F": starts at the end of the
+// file, opens the file again when it is rotated, and reads it from the start when it is truncated.
+pub fn log_file_sync(config: &SharedConfig, file_name: &str, tx: mpsc::SyncSender<SecretOp>, client: Arc<Mutex<VaultClient>>) {
+    info!("Reading audit log from {}", file_name);
+    let mut seek_to_end = true;
+    loop {
+        let mut reader = match File::open(file_name) {
+            Ok(file) => file,
+            Err(error) => {
+                warn!("Failed to open audit log {}: {}", file_name, error);
+                thread::sleep(AUDIT_FILE_POLL_INTERVAL);
+                // A new file is read from the start
+                seek_to_end = false;
+                continue;
+            }
+        };
+        if seek_to_end {
+            // Existing entries are synced by the full sync
+            if let Err(error) = reader.seek(SeekFrom::End(0)) {
+                warn!("Failed to seek audit log {}: {}", file_name, error);
+            }
+        }
+        seek_to_end = false;
+        tail(config, file_name, &mut reader, &tx, &client);
     }
 }
 
-pub fn sync_worker(
-    rx: mpsc::Receiver<SecretOp>,
-    config: &VaultSyncConfig,
-    src_client: Arc<Mutex<VaultClient>>,
-    dst_client: Arc<Mutex<VaultClient>>,
-    dry_run: bool,
-    run_once: bool,
+#[cfg(not(test))]
+const AUDIT_FILE_POLL_INTERVAL: time::Duration = time::Duration::from_secs(1);
+#[cfg(test)]
+const AUDIT_FILE_POLL_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+// Reads lines until the file is rotated or cannot be read anymore
+fn tail(
+    config: &SharedConfig,
+    file_name: &str,
+    reader: &mut File,
+    tx: &mpsc::SyncSender<SecretOp>,
+    client: &Arc<Mutex<VaultClient>>,
 ) {
-    let src_prefix = normalize_prefix(&config.src.prefix);
-    let dst_prefix = normalize_prefix(&config.dst.prefix);
-    let src_mounts = get_backends(&config.src.backend);
-    let dst_mounts = get_backends(&config.dst.backend);
-    let mount_map: HashMap<&str, &str> = src_mounts.iter().map(|s| s.as_str()).zip(dst_mounts.iter().map(|s| s.as_str())).collect();
-    info!("Sync worker started");
-    let mut stats = SyncStats::new();
+    let opened = reader.metadata().ok();
+    // An entry that is not written completely yet is kept until the rest is read
+    let mut entries = audit::EntryReader::default();
     loop {
-        let op = rx.recv();
-        if let Ok(op) = op {
-            match op {
-                SecretOp::Update(path) | SecretOp::Create(path) => {
-                    let src_path = &path.path;
-                    let dst_path = secret_src_to_dst_path(&src_prefix, &dst_prefix, &src_path);
-                    let src_secret: Result<Value, _> = {
-                        let mut client = src_client.lock().unwrap();
-                        client.secret_backend(&path.mount);
-                        client.get_custom_secret(&src_path)
-                    };
-                    let dst_secret: Result<Value, _> = {
-                        let mut client = dst_client.lock().unwrap();
-                        client.secret_backend(mount_map[path.mount.as_str()]);
-                        client.get_custom_secret(&dst_path)
-                    };
-                    if let Err(error) = src_secret {
-                        warn!("Failed to get secret {}: {}", &src_path, error);
-                        continue;
-                    }
-                    let src_secret = src_secret.unwrap();
-                    if let Ok(dst_secret) = dst_secret {
-                        if dst_secret == src_secret {
-                            continue;
-                        }
-                    }
-                    info!("Creating/updating secret {}", &dst_path);
-                    if !dry_run {
-                        let result = {
-                            let client = dst_client.lock().unwrap();
-                            client.set_custom_secret(&dst_path, &src_secret)
-                        };
-                        if let Err(error) = result {
-                            warn!("Failed to set secret {}: {}", &dst_path, error);
-                        } else {
-                            stats.updated += 1;
-                        }
-                    }
-                },
-                SecretOp::Delete(path) => {
-                    let secret = secret_src_to_dst_path(&src_prefix, &dst_prefix, &path.path);
-                    info!("Deleting secret {}", &secret);
-                    if !dry_run {
-                        let mut client = dst_client.lock().unwrap();
-                        client.secret_backend(mount_map[path.mount.as_str()]);
-                        let _ = client.delete_secret(&path.path);
-                    } else {
-                        stats.deleted += 1;
-                    }
-                },
-                SecretOp::FullSyncFinished => {
-                    info!("Secrets created/updated: {}, deleted: {}", &stats.updated, &stats.deleted);
-                    stats.reset();
-                    if run_once {
-                        break;
+        if let Err(error) = read_entries(config, reader, &mut entries, tx, client) {
+            warn!("Failed to read audit log {}: {}", file_name, error);
+            return;
+        }
+        thread::sleep(AUDIT_FILE_POLL_INTERVAL);
+        let position = reader.stream_position().unwrap_or(0);
+        match fs::metadata(file_name) {
+            Ok(current) if is_same_file(opened.as_ref(), &current) => {
+                if current.len() < position {
+                    info!("Audit log {} is truncated, reading from the start", file_name);
+                    entries.clear();
+                    if reader.seek(SeekFrom::Start(0)).is_err() {
+                        return;
                     }
-                },
-            }
+                }
+            },
+            Ok(_) => {
+                // Entries written to the old file since the last read come before the new file
+                if let Err(error) = read_entries(config, reader, &mut entries, tx, client) {
+                    warn!("Failed to read rotated audit log {}: {}", file_name, error);
+                }
+                info!("Audit log {} is rotated, opening the new file", file_name);
+                return;
+            },
+            // Still writing to the old file, until the new file is created
+            Err(_) => {},
         }
     }
 }
 
-
-// Convert AuditLog to SecretOp
-fn audit_log_op(mounts: &Vec<String>, prefix: &str, version: &EngineVersion, log: &audit::AuditLog) -> Option<SecretOp> {
-    if log.log_type != "response" {
-        return None;
-    }
-    if log.request.mount_type.is_none() {
-        return None;
-    }
-    if log.request.mount_type != Some("kv".to_string()) {
-        return None;
+// Reads to the end of the file and sends the complete entries
+fn read_entries(
+    config: &SharedConfig,
+    reader: &mut File,
+    entries: &mut audit::EntryReader,
+    tx: &mpsc::SyncSender<SecretOp>,
+    client: &Arc<Mutex<VaultClient>>,
+) -> io::Result<()> {
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => audit_log_entries(config, entries.push(&buf[..n]), tx, client),
+            Err(error) if error.kind() == ErrorKind::Interrupted => {},
+            Err(error) => return Err(error),
+        }
     }
+}
 
-    let operation = log.request.operation.clone();
-    if operation != "create" && operation != "update" && operation != "delete" {
-        return None;
-    }
+#[cfg(unix)]
+fn is_same_file(opened: Option<&fs::Metadata>, current: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    opened.is_none_or(|opened| opened.dev() == current.dev() && opened.ino() == current.ino())
+}
 
-    let path = match version {
-        EngineVersion::V1 => secret_path_v1(&log.request.path),
-        EngineVersion::V2 => secret_path_v2(&log.request.path),
-    };
-    if let Some(path) = path {
-        if !mounts.contains(&path.0) {
-            return None;
+#[cfg(not(unix))]
+fn is_same_file(_opened: Option<&fs::Metadata>, _current: &fs::Metadata) -> bool {
+    true
+}
+
+// Sends the ops for the audit log entries, from the socket or the file audit device. Entries
+// that cannot be parsed are logged and skipped.
+fn audit_log_entries(
+    config: &SharedConfig,
+    entries: Vec<Result<audit::AuditLog, String>>,
+    tx: &mpsc::SyncSender<SecretOp>,
+    client: &Arc<Mutex<VaultClient>>,
+) {
+    for entry in entries {
+        let audit_log = match entry {
+            Ok(audit_log) => audit_log,
+            Err(error) => {
+                warn!("Failed to parse audit log entry: {}", error);
+                continue;
+            }
+        };
+        STATUS.last_audit_entry.store(unix_time(), Ordering::Relaxed);
+        debug!("Log: {:?}", &audit_log);
+        if SELF_TEST.check(&audit_log) {
+            debug!("Request {} is for the audit self-test, skipping", &audit_log.request.path);
+            continue;
         }
-        if !path.1.starts_with(prefix) {
-            return None;
+        if is_own_request(&audit_log, client) {
+            debug!("Request {} is made by vault-sync, skipping", &audit_log.request.path);
+            continue;
         }
-        if operation == "create" {
-            return Some(SecretOp::Create(SecretPath {mount: path.0, path: path.1 }));
-        } else if operation == "update" {
-            return Some(SecretOp::Update(SecretPath {mount: path.0, path: path.1 }));
-        } else if operation == "delete" {
-            return Some(SecretOp::Delete(SecretPath {mount: path.0, path: path.1 }));
+        let op = {
+            let config = config.read().unwrap();
+            let backends = config.src.synced_backends();
+            audit_log_op(&backends, &config.src.prefix, &config.src.version, &config.src.operations, &audit_log)
+                .filter(|op| op_matches(op, &config.src.filter))
+        };
+        if let Some(op) = op {
+            send_op(tx, op);
         }
     }
-    None
 }
 
-// Convert Vault path to a secret path for KV v1
-// Example: "secret/path/to/secret" -> "secret", "path/to/secret"
-fn secret_path_v1(path: &str) -> Option<(String, String)> {
-    let parts: Vec<&str> = path.split("/").collect();
-    if parts.len() < 2 {
-        return None
+static HMAC_ACCESSOR_WARNED: AtomicBool = AtomicBool::new(false);
+
+// In bidirectional mode, a secret written by vault-sync to one Vault is in the audit log of that
+// Vault. Such requests are recognized by the token accessor, so the secret is not synced back.
+fn is_own_request(audit_log: &audit::AuditLog, client: &Arc<Mutex<VaultClient>>) -> bool {
+    let Some(accessor) = audit_log.auth.as_ref().and_then(|auth| auth.accessor.as_ref()) else {
+        return false;
+    };
+    if accessor.starts_with("hmac-") && !HMAC_ACCESSOR_WARNED.swap(true, Ordering::Relaxed) {
+        warn!("Token accessors in the audit log are hashed, enable the audit device with hmac_accessor=false");
     }
-    Some((parts[0].to_string(), parts[1..].join("/")))
+    vault::token_accessor(&client.lock().unwrap()).as_ref() == Some(accessor)
+}
+
+// Sentinel secret of the audit self-test. Its audit log entries are never synced, also the ones
+// received after the test, for example of the removal.
+struct SelfTest {
+    // Request path in the audit log, for example "secret/data/src/vault-sync-self-test-vault-sync"
+    request_path: Mutex<Option<String>>,
+    received: AtomicBool,
+}
+
+static SELF_TEST: SelfTest = SelfTest { request_path: Mutex::new(None), received: AtomicBool::new(false) };
+
+const SELF_TEST_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+impl SelfTest {
+    // Returns true for the entries of the sentinel secret
+    fn check(&self, audit_log: &audit::AuditLog) -> bool {
+        if self.request_path.lock().unwrap().as_deref() != Some(audit_log.request.path.as_str()) {
+            return false;
+        }
+        self.received.store(true, Ordering::Relaxed);
+        true
+    }
+}
+
+// Writes a sentinel secret under the first source prefix and waits audit_self_test_timeout seconds
+// for its entry in the audit log, for example to catch an audit device that sends the log to an
+// address vault-sync does not listen on. The secret is removed afterwards. The token needs write
+// and delete permissions for it. Returns the reason if the test failed.
+pub fn audit_self_test(config: &VaultSyncConfig, client: &Arc<Mutex<VaultClient>>) -> Result<(), String> {
+    let mount = &config.src.synced_backends()[0];
+    let path = format!("{}vault-sync-self-test-{}", get_prefixes(&config.src.prefix)[0], config.id.replace('/', "-"));
+    let request_path = match config.src.version {
+        EngineVersion::V1 => format!("{}/{}", mount, path),
+        EngineVersion::V2 => format!("{}/data/{}", mount, path),
+    };
+    SELF_TEST.received.store(false, Ordering::Relaxed);
+    *SELF_TEST.request_path.lock().unwrap() = Some(request_path);
+
+    info!("Audit self-test: writing secret {}/{}", mount, &path);
+    let mut client = client.lock().unwrap().clone_without_data();
+    client.secret_backend(mount);
+    let data = serde_json::json!({"vault-sync": "audit self-test, safe to remove"});
+    client.set_custom_secret(path.as_str(), &data)
+        .map_err(|error| format!("failed to write secret {}/{}: {}", mount, &path, error))?;
+    let started = time::Instant::now();
+    let timeout = time::Duration::from_secs(config.audit_self_test_timeout);
+    while !SELF_TEST.received.load(Ordering::Relaxed) && started.elapsed() < timeout {
+        thread::sleep(SELF_TEST_POLL_INTERVAL);
+    }
+    let received = SELF_TEST.received.load(Ordering::Relaxed);
+
+    let removed = match config.src.version {
+        EngineVersion::V1 => client.delete_secret(&path),
+        EngineVersion::V2 => vault::delete_metadata(&client, mount, &path),
+    };
+    if let Err(error) = removed {
+        warn!("Audit self-test: failed to remove secret {}/{}: {}", mount, &path, error);
+    }
+    if !received {
+        return Err(format!(
+            "no audit log entry for secret {}/{} received in {}s, check that the audit device {} sends the audit log to vault-sync",
+            mount, &path, config.audit_self_test_timeout, config.audit_path(),
+        ));
+    }
+    info!("Audit self-test: audit log entry received in {}ms", started.elapsed().as_millis());
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub struct SecretPath{
+    pub mount: String,
+    pub path: String,
+}
+
+// Error of a Vault request for a secret, with the secret and the URL of the Vault, usually the
+// destination of the op
+#[derive(Debug)]
+pub struct SyncError {
+    pub secret: SecretPath,
+    pub host: String,
+    pub error: VaultError,
+}
+
+impl SyncError {
+    pub fn new(secret: &SecretPath, host: &str, error: VaultError) -> SyncError {
+        SyncError { secret: secret.clone(), host: host.to_string(), error }
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} in {}: {}", &self.secret.mount, &self.secret.path, &self.host, &self.error)
+    }
+}
+
+impl Error for SyncError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SecretOp {
+    Create(SecretPath),
+    Update(SecretPath),
+    // Deletes the latest version of the secret
+    Delete(SecretPath),
+    // Removes the secret that is missing in the source, including all versions and metadata
+    Destroy(SecretPath),
+    FullSyncFinished,
+    // Stops the sync worker after the ops sent before are applied
+    Shutdown,
+    // Create or Destroy of a full sync with fail_fast, the sync threads count its failures
+    Counted(Box<SecretOp>, OpFailures),
+}
+
+// Failed ops of one full sync, shared by the full sync and the sync threads
+#[derive(Clone, Debug, Default)]
+pub struct OpFailures(Arc<AtomicU64>);
+
+impl OpFailures {
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// The same counter, the number of failures is not compared
+impl PartialEq for OpFailures {
+    fn eq(&self, other: &OpFailures) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+thread_local! {
+    // Failures of the op applied by the current sync thread, see sync_thread
+    static OP_FAILURES: Cell<u64> = const { Cell::new(0) };
+}
+
+// Sends the op to the sync worker, the queue depth is decreased when the op is received. If the
+// queue is full (see queue_capacity), waits for the sync worker, so a burst of changes or a slow
+// destination slows down the audit log and full sync instead of growing the queue.
+pub fn send_op(tx: &mpsc::SyncSender<SecretOp>, op: SecretOp) {
+    METRICS.queue_depth.fetch_add(1, Ordering::Relaxed);
+    let result = match tx.try_send(op) {
+        Err(mpsc::TrySendError::Full(op)) => {
+            METRICS.queue_blocked.fetch_add(1, Ordering::Relaxed);
+            let started = time::Instant::now();
+            let result = tx.send(op);
+            if started.elapsed() >= QUEUE_FULL_WARNING {
+                warn!("Queue of secret operations was full for {}ms, the destinations are slower than the changes", started.elapsed().as_millis());
+            }
+            result
+        },
+        Err(mpsc::TrySendError::Disconnected(op)) => Err(mpsc::SendError(op)),
+        Ok(()) => Ok(()),
+    };
+    if let Err(error) = result {
+        METRICS.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        warn!("Failed to send a secret to a sync thread: {}", error);
+    }
+}
+
+const QUEUE_FULL_WARNING: time::Duration = time::Duration::from_secs(1);
+
+// Also checks the operations of the include rules
+fn op_matches(op: &SecretOp, filter: &PathFilter) -> bool {
+    let (secret, operation) = match op {
+        SecretOp::Create(secret) => (secret, WatchedOperation::Create),
+        SecretOp::Update(secret) => (secret, WatchedOperation::Update),
+        SecretOp::Delete(secret) | SecretOp::Destroy(secret) => (secret, WatchedOperation::Delete),
+        SecretOp::Counted(op, _) => return op_matches(op, filter),
+        SecretOp::FullSyncFinished | SecretOp::Shutdown => return true,
+    };
+    if !filter.matches(&secret.path) {
+        debug!("Secret {}/{} is filtered out, skipping", &secret.mount, &secret.path);
+        return false;
+    }
+    if !filter.watches(&secret.path, &operation) {
+        debug!("Operation {:?} for {}/{} is not watched by the matching include rules, skipping", operation, &secret.mount, &secret.path);
+        return false;
+    }
+    true
+}
+
+// Counted by all sync threads. In dry run, the changes are collected as actions instead.
+struct SyncStats {
+    updated: AtomicU64,
+    // Not written because the secret is unchanged
+    skipped: AtomicU64,
+    deleted: AtomicU64,
+    errors: AtomicU64,
+    actions: Mutex<Vec<Action>>,
+}
+
+impl SyncStats {
+    fn new() -> SyncStats {
+        SyncStats {
+            updated: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            deleted: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            actions: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Adds the numbers since the previous reset to the summary
+    fn reset(&self, summary: &mut FullSyncSummary) {
+        summary.written += self.updated.swap(0, Ordering::Relaxed);
+        summary.skipped += self.skipped.swap(0, Ordering::Relaxed);
+        summary.deleted += self.deleted.swap(0, Ordering::Relaxed);
+        summary.errors += self.errors.swap(0, Ordering::Relaxed);
+    }
+
+    fn add_action(&self, action: ActionKind, dst_config: &VaultDestination, dst: &SecretPath, changed: Option<bool>) {
+        self.actions.lock().unwrap().push(Action {
+            action,
+            destination: dst_config.host.url.clone(),
+            mount: dst.mount.clone(),
+            path: dst.path.clone(),
+            changed,
+        });
+    }
+
+    fn take_actions(&self) -> Vec<Action> {
+        std::mem::take(&mut self.actions.lock().unwrap())
+    }
+}
+
+// Mapping of the source secrets to the destination secrets
+pub struct PathMap {
+    // Normalized source and destination prefixes, in the order of the configuration
+    pub prefixes: Vec<(String, String)>,
+    pub mounts: HashMap<String, String>,
+    rewriter: PathRewriter,
+    template: Option<PathTemplate>,
+    template_default: Option<String>,
+}
+
+impl PathMap {
+    pub fn new(src: &VaultSource, dst: &VaultDestination) -> PathMap {
+        let src_mounts = get_backends(&src.backend);
+        let dst_mounts = get_backends(&dst.backend);
+        PathMap {
+            prefixes: get_prefixes(&src.prefix).into_iter().zip(get_prefixes(&dst.prefix)).collect(),
+            mounts: src_mounts.into_iter().zip(dst_mounts).collect(),
+            rewriter: dst.rewriter.clone(),
+            template: dst.template.clone(),
+            template_default: dst.path_template_default.clone(),
+        }
+    }
+
+    // Whether the destination paths depend on the metadata of the source secrets, see dst_path_with
+    pub fn has_template(&self) -> bool {
+        self.template.is_some()
+    }
+
+    // The path relative to the source prefix is rewritten by the first matching rule, or kept as
+    // is, then joined with the corresponding destination prefix. Prefixes do not overlap, so at
+    // most one of them matches, the first pair is used for paths outside of all prefixes.
+    pub fn dst_path(&self, path: &str) -> String {
+        let (src_prefix, dst_prefix) = self.prefixes.iter()
+            .find(|(src_prefix, _)| path.starts_with(src_prefix.as_str()))
+            .unwrap_or(&self.prefixes[0]);
+        let relative = secret_src_to_dst_path(src_prefix, "", path);
+        match self.rewriter.rewrite(&relative) {
+            Some(relative) => format!("{}{}", dst_prefix, relative),
+            None => secret_src_to_dst_path(src_prefix, dst_prefix, path),
+        }
+    }
+
+    // Like dst_path, with path_template rendered from the custom_metadata of the source secret
+    // between the destination prefix and the path. Returns None if the secret is skipped.
+    pub fn dst_path_with(&self, path: &str, custom_metadata: &HashMap<String, String>) -> Option<String> {
+        let Some(template) = &self.template else {
+            return Some(self.dst_path(path));
+        };
+        let (src_prefix, dst_prefix) = self.prefixes.iter()
+            .find(|(src_prefix, _)| path.starts_with(src_prefix.as_str()))
+            .unwrap_or(&self.prefixes[0]);
+        let relative = secret_src_to_dst_path(src_prefix, "", path);
+        let relative = self.rewriter.rewrite(&relative).unwrap_or(relative);
+        let relative = template.render(&relative, custom_metadata, self.template_default.as_deref())?;
+        Some(format!("{}{}", dst_prefix, relative))
+    }
+}
+
+// Destination path of the source secret, with path_template the custom_metadata is read from the
+// source. A deleted secret without metadata has no custom_metadata. Returns None if the secret is
+// skipped, see PathMap::dst_path_with.
+pub fn resolve_dst_path(
+    config: &VaultSyncConfig,
+    paths: &PathMap,
+    src_client: &Arc<Mutex<VaultClient>>,
+    path: &SecretPath,
+) -> Result<Option<String>, SyncError> {
+    if !paths.has_template() {
+        return Ok(Some(paths.dst_path(&path.path)));
+    }
+    let metadata = with_retries(config.max_retries, path, &config.src.host.url, || {
+        vault::read_metadata(&src_client.lock().unwrap(), &path.mount, &path.path)
+    })?;
+    let custom_metadata = metadata.and_then(|metadata| metadata.custom_metadata).unwrap_or_default();
+    let dst_path = paths.dst_path_with(&path.path, &custom_metadata);
+    if dst_path.is_none() {
+        debug!(secret = path.path.as_str(); "Secret {} has no custom_metadata for path_template, skipping", &path.path);
+    }
+    Ok(dst_path)
+}
+
+// Destination state shared by the sync threads
+struct Destination {
+    pool: ClientPool,
+    // Active node that the ops are written to while the configured node is a standby
+    active: Mutex<Option<reqwest::Url>>,
+    stats: SyncStats,
+    limiter: Mutex<Option<RateLimiter>>,
+    // Same for all destinations
+    state: Arc<SyncState>,
+    cache: Arc<ContentCache>,
+}
+
+// Work for a sync thread, with the counter of the full sync that sent the op. Flush is answered
+// when all ops received before are applied.
+enum Work {
+    Op(SecretOp, Option<OpFailures>),
+    Flush(mpsc::Sender<()>),
+}
+
+// Create and Update ops for the same secret received within the window are applied once, as the
+// latest op. An op waits at most the window after the first op for the secret. A Delete or
+// Destroy applies the pending op for the secret first, FullSyncFinished and Shutdown apply all
+// pending ops first.
+struct Coalescer {
+    window: time::Duration,
+    // In the order the ops are received, so deadlines only grow. Deadlines of the ops that are
+    // already applied are skipped.
+    deadlines: VecDeque<(time::Instant, SecretPath)>,
+    pending: HashMap<SecretPath, (time::Instant, SecretOp)>,
+}
+
+impl Coalescer {
+    fn new(window: time::Duration) -> Coalescer {
+        Coalescer { window, deadlines: VecDeque::new(), pending: HashMap::new() }
+    }
+
+    // Returns the ops to apply now
+    fn push(&mut self, op: SecretOp, now: time::Instant) -> Vec<SecretOp> {
+        match &op {
+            SecretOp::Create(secret) | SecretOp::Update(secret) if !self.window.is_zero() => {
+                if let Some(pending) = self.pending.get_mut(secret) {
+                    pending.1 = op;
+                } else {
+                    let deadline = now + self.window;
+                    self.deadlines.push_back((deadline, secret.clone()));
+                    self.pending.insert(secret.clone(), (deadline, op));
+                }
+                Vec::new()
+            },
+            SecretOp::Create(_) | SecretOp::Update(_) => vec![op],
+            SecretOp::Delete(secret) | SecretOp::Destroy(secret) => {
+                let mut ops: Vec<SecretOp> = self.pending.remove(secret).map(|(_, op)| op).into_iter().collect();
+                ops.push(op);
+                ops
+            },
+            // Unwrapped by the sync worker before
+            SecretOp::Counted(..) => vec![op],
+            SecretOp::FullSyncFinished | SecretOp::Shutdown => {
+                let mut ops = self.drain();
+                ops.push(op);
+                ops
+            },
+        }
+    }
+
+    // Returns the pending ops with the deadline before now
+    fn expired(&mut self, now: time::Instant) -> Vec<SecretOp> {
+        let mut ops = Vec::new();
+        while let Some((deadline, _)) = self.deadlines.front() {
+            if *deadline > now {
+                break;
+            }
+            let (deadline, secret) = self.deadlines.pop_front().unwrap();
+            if self.pending.get(&secret).is_some_and(|pending| pending.0 == deadline) {
+                ops.push(self.pending.remove(&secret).unwrap().1);
+            }
+        }
+        ops
+    }
+
+    // Returns all pending ops, in the order they are received
+    fn drain(&mut self) -> Vec<SecretOp> {
+        let mut ops = Vec::new();
+        for (deadline, secret) in self.deadlines.drain(..) {
+            if self.pending.get(&secret).is_some_and(|pending| pending.0 == deadline) {
+                ops.push(self.pending.remove(&secret).unwrap().1);
+            }
+        }
+        ops
+    }
+
+    fn next_deadline(&self) -> Option<time::Instant> {
+        self.deadlines.front().map(|(deadline, _)| *deadline)
+    }
+}
+
+// Receives ops and applies them in full_sync_concurrency threads. Ops for the same secret always
+// go to the same thread, so they are applied in order. Ops for different secrets can be applied
+// in any order, FullSyncFinished waits until all ops received before it are applied.
+// In dry run, the report is written to dry_run_output (or stdout) on FullSyncFinished and Shutdown.
+// The sync state is saved on FullSyncFinished and when the worker stops. With coalesce_ms, rapid
+// updates of the same secret are applied once, see Coalescer.
+pub fn sync_worker(
+    rx: mpsc::Receiver<SecretOp>,
+    config: &SharedConfig,
+    src_client: Arc<Mutex<VaultClient>>,
+    dst_clients: Vec<Arc<Mutex<VaultClient>>>,
+    dry_run: Option<DryRun>,
+    dry_run_output: Option<&str>,
+    run_once: bool,
+) {
+    // The number of threads and the coalesce window do not change on reload
+    let concurrency = config.read().unwrap().full_sync_concurrency;
+    // The threads have bounded queues too, so the senders wait when the threads are behind
+    let capacity = config.read().unwrap().queue_capacity;
+    let mut coalescer = Coalescer::new(time::Duration::from_millis(config.read().unwrap().coalesce_ms));
+    info!("Sync worker started with {} threads", concurrency);
+    // The state file and the pools do not change on reload
+    let state = Arc::new(SyncState::load(config.read().unwrap().state_path.as_deref()));
+    let current = config.read().unwrap().clone();
+    let cache = Arc::new(ContentCache::load(current.content_cache, current.content_cache_path.as_deref(), current.content_cache_key.as_deref()));
+    let src_pool = ClientPool::new(&current.src.host, src_client, current.pool_size);
+    let destinations: Vec<Destination> = dst_clients.into_iter().zip(&current.dst)
+        .map(|(client, dst)| Destination {
+            pool: ClientPool::new(&dst.host, client, current.pool_size),
+            active: Mutex::new(None),
+            stats: SyncStats::new(),
+            limiter: Mutex::new(None),
+            state: state.clone(),
+            cache: cache.clone(),
+        })
+        .collect();
+    let src_pool = &src_pool;
+    let destinations = &destinations;
+    // Ops that are not applied because of a shutdown while paused
+    let unapplied = &AtomicUsize::new(0);
+    thread::scope(|scope| {
+        let threads: Vec<mpsc::SyncSender<Work>> = (0..concurrency).map(|_| {
+            let (work_tx, work_rx) = mpsc::sync_channel(capacity);
+            scope.spawn(move || sync_thread(work_rx, config, src_pool, destinations, dry_run, unapplied));
+            work_tx
+        }).collect();
+        // Counters of the full sync ops waiting in the coalescer, by secret
+        let mut counted: HashMap<SecretPath, OpFailures> = HashMap::new();
+        'receive: loop {
+            let received = match coalescer.next_deadline() {
+                Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(time::Instant::now())),
+                None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            let ops = match received {
+                Ok(op) => {
+                    METRICS.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                    let op = match op {
+                        SecretOp::Counted(op, failures) => {
+                            if let Some(secret) = op_secret(&op) {
+                                counted.insert(secret.clone(), failures);
+                            }
+                            *op
+                        },
+                        op => op,
+                    };
+                    coalescer.push(op, time::Instant::now())
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => coalescer.expired(time::Instant::now()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    for op in coalescer.drain() {
+                        dispatch(&threads, &mut counted, op);
+                    }
+                    break;
+                },
+            };
+            for op in ops {
+                if let SecretOp::Shutdown = &op {
+                    flush(&threads);
+                    if dry_run.is_some() {
+                        write_report(destinations, dry_run_output);
+                    }
+                    info!("Sync worker stopped");
+                    break 'receive;
+                }
+                let SecretOp::FullSyncFinished = &op else {
+                    dispatch(&threads, &mut counted, op);
+                    continue;
+                };
+                flush(&threads);
+                METRICS.full_sync_finished();
+                state.save();
+                cache.save();
+                let current = config.read().unwrap().clone();
+                let mut summary = FullSyncSummary::default();
+                for (dst, destination) in current.dst.iter().zip(destinations) {
+                    let mut counts = FullSyncSummary::default();
+                    destination.stats.reset(&mut counts);
+                    info!(host = dst.host.url.as_str(); "Secrets created/updated in {}: {}, deleted: {}", &dst.host.url, counts.written, counts.deleted);
+                    summary.written += counts.written;
+                    summary.skipped += counts.skipped;
+                    summary.deleted += counts.deleted;
+                    summary.errors += counts.errors;
+                }
+                summary.read = STATUS.full_sync_secrets.load(Ordering::Relaxed);
+                summary.duration_ms = STATUS.full_sync_duration_ms();
+                info!(
+                    read = summary.read, written = summary.written, skipped = summary.skipped,
+                    deleted = summary.deleted, errors = summary.errors, duration_ms = summary.duration_ms;
+                    "FullSync summary: {} secrets read, {} written, {} skipped unchanged, {} deleted, {} errors, in {}ms",
+                    summary.read, summary.written, summary.skipped, summary.deleted, summary.errors, summary.duration_ms,
+                );
+                *STATUS.full_sync_summary.lock().unwrap() = Some(summary);
+                if dry_run.is_some() {
+                    write_report(destinations, dry_run_output);
+                }
+                if run_once {
+                    break 'receive;
+                }
+            }
+        }
+        // Dropping the senders stops the threads
+    });
+    let unapplied = unapplied.load(Ordering::Relaxed);
+    if unapplied > 0 {
+        warn!("Stopped while the sync is paused, {} queued secret operations are not applied, the next full sync picks up the changes", unapplied);
+    }
+    state.save();
+    cache.save();
+}
+
+// Writes the actions collected since the previous report
+fn write_report(destinations: &[Destination], file_name: Option<&str>) {
+    let actions = destinations.iter().flat_map(|destination| destination.stats.take_actions()).collect();
+    let report = DryRunReport::new(actions);
+    info!(
+        "Dry run: {} secrets to create, {} to update, {} to delete, {} to remove",
+        report.summary.create, report.summary.update, report.summary.delete, report.summary.destroy,
+    );
+    if let Err(error) = report.write(file_name) {
+        error!("Failed to write the dry run report to {}: {}", file_name.unwrap_or("stdout"), error);
+    }
+}
+
+// Sends the op to its thread, with the counter of the full sync that sent it
+fn dispatch(threads: &[mpsc::SyncSender<Work>], counted: &mut HashMap<SecretPath, OpFailures>, op: SecretOp) {
+    let failures = op_secret(&op).and_then(|secret| counted.remove(secret));
+    let _ = threads[thread_index(&op, threads.len())].send(Work::Op(op, failures));
+}
+
+// Only the secret is hashed, so for example Create and Delete of a secret go to the same thread
+fn thread_index(op: &SecretOp, threads: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    if let Some(secret) = op_secret(op) {
+        secret.hash(&mut hasher);
+    }
+    (hasher.finish() % threads as u64) as usize
+}
+
+fn op_secret(op: &SecretOp) -> Option<&SecretPath> {
+    match op {
+        SecretOp::Create(secret) | SecretOp::Update(secret) | SecretOp::Delete(secret) | SecretOp::Destroy(secret) => Some(secret),
+        SecretOp::Counted(op, _) => op_secret(op),
+        SecretOp::FullSyncFinished | SecretOp::Shutdown => None,
+    }
+}
+
+// Waits until every thread applies the ops sent to it before
+fn flush(threads: &[mpsc::SyncSender<Work>]) {
+    let (done_tx, done_rx) = mpsc::channel();
+    for thread in threads {
+        let _ = thread.send(Work::Flush(done_tx.clone()));
+    }
+    drop(done_tx);
+    for _ in done_rx {}
+}
+
+fn sync_thread(
+    rx: mpsc::Receiver<Work>,
+    config: &SharedConfig,
+    src_pool: &ClientPool,
+    destinations: &[Destination],
+    dry_run: Option<DryRun>,
+    unapplied: &AtomicUsize,
+) {
+    for work in rx {
+        let (op, failures) = match work {
+            Work::Op(op, failures) => (op, failures),
+            Work::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        if !PAUSE.wait() {
+            unapplied.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        // Configuration can be reloaded, so the mapping is always taken from the current one.
+        // The number of destinations does not change on reload.
+        let current = config.read().unwrap().clone();
+        // Copies of the shared clients for a single op, with the current token
+        let src_client = src_pool.checkout();
+        // Every destination is synced independently, a failure in one does not affect others.
+        // Waiting for the rate limit does not block senders, the channel keeps the ops.
+        for (dst, destination) in current.dst.iter().zip(destinations) {
+            pace(&destination.limiter, dst.rate_limit);
+            let dst_client = destination.pool.checkout();
+            sync_destination(&op, &current, dst, &src_client, &dst_client, dry_run, destination);
+        }
+        let failed = OP_FAILURES.take();
+        if let Some(failures) = &failures {
+            failures.0.fetch_add(failed, Ordering::Relaxed);
+        }
+    }
+}
+
+// Waits for the rate limit of the destination. The limiter is created again if the limit is
+// changed on reload.
+fn pace(limiter: &Mutex<Option<RateLimiter>>, rate_limit: Option<f64>) {
+    // Other threads wait for the same destination while the lock is held
+    let mut limiter = limiter.lock().unwrap();
+    if limiter.as_ref().map(|limiter| limiter.rate()) != rate_limit {
+        *limiter = rate_limit.map(RateLimiter::new);
+    }
+    if let Some(limiter) = limiter.as_mut() {
+        limiter.acquire();
+    }
+}
+
+// A destination path outside of allowed_dst_prefixes is most likely a wrong prefix or rewrite, the
+// op is not retried or written to the dead letter file
+fn reject(dst_config: &VaultDestination, dst: &SecretPath) {
+    error!(secret = dst.path.as_str(), host = dst_config.host.url.as_str();
+        "Secret {}/{} is not under allowed_dst_prefixes of {}, rejecting", &dst.mount, &dst.path, &dst_config.host.url);
+    METRICS.secrets_rejected.fetch_add(1, Ordering::Relaxed);
+}
+
+// Applies the op to one destination, retrying transient errors
+fn sync_destination(
+    op: &SecretOp,
+    config: &VaultSyncConfig,
+    dst_config: &VaultDestination,
+    src_client: &Arc<Mutex<VaultClient>>,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    dry_run: Option<DryRun>,
+    destination: &Destination,
+) {
+    let stats = &destination.stats;
+    let state = &destination.state;
+    let src = &config.src;
+    let paths = PathMap::new(src, dst_config);
+    if let SecretOp::Create(path) | SecretOp::Update(path) | SecretOp::Delete(path) | SecretOp::Destroy(path) = op {
+        if !dst_config.in_canary(&path.path) {
+            debug!(secret = path.path.as_str(); "Secret {} is not in canary_percent of {}, skipping", &path.path, &dst_config.host.url);
+            return;
+        }
+    }
+    if src.secret_engine == SecretEngine::Transit {
+        sync_transit_key(op, config, dst_config, src_client, dst_client, dry_run, destination);
+        return;
+    }
+    // Without destination reads, the ops are only recorded in the report
+    let plan = dry_run == Some(DryRun::Plan);
+    let dry_run = dry_run.is_some();
+    match op {
+        SecretOp::Update(path) | SecretOp::Create(path) => {
+            let Some(dst_mount) = paths.mounts.get(&path.mount) else {
+                warn!(secret = path.path.as_str(); "No destination backend for {}, skipping secret {}", &path.mount, &path.path);
+                return;
+            };
+            let Some(dst_path) = op_dst_path(config, &paths, src_client, path, stats) else {
+                return;
+            };
+            let dst = SecretPath { mount: dst_mount.clone(), path: dst_path };
+            if !dst_config.is_allowed(&dst.path) {
+                reject(dst_config, &dst);
+                return;
+            }
+            let updated_time = source_updated_time(config, state, src_client, path);
+            if let Some(updated_time) = &updated_time {
+                if state.is_synced(&dst_config.host.url, &dst, updated_time) {
+                    debug!(secret = dst.path.as_str(); "Secret {} is not changed since the last sync, skipping", &dst.path);
+                    stats.skipped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+            if plan {
+                info!(secret = dst.path.as_str(); "Creating/updating secret {}", &dst.path);
+                stats.add_action(ActionKind::Write, dst_config, &dst, None);
+                return;
+            }
+            let updated = with_destination(config, dst_config, destination, &dst, dst_client, |dst_client| {
+                if dst_config.sync_versions {
+                    sync_secret_versions(src_client, dst_client, path, &dst, &src.blacklist, &dst_config.transformer, config.max_secret_bytes, dry_run)
+                        .map(|written| written > 0)
+                } else {
+                    sync_secret(src_client, dst_client, path, &dst, dst_config, &src.version, config.binary_safe, &src.blacklist, &destination.cache, config.max_secret_bytes, dry_run)
+                }
+            });
+            match updated {
+                Ok(true) => {
+                    if dry_run {
+                        match planned_update(src_client, dst_client, path, &dst, dst_config, &src.blacklist) {
+                            Ok((action, changed)) => stats.add_action(action, dst_config, &dst, changed),
+                            Err(error) => {
+                                sync_failed("Failed to verify secret", SyncError::new(&dst, &dst_config.host.url, error), stats);
+                                return;
+                            },
+                        }
+                    } else {
+                        stats.updated.fetch_add(1, Ordering::Relaxed);
+                        METRICS.secrets_synced.fetch_add(1, Ordering::Relaxed);
+                    }
+                },
+                Ok(false) => {
+                    stats.skipped.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(error) => {
+                    write_dead_letter(config, op, dst_config, src_client, &error.error, dry_run);
+                    emit_event(ActionKind::Write, config, path, dst_config, &dst, Some(&error), dry_run);
+                    sync_failed("Failed to sync secret", error, stats);
+                    return;
+                }
+            }
+            // Metadata is written after the data, so a new secret gets its metadata in the same op
+            if src.version == EngineVersion::V2 && dst_config.version == EngineVersion::V2 {
+                let result = with_destination(config, dst_config, destination, &dst, dst_client, |dst_client| {
+                    sync_secret_metadata(src_client, dst_client, path, &dst, dry_run)
+                });
+                if let Err(error) = result {
+                    write_dead_letter(config, op, dst_config, src_client, &error.error, dry_run);
+                    emit_event(ActionKind::Write, config, path, dst_config, &dst, Some(&error), dry_run);
+                    sync_failed("Failed to sync metadata of secret", error, stats);
+                    return;
+                }
+            }
+            if let Ok(true) = updated {
+                emit_event(ActionKind::Write, config, path, dst_config, &dst, None, dry_run);
+            }
+            if let (Some(updated_time), false) = (&updated_time, dry_run) {
+                state.set_synced(&dst_config.host.url, &dst, updated_time);
+            }
+        },
+        SecretOp::Delete(path) | SecretOp::Destroy(path) => {
+            let Some(dst_mount) = paths.mounts.get(&path.mount) else {
+                warn!(secret = path.path.as_str(); "No destination backend for {}, skipping secret {}", &path.mount, &path.path);
+                return;
+            };
+            if !dst_config.propagate_deletes {
+                debug!("Deletes are not propagated to {}, skipping secret {}", &dst_config.host.url, &path.path);
+                return;
+            }
+            let Some(dst_path) = op_dst_path(config, &paths, src_client, path, stats) else {
+                return;
+            };
+            let dst = SecretPath { mount: dst_mount.clone(), path: dst_path };
+            if !dst_config.is_allowed(&dst.path) {
+                reject(dst_config, &dst);
+                return;
+            }
+            if plan {
+                let action = if let SecretOp::Destroy(_) = op { ActionKind::Destroy } else { ActionKind::Delete };
+                info!(secret = dst.path.as_str(); "Removing secret {}", &dst.path);
+                stats.add_action(action, dst_config, &dst, None);
+                return;
+            }
+            let deleted = with_destination(config, dst_config, destination, &dst, dst_client, |dst_client| {
+                if let SecretOp::Destroy(_) = op {
+                    destroy_secret(src_client, dst_client, path, &dst, &src.version, &dst_config.version, dry_run)
+                } else {
+                    info!(secret = dst.path.as_str(); "Deleting secret {}", &dst.path);
+                    if dry_run {
+                        Ok(true)
+                    } else {
+                        let mut client = dst_client.lock().unwrap();
+                        client.secret_backend(&dst.mount);
+                        METRICS.time(Request::Delete, || client.delete_secret(&dst.path)).map(|_| true)
+                    }
+                }
+            });
+            if !dry_run {
+                state.remove(&dst_config.host.url, &dst);
+                destination.cache.remove(&dst_config.host.url, &dst);
+            }
+            let action = if let SecretOp::Destroy(_) = op { ActionKind::Destroy } else { ActionKind::Delete };
+            match deleted {
+                Ok(true) => {
+                    if dry_run {
+                        stats.add_action(action, dst_config, &dst, None);
+                    } else {
+                        emit_event(action, config, path, dst_config, &dst, None, dry_run);
+                        stats.deleted.fetch_add(1, Ordering::Relaxed);
+                        METRICS.secrets_deleted.fetch_add(1, Ordering::Relaxed);
+                        webhook::notify(WebhookEvent::Delete, Some(format!("{}/{}", &dst.mount, &dst.path)), Some(&dst_config.host.url), None);
+                    }
+                },
+                Ok(false) => {},
+                Err(error) => {
+                    write_dead_letter(config, op, dst_config, src_client, &error.error, dry_run);
+                    emit_event(action, config, path, dst_config, &dst, Some(&error), dry_run);
+                    sync_failed("Failed to delete secret", error, stats);
+                }
+            }
+        },
+        SecretOp::Counted(..) | SecretOp::FullSyncFinished | SecretOp::Shutdown => {},
+    }
+}
+
+// Destination path of the op, see resolve_dst_path. Counts the skipped secret, or the error if
+// the metadata cannot be read, the next full sync tries again.
+fn op_dst_path(config: &VaultSyncConfig, paths: &PathMap, src_client: &Arc<Mutex<VaultClient>>, path: &SecretPath, stats: &SyncStats) -> Option<String> {
+    match resolve_dst_path(config, paths, src_client, path) {
+        Ok(Some(dst_path)) => Some(dst_path),
+        Ok(None) => {
+            stats.skipped.fetch_add(1, Ordering::Relaxed);
+            None
+        },
+        Err(error) => {
+            sync_failed("Failed to read metadata of secret", error, stats);
+            None
+        },
+    }
+}
+
+// Transit keys are created and updated only, the state and dead letters are for KV secrets
+fn sync_transit_key(
+    op: &SecretOp,
+    config: &VaultSyncConfig,
+    dst_config: &VaultDestination,
+    src_client: &Arc<Mutex<VaultClient>>,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    dry_run: Option<DryRun>,
+    destination: &Destination,
+) {
+    let (SecretOp::Create(path) | SecretOp::Update(path)) = op else {
+        return;
+    };
+    let stats = &destination.stats;
+    let paths = PathMap::new(&config.src, dst_config);
+    let Some(dst_mount) = paths.mounts.get(&path.mount) else {
+        warn!(secret = path.path.as_str(); "No destination backend for {}, skipping transit key {}", &path.mount, &path.path);
+        return;
+    };
+    let dst = SecretPath { mount: dst_mount.clone(), path: paths.dst_path(&path.path) };
+    if dry_run == Some(DryRun::Plan) {
+        info!(secret = dst.path.as_str(); "Creating/updating transit key {}", &dst.path);
+        stats.add_action(ActionKind::Write, dst_config, &dst, None);
+        return;
+    }
+    let result = with_destination(config, dst_config, destination, &dst, dst_client, |dst_client| {
+        transit::sync_key(src_client, dst_client, path, &dst, dry_run.is_some())
+    });
+    match result {
+        Ok(Some(action)) => {
+            if dry_run.is_some() {
+                stats.add_action(action, dst_config, &dst, None);
+            } else {
+                emit_event(action, config, path, dst_config, &dst, None, false);
+                stats.updated.fetch_add(1, Ordering::Relaxed);
+                METRICS.secrets_synced.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+        Ok(None) => {
+            stats.skipped.fetch_add(1, Ordering::Relaxed);
+        },
+        Err(error) => {
+            emit_event(ActionKind::Write, config, path, dst_config, &dst, Some(&error), dry_run.is_some());
+            sync_failed("Failed to sync transit key", error, stats);
+        },
+    }
+}
+
+// With state_path, the source updated_time of a KV v2 secret, read before the secret, so a
+// change in the meantime is synced again on the next full sync. None if the secret is synced
+// anyway, for example when the metadata cannot be read.
+fn source_updated_time(
+    config: &VaultSyncConfig,
+    state: &SyncState,
+    src_client: &Arc<Mutex<VaultClient>>,
+    path: &SecretPath,
+) -> Option<String> {
+    if !state.is_enabled() || config.src.version != EngineVersion::V2 {
+        return None;
+    }
+    let metadata = with_retries(config.max_retries, path, &config.src.host.url, || {
+        vault::read_metadata(&src_client.lock().unwrap(), &path.mount, &path.path)
+    });
+    match metadata {
+        Ok(Some(metadata)) if !metadata.updated_time.is_empty() => Some(metadata.updated_time),
+        Ok(_) => None,
+        Err(error) => {
+            debug!(secret = path.path.as_str(), host = error.host.as_str(); "Failed to read metadata of secret {}", error);
+            None
+        }
+    }
+}
+
+// The op could not be applied, also after retries. The next full sync tries again.
+fn sync_failed(message: &str, error: SyncError, stats: &SyncStats) {
+    error!(secret = error.secret.path.as_str(), host = error.host.as_str(); "{} {}", message, error);
+    METRICS.sync_errors.fetch_add(1, Ordering::Relaxed);
+    OP_FAILURES.set(OP_FAILURES.get() + 1);
+    stats.errors.fetch_add(1, Ordering::Relaxed);
+    let path = format!("{}/{}", &error.secret.mount, &error.secret.path);
+    webhook::notify(WebhookEvent::Error, Some(path), Some(&error.host), Some(format!("{} {}", message, error)));
+}
+
+// Writes the result of the op with --events-ndjson. In dry run nothing is changed, the report has
+// the planned actions.
+fn emit_event(
+    action: ActionKind,
+    config: &VaultSyncConfig,
+    path: &SecretPath,
+    dst_config: &VaultDestination,
+    dst: &SecretPath,
+    error: Option<&SyncError>,
+    dry_run: bool,
+) {
+    if dry_run {
+        return;
+    }
+    events::emit(&Event::new(
+        action,
+        format!("{}/{}", &path.mount, &path.path),
+        &config.src.host.url,
+        &dst_config.host.url,
+        format!("{}/{}", &dst.mount, &dst.path),
+        error.map(|error| error.error.to_string()),
+    ));
+}
+
+// Appends the failed op to dead_letter_path, if it is set, so the op can be replayed later
+fn write_dead_letter(
+    config: &VaultSyncConfig,
+    op: &SecretOp,
+    dst_config: &VaultDestination,
+    src_client: &Arc<Mutex<VaultClient>>,
+    error: &VaultError,
+    dry_run: bool,
+) {
+    let Some(file_name) = &config.dead_letter_path else {
+        return;
+    };
+    if dry_run {
+        return;
+    }
+    let data = match op {
+        SecretOp::Create(secret) | SecretOp::Update(secret) => {
+            let mut client = src_client.lock().unwrap();
+            client.secret_backend(&secret.mount);
+            client.get_custom_secret::<_, Value>(&secret.path).ok().map(|mut data| {
+                config.src.blacklist.strip(&secret.path, &mut data);
+                data
+            })
+        },
+        _ => None,
+    };
+    let Some(letter) = DeadLetter::new(op, &dst_config.host.url, data, error.to_string()) else {
+        return;
+    };
+    if let Err(error) = dead_letter::append(file_name, &letter) {
+        error!(secret = letter.path.as_str(); "Failed to write secret {} to {}: {}", &letter.path, file_name, error);
+    }
+}
+
+// Calls f again on transient errors, at most max_retries times, with exponential backoff. Errors
+// are returned with the secret and the host.
+pub fn with_retries<T, F: FnMut() -> VaultResult<T>>(max_retries: u32, secret: &SecretPath, host: &str, mut f: F) -> Result<T, SyncError> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < max_retries && vault::is_transient(&error) => {
+                attempt += 1;
+                let delay = retry_delay(attempt);
+                let error = SyncError::new(secret, host, error);
+                warn!(secret = secret.path.as_str(), host = host; "Retrying in {}ms ({}/{}): {}", delay.as_millis(), attempt, max_retries, error);
+                METRICS.sync_retries.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(delay);
+            },
+            Err(error) => return Err(SyncError::new(secret, host, error)),
+        }
+    }
+}
+
+// Set on SIGINT, SIGTERM and after --max-runtime, so the sync threads stop waiting for a standby
+// destination and the shutdown does not wait for it until shutdown_timeout
+pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+// Runs f with retries, with the client for the destination. Errors of a standby node are not
+// retried like other errors: with on_standby wait, f runs again when the node is active, with
+// redirect, it runs with a client for the active node from sys/leader, which is kept for the next
+// ops until the active node changes again. During the shutdown, the error is returned instead.
+fn with_destination<T, F: FnMut(&Arc<Mutex<VaultClient>>) -> VaultResult<T>>(
+    config: &VaultSyncConfig,
+    dst_config: &VaultDestination,
+    destination: &Destination,
+    dst: &SecretPath,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    mut f: F,
+) -> Result<T, SyncError> {
+    let host = dst_config.host.url.as_str();
+    loop {
+        let active = destination.active.lock().unwrap().clone();
+        let client = match &active {
+            Some(url) => {
+                let mut client = dst_client.lock().unwrap().clone_without_data();
+                client.host = url.clone();
+                Arc::new(Mutex::new(client))
+            },
+            None => dst_client.clone(),
+        };
+        let error = match with_retries(config.max_retries, dst, host, || f(&client)) {
+            Err(error) if vault::is_standby(&error.error) => error,
+            result => {
+                if HEALTH.set_standby(host, false) {
+                    info!(host = host; "Destination {} is active again", host);
+                }
+                return result;
+            },
+        };
+        if dst_config.on_standby == OnStandby::Redirect {
+            match vault::leader_address(&dst_config.host).map(|address| address.map(|address| reqwest::Url::parse(&address))) {
+                Ok(Some(Ok(url))) if Some(&url) != active.as_ref() => {
+                    info!(host = host; "Destination {} is a standby node, writing to the active node {}", host, &url);
+                    *destination.active.lock().unwrap() = Some(url);
+                    continue;
+                },
+                // The configured node is active again
+                Ok(None) if active.is_some() => {
+                    *destination.active.lock().unwrap() = None;
+                    continue;
+                },
+                Ok(Some(Err(error))) => warn!(host = host; "Invalid leader address of {}: {}", host, error),
+                Err(error) => warn!(host = host; "Failed to read the active node of {}: {}", host, error),
+                _ => {},
+            }
+        }
+        if SHUTDOWN.load(Ordering::Relaxed) {
+            return Err(error);
+        }
+        if HEALTH.set_standby(host, true) {
+            warn!(host = host; "Destination {} is a standby node, waiting until it is active: {}", host, error);
+        }
+        thread::sleep(STANDBY_POLL_INTERVAL);
+    }
+}
+
+#[cfg(not(test))]
+const STANDBY_POLL_INTERVAL: time::Duration = time::Duration::from_secs(5);
+#[cfg(test)]
+const STANDBY_POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+const RETRY_BASE_DELAY: time::Duration = time::Duration::from_millis(200);
+const RETRY_MAX_DELAY: time::Duration = time::Duration::from_secs(30);
+
+// Doubles the delay for every attempt, a random half of the delay is a jitter, so retries of
+// several workers do not hit Vault at the same time
+fn retry_delay(attempt: u32) -> time::Duration {
+    let delay = RETRY_BASE_DELAY.saturating_mul(1 << (attempt - 1).min(16)).min(RETRY_MAX_DELAY);
+    delay / 2 + (delay / 2).mul_f64(random())
+}
+
+// Good enough for jitter, in [0, 1)
+fn random() -> f64 {
+    let nanos = time::SystemTime::now().duration_since(time::UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+    nanos as f64 / 1e9
+}
+
+// Full sync lists the destination after the source, so a secret created in the source in the
+// meantime would be removed as missing. Check that the source secret is still missing first.
+// The secret can be missing only in some of the destinations, so check the destination too.
+// Returns true if the destination secret is removed.
+fn destroy_secret(
+    src_client: &Arc<Mutex<VaultClient>>,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    src: &SecretPath,
+    dst: &SecretPath,
+    src_version: &EngineVersion,
+    dst_version: &EngineVersion,
+    dry_run: bool,
+) -> VaultResult<bool> {
+    if secret_exists(&src_client.lock().unwrap(), src, src_version)? {
+        debug!("Secret {} exists in the source, skipping", &src.path);
+        return Ok(false);
+    }
+    let exists = {
+        let client = dst_client.lock().unwrap();
+        METRICS.time(Request::Read, || secret_exists(&client, dst, dst_version))?
+    };
+    if !exists {
+        return Ok(false);
+    }
+    info!(secret = dst.path.as_str(); "Removing secret {}", &dst.path);
+    if !dry_run {
+        let mut client = dst_client.lock().unwrap();
+        client.secret_backend(&dst.mount);
+        METRICS.time(Request::Delete, || match dst_version {
+            EngineVersion::V1 => client.delete_secret(&dst.path),
+            EngineVersion::V2 => vault::delete_metadata(&client, &dst.mount, &dst.path),
+        })?;
+    }
+    Ok(true)
+}
+
+// For the dry run report: create if the destination secret does not exist, otherwise update, and
+// whether the value is different. The change is unknown if the source secret cannot be read.
+// Errors reading the destination, for example missing permissions, are returned.
+fn planned_update(
+    src_client: &Arc<Mutex<VaultClient>>,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    src: &SecretPath,
+    dst: &SecretPath,
+    dst_config: &VaultDestination,
+    blacklist: &KeyBlacklist,
+) -> VaultResult<(ActionKind, Option<bool>)> {
+    let mut client = dst_client.lock().unwrap();
+    if !METRICS.time(Request::Read, || secret_exists(&client, dst, &dst_config.version))? {
+        return Ok((ActionKind::Create, None));
+    }
+    // Blacklisted keys are compared neither, see sync_secret
+    let read = |client: &mut VaultClient, secret: &SecretPath| -> VaultResult<Value> {
+        client.secret_backend(&secret.mount);
+        let mut data = client.get_custom_secret(&secret.path)?;
+        blacklist.strip(&src.path, &mut data);
+        Ok(data)
+    };
+    let dst_secret = METRICS.time(Request::Read, || read(&mut client, dst))?;
+    drop(client);
+    let src_secret = read(&mut src_client.lock().unwrap(), src).ok().map(|mut src_secret| {
+        dst_config.transformer.apply(&src.path, &mut src_secret);
+        src_secret
+    });
+    Ok((ActionKind::Update, src_secret.map(|src_secret| src_secret != dst_secret)))
+}
+
+fn secret_exists(client: &VaultClient, secret: &SecretPath, version: &EngineVersion) -> VaultResult<bool> {
+    match version {
+        EngineVersion::V1 => vault::secret_exists_v1(client, &secret.mount, &secret.path),
+        EngineVersion::V2 => Ok(vault::read_metadata(client, &secret.mount, &secret.path)?.is_some()),
+    }
+}
+
+// Writes the latest version of the source secret. With skip_unchanged, the secret is written only
+// if it is different from the destination secret. Blacklisted keys are removed from the source
+// secret, and the destination secret keeps its own values of them, so they never make a difference.
+// With blacklisted keys, an error reading the destination secret fails the sync instead of writing.
+// The transforms of the destination are applied before the comparison, so a transformed value
+// that is already written is unchanged. Secrets larger than max_bytes are skipped. With skip_unchanged
+// and the cache enabled, the destination is not read if the source data is the data last synced.
+// Returns true if the destination secret is updated.
+#[allow(clippy::too_many_arguments)]
+fn sync_secret(
+    src_client: &Arc<Mutex<VaultClient>>,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    src: &SecretPath,
+    dst: &SecretPath,
+    dst_config: &VaultDestination,
+    src_version: &EngineVersion,
+    binary_safe: bool,
+    blacklist: &KeyBlacklist,
+    cache: &ContentCache,
+    max_bytes: Option<usize>,
+    dry_run: bool,
+) -> VaultResult<bool> {
+    let skip_unchanged = dst_config.skip_unchanged;
+    let mut src_secret = read_secret(&mut src_client.lock().unwrap(), src, src_version, binary_safe)?;
+    let keys = blacklist.keys(&src.path);
+    if blacklist.strip(&src.path, &mut src_secret) && src_secret.as_object().is_some_and(|data| data.is_empty()) {
+        debug!("Secret {} has only blacklisted keys, skipping", &src.path);
+        return Ok(false);
+    }
+    dst_config.transformer.apply(&src.path, &mut src_secret);
+    if too_large(src, &src_secret, max_bytes) {
+        return Ok(false);
+    }
+    // Without the values of the blacklisted keys of the destination
+    let checksum = if skip_unchanged { cache.checksum(&src_secret) } else { None };
+    if let Some(checksum) = &checksum {
+        if cache.is_unchanged(&dst_config.host.url, dst, checksum) {
+            debug!("Secret {} is unchanged since the last sync, skipping", &dst.path);
+            METRICS.content_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+        METRICS.content_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+    if skip_unchanged || !keys.is_empty() {
+        let dst_secret = {
+            let mut client = dst_client.lock().unwrap();
+            METRICS.time(Request::Read, || read_secret(&mut client, dst, &dst_config.version, binary_safe))
+        };
+        let dst_secret = match dst_secret {
+            Ok(dst_secret) => Some(dst_secret),
+            // A new secret has no values of the blacklisted keys to keep
+            Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => None,
+            // Writing without them would remove the values of the blacklisted keys in the destination
+            Err(error) if !keys.is_empty() => return Err(error),
+            Err(_) => None,
+        };
+        if let Some(dst_secret) = dst_secret {
+            if let (Value::Object(data), Value::Object(dst_data)) = (&mut src_secret, &dst_secret) {
+                for key in keys {
+                    if let Some(value) = dst_data.get(key) {
+                        data.insert(key.to_string(), value.clone());
+                    }
+                }
+            }
+            // JSON objects are compared regardless of the key order
+            if skip_unchanged && dst_secret == src_secret {
+                debug!("Secret {} is unchanged, skipping", &dst.path);
+                if let (Some(checksum), false) = (&checksum, dry_run) {
+                    cache.set(&dst_config.host.url, dst, checksum);
+                }
+                return Ok(false);
+            }
+        }
+    }
+    info!(secret = dst.path.as_str(); "Creating/updating secret {}", &dst.path);
+    if !dry_run {
+        let mut client = dst_client.lock().unwrap();
+        client.secret_backend(&dst.mount);
+        METRICS.time(Request::Write, || write_secret(&client, dst, &src_secret, dst_config))?;
+        if let Some(checksum) = &checksum {
+            cache.set(&dst_config.host.url, dst, checksum);
+        }
+    }
+    Ok(true)
+}
+
+// With binary_safe, the values that are not valid UTF-8 are base64-encoded, see binary
+fn read_secret(client: &mut VaultClient, secret: &SecretPath, version: &EngineVersion, binary_safe: bool) -> VaultResult<Value> {
+    if binary_safe {
+        return vault::read_secret_binary(client, &secret.mount, &secret.path, version);
+    }
+    client.secret_backend(&secret.mount);
+    client.get_custom_secret(&secret.path)
+}
+
+// Writes the latest version of the secret. With require_cas, or if the KV v2 destination rejects
+// the write without the cas value, the secret is written with check-and-set. With decode_binary,
+// the values encoded by binary_safe are written decoded.
+pub fn write_secret(client: &VaultClient, dst: &SecretPath, data: &Value, dst_config: &VaultDestination) -> VaultResult<()> {
+    if let Some(body) = binary::decode(data).filter(|_| dst_config.decode_binary) {
+        let body = body.map_err(VaultError::Vault)?;
+        return vault::write_secret_binary(client, &dst.mount, &dst.path, &dst_config.version, &body);
+    }
+    if dst_config.require_cas {
+        return vault::write_secret_cas(client, &dst.mount, &dst.path, data);
+    }
+    match client.set_custom_secret(&dst.path, data) {
+        Err(error) if dst_config.version == EngineVersion::V2 && vault::is_cas_required(&error) => {
+            debug!(secret = dst.path.as_str(); "Secret {} requires check-and-set, writing with the current version, set require_cas to skip the first write", &dst.path);
+            vault::write_secret_cas(client, &dst.mount, &dst.path, data)
+        },
+        result => result,
+    }
+}
+
+// Logs and returns true if the data as JSON is larger than max_bytes. The size is counted
+// without serializing the data into memory again.
+fn too_large(path: &SecretPath, data: &Value, max_bytes: Option<usize>) -> bool {
+    struct Counter(usize);
+    impl std::io::Write for Counter {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0 += bytes.len();
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let Some(max_bytes) = max_bytes else {
+        return false;
+    };
+    let mut counter = Counter(0);
+    if serde_json::to_writer(&mut counter, data).is_err() || counter.0 <= max_bytes {
+        return false;
+    }
+    warn!(secret = path.path.as_str(); "Secret {} is {} bytes, larger than max_secret_bytes {}, skipping", &path.path, counter.0, max_bytes);
+    true
+}
+
+// Copies custom_metadata, max_versions and delete_version_after of a KV v2 secret if they are
+// different in the destination
+fn sync_secret_metadata(
+    src_client: &Arc<Mutex<VaultClient>>,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    src: &SecretPath,
+    dst: &SecretPath,
+    dry_run: bool,
+) -> VaultResult<()> {
+    let Some(src_metadata) = vault::read_metadata(&src_client.lock().unwrap(), &src.mount, &src.path)? else {
+        return Ok(());
+    };
+    let client = dst_client.lock().unwrap();
+    let dst_metadata = METRICS.time(Request::Read, || vault::read_metadata(&client, &dst.mount, &dst.path))?;
+    let src_settings = vault::MetadataSettings::from(&src_metadata);
+    if dst_metadata.map(|metadata| vault::MetadataSettings::from(&metadata)).as_ref() == Some(&src_settings) {
+        return Ok(());
+    }
+    info!(secret = dst.path.as_str(); "Updating metadata of secret {}", &dst.path);
+    if !dry_run {
+        METRICS.time(Request::Write, || vault::write_metadata(&client, &dst.mount, &dst.path, &src_settings))?;
+    }
+    Ok(())
+}
+
+// Writes the source versions missing in the destination in order, so version numbers are the same
+// in both Vaults. Versions that cannot be read from the source (deleted, destroyed or removed due
+// to max_versions) are written as empty secrets, then deleted or destroyed in the destination.
+// Blacklisted keys are removed from every version, versions are written even if nothing is left.
+// The transforms are applied to every version. A version larger than max_bytes and the versions
+// after it are skipped, so the version numbers stay the same. Returns number of written versions.
+#[allow(clippy::too_many_arguments)]
+fn sync_secret_versions(
+    src_client: &Arc<Mutex<VaultClient>>,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    src: &SecretPath,
+    dst: &SecretPath,
+    blacklist: &KeyBlacklist,
+    transformer: &ValueTransformer,
+    max_bytes: Option<usize>,
+    dry_run: bool,
+) -> VaultResult<u64> {
+    let Some(src_metadata) = vault::read_metadata(&src_client.lock().unwrap(), &src.mount, &src.path)? else {
+        return Ok(0);
+    };
+    let dst_version = {
+        let client = dst_client.lock().unwrap();
+        METRICS.time(Request::Read, || vault::read_metadata(&client, &dst.mount, &dst.path))?
+    };
+    let dst_version = dst_version.map_or(0, |metadata| metadata.current_version);
+    if dst_version > src_metadata.current_version {
+        return Err(VaultError::Vault(format!(
+            "destination has version {}, but the source has only version {}",
+            dst_version, src_metadata.current_version,
+        )));
+    }
+    let mut written = 0;
+    for version in dst_version + 1..=src_metadata.current_version {
+        let destroyed = src_metadata.versions.get(&version.to_string()).is_none_or(|metadata| metadata.destroyed);
+        let data = if destroyed {
+            None
+        } else {
+            vault::read_version(&src_client.lock().unwrap(), &src.mount, &src.path, version)?.map(|mut data| {
+                blacklist.strip(&src.path, &mut data);
+                transformer.apply(&src.path, &mut data);
+                data
+            })
+        };
+        if data.as_ref().is_some_and(|data| too_large(src, data, max_bytes)) {
+            break;
+        }
+        debug!("Creating version {} of secret {}, deleted: {}, destroyed: {}", version, &dst.path, data.is_none(), destroyed);
+        written += 1;
+        if dry_run {
+            continue;
+        }
+        let client = dst_client.lock().unwrap();
+        let empty = Value::Object(serde_json::Map::new());
+        METRICS.time(Request::Write, || vault::write_version(&client, &dst.mount, &dst.path, data.as_ref().unwrap_or(&empty), version - 1))?;
+        if data.is_none() {
+            METRICS.time(Request::Delete, || vault::delete_versions(&client, &dst.mount, &dst.path, &[version], destroyed))?;
+        }
+    }
+    if written > 0 {
+        info!(secret = dst.path.as_str(); "Created {} versions of secret {}", written, &dst.path);
+    }
+    Ok(written)
+}
+
+// Convert AuditLog to SecretOp
+fn audit_log_op(
+    mounts: &[String],
+    prefixes: &[String],
+    version: &EngineVersion,
+    operations: &[WatchedOperation],
+    log: &audit::AuditLog,
+) -> Option<SecretOp> {
+    if log.log_type != "response" {
+        return None;
+    }
+    // Also skips the entries with the hashed mount type
+    if log.request.mount_type.as_deref() != Some("kv") {
+        debug!("Request {} is not for a KV secrets engine, skipping", &log.request.path);
+        return None;
+    }
+
+    let operation = match log.request.operation.as_str() {
+        "create" => WatchedOperation::Create,
+        "update" => WatchedOperation::Update,
+        "delete" => WatchedOperation::Delete,
+        _ => return None,
+    };
+    if !operations.contains(&operation) {
+        debug!("Operation {:?} for {} is not watched, skipping", operation, &log.request.path);
+        return None;
+    }
+
+    let path = match version {
+        EngineVersion::V1 => secret_path_v1(&log.request.path),
+        EngineVersion::V2 => secret_path_v2(&log.request.path),
+    };
+    if let Some(path) = path {
+        if !mounts.contains(&path.0) {
+            return None;
+        }
+        if !prefixes.iter().any(|prefix| path.1.starts_with(prefix.as_str())) {
+            return None;
+        }
+        let path = SecretPath {mount: path.0, path: path.1 };
+        return Some(match operation {
+            WatchedOperation::Create => SecretOp::Create(path),
+            WatchedOperation::Update => SecretOp::Update(path),
+            WatchedOperation::Delete => SecretOp::Delete(path),
+        });
+    }
+    None
+}
+
+// Convert Vault path to a secret path for KV v1
+// Example: "secret/path/to/secret" -> "secret", "path/to/secret"
+fn secret_path_v1(path: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = path.split("/").collect();
+    if parts.len() < 2 {
+        return None
+    }
+    Some((parts[0].to_string(), parts[1..].join("/")))
 }
 
 // Convert Vault path to a secret path for KV v2
@@ -331,98 +2423,2287 @@ fn secret_path_v2(path: &str) -> Option<(String, String)> {
     if parts.len() < 3 {
         return None
     }
-    // `vault kv metadata delete secret/path` has `metadata` instead of `data`,
-    // we do not support this yet
-    if parts[1] == "data" {
-        Some((parts[0].to_string(), parts[2..].join("/")))
-    } else {
-        None
+    // `vault kv metadata delete secret/path` has `metadata` instead of `data`,
+    // we do not support this yet
+    if parts[1] == "data" {
+        Some((parts[0].to_string(), parts[2..].join("/")))
+    } else {
+        None
+    }
+}
+
+// Canonical form of a prefix: no leading or repeated slashes, a single trailing slash, or an empty
+// string for all secrets. Example: "/src//team" -> "src/team/"
+pub fn normalize_prefix(prefix: &str) -> String {
+    let parts: Vec<&str> = prefix.split('/').filter(|part| !part.is_empty()).collect();
+    if parts.is_empty() {
+        return "".to_string();
+    }
+    format!("{}/", parts.join("/"))
+}
+
+// Convert source secret path to destination secret path. Prefixes must be normalized!
+// Example: "src/secret1" -> "dst/secret2"
+fn secret_src_to_dst_path(src_prefix: &str, dst_prefix: &str, path: &str) -> String {
+    let mut path = path.to_string();
+    if !src_prefix.is_empty() {
+        path = path.trim_start_matches(src_prefix).to_string();
+    }
+    format!("{}{}", dst_prefix, &path)
+}
+
+// Convert destination secret path to source secret path. Prefixes must be normalized!
+// Example: "dst/secret2" -> "src/secret1"
+pub fn secret_dst_to_src_path(src_prefix: &str, dst_prefix: &str, path: &str) -> String {
+    secret_src_to_dst_path(dst_prefix, src_prefix, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    use ::time::OffsetDateTime;
+    use ::time::format_description::well_known::Rfc3339;
+    use hashicorp_vault::client::error::Error as VaultError;
+    use serde_json::Value;
+
+    use crate::audit::AuditLog;
+    use crate::config::{Backend, EngineVersion, OnStandby, VaultAuthMethod, VaultDestination, VaultHost, VaultSyncConfig, WatchedOperation};
+    use crate::dead_letter;
+    use crate::filter::{IncludeRule, KeyBlacklist, KeyRule, PathFilter};
+    use crate::health::HEALTH;
+    use crate::metrics::METRICS;
+    use crate::mock::{self, MockServer};
+    use crate::admin::FullSyncSummary;
+    use crate::report::{ActionKind, DryRun, DryRunReport};
+    use crate::rewrite::{PathRewriter, PathTemplate, Rewrite};
+    use crate::state::{ContentCache, FullSyncCursor, SyncState};
+    use crate::transform::{Transform, ValueTransformer};
+    use crate::sync::{AuditListener, AuditWatchdog, Coalescer, DeleteBreaker, Idle, audit_self_test, log_listener, paths_sync, read_paths, audit_device, audit_log_op, check_audit_device_options, same_audit_device, enable_audit_device, full_sync, full_sync_delay, full_sync_worker, log_file_sync, log_sync, normalize_prefix, parse_duration, retry_delay, thread_index, RETRY_BASE_DELAY, RETRY_MAX_DELAY, SHUTDOWN, secret_dst_to_src_path, secret_path_v1, secret_path_v2, send_op, secret_src_to_dst_path, sync_destination, sync_secret, sync_secret_metadata, sync_secret_versions, sync_worker, with_retries, Destination, PathMap, SecretOp, SecretPath, SyncStats};
+    use crate::vault::{vault_client, ClientPool, VaultClient};
+
+    fn config(propagate_deletes: bool) -> VaultSyncConfig {
+        let yaml = format!(r#"
+            id: vault-sync-id
+            full_sync_interval: 60
+            src:
+              url: http://127.0.0.1:8200/
+              prefix: src
+            dst:
+              url: http://127.0.0.1:8200/
+              prefix: dst
+              propagate_deletes: {}
+        "#, propagate_deletes);
+        let mut config: VaultSyncConfig = serde_yaml::from_str(&yaml).unwrap();
+        config.src.backend = Some(Backend::Backend("secret".to_string()));
+        config.dst[0].backend = Some(Backend::Backend("secret".to_string()));
+        config
+    }
+
+    fn secret(path: &str) -> SecretPath {
+        SecretPath { mount: "secret".to_string(), path: path.to_string() }
+    }
+
+    fn versioned_client(server: &MockServer, version: &EngineVersion) -> Arc<Mutex<VaultClient>> {
+        let host = VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::TokenAuth { token: "token".to_string() }),
+            ..Default::default()
+        };
+        Arc::new(Mutex::new(vault_client(&host, version).unwrap()))
+    }
+
+    #[test]
+    fn test_secret_path_v1_matches() {
+        let path = "secret/path/to/secret";
+        let path = secret_path_v1(path).unwrap();
+        assert_eq!(path.0, "secret");
+        assert_eq!(path.1, "path/to/secret");
+    }
+
+    #[test]
+    fn test_custom_secret_path_v1_matches() {
+        let path = "custom/path/to/secret";
+        let path = secret_path_v1(path).unwrap();
+        assert_eq!(path.0, "custom");
+        assert_eq!(path.1, "path/to/secret");
+    }
+
+    #[test]
+    fn test_secret_path_v1_not_matches() {
+        let path = "secret";
+        let path = secret_path_v1(path);
+        assert!(path.is_none());
+    }
+
+    #[test]
+    fn test_secret_path_v2_matches() {
+        let path = "secret/data/path/to/secret";
+        let path = secret_path_v2(path).unwrap();
+        assert_eq!(path.0, "secret");
+        assert_eq!(path.1, "path/to/secret");
+    }
+
+    #[test]
+    fn test_custom_secret_path_v2_matches() {
+        let path = "custom/data/path/to/secret";
+        let path = secret_path_v2(path).unwrap();
+        assert_eq!(path.0, "custom");
+        assert_eq!(path.1, "path/to/secret");
+    }
+
+    #[test]
+    fn test_secret_path_v2_not_matches() {
+        let path = "secret/metadata/path/to/secret";
+        let path = secret_path_v2(path);
+        assert!(path.is_none());
+    }
+
+    #[test]
+    fn test_secret_dst_to_src_path() {
+        assert_eq!(secret_dst_to_src_path("src/", "dst/", "dst/secret"), "src/secret");
+        assert_eq!(secret_dst_to_src_path("", "dst/", "dst/secret"), "secret");
+        assert_eq!(secret_dst_to_src_path("src/", "", "secret"), "src/secret");
+    }
+
+    #[test]
+    fn test_normalize_prefix() {
+        assert_eq!(normalize_prefix(""), "");
+        assert_eq!(normalize_prefix("src"), "src/");
+        assert_eq!(normalize_prefix("src/"), "src/");
+        assert_eq!(normalize_prefix("/src/"), "src/");
+        assert_eq!(normalize_prefix("src//team//"), "src/team/");
+        assert_eq!(normalize_prefix("/"), "");
+    }
+
+    #[test]
+    fn test_secret_src_to_dst_path() {
+        assert_eq!(secret_src_to_dst_path("src/", "dst/", "src/secret"), "dst/secret");
+        assert_eq!(secret_src_to_dst_path("", "dst/", "src/secret"), "dst/src/secret");
+        assert_eq!(secret_src_to_dst_path("", "", "src/secret"), "src/secret");
+    }
+
+    #[test]
+    fn test_path_map_rewrites() {
+        let mut config = config(false);
+        config.dst[0].rewrites = vec![Rewrite { pattern: "^team-a/(.*)$".to_string(), replacement: "imported/team-a/$1".to_string() }];
+        config.dst[0].rewriter = PathRewriter::new(&config.dst[0].rewrites).unwrap();
+        let paths = PathMap::new(&config.src, &config.dst[0]);
+        assert_eq!(paths.dst_path("src/team-a/secret"), "dst/imported/team-a/secret");
+        assert_eq!(paths.dst_path("src/team-b/secret"), "dst/team-b/secret");
+    }
+
+    #[test]
+    fn test_sync_worker_path_template() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/src/app/db") => (200, r#"{"request_id":"1","data":{
+                    "current_version":1,"versions":{},"custom_metadata":{"team":"a"},"max_versions":0,"delete_version_after":"0s"
+                }}"#.to_string()),
+                ("GET", "/v1/secret/metadata/src/app/other") => (200, r#"{"request_id":"1","data":{
+                    "current_version":1,"versions":{},"custom_metadata":null,"max_versions":0,"delete_version_after":"0s"
+                }}"#.to_string()),
+                ("GET", _) => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) | ("POST", _) | ("DELETE", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let run = |default: Option<&str>| {
+            let mut config = config(true);
+            config.dst[0].path_template = Some("teams/{{custom_metadata.team}}/{{path}}".to_string());
+            config.dst[0].template = Some(PathTemplate::new(config.dst[0].path_template.as_ref().unwrap()).unwrap());
+            config.dst[0].path_template_default = default.map(String::from);
+            let (tx, rx) = mpsc::sync_channel(100);
+            send_op(&tx, SecretOp::Update(secret("src/app/db")));
+            send_op(&tx, SecretOp::Update(secret("src/app/other")));
+            send_op(&tx, SecretOp::Delete(secret("src/app/db")));
+            send_op(&tx, SecretOp::FullSyncFinished);
+            sync_worker(rx, &Arc::new(RwLock::new(config)), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        };
+
+        run(None);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/teams/a/app/db"), 1);
+        assert_eq!(dst.count("DELETE", "/v1/secret/data/dst/teams/a/app/db"), 1);
+        // Without the field and a default the secret is skipped
+        assert!(dst.requests().iter().all(|request| !request.path.contains("app/other")));
+
+        run(Some("shared"));
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/teams/shared/app/other"), 1);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/app/db"), 0);
+    }
+
+    #[test]
+    fn test_sync_worker_content_cache() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/app") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) => (200, r#"{"request_id":"1","data":{"version":1}}"#.to_string()),
+                ("DELETE", _) => (204, String::new()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(true);
+        config.content_cache = true;
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Update(secret("src/app")));
+        send_op(&tx, SecretOp::Update(secret("src/app")));
+        send_op(&tx, SecretOp::Update(secret("src/app")));
+        // The deleted secret is read and written again
+        send_op(&tx, SecretOp::Delete(secret("src/app")));
+        send_op(&tx, SecretOp::Update(secret("src/app")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        sync_worker(rx, &Arc::new(RwLock::new(config)), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        assert_eq!(src.count("GET", "/v1/secret/data/src/app"), 4);
+        assert_eq!(dst.count("GET", "/v1/secret/data/dst/app"), 2);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/app"), 2);
+    }
+
+    #[test]
+    fn test_sync_worker_require_cas() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", _) => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        // The secret is written by someone else after the first read of the version
+        let version = Arc::new(AtomicU64::new(3));
+        let dst = {
+            let version = version.clone();
+            MockServer::start(move |request| {
+                let current_version = version.load(Ordering::SeqCst);
+                match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                    ("GET", "/v1/secret/metadata/dst/path/to/secret") => (200, format!(r#"{{"request_id":"1","data":{{
+                        "current_version":{},"versions":{{}},"custom_metadata":null,"max_versions":0,"delete_version_after":"0s"
+                    }}}}"#, current_version)),
+                    ("PUT", "/v1/secret/data/dst/path/to/secret") => {
+                        let body: Value = serde_json::from_str(&request.body).unwrap();
+                        match body["options"]["cas"].as_u64() {
+                            None => (400, r#"{"errors":["check-and-set parameter required for this call"]}"#.to_string()),
+                            Some(3) => {
+                                version.store(4, Ordering::SeqCst);
+                                (400, r#"{"errors":["check-and-set parameter did not match the current version"]}"#.to_string())
+                            },
+                            Some(cas) if cas == current_version => (200, r#"{"request_id":"1","data":{"version":5}}"#.to_string()),
+                            Some(_) => (400, r#"{"errors":["check-and-set parameter did not match the current version"]}"#.to_string()),
+                        }
+                    },
+                    _ => (404, r#"{"errors":[]}"#.to_string()),
+                }
+            })
+        };
+        let run = |require_cas: bool| {
+            version.store(3, Ordering::SeqCst);
+            let mut config = config(false);
+            config.dst[0].require_cas = require_cas;
+            let (tx, rx) = mpsc::sync_channel(100);
+            send_op(&tx, SecretOp::Update(secret("src/path/to/secret")));
+            send_op(&tx, SecretOp::FullSyncFinished);
+            sync_worker(rx, &Arc::new(RwLock::new(config)), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        };
+        let cas_values = || -> Vec<Value> {
+            dst.requests().iter()
+                .filter(|request| request.method == "PUT")
+                .map(|request| serde_json::from_str::<Value>(&request.body).unwrap()["options"]["cas"].clone())
+                .collect()
+        };
+
+        // Detected from the error of the write without cas
+        run(false);
+        assert_eq!(cas_values(), [Value::Null, Value::from(3), Value::from(4)]);
+        assert_eq!(version.load(Ordering::SeqCst), 4);
+
+        run(true);
+        assert_eq!(cas_values()[3..], [Value::from(3), Value::from(4)]);
+    }
+
+    #[test]
+    fn test_sync_worker_allowed_dst_prefixes() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", _) => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) | ("DELETE", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut allowed = config(true);
+        allowed.dst[0].allowed_dst_prefixes = vec!["dst/team-b/".to_string()];
+        // A wrong rewrite maps team-b into the secrets of team-a
+        let mut rewritten = allowed.clone();
+        rewritten.dst[0].rewrites = vec![Rewrite { pattern: "^team-b/(.*)$".to_string(), replacement: "team-a/$1".to_string() }];
+        rewritten.dst[0].rewriter = PathRewriter::new(&rewritten.dst[0].rewrites).unwrap();
+
+        let rejected = METRICS.secrets_rejected.load(Ordering::Relaxed);
+        for config in [rewritten, allowed] {
+            let (tx, rx) = mpsc::sync_channel(100);
+            send_op(&tx, SecretOp::Update(secret("src/team-b/foo")));
+            send_op(&tx, SecretOp::Delete(secret("src/team-b/bar")));
+            send_op(&tx, SecretOp::FullSyncFinished);
+            sync_worker(rx, &Arc::new(RwLock::new(config)), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        }
+        // Other tests may reject secrets at the same time
+        assert!(METRICS.secrets_rejected.load(Ordering::Relaxed) >= rejected + 2);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/team-a/foo"), 0);
+        assert_eq!(dst.count("DELETE", "/v1/secret/data/dst/team-a/bar"), 0);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/team-b/foo"), 1);
+        assert_eq!(dst.count("DELETE", "/v1/secret/data/dst/team-b/bar"), 1);
+    }
+
+    #[test]
+    fn test_sync_worker_standby() {
+        const STANDBY: &str = r#"{"errors":["local node not active but active cluster node not found"]}"#;
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", _) => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        // Rejects the first writes, then becomes active
+        let writes = Arc::new(AtomicUsize::new(0));
+        let dst = {
+            let writes = writes.clone();
+            MockServer::start(move |request| {
+                match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                    ("PUT", _) if writes.fetch_add(1, Ordering::Relaxed) < 3 => (500, STANDBY.to_string()),
+                    ("PUT", _) => (204, "".to_string()),
+                    _ => (404, r#"{"errors":[]}"#.to_string()),
+                }
+            })
+        };
+        let mut config = config(false);
+        config.max_retries = 0;
+        config.dst[0].host.url = dst.url.clone();
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Update(secret("src/foo")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        sync_worker(rx, &Arc::new(RwLock::new(config.clone())), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        // Without retries, the write is applied when the node is active
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/foo"), 4);
+        assert!(!HEALTH.standby().contains(&dst.url));
+
+        // During the shutdown, the op fails instead of waiting. In this test only, other tests would
+        // not wait for their standby nodes either.
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) => (500, STANDBY.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        config.dst[0].host.url = dst.url.clone();
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Update(secret("src/foo")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        SHUTDOWN.store(true, Ordering::Relaxed);
+        sync_worker(rx, &Arc::new(RwLock::new(config)), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        SHUTDOWN.store(false, Ordering::Relaxed);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/foo"), 1);
+    }
+
+    #[test]
+    fn test_sync_worker_standby_redirect() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", _) => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let active = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let leader = format!(r#"{{"ha_enabled":true,"is_self":false,"leader_address":"{}"}}"#, active.url);
+        let dst = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/sys/leader") => (200, leader.clone()),
+                ("PUT", _) => (500, r#"{"errors":["cannot write on a performance standby"]}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        config.dst[0].host.url = dst.url.clone();
+        config.dst[0].on_standby = OnStandby::Redirect;
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Update(secret("src/foo")));
+        send_op(&tx, SecretOp::Update(secret("src/bar")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        sync_worker(rx, &Arc::new(RwLock::new(config)), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        assert_eq!(active.count("PUT", "/v1/secret/data/dst/foo"), 1);
+        assert_eq!(active.count("PUT", "/v1/secret/data/dst/bar"), 1);
+        // The active node is kept for the next op
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/foo") + dst.count("PUT", "/v1/secret/data/dst/bar"), 1);
+    }
+
+    #[test]
+    fn test_sync_worker_canary_percent() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", _) => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) | ("DELETE", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(true);
+        config.dst[0].canary_percent = Some(30.0);
+        let paths: Vec<String> = (0..20).map(|i| format!("src/app-{}", i)).collect();
+        let canary: Vec<&String> = paths.iter().filter(|path| config.dst[0].in_canary(path)).collect();
+        assert!(!canary.is_empty() && canary.len() < paths.len());
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        for path in &paths {
+            send_op(&tx, SecretOp::Update(secret(path)));
+            send_op(&tx, SecretOp::Delete(secret(path)));
+        }
+        send_op(&tx, SecretOp::FullSyncFinished);
+        sync_worker(rx, &Arc::new(RwLock::new(config)), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        for path in &paths {
+            let dst_path = path.replacen("src/", "dst/", 1);
+            let expected = canary.contains(&path) as usize;
+            assert_eq!(dst.count("PUT", &format!("/v1/secret/data/{}", dst_path)), expected, "{}", path);
+            assert_eq!(dst.count("DELETE", &format!("/v1/secret/data/{}", dst_path)), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_sync_secret_versions() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/src/foo") => (200, r#"{"request_id":"1","data":{"current_version":4,"versions":{
+                    "2":{"deletion_time":"","destroyed":true},
+                    "3":{"deletion_time":"2023-01-01T00:00:00Z","destroyed":false},
+                    "4":{"deletion_time":"","destroyed":false}
+                }}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/foo?version=3") => (404, r#"{"request_id":"1","data":{"data":null}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/foo?version=4") => (200, r#"{"request_id":"1","data":{"data":{"key":"value"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/dst/foo") => (200, r#"{"request_id":"1","data":{"current_version":1,"versions":{}}}"#.to_string()),
+                (_, "/v1/secret/data/dst/foo") | (_, "/v1/secret/delete/dst/foo") | (_, "/v1/secret/destroy/dst/foo") => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let src_path = secret("src/foo");
+        let dst_path = secret("dst/foo");
+
+        let written = sync_secret_versions(&mock::client(&src), &mock::client(&dst), &src_path, &dst_path, &KeyBlacklist::default(), &ValueTransformer::default(), None, true).unwrap();
+        assert_eq!(written, 3);
+        assert!(dst.requests().iter().all(|r| r.method == "GET"));
+        // Version 4 is too large, the versions before it are still written
+        let written = sync_secret_versions(&mock::client(&src), &mock::client(&dst), &src_path, &dst_path, &KeyBlacklist::default(), &ValueTransformer::default(), Some(10), true).unwrap();
+        assert_eq!(written, 2);
+
+        let written = sync_secret_versions(&mock::client(&src), &mock::client(&dst), &src_path, &dst_path, &KeyBlacklist::default(), &ValueTransformer::default(), None, false).unwrap();
+        assert_eq!(written, 3);
+        let writes: Vec<(String, String, String)> = dst.requests().into_iter()
+            .filter(|r| r.method != "GET")
+            .map(|r| (r.method, r.path, r.body))
+            .collect();
+        assert_eq!(writes, vec![
+            ("PUT".to_string(), "/v1/secret/data/dst/foo".to_string(), r#"{"data":{},"options":{"cas":1}}"#.to_string()),
+            ("PUT".to_string(), "/v1/secret/destroy/dst/foo".to_string(), r#"{"versions":[2]}"#.to_string()),
+            ("PUT".to_string(), "/v1/secret/data/dst/foo".to_string(), r#"{"data":{},"options":{"cas":2}}"#.to_string()),
+            ("POST".to_string(), "/v1/secret/delete/dst/foo".to_string(), r#"{"versions":[3]}"#.to_string()),
+            ("PUT".to_string(), "/v1/secret/data/dst/foo".to_string(), r#"{"data":{"key":"value"},"options":{"cas":3}}"#.to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_sync_secret_metadata() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/src/foo") | ("GET", "/v1/secret/metadata/src/same") => (200, r#"{"request_id":"1","data":{
+                    "current_version":1,"versions":{},"custom_metadata":{"owner":"team-a"},"max_versions":5,"delete_version_after":"1h0m0s"
+                }}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/dst/foo") => (200, r#"{"request_id":"1","data":{
+                    "current_version":1,"versions":{},"custom_metadata":null,"max_versions":0,"delete_version_after":"0s"
+                }}"#.to_string()),
+                ("GET", "/v1/secret/metadata/dst/same") => (200, r#"{"request_id":"1","data":{
+                    "current_version":1,"versions":{},"custom_metadata":{"owner":"team-a"},"max_versions":5,"delete_version_after":"1h0m0s"
+                }}"#.to_string()),
+                ("POST", "/v1/secret/metadata/dst/foo") => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        sync_secret_metadata(&mock::client(&src), &mock::client(&dst), &secret("src/same"), &secret("dst/same"), false).unwrap();
+        assert_eq!(dst.count("POST", "/v1/secret/metadata/dst/same"), 0);
+
+        sync_secret_metadata(&mock::client(&src), &mock::client(&dst), &secret("src/foo"), &secret("dst/foo"), true).unwrap();
+        assert_eq!(dst.count("POST", "/v1/secret/metadata/dst/foo"), 0);
+
+        sync_secret_metadata(&mock::client(&src), &mock::client(&dst), &secret("src/foo"), &secret("dst/foo"), false).unwrap();
+        let writes: Vec<String> = dst.requests().into_iter()
+            .filter(|r| r.method == "POST")
+            .map(|r| r.body)
+            .collect();
+        assert_eq!(writes, vec![r#"{"custom_metadata":{"owner":"team-a"},"max_versions":5,"delete_version_after":"1h0m0s"}"#.to_string()]);
+    }
+
+    #[test]
+    fn test_sync_secret_metadata_round_trip() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/src/foo") => (200, r#"{"request_id":"1","data":{
+                    "current_version":1,"versions":{},"custom_metadata":null,"max_versions":0,"delete_version_after":"720h0m0s"
+                }}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        // The destination keeps the written settings, like Vault
+        let settings = Mutex::new(r#"{"custom_metadata":null,"max_versions":0,"delete_version_after":"0s"}"#.to_string());
+        let dst = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/dst/foo") => {
+                    let mut metadata: Value = serde_json::from_str(&settings.lock().unwrap()).unwrap();
+                    metadata["current_version"] = 1.into();
+                    (200, serde_json::json!({"request_id": "1", "data": metadata}).to_string())
+                },
+                ("POST", "/v1/secret/metadata/dst/foo") => {
+                    *settings.lock().unwrap() = request.body.clone();
+                    (204, "".to_string())
+                },
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        sync_secret_metadata(&mock::client(&src), &mock::client(&dst), &secret("src/foo"), &secret("dst/foo"), false).unwrap();
+        let write = dst.requests().into_iter().find(|r| r.method == "POST").unwrap();
+        let written: Value = serde_json::from_str(&write.body).unwrap();
+        assert_eq!(written["delete_version_after"], "720h0m0s");
+
+        // The settings read back from the destination are the same, so they are not written again
+        sync_secret_metadata(&mock::client(&src), &mock::client(&dst), &secret("src/foo"), &secret("dst/foo"), false).unwrap();
+        assert_eq!(dst.count("POST", "/v1/secret/metadata/dst/foo"), 1);
+    }
+
+    #[test]
+    fn test_full_sync_propagate_deletes() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a","dir/"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/src/dir/") => (200, r#"{"request_id":"1","data":{"keys":["b"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/dst/") => (200, r#"{"request_id":"1","data":{"keys":["a","stale","dir/"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/dst/dir/") => (200, r#"{"request_id":"1","data":{"keys":["b","old"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(full_sync(&config(false), mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![
+            SecretOp::Create(secret("src/a")),
+            SecretOp::Create(secret("src/dir/b")),
+            SecretOp::FullSyncFinished,
+        ]);
+        assert_eq!(dst.count("LIST", "/v1/secret/metadata/dst/"), 0);
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        full_sync(&config(true), mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None);
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![
+            SecretOp::Create(secret("src/a")),
+            SecretOp::Create(secret("src/dir/b")),
+            SecretOp::Destroy(secret("src/stale")),
+            SecretOp::Destroy(secret("src/dir/old")),
+            SecretOp::FullSyncFinished,
+        ]);
+
+        // Secrets of an include rule without delete are updated, but not removed
+        let mut config = config(true);
+        config.src.filter = PathFilter::from_rules(&[
+            IncludeRule::Rule { pattern: "^src/dir/".to_string(), operations: vec![WatchedOperation::Create, WatchedOperation::Update] },
+            IncludeRule::Pattern("^src/[^/]+$".to_string()),
+        ], &[]).unwrap();
+        let (tx, rx) = mpsc::sync_channel(100);
+        full_sync(&config, mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None);
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![
+            SecretOp::Create(secret("src/a")),
+            SecretOp::Create(secret("src/dir/b")),
+            SecretOp::Destroy(secret("src/stale")),
+            SecretOp::FullSyncFinished,
+        ]);
+    }
+
+    #[test]
+    fn test_paths_sync() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/src/a") => (200, r#"{"request_id":"1","data":{"current_version":1,"versions":{}}}"#.to_string()),
+                ("GET", "/v1/secret/metadata/src/broken") => (500, r#"{"errors":[]}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let file_name = std::env::temp_dir().join(format!("vault-sync-paths-{}.txt", std::process::id()));
+        std::fs::write(&file_name, "# secrets to fix\na\n\n/gone\ndir/\nbroken\n").unwrap();
+        let paths = read_paths(file_name.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&file_name).unwrap();
+        assert_eq!(paths, vec!["a", "gone", "broken"]);
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(!paths_sync(&config(false), mock::client(&src), &paths, tx));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Create(secret("src/a")), SecretOp::FullSyncFinished]);
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        paths_sync(&config(true), mock::client(&src), &paths, tx);
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![
+            SecretOp::Create(secret("src/a")),
+            SecretOp::Destroy(secret("src/gone")),
+            SecretOp::FullSyncFinished,
+        ]);
+
+        // Deletes are not synced for the include rule of the missing secret
+        let mut config = config(true);
+        config.src.filter = PathFilter::from_rules(&[IncludeRule::Rule {
+            pattern: "^src/".to_string(),
+            operations: vec![WatchedOperation::Create, WatchedOperation::Update],
+        }], &[]).unwrap();
+        let (tx, rx) = mpsc::sync_channel(100);
+        paths_sync(&config, mock::client(&src), &paths, tx);
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Create(secret("src/a")), SecretOp::FullSyncFinished]);
+    }
+
+    #[test]
+    fn test_full_sync_delete_breaker() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/dst/") => (200, r#"{"request_id":"1","data":{"keys":["a","b","c","d"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(true);
+        config.max_delete_ratio = Some(0.5);
+        let mut breaker = DeleteBreaker::default();
+        let mut run = || {
+            let (tx, rx) = mpsc::sync_channel(100);
+            assert!(full_sync(&config, mock::client(&src), &[mock::client(&dst)], tx, &mut breaker, &mut FullSyncCursor::default(), None, None));
+            rx.iter().filter(|op| matches!(op, SecretOp::Destroy(_))).count()
+        };
+        // 3 of 4 secrets would be removed, the next full sync confirms it
+        assert_eq!(run(), 0);
+        assert_eq!(run(), 3);
+        assert_eq!(run(), 0);
+        assert_eq!(run(), 3);
+
+        // The pending secrets are kept for the next run, like with --once
+        let file_name = std::env::temp_dir().join(format!("vault-sync-deletes-{}.json", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+        let _ = std::fs::remove_file(file_name);
+        let run = || {
+            let (tx, rx) = mpsc::sync_channel(100);
+            assert!(full_sync(&config, mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::load(Some(file_name)), &mut FullSyncCursor::default(), None, None));
+            rx.iter().filter(|op| matches!(op, SecretOp::Destroy(_))).count()
+        };
+        assert_eq!(run(), 0);
+        assert_eq!(run(), 3);
+        assert_eq!(run(), 0);
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_full_sync_multiple_prefixes() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/apps/") => (200, r#"{"request_id":"1","data":{"keys":["a"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/platform/") => (200, r#"{"request_id":"1","data":{"keys":["b"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/dst/apps/") => (200, r#"{"request_id":"1","data":{"keys":["a","stale"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/dst/platform/") => (200, r#"{"request_id":"1","data":{"keys":["b"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(true);
+        config.src.prefix = vec!["apps".to_string(), "platform".to_string()];
+        config.dst[0].prefix = vec!["dst/apps".to_string(), "dst/platform".to_string()];
+
+        let paths = PathMap::new(&config.src, &config.dst[0]);
+        assert_eq!(paths.dst_path("apps/a"), "dst/apps/a");
+        assert_eq!(paths.dst_path("platform/b"), "dst/platform/b");
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(full_sync(&config, mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![
+            SecretOp::Create(secret("apps/a")),
+            SecretOp::Create(secret("platform/b")),
+            SecretOp::Destroy(secret("apps/stale")),
+            SecretOp::FullSyncFinished,
+        ]);
+    }
+
+    #[test]
+    fn test_full_sync_incomplete() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a","dir/"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/src/dir/") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(!full_sync(&config(false), mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Create(secret("src/a")), SecretOp::FullSyncFinished]);
+    }
+
+    #[test]
+    fn test_full_sync_other_mounts() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/sys/mounts") => (200, r#"{"request_id":"1","data":{
+                    "secret/": {"type": "kv", "options": {"version": "2"}},
+                    "secret/src/pki/": {"type": "pki"},
+                    "cubbyhole/": {"type": "cubbyhole"}
+                }}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a","pki/"]}}"#.to_string()),
+                ("LIST", _) => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/dst/") => (200, r#"{"request_id":"1","data":{"keys":["a","pki/"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/dst/pki/") => (200, r#"{"request_id":"1","data":{"keys":["cert"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(true);
+        config.src.backend = Some(Backend::Backends(vec!["secret".to_string(), "cubbyhole".to_string()]));
+        config.dst[0].backend = Some(Backend::Backends(vec!["secret".to_string(), "cubbyhole".to_string()]));
+
+        // The other mounts are skipped, not failed, and their paths are not removed
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(full_sync(&config, mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Create(secret("src/a")), SecretOp::FullSyncFinished]);
+        assert_eq!(src.count("LIST", "/v1/secret/metadata/src/pki/"), 0);
+        assert_eq!(src.count("LIST", "/v1/cubbyhole/metadata/src/"), 0);
+    }
+
+    #[test]
+    fn test_mounts() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a"]}}"#.to_string()),
+                ("LIST", "/v1/team/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["b"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        config.src.backend = Some(Backend::Backends(vec!["secret".to_string(), "team".to_string()]));
+        config.dst[0].backend = Some(Backend::Backends(vec!["secret".to_string(), "team".to_string()]));
+        config.src.mounts = vec!["team".to_string()];
+        let team = |path: &str| SecretPath { mount: "team".to_string(), path: path.to_string() };
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(full_sync(&config, mock::client(&src), &[], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Create(team("src/b")), SecretOp::FullSyncFinished]);
+        assert_eq!(src.count("LIST", "/v1/secret/metadata/src/"), 0);
+
+        // The audit log of other mounts is ignored too
+        let lines = [
+            r#"{"time":"","type":"response","request":{"operation":"update","mount_type":"kv","path":"secret/data/src/a"}}"#,
+            r#"{"time":"","type":"response","request":{"operation":"update","mount_type":"kv","path":"team/data/src/b"}}"#,
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        for line in lines {
+            writeln!(writer, "{}", line).unwrap();
+        }
+        drop(writer);
+        let (stream, _) = listener.accept().unwrap();
+        let (tx, rx) = mpsc::sync_channel(100);
+        log_sync(&Arc::new(RwLock::new(config)), stream, tx, mock::client(&src), &AtomicU64::new(0));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Update(team("src/b"))]);
+    }
+
+    #[test]
+    fn test_full_sync_since() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["old","new","broken"]}}"#.to_string()),
+                ("GET", "/v1/secret/metadata/src/old") => (200, r#"{"request_id":"1","data":{"current_version":1,"updated_time":"2024-01-01T00:00:00.123456Z"}}"#.to_string()),
+                ("GET", "/v1/secret/metadata/src/new") => (200, r#"{"request_id":"1","data":{"current_version":1,"updated_time":"2024-01-03T00:00:00Z"}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let since = OffsetDateTime::parse("2024-01-02T00:00:00Z", &Rfc3339).unwrap();
+
+        // Secrets without metadata are synced anyway
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(full_sync(&config(false), mock::client(&src), &[], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, Some(since)));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Create(secret("src/new")), SecretOp::Create(secret("src/broken")), SecretOp::FullSyncFinished]);
+
+        // KV v1 secrets have no updated_time, the metadata is not read
+        let mut config = config(false);
+        config.src.version = EngineVersion::V1;
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(full_sync(&config, mock::client(&src), &[], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, Some(since)));
+        assert_eq!(rx.iter().count(), 4);
+        assert_eq!(src.count("GET", "/v1/secret/metadata/src/old"), 1);
+    }
+
+    #[test]
+    fn test_full_sync_batch_limit() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a","b/","c"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/src/b/") => (200, r#"{"request_id":"1","data":{"keys":["1","2"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        config.full_sync_batch_limit = Some(2);
+        let mut cursor = FullSyncCursor::default();
+        let mut run = |dry_run: Option<DryRun>| {
+            let (tx, rx) = mpsc::sync_channel(100);
+            assert!(full_sync(&config, mock::client(&src), &[], tx, &mut DeleteBreaker::default(), &mut cursor, dry_run, None));
+            rx.iter().filter_map(|op| match op {
+                SecretOp::Create(secret) => Some(secret.path),
+                _ => None,
+            }).collect::<Vec<String>>()
+        };
+        assert_eq!(run(None), ["src/a", "src/b/1"]);
+        // Dry run does not move the cursor
+        assert_eq!(run(Some(DryRun::Verify)), ["src/b/2", "src/c"]);
+        assert_eq!(run(None), ["src/b/2", "src/c"]);
+        // After the end, the next full sync starts from the beginning
+        assert_eq!(run(None), ["src/a", "src/b/1"]);
+    }
+
+    #[test]
+    fn test_full_sync_errors() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a","b/","c","d"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/src/b/") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                ("GET", "/v1/secret/data/src/c") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                ("GET", _) => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        let (tx, rx) = mpsc::sync_channel(100);
+        let listed = full_sync(&config, mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None);
+        sync_worker(rx, &Arc::new(RwLock::new(config.clone())), mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        // The other secrets are synced after the failed listing and the failed read
+        assert!(!listed);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/a"), 1);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/c"), 0);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/d"), 1);
+
+        config.fail_fast = true;
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(!full_sync(&config, mock::client(&src), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None));
+        let sent: Vec<String> = rx.iter().filter_map(|op| match op {
+            SecretOp::Counted(op, _) => match *op {
+                SecretOp::Create(secret) => Some(secret.path),
+                _ => None,
+            },
+            _ => None,
+        }).collect();
+        assert!(!sent.contains(&"src/c".to_string()) && !sent.contains(&"src/d".to_string()));
+    }
+
+    #[test]
+    fn test_full_sync_fail_fast_failed_op() {
+        // Listing src/b/ waits until the write of src/a has failed, the ops are read from another
+        // server as the mock handles one request at a time
+        let failed = Arc::new(AtomicBool::new(false));
+        let src_list = {
+            let failed = failed.clone();
+            MockServer::start(move |request| {
+                match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                    ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a","b/","c"]}}"#.to_string()),
+                    ("LIST", "/v1/secret/metadata/src/b/") => {
+                        let started = std::time::Instant::now();
+                        while !failed.load(Ordering::SeqCst) && started.elapsed() < std::time::Duration::from_secs(5) {
+                            std::thread::sleep(std::time::Duration::from_millis(10));
+                        }
+                        // The failure is counted after the response
+                        std::thread::sleep(std::time::Duration::from_millis(200));
+                        (200, r#"{"request_id":"1","data":{"keys":["x"]}}"#.to_string())
+                    },
+                    _ => (404, r#"{"errors":[]}"#.to_string()),
+                }
+            })
+        };
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", _) => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = {
+            let failed = failed.clone();
+            MockServer::start(move |request| {
+                match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                    ("PUT", "/v1/secret/data/dst/a") => {
+                        failed.store(true, Ordering::SeqCst);
+                        (400, r#"{"errors":["invalid request"]}"#.to_string())
+                    },
+                    ("PUT", _) => (204, "".to_string()),
+                    _ => (404, r#"{"errors":[]}"#.to_string()),
+                }
+            })
+        };
+        let mut config = config(false);
+        config.fail_fast = true;
+        let worker_config = Arc::new(RwLock::new(config.clone()));
+        let (tx, rx) = mpsc::sync_channel(100);
+        let listed = std::thread::scope(|scope| {
+            scope.spawn(|| sync_worker(rx, &worker_config, mock::client(&src), vec![mock::client(&dst)], None, None, true));
+            full_sync(&config, mock::client(&src_list), &[mock::client(&dst)], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None)
+        });
+        assert!(!listed);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/a"), 1);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/b/x"), 0);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/c"), 0);
+
+        // A failure before the full sync does not stop it
+        failed.store(true, Ordering::SeqCst);
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert!(full_sync(&config, mock::client(&src_list), &[], tx, &mut DeleteBreaker::default(), &mut FullSyncCursor::default(), None, None));
+        assert_eq!(rx.iter().filter(|op| matches!(op, SecretOp::Counted(..))).count(), 3);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").map(|duration| duration.as_secs()), Some(90));
+        assert_eq!(parse_duration("30m").map(|duration| duration.as_secs()), Some(30 * 60));
+        assert_eq!(parse_duration("6h").map(|duration| duration.as_secs()), Some(6 * 60 * 60));
+        assert_eq!(parse_duration("2d").map(|duration| duration.as_secs()), Some(2 * 24 * 60 * 60));
+        for value in ["", "0h", "h", "6w", "1.5h", "-1h"] {
+            assert_eq!(parse_duration(value), None, "{}", value);
+        }
     }
-}
 
-fn normalize_prefix(prefix: &str) -> String {
-    if prefix.len() == 0 {
-        return "".to_string();
+    #[test]
+    fn test_sync_worker_deletes() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/src/exists") => (200, r#"{"request_id":"1","data":{"current_version":1,"versions":{}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/dst/gone") => (200, r#"{"request_id":"1","data":{"current_version":1,"versions":{}}}"#.to_string()),
+                ("DELETE", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let run = |propagate_deletes: bool, dry_run: bool| {
+            let (tx, rx) = mpsc::sync_channel(100);
+            send_op(&tx, SecretOp::Delete(secret("src/foo")));
+            send_op(&tx, SecretOp::Destroy(secret("src/exists")));
+            send_op(&tx, SecretOp::Destroy(secret("src/gone")));
+            send_op(&tx, SecretOp::FullSyncFinished);
+            let config = Arc::new(RwLock::new(config(propagate_deletes)));
+            sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst)], dry_run.then_some(DryRun::Verify), None, true);
+        };
+
+        run(false, false);
+        run(true, true);
+        assert!(dst.requests().iter().all(|r| r.method == "GET"));
+
+        run(true, false);
+        assert_eq!(dst.count("DELETE", "/v1/secret/data/dst/foo"), 1);
+        assert_eq!(dst.count("DELETE", "/v1/secret/metadata/dst/gone"), 1);
+        assert_eq!(dst.count("DELETE", "/v1/secret/metadata/dst/exists"), 0);
     }
-    if prefix.ends_with("/") {
-        prefix.to_string()
-    } else {
-        format!("{}/", prefix)
+
+    #[test]
+    fn test_sync_worker_kv_versions() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/src/a") => (200, r#"{"request_id":"1","data":{"a":"1"}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/a") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                ("GET", "/v1/secret/metadata/src/a") => (200, r#"{"request_id":"1","data":{"current_version":1,"max_versions":5,"versions":{}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        for src_version in [EngineVersion::V1, EngineVersion::V2] {
+            for dst_version in [EngineVersion::V1, EngineVersion::V2] {
+                let dst = MockServer::start(|request| {
+                    match (request.method.as_str(), request.path.as_str()) {
+                        ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                        ("PUT", _) | ("POST", _) => (204, "".to_string()),
+                        _ => (404, r#"{"errors":[]}"#.to_string()),
+                    }
+                });
+                let (tx, rx) = mpsc::sync_channel(100);
+                send_op(&tx, SecretOp::Create(secret("src/a")));
+                send_op(&tx, SecretOp::FullSyncFinished);
+                let mut config = config(false);
+                config.src.version = src_version.clone();
+                config.dst[0].version = dst_version.clone();
+                let config = Arc::new(RwLock::new(config));
+                sync_worker(rx, &config, versioned_client(&src, &src_version), vec![versioned_client(&dst, &dst_version)], None, None, true);
+
+                // The data is the same map, in the format of the destination version
+                let writes: Vec<_> = dst.requests().into_iter().filter(|r| r.method != "GET").collect();
+                let (path, body) = (writes[0].path.as_str(), writes[0].body.as_str());
+                match dst_version {
+                    EngineVersion::V1 => assert_eq!((path, body), ("/v1/secret/dst/a", r#"{"a":"1"}"#)),
+                    EngineVersion::V2 => assert_eq!((path, body), ("/v1/secret/data/dst/a", r#"{"data":{"a":"1"}}"#)),
+                }
+                // Metadata exists in KV v2 only
+                let metadata = (src_version.clone(), dst_version.clone()) == (EngineVersion::V2, EngineVersion::V2);
+                assert_eq!(dst.count("POST", "/v1/secret/metadata/dst/a"), metadata as usize);
+                assert_eq!(writes.len(), 1 + metadata as usize);
+            }
+        }
     }
-}
 
-// Convert source secret path to destination secret path. Prefixes must be normalized!
-// Example: "src/secret1" -> "dst/secret2"
-fn secret_src_to_dst_path(src_prefix: &str, dst_prefix: &str, path: &str) -> String {
-    let mut path = path.to_string();
-    if src_prefix.len() > 0 {
-        path = path.trim_start_matches(src_prefix).to_string();
+    #[test]
+    fn test_sync_secret_too_large() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/blob") => (200, format!(r#"{{"request_id":"1","data":{{"data":{{"blob":"{}"}}}}}}"#, "x".repeat(100_000))),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", "/v1/secret/data/dst/blob") => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut dst_config = config(false).dst[0].clone();
+        dst_config.skip_unchanged = false;
+        let sync = |max_bytes| sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/blob"), &secret("dst/blob"), &dst_config, &EngineVersion::V2, false, &KeyBlacklist::default(), &ContentCache::default(), max_bytes, false).unwrap();
+
+        assert!(!sync(Some(100_000)));
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/blob"), 0);
+        // {"blob":"..."} is 11 bytes more than the value
+        assert!(sync(Some(100_011)));
+        assert!(sync(None));
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/blob"), 2);
     }
-    format!("{}{}", dst_prefix, &path)
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::sync::{normalize_prefix, secret_path_v1, secret_path_v2, secret_src_to_dst_path};
+    #[test]
+    fn test_sync_secret_skip_unchanged() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/same") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","b":"2"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/changed") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","b":"3"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/dst/same") | ("GET", "/v1/secret/data/dst/changed") => (200, r#"{"request_id":"1","data":{"data":{"b":"2","a":"1"}}}"#.to_string()),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let skip = config(false).dst[0].clone();
+        let always = VaultDestination { skip_unchanged: false, ..skip.clone() };
+
+        assert!(!sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/same"), &secret("dst/same"), &skip, &EngineVersion::V2, false, &KeyBlacklist::default(), &ContentCache::default(), None, false).unwrap());
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/same"), 0);
+
+        assert!(sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/changed"), &secret("dst/changed"), &skip, &EngineVersion::V2, false, &KeyBlacklist::default(), &ContentCache::default(), None, true).unwrap());
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/changed"), 0);
+
+        assert!(sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/changed"), &secret("dst/changed"), &skip, &EngineVersion::V2, false, &KeyBlacklist::default(), &ContentCache::default(), None, false).unwrap());
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/changed"), 1);
+
+        assert!(sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/same"), &secret("dst/same"), &always, &EngineVersion::V2, false, &KeyBlacklist::default(), &ContentCache::default(), None, false).unwrap());
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/same"), 1);
+        assert_eq!(dst.count("GET", "/v1/secret/data/dst/same"), 1);
+    }
 
     #[test]
-    fn test_secret_path_v1_matches() {
-        let path = "secret/path/to/secret";
-        let path = secret_path_v1(&path).unwrap();
-        assert_eq!(path.0, "secret");
-        assert_eq!(path.1, "path/to/secret");
+    fn test_sync_destination_stats() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/changed") => (200, r#"{"request_id":"1","data":{"data":{"a":"2"}}}"#.to_string()),
+                ("GET", path) if path.starts_with("/v1/secret/data/") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/dst/same") | ("GET", "/v1/secret/data/dst/changed") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                ("PUT", "/v1/secret/data/dst/broken") => (500, r#"{"errors":["internal error"]}"#.to_string()),
+                ("PUT", _) | ("DELETE", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(true);
+        config.max_retries = 0;
+        let destination = Destination {
+            pool: ClientPool::new(&config.dst[0].host, mock::client(&dst), 1),
+            active: Mutex::new(None),
+            stats: SyncStats::new(),
+            limiter: Mutex::new(None),
+            state: Arc::new(SyncState::load(None)),
+            cache: Arc::new(ContentCache::default()),
+        };
+        for op in [
+            SecretOp::Create(secret("src/same")),
+            SecretOp::Update(secret("src/changed")),
+            SecretOp::Update(secret("src/broken")),
+            SecretOp::Delete(secret("src/gone")),
+        ] {
+            sync_destination(&op, &config, &config.dst[0], &mock::client(&src), &mock::client(&dst), None, &destination);
+        }
+        let mut summary = FullSyncSummary { read: 4, ..Default::default() };
+        destination.stats.reset(&mut summary);
+        assert_eq!(summary, FullSyncSummary { read: 4, written: 1, skipped: 1, deleted: 1, errors: 1, duration_ms: 0 });
+        // The counters start again after a reset
+        let mut summary = FullSyncSummary::default();
+        destination.stats.reset(&mut summary);
+        assert_eq!(summary, FullSyncSummary::default());
     }
 
     #[test]
-    fn test_custom_secret_path_v1_matches() {
-        let path = "custom/path/to/secret";
-        let path = secret_path_v1(&path).unwrap();
-        assert_eq!(path.0, "custom");
-        assert_eq!(path.1, "path/to/secret");
+    fn test_sync_secret_key_blacklist() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/app") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","token":"src"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/bootstrap") => (200, r#"{"request_id":"1","data":{"data":{"token":"src"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/dst/app") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","token":"dst"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/new") => (200, r#"{"request_id":"1","data":{"data":{"a":"0","token":"dst"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/unavailable") => (500, r#"{"errors":["internal error"]}"#.to_string()),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let blacklist = KeyBlacklist::new(&[KeyRule { path: None, keys: vec!["token".to_string()] }]).unwrap();
+        let skip = config(false).dst[0].clone();
+
+        // The values of the blacklisted key differ, but the secrets are the same otherwise
+        assert!(!sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/app"), &secret("dst/app"), &skip, &EngineVersion::V2, false, &blacklist, &ContentCache::default(), None, false).unwrap());
+        // Nothing is left to write
+        assert!(!sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/bootstrap"), &secret("dst/bootstrap"), &skip, &EngineVersion::V2, false, &blacklist, &ContentCache::default(), None, false).unwrap());
+        // The destination keeps its own value of the blacklisted key
+        assert!(sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/app"), &secret("dst/new"), &skip, &EngineVersion::V2, false, &blacklist, &ContentCache::default(), None, false).unwrap());
+        let writes: Vec<String> = dst.requests().into_iter().filter(|r| r.method == "PUT").map(|r| r.body).collect();
+        let written: Value = serde_json::from_str(&writes[0]).unwrap();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(written, serde_json::json!({"data": {"a": "1", "token": "dst"}}));
+
+        // The destination values of the blacklisted key are unknown, nothing is written
+        assert!(sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/app"), &secret("dst/unavailable"), &skip, &EngineVersion::V2, false, &blacklist, &ContentCache::default(), None, false).is_err());
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/unavailable"), 0);
+        // Without blacklisted keys, the secret is written as if it was changed
+        assert!(sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/app"), &secret("dst/unavailable"), &skip, &EngineVersion::V2, false, &KeyBlacklist::default(), &ContentCache::default(), None, false).unwrap());
     }
 
     #[test]
-    fn test_secret_path_v1_not_matches() {
-        let path = "secret";
-        let path = secret_path_v1(&path);
-        assert_eq!(path.is_none(), true);
+    fn test_sync_secret_transforms() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/db") => (200, r#"{"request_id":"1","data":{"data":{"url":"postgres://db.internal:5432/","user":"db.internal"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/dst/synced") => (200, r#"{"request_id":"1","data":{"data":{"url":"postgres://db.staging:5432/","user":"db.internal"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/old") => (200, r#"{"request_id":"1","data":{"data":{"url":"postgres://db.internal:5432/","user":"db.internal"}}}"#.to_string()),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut dst_config = config(false).dst[0].clone();
+        dst_config.transformer = ValueTransformer::new(&[Transform {
+            path_pattern: Some("^src/db$".to_string()),
+            key: "url".to_string(),
+            find: "db.internal".to_string(),
+            replace: "db.staging".to_string(),
+        }]).unwrap();
+
+        // The transformed value is already written
+        assert!(!sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/db"), &secret("dst/synced"), &dst_config, &EngineVersion::V2, false, &KeyBlacklist::default(), &ContentCache::default(), None, false).unwrap());
+        // The untransformed value is different
+        assert!(sync_secret(&mock::client(&src), &mock::client(&dst), &secret("src/db"), &secret("dst/old"), &dst_config, &EngineVersion::V2, false, &KeyBlacklist::default(), &ContentCache::default(), None, false).unwrap());
+        let writes: Vec<String> = dst.requests().into_iter().filter(|r| r.method == "PUT").map(|r| r.body).collect();
+        let written: Value = serde_json::from_str(&writes[0]).unwrap();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(written, serde_json::json!({"data": {"url": "postgres://db.staging:5432/", "user": "db.internal"}}));
     }
 
     #[test]
-    fn test_secret_path_v2_matches() {
-        let path = "secret/data/path/to/secret";
-        let path = secret_path_v2(&path).unwrap();
-        assert_eq!(path.0, "secret");
-        assert_eq!(path.1, "path/to/secret");
+    fn test_sync_worker_many_destinations() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/foo") | ("GET", "/v1/secret/data/src/bar") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst1 = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst2 = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", "/v1/secret/data/other/bar") => (204, "".to_string()),
+                ("PUT", _) => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        let mut other = config.dst[0].clone();
+        other.prefix = vec!["other".to_string()];
+        config.dst.push(other);
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Create(secret("src/foo")));
+        send_op(&tx, SecretOp::Update(secret("src/bar")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        let config = Arc::new(RwLock::new(config));
+        sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst1), mock::client(&dst2)], None, None, true);
+
+        assert_eq!(dst1.count("PUT", "/v1/secret/data/dst/foo"), 1);
+        assert_eq!(dst1.count("PUT", "/v1/secret/data/dst/bar"), 1);
+        assert_eq!(dst2.count("PUT", "/v1/secret/data/other/foo"), 1);
+        assert_eq!(dst2.count("PUT", "/v1/secret/data/other/bar"), 1);
     }
 
     #[test]
-    fn test_custom_secret_path_v2_matches() {
-        let path = "custom/data/path/to/secret";
-        let path = secret_path_v2(&path).unwrap();
-        assert_eq!(path.0, "custom");
-        assert_eq!(path.1, "path/to/secret");
+    fn test_log_sync_skips_own_requests() {
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        // vault-sync wrote src/foo from the source to dst/foo, then someone else wrote dst/bar
+        let lines = [
+            r#"{"time":"","type":"response","auth":{"accessor":"accessor"},"request":{"operation":"update","mount_type":"kv","path":"secret/data/dst/foo"}}"#,
+            r#"{"time":"","type":"response","auth":{"accessor":"other"},"request":{"operation":"update","mount_type":"kv","path":"secret/data/dst/bar"}}"#,
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        for line in lines {
+            writeln!(writer, "{}", line).unwrap();
+        }
+        drop(writer);
+        let (stream, _) = listener.accept().unwrap();
+
+        let mut config = config(false);
+        config.dst[0].bind = Some("127.0.0.1:0".to_string());
+        let reversed = Arc::new(RwLock::new(config.reversed()));
+        let (tx, rx) = mpsc::sync_channel(100);
+        log_sync(&reversed, stream, tx, mock::client(&dst), &AtomicU64::new(0));
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Update(secret("dst/bar"))]);
     }
 
     #[test]
-    fn test_secret_path_v2_not_matches() {
-        let path = "secret/metadata/path/to/secret";
-        let path = secret_path_v2(&path);
-        assert_eq!(path.is_none(), true);
+    fn test_sync_worker_retries() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/flaky") | ("GET", "/v1/secret/data/src/denied") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        // Fails twice, then succeeds
+        let failures = AtomicUsize::new(0);
+        let dst = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", "/v1/secret/data/dst/flaky") if failures.fetch_add(1, Ordering::Relaxed) < 2 => (503, r#"{"errors":["unavailable"]}"#.to_string()),
+                ("PUT", "/v1/secret/data/dst/flaky") => (204, "".to_string()),
+                ("PUT", "/v1/secret/data/dst/denied") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Create(secret("src/flaky")));
+        send_op(&tx, SecretOp::Create(secret("src/denied")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        let config = Arc::new(RwLock::new(config(false)));
+        sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst)], None, None, true);
+
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/flaky"), 3);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/denied"), 1);
     }
 
     #[test]
-    fn test_normalize_prefix() {
-        assert_eq!(normalize_prefix(""), "");
-        assert_eq!(normalize_prefix("src"), "src/");
-        assert_eq!(normalize_prefix("src/"), "src/");
+    fn test_sync_worker_different_mounts() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/team/a") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                ("GET", "/v1/secret/metadata/src/team/a") => (200, r#"{"request_id":"1","data":{
+                    "current_version":1,"versions":{},"custom_metadata":{"team":"a"},"max_versions":0,"delete_version_after":"0s"
+                }}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) | ("POST", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        config.dst[0].backend = Some(Backend::Backend("kv".to_string()));
+        let mut v1 = config.dst[0].clone();
+        v1.backend = Some(Backend::Backend("kv1".to_string()));
+        v1.prefix = vec!["imported/".to_string()];
+        v1.version = EngineVersion::V1;
+        config.dst.push(v1);
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Create(secret("src/team/a")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        let config = Arc::new(RwLock::new(config));
+        let dst_clients = vec![mock::client(&dst), versioned_client(&dst, &EngineVersion::V1)];
+        sync_worker(rx, &config, mock::client(&src), dst_clients, None, None, true);
+
+        // The mount is replaced, the KV v2 data and metadata segments follow the mount
+        assert_eq!(dst.count("PUT", "/v1/kv/data/dst/team/a"), 1);
+        assert_eq!(dst.count("POST", "/v1/kv/metadata/dst/team/a"), 1);
+        assert_eq!(dst.count("PUT", "/v1/kv1/imported/team/a"), 1);
+        assert!(dst.requests().iter().all(|request| !request.path.contains("secret")));
     }
 
     #[test]
-    fn test_secret_src_to_dst_path() {
-        assert_eq!(secret_src_to_dst_path("src/", "dst/", "src/secret"), "dst/secret");
-        assert_eq!(secret_src_to_dst_path("", "dst/", "src/secret"), "dst/src/secret");
-        assert_eq!(secret_src_to_dst_path("", "", "src/secret"), "src/secret");
+    fn test_sync_worker_invalid_utf8() {
+        let src = MockServer::start_bytes(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0).into_bytes()),
+                ("GET", "/v1/secret/data/src/binary") => {
+                    let mut body = br#"{"request_id":"1","data":{"data":{"key":""#.to_vec();
+                    body.extend_from_slice(&[0xff, 0xfe, 0x00, 0x80]);
+                    body.extend_from_slice(br#""}}}"#);
+                    (200, body)
+                },
+                ("GET", "/v1/secret/data/src/text") => (200, br#"{"request_id":"1","data":{"data":{"key":"text"}}}"#.to_vec()),
+                _ => (404, br#"{"errors":[]}"#.to_vec()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Create(secret("src/binary")));
+        send_op(&tx, SecretOp::Create(secret("src/text")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        let config = Arc::new(RwLock::new(config(false)));
+        sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst)], None, None, true);
+
+        // The secret that is not valid JSON is skipped instead of being written with replaced bytes
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/binary"), 0);
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/text"), 1);
+    }
+
+    #[test]
+    fn test_sync_secret_binary_safe() {
+        let value = [0xff, 0xfe, b'\\', b'"', 0x80];
+        let src = MockServer::start_bytes(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0).into_bytes()),
+                ("GET", "/v1/secret/data/src/binary") => {
+                    let mut body = br#"{"request_id":"1","data":{"data":{"key":""#.to_vec();
+                    body.extend_from_slice(&value);
+                    body.extend_from_slice(br#"","text":"a"}}}"#);
+                    (200, body)
+                },
+                _ => (404, br#"{"errors":[]}"#.to_vec()),
+            }
+        });
+        // Keeps the written secret and returns it, like a Vault
+        let vault = |path: &'static str| {
+            let written = Arc::new(Mutex::new(Vec::new()));
+            let stored = written.clone();
+            let server = MockServer::start_bytes(move |request| {
+                let mut written = stored.lock().unwrap();
+                match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0).into_bytes()),
+                    ("PUT", request_path) if request_path == path => {
+                        *written = request.bytes.clone();
+                        (204, Vec::new())
+                    },
+                    ("GET", request_path) if request_path == path && !written.is_empty() => {
+                        let mut body = br#"{"request_id":"1","data":"#.to_vec();
+                        body.extend_from_slice(&written);
+                        body.push(b'}');
+                        (200, body)
+                    },
+                    _ => (404, br#"{"errors":[]}"#.to_vec()),
+                }
+            });
+            (server, written)
+        };
+        let (dst, dst_written) = vault("/v1/secret/data/dst/binary");
+        let mut dst_config = config(false).dst[0].clone();
+        let sync = |src: &MockServer, dst: &MockServer, from: &str, to: &str, dst_config: &VaultDestination| {
+            sync_secret(&mock::client(src), &mock::client(dst), &secret(from), &secret(to), dst_config, &EngineVersion::V2, true, &KeyBlacklist::default(), &ContentCache::default(), None, false).unwrap()
+        };
+
+        // The value is base64-encoded in the destination, and is unchanged when synced again
+        assert!(sync(&src, &dst, "src/binary", "dst/binary", &dst_config));
+        let data: Value = serde_json::from_slice(&dst_written.lock().unwrap()).unwrap();
+        assert_eq!(data, serde_json::json!({"data": {"key": "//4igA==", "text": "a", "vault-sync-base64": ["key"]}}));
+        assert!(!sync(&src, &dst, "src/binary", "dst/binary", &dst_config));
+
+        // Synced back to the source Vault, the value is decoded again
+        let (back, back_written) = vault("/v1/secret/data/src/binary");
+        dst_config.decode_binary = true;
+        assert!(sync(&dst, &back, "dst/binary", "src/binary", &dst_config));
+        let mut expected = br#"{"data":{"key":""#.to_vec();
+        expected.extend_from_slice(&value);
+        expected.extend_from_slice(br#"","text":"a"}}"#);
+        assert_eq!(*back_written.lock().unwrap(), expected);
+        assert!(!sync(&dst, &back, "dst/binary", "src/binary", &dst_config));
+    }
+
+    #[test]
+    fn test_with_retries_error() {
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(3, &secret("dst/a"), "http://dst:8200/", || {
+            calls += 1;
+            Err(VaultError::Vault("permission denied".to_string()))
+        });
+        // Not a transient error, so not retried
+        assert_eq!(calls, 1);
+        let error = result.unwrap_err();
+        assert_eq!(error.secret, secret("dst/a"));
+        assert_eq!(error.host, "http://dst:8200/");
+        assert_eq!(error.to_string(), "secret/dst/a in http://dst:8200/: vault error: permission denied");
+    }
+
+    #[test]
+    fn test_full_sync_delay() {
+        assert_eq!(full_sync_delay(3600, 0.0, 0.7), std::time::Duration::from_secs(3600));
+        assert_eq!(full_sync_delay(3600, 10.0, 0.0), std::time::Duration::from_secs(3240));
+        assert_eq!(full_sync_delay(3600, 10.0, 0.5), std::time::Duration::from_secs(3600));
+        assert_eq!(full_sync_delay(3600, 100.0, 0.0), std::time::Duration::ZERO);
+        assert!(full_sync_delay(3600, 10.0, 0.999) < std::time::Duration::from_secs(3960));
+    }
+
+    #[test]
+    fn test_full_sync_worker_startup_only() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["a"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        config.full_sync_interval = 0;
+        let config = Arc::new(RwLock::new(config));
+        let (tx, rx) = mpsc::sync_channel(100);
+        // Returns after the full sync on start
+        full_sync_worker(&config, mock::client(&src), vec![], tx, None, None);
+        let ops: Vec<SecretOp> = rx.iter().collect();
+        assert_eq!(ops, vec![SecretOp::Create(secret("src/a")), SecretOp::FullSyncFinished]);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        for attempt in 1..=20 {
+            let delay = retry_delay(attempt);
+            let max = RETRY_BASE_DELAY.saturating_mul(1 << (attempt - 1).min(16)).min(RETRY_MAX_DELAY);
+            assert!(delay >= max / 2 && delay <= max);
+        }
+    }
+
+    #[test]
+    fn test_sync_worker_concurrency() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", path) if path.starts_with("/v1/secret/data/src/") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", "/v1/secret/data/dst/3") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        config.full_sync_concurrency = 4;
+        let (tx, rx) = mpsc::sync_channel(100);
+        for i in 0..20 {
+            send_op(&tx, SecretOp::Create(secret(&format!("src/{}", i))));
+        }
+        send_op(&tx, SecretOp::FullSyncFinished);
+        let config = Arc::new(RwLock::new(config));
+        sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst)], None, None, true);
+
+        // FullSyncFinished waits for all ops, a failed secret does not stop others
+        for i in 0..20 {
+            assert_eq!(dst.count("PUT", &format!("/v1/secret/data/dst/{}", i)), 1);
+        }
+    }
+
+    #[test]
+    fn test_send_op_full_queue() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let blocked = METRICS.queue_blocked.load(Ordering::Relaxed);
+        send_op(&tx, SecretOp::Create(secret("src/a")));
+        // The second op waits until the first one is received
+        let sender = std::thread::spawn(move || send_op(&tx, SecretOp::Create(secret("src/b"))));
+        while METRICS.queue_blocked.load(Ordering::Relaxed) == blocked {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(rx.recv().unwrap(), SecretOp::Create(secret("src/a")));
+        sender.join().unwrap();
+        assert_eq!(rx.recv().unwrap(), SecretOp::Create(secret("src/b")));
+    }
+
+    #[test]
+    fn test_thread_index() {
+        let create = thread_index(&SecretOp::Create(secret("src/foo")), 8);
+        assert!(create < 8);
+        assert_eq!(thread_index(&SecretOp::Update(secret("src/foo")), 8), create);
+        assert_eq!(thread_index(&SecretOp::Delete(secret("src/foo")), 8), create);
+        assert_eq!(thread_index(&SecretOp::Destroy(secret("src/foo")), 8), create);
+        assert_eq!(thread_index(&SecretOp::Create(secret("src/foo")), 1), 0);
+    }
+
+    #[test]
+    fn test_sync_worker_shutdown() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", path) if path.starts_with("/v1/secret/data/src/") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        config.full_sync_concurrency = 2;
+        let (tx, rx) = mpsc::sync_channel(100);
+        for i in 0..5 {
+            send_op(&tx, SecretOp::Create(secret(&format!("src/{}", i))));
+        }
+        send_op(&tx, SecretOp::Shutdown);
+        send_op(&tx, SecretOp::Create(secret("src/after")));
+        let config = Arc::new(RwLock::new(config));
+        // Not run_once, the worker stops on Shutdown after applying the ops sent before
+        sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst)], None, None, false);
+
+        for i in 0..5 {
+            assert_eq!(dst.count("PUT", &format!("/v1/secret/data/dst/{}", i)), 1);
+        }
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/after"), 0);
+    }
+
+    #[test]
+    fn test_enable_audit_device() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/sys/audit") => (200, r#"{"request_id":"1","data":{"vault-sync/":{"type":"socket","description":"","local":false,"path":"vault-sync/","options":{"address":"vault-sync:8202","socket_type":"tcp"}}}}"#.to_string()),
+                ("PUT", "/v1/sys/audit/vault-sync") => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let client = mock::client(&server);
+        assert!(audit_device("missing", client.clone()).is_none());
+        let device = audit_device("vault-sync", client.clone()).unwrap();
+        assert!(enable_audit_device("vault-sync", &device, client));
+
+        let request = server.requests().into_iter().find(|r| r.method == "PUT").unwrap();
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["type"], "socket");
+        assert_eq!(body["options"]["address"], "vault-sync:8202");
+
+        assert!(same_audit_device(&device, &device.clone()));
+        let mut other = device.clone();
+        other["options"]["address"] = Value::from("other:8202");
+        assert!(!same_audit_device(&device, &other));
+    }
+
+    #[test]
+    fn test_check_audit_device_options() {
+        let mut device = serde_json::json!({"type": "socket", "options": {"address": "vault-sync:8202"}});
+        assert_eq!(check_audit_device_options(&device, false, None), Ok(vec![]));
+        assert_eq!(check_audit_device_options(&device, true, None).unwrap().len(), 1);
+        device["options"]["hmac_accessor"] = Value::from("false");
+        device["options"]["log_raw"] = Value::from("true");
+        let warnings = check_audit_device_options(&device, true, None).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("log_raw=true"));
+        device["options"]["format"] = Value::from("jsonx");
+        assert!(check_audit_device_options(&device, false, None).unwrap_err().contains("format jsonx"));
+
+        let mut device = serde_json::json!({"type": "socket", "options": {"address": "vault-sync:8202"}});
+        assert_eq!(check_audit_device_options(&device, false, Some("0.0.0.0:8202")), Ok(vec![]));
+        let warnings = check_audit_device_options(&device, false, Some("unix:/run/vault-sync.sock")).unwrap();
+        assert_eq!(warnings, ["Audit device sends the audit log to tcp vault-sync:8202, enable it with socket_type=unix address=/run/vault-sync.sock for bind unix:/run/vault-sync.sock"]);
+        device["options"] = serde_json::json!({"address": "/run/vault-sync.sock", "socket_type": "unix"});
+        assert_eq!(check_audit_device_options(&device, false, Some("unix:/run/vault-sync.sock")), Ok(vec![]));
+        assert_eq!(check_audit_device_options(&device, false, Some("0.0.0.0:8202")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_enable_audit_device_in_use() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/sys/audit") => (200, r#"{"request_id":"1","data":{"vault-sync/":{"type":"socket","description":"","local":false,"path":"vault-sync/","options":{"address":"vault-sync:8202","socket_type":"tcp"}}}}"#.to_string()),
+                ("PUT", "/v1/sys/audit/vault-sync") => (400, r#"{"errors":["path already in use at vault-sync/"]}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let client = mock::client(&server);
+        let device = audit_device("vault-sync", client.clone()).unwrap();
+        assert!(enable_audit_device("vault-sync", &device, client.clone()));
+
+        // Another device at the same path is not ours
+        let mut other = device.clone();
+        other["options"]["address"] = Value::from("other:8202");
+        assert!(!enable_audit_device("vault-sync", &other, client));
+    }
+
+    #[test]
+    fn test_sync_worker_dead_letter() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", path) if path.starts_with("/v1/secret/data/src/") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", "/v1/secret/data/dst/forbidden") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let file_name = std::env::temp_dir().join(format!("vault-sync-sync-dead-letters-{}.jsonl", std::process::id()));
+        let file_name = file_name.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&file_name);
+        let mut config = config(false);
+        config.dead_letter_path = Some(file_name.clone());
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Create(secret("src/ok")));
+        send_op(&tx, SecretOp::Update(secret("src/forbidden")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        let config = Arc::new(RwLock::new(config));
+        sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst)], None, None, true);
+
+        let letters = dead_letter::read(&file_name).unwrap();
+        std::fs::remove_file(&file_name).unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].op(), SecretOp::Update(secret("src/forbidden")));
+        assert_eq!(letters[0].destination, "http://127.0.0.1:8200/");
+        assert_eq!(letters[0].data, Some(serde_json::json!({"a": "1"})));
+        assert!(letters[0].error.contains("permission denied"));
+    }
+
+    #[test]
+    fn test_sync_worker_dry_run_report() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/src/changed") => (200, r#"{"request_id":"1","data":{"data":{"a":"2"}}}"#.to_string()),
+                ("GET", path) if path.starts_with("/v1/secret/data/src/") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/dst/same") | ("GET", "/v1/secret/metadata/dst/changed") | ("GET", "/v1/secret/metadata/dst/gone") =>
+                    (200, r#"{"request_id":"1","data":{"current_version":1,"versions":{}}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/same") | ("GET", "/v1/secret/data/dst/changed") =>
+                    (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                ("GET", "/v1/secret/metadata/dst/forbidden") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let file_name = std::env::temp_dir().join(format!("vault-sync-dry-run-{}.json", std::process::id()));
+        let file_name = file_name.to_str().unwrap().to_string();
+        let mut config = config(true);
+        config.dst[0].skip_unchanged = false;
+        let config = Arc::new(RwLock::new(config));
+        let run = |dry_run: DryRun| {
+            let (tx, rx) = mpsc::sync_channel(100);
+            send_op(&tx, SecretOp::Create(secret("src/new")));
+            send_op(&tx, SecretOp::Update(secret("src/same")));
+            send_op(&tx, SecretOp::Update(secret("src/changed")));
+            send_op(&tx, SecretOp::Update(secret("src/forbidden")));
+            send_op(&tx, SecretOp::Destroy(secret("src/gone")));
+            send_op(&tx, SecretOp::FullSyncFinished);
+            sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst)], Some(dry_run), Some(&file_name), true);
+            let report: DryRunReport = serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+            std::fs::remove_file(&file_name).unwrap();
+            report
+        };
+
+        // The secret that cannot be read in the destination fails instead of being planned
+        let report = run(DryRun::Verify);
+        assert!(dst.requests().iter().all(|r| r.method == "GET"));
+        let actions: Vec<(ActionKind, &str, Option<bool>)> = report.actions.iter()
+            .map(|action| (action.action, action.path.as_str(), action.changed))
+            .collect();
+        assert_eq!(actions, vec![
+            (ActionKind::Update, "dst/changed", Some(true)),
+            (ActionKind::Destroy, "dst/gone", None),
+            (ActionKind::Create, "dst/new", None),
+            (ActionKind::Update, "dst/same", Some(false)),
+        ]);
+        assert_eq!(report.summary.update, 2);
+
+        // Only the client checks its token in the destination
+        let requests = dst.requests().len();
+        let report = run(DryRun::Plan);
+        assert!(dst.requests()[requests..].iter().all(|r| r.path == "/v1/auth/token/lookup-self"));
+        let actions: Vec<(ActionKind, &str)> = report.actions.iter().map(|action| (action.action, action.path.as_str())).collect();
+        assert_eq!(actions, vec![
+            (ActionKind::Write, "dst/changed"),
+            (ActionKind::Write, "dst/forbidden"),
+            (ActionKind::Destroy, "dst/gone"),
+            (ActionKind::Write, "dst/new"),
+            (ActionKind::Write, "dst/same"),
+        ]);
+    }
+
+    #[test]
+    fn test_log_file_sync() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let line = |path: &str| format!(r#"{{"time":"","type":"response","request":{{"operation":"update","mount_type":"kv","path":"secret/data/{}"}}}}"#, path);
+        let append = |file_name: &str, text: &str| {
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(file_name).unwrap();
+            file.write_all(text.as_bytes()).unwrap();
+        };
+        let file_name = std::env::temp_dir().join(format!("vault-sync-audit-{}.log", std::process::id()));
+        let file_name = file_name.to_str().unwrap().to_string();
+        std::fs::write(&file_name, format!("{}\n", line("src/old"))).unwrap();
+
+        let config = Arc::new(RwLock::new(config(false)));
+        let (tx, rx) = mpsc::sync_channel(100);
+        let worker_file_name = file_name.clone();
+        let client = mock::client(&src);
+        std::thread::spawn(move || log_file_sync(&config, &worker_file_name, tx, client));
+        let next = || rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // Existing entries are skipped, a line is sent only when it is complete
+        let new_line = line("src/new");
+        let (first, second) = new_line.split_at(20);
+        append(&file_name, first);
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        append(&file_name, &format!("{}\n", second));
+        assert_eq!(next(), SecretOp::Update(secret("src/new")));
+
+        std::fs::write(&file_name, "").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        append(&file_name, &format!("{}\n", line("src/truncated")));
+        assert_eq!(next(), SecretOp::Update(secret("src/truncated")));
+
+        // The entries written to the old file after the rotation are read first
+        let rotated = format!("{}.1", &file_name);
+        std::fs::rename(&file_name, &rotated).unwrap();
+        append(&rotated, &format!("{}\n", line("src/late")));
+        append(&file_name, &format!("{}\n", line("src/rotated")));
+        assert_eq!(next(), SecretOp::Update(secret("src/late")));
+        assert_eq!(next(), SecretOp::Update(secret("src/rotated")));
+
+        std::fs::remove_file(&rotated).unwrap();
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_log_listener_reconnect() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let line = |path: &str| format!(r#"{{"time":"","type":"response","request":{{"operation":"update","mount_type":"kv","path":"secret/data/{}"}}}}"#, path);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(RwLock::new(config(false)));
+        let (tx, rx) = mpsc::sync_channel(100);
+        let client = mock::client(&src);
+        std::thread::spawn(move || log_listener(&config, AuditListener::Tcp(listener), tx, client));
+        let next = || rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+
+        // Vault connects again after the connection is dropped
+        let mut stream = TcpStream::connect(addr).unwrap();
+        writeln!(stream, "{}", line("src/first")).unwrap();
+        drop(stream);
+        assert_eq!(next(), SecretOp::Update(secret("src/first")));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        writeln!(stream, "{}", line("src/second")).unwrap();
+        drop(stream);
+        assert_eq!(next(), SecretOp::Update(secret("src/second")));
+    }
+
+    #[test]
+    fn test_log_listener_unix() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let path = std::env::temp_dir().join(format!("vault-sync-audit-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let bind = format!("unix:{}", path);
+        // The socket of a previous run is replaced
+        drop(AuditListener::bind(&bind).unwrap());
+        let listener = AuditListener::bind(&bind).unwrap();
+        assert_eq!(listener.addr(), bind);
+        assert_eq!(listener.socket_file(), Some(path.as_str()));
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+        // The socket of a running vault-sync is not replaced
+        assert_eq!(AuditListener::bind(&bind).err().unwrap().kind(), std::io::ErrorKind::AddrInUse);
+        let config = Arc::new(RwLock::new(config(false)));
+        let (tx, rx) = mpsc::sync_channel(100);
+        let client = mock::client(&src);
+        std::thread::spawn(move || log_listener(&config, listener, tx, client));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        writeln!(stream, r#"{{"time":"","type":"response","request":{{"operation":"update","mount_type":"kv","path":"secret/data/src/unix"}}}}"#).unwrap();
+        drop(stream);
+        assert_eq!(rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap(), SecretOp::Update(secret("src/unix")));
+        std::fs::remove_file(&path).unwrap();
+
+        // Other files are not removed
+        std::fs::write(&path, "").unwrap();
+        assert!(AuditListener::bind(&bind).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_audit_self_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Vault sends the audit log entry of the write only if the device works
+        let working = Arc::new(AtomicBool::new(true));
+        let src = {
+            let working = working.clone();
+            MockServer::start(move |request| {
+                match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                    ("PUT", "/v1/secret/data/src/vault-sync-self-test-vault-sync-id") => {
+                        if working.load(Ordering::Relaxed) {
+                            let mut stream = TcpStream::connect(addr).unwrap();
+                            stream.write_all(br#"{"time":"","type":"response","request":{"operation":"update","mount_type":"kv","path":"secret/data/src/vault-sync-self-test-vault-sync-id"}}
+"#).unwrap();
+                        }
+                        (200, r#"{"request_id":"1","data":{"version":1}}"#.to_string())
+                    },
+                    ("DELETE", _) => (204, "".to_string()),
+                    _ => (404, r#"{"errors":[]}"#.to_string()),
+                }
+            })
+        };
+        let mut config = config(false);
+        config.audit_self_test_timeout = 1;
+        let shared = Arc::new(RwLock::new(config.clone()));
+        let (tx, rx) = mpsc::sync_channel(100);
+        let src_client = mock::client(&src);
+        {
+            let src_client = src_client.clone();
+            std::thread::spawn(move || log_listener(&shared, AuditListener::Tcp(listener), tx, src_client));
+        }
+
+        audit_self_test(&config, &src_client).unwrap();
+        working.store(false, Ordering::Relaxed);
+        let error = audit_self_test(&config, &src_client).unwrap_err();
+        assert!(error.starts_with("no audit log entry for secret secret/src/vault-sync-self-test-vault-sync-id received in 1s"), "{}", error);
+        // The sentinel secret is removed and not synced
+        assert_eq!(src.count("DELETE", "/v1/secret/metadata/src/vault-sync-self-test-vault-sync-id"), 2);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_audit_watchdog() {
+        let mut watchdog = AuditWatchdog::default();
+        assert_eq!(watchdog.check(100, 1000, None), None);
+        assert_eq!(watchdog.check(100, 109, Some(10)), None);
+        assert_eq!(watchdog.check(100, 110, Some(10)), Some(Idle::Warning(10)));
+        // A single warning while idle
+        assert_eq!(watchdog.check(100, 200, Some(10)), None);
+        assert_eq!(watchdog.check(195, 200, Some(10)), Some(Idle::Resumed));
+        assert_eq!(watchdog.check(195, 201, Some(10)), None);
+        assert_eq!(watchdog.check(195, 300, Some(10)), Some(Idle::Warning(105)));
+        // Disabling the check by reloading the config is a recovery too
+        assert_eq!(watchdog.check(195, 300, None), Some(Idle::Resumed));
+    }
+
+    #[test]
+    fn test_log_sync_malformed() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let line = |mount_type: &str, path: &str| format!(r#"{{"time":"","type":"response","request":{{"operation":"update","mount_type":"{}","path":"secret/data/{}"}}}}"#, mount_type, path);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(RwLock::new(config(false)));
+        let (tx, rx) = mpsc::sync_channel(100);
+        let client = mock::client(&src);
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            log_sync(&config, stream, tx, client, &AtomicU64::new(0));
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let first = line("kv", "src/first");
+        let (head, tail) = first.split_at(30);
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.flush().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        stream.write_all(tail.as_bytes()).unwrap();
+        stream.write_all(b"\nnot json\n\xff\xfe\n{\"broken\": \n").unwrap();
+        stream.write_all(line("hmac-sha256:0123", "src/hashed").as_bytes()).unwrap();
+        stream.write_all(line("kv", "src/second").as_bytes()).unwrap();
+        drop(stream);
+
+        let next = || rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(next(), SecretOp::Update(secret("src/first")));
+        assert_eq!(next(), SecretOp::Update(secret("src/second")));
+        assert!(rx.recv_timeout(std::time::Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_audit_log_op() {
+        let log = |operation: &str, mount_type: &str, path: &str| -> AuditLog {
+            serde_json::from_str(&format!(
+                r#"{{"type":"response","request":{{"operation":"{}","mount_type":"{}","path":"{}"}}}}"#,
+                operation, mount_type, path,
+            )).unwrap()
+        };
+        let mounts = vec!["secret".to_string()];
+        let prefixes = vec!["src/".to_string(), "apps/".to_string()];
+        let all = vec![WatchedOperation::Create, WatchedOperation::Update, WatchedOperation::Delete];
+        let op = |operations: &[WatchedOperation], log: &AuditLog| audit_log_op(&mounts, &prefixes, &EngineVersion::V2, operations, log);
+
+        assert_eq!(op(&all, &log("update", "kv", "secret/data/src/a")), Some(SecretOp::Update(secret("src/a"))));
+        assert_eq!(op(&all, &log("delete", "kv", "secret/data/src/a")), Some(SecretOp::Delete(secret("src/a"))));
+        assert_eq!(op(&all, &log("read", "kv", "secret/data/src/a")), None);
+        assert_eq!(op(&all, &log("update", "kv", "other/data/src/a")), None);
+        assert_eq!(op(&all, &log("update", "kv", "secret/data/dst/a")), None);
+        assert_eq!(op(&all, &log("update", "kv", "secret/data/apps/a")), Some(SecretOp::Update(secret("apps/a"))));
+        assert_eq!(op(&all, &log("update", "kv", "secret/metadata/src/a")), None);
+        assert_eq!(op(&all, &log("update", "", "sys/policies/acl/src")), None);
+        assert_eq!(op(&all, &log("update", "approle", "auth/approle/login")), None);
+
+        let updates = vec![WatchedOperation::Update];
+        assert_eq!(op(&updates, &log("update", "kv", "secret/data/src/a")), Some(SecretOp::Update(secret("src/a"))));
+        assert_eq!(op(&updates, &log("delete", "kv", "secret/data/src/a")), None);
+    }
+
+    #[test]
+    fn test_sync_worker_incremental() {
+        let updated = Arc::new(AtomicUsize::new(1));
+        let src_updated = updated.clone();
+        let src = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/metadata/src/foo") => {
+                    let updated = src_updated.load(Ordering::Relaxed);
+                    (200, format!(r#"{{"request_id":"1","data":{{"current_version":{},"updated_time":"2024-01-0{}T00:00:00Z","versions":{{}}}}}}"#, updated, updated))
+                },
+                ("GET", "/v1/secret/data/src/foo") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) | ("POST", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let file_name = std::env::temp_dir().join(format!("vault-sync-sync-state-{}.json", std::process::id()));
+        let file_name = file_name.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&file_name);
+        let mut config = config(false);
+        config.state_path = Some(file_name.clone());
+        let config = Arc::new(RwLock::new(config));
+        let run = || {
+            let (tx, rx) = mpsc::sync_channel(100);
+            send_op(&tx, SecretOp::Create(secret("src/foo")));
+            send_op(&tx, SecretOp::FullSyncFinished);
+            sync_worker(rx, &config, mock::client(&src), vec![mock::client(&dst)], None, None, true);
+        };
+
+        run();
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/foo"), 1);
+
+        // The state is loaded again by the new worker
+        run();
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/foo"), 1);
+        assert_eq!(src.count("GET", "/v1/secret/data/src/foo"), 1);
+
+        updated.store(2, Ordering::Relaxed);
+        run();
+        std::fs::remove_file(&file_name).unwrap();
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/foo"), 2);
+    }
+
+    #[test]
+    fn test_coalescer() {
+        let window = std::time::Duration::from_millis(100);
+        let start = std::time::Instant::now();
+        let mut coalescer = Coalescer::new(window);
+        assert!(coalescer.push(SecretOp::Create(secret("src/a")), start).is_empty());
+        assert!(coalescer.push(SecretOp::Update(secret("src/b")), start).is_empty());
+        assert!(coalescer.push(SecretOp::Update(secret("src/a")), start + window / 2).is_empty());
+        assert_eq!(coalescer.next_deadline(), Some(start + window));
+        assert!(coalescer.expired(start + window / 2).is_empty());
+        assert_eq!(coalescer.expired(start + window), vec![SecretOp::Update(secret("src/a")), SecretOp::Update(secret("src/b"))]);
+        assert_eq!(coalescer.next_deadline(), None);
+
+        // A delete applies the pending update first
+        assert!(coalescer.push(SecretOp::Update(secret("src/a")), start).is_empty());
+        assert_eq!(coalescer.push(SecretOp::Delete(secret("src/a")), start), vec![
+            SecretOp::Update(secret("src/a")),
+            SecretOp::Delete(secret("src/a")),
+        ]);
+        assert!(coalescer.push(SecretOp::Update(secret("src/a")), start + window / 2).is_empty());
+        assert!(coalescer.expired(start + window).is_empty());
+        assert_eq!(coalescer.push(SecretOp::FullSyncFinished, start + window), vec![
+            SecretOp::Update(secret("src/a")),
+            SecretOp::FullSyncFinished,
+        ]);
+
+        let mut coalescer = Coalescer::new(std::time::Duration::ZERO);
+        assert_eq!(coalescer.push(SecretOp::Update(secret("src/a")), start), vec![SecretOp::Update(secret("src/a"))]);
+    }
+
+    #[test]
+    fn test_sync_worker_coalesce() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", path) if path.starts_with("/v1/secret/data/src/") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = config(false);
+        config.coalesce_ms = 100;
+        let config = Arc::new(RwLock::new(config));
+        let (tx, rx) = mpsc::sync_channel(100);
+        let worker_config = config.clone();
+        let src_client = mock::client(&src);
+        let dst_client = mock::client(&dst);
+        let worker = std::thread::spawn(move || sync_worker(rx, &worker_config, src_client, vec![dst_client], None, None, false));
+
+        for _ in 0..3 {
+            send_op(&tx, SecretOp::Update(secret("src/hot")));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/hot"), 1);
+
+        send_op(&tx, SecretOp::Shutdown);
+        worker.join().unwrap();
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/hot"), 1);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_sync_worker_timeout() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", path) if path.starts_with("/v1/secret/data/src/") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("PUT", _) => {
+                    std::thread::sleep(std::time::Duration::from_millis(1500));
+                    (204, "".to_string())
+                },
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let file_name = std::env::temp_dir().join(format!("vault-sync-sync-timeout-{}.jsonl", std::process::id()));
+        let file_name = file_name.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&file_name);
+        let mut config = config(false);
+        config.max_retries = 1;
+        config.dead_letter_path = Some(file_name.clone());
+        let host = VaultHost {
+            url: dst.url.clone(),
+            auth: Some(VaultAuthMethod::TokenAuth { token: "token".to_string() }),
+            timeout_secs: Some(1),
+            ..Default::default()
+        };
+        let dst_client = Arc::new(Mutex::new(vault_client(&host, &EngineVersion::V2).unwrap()));
+        let (tx, rx) = mpsc::sync_channel(100);
+        send_op(&tx, SecretOp::Update(secret("src/slow")));
+        send_op(&tx, SecretOp::FullSyncFinished);
+        let config = Arc::new(RwLock::new(config));
+        sync_worker(rx, &config, mock::client(&src), vec![dst_client], None, None, true);
+
+        // Timeouts are retried, then the op fails as usual
+        let letters = dead_letter::read(&file_name).unwrap();
+        std::fs::remove_file(&file_name).unwrap();
+        assert_eq!(dst.count("PUT", "/v1/secret/data/dst/slow"), 2);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].op(), SecretOp::Update(secret("src/slow")));
+    }
+}
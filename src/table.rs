@@ -0,0 +1,33 @@
+// Plain text tables of the --diff, --verify and --once-validate-permissions reports
+
+// Renders the lines, the first one is the header, with the columns aligned to the widest cell
+pub fn render(lines: &[Vec<String>]) -> String {
+    let mut widths = vec![0; lines[0].len()];
+    for line in lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for line in lines {
+        let cells: Vec<String> = line.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::table::render;
+
+    #[test]
+    fn test_render() {
+        let lines = vec![
+            vec!["NAME".to_string(), "VALUE".to_string()],
+            vec!["a".to_string(), "-".to_string()],
+            vec!["longer".to_string(), "".to_string()],
+        ];
+        assert_eq!(render(&lines), "NAME    VALUE\na       -\nlonger\n");
+    }
+}
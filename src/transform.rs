@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::ConfigError;
+use crate::filter::PathFilter;
+
+// Rule of the transforms list of a destination
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Transform {
+    // Regex matched against the source secret path like include, all secrets if not set
+    pub path_pattern: Option<String>,
+    // Top-level key of the secret data, values that are not strings are kept as is
+    pub key: String,
+    // Replaces every occurrence, the strings are not patterns
+    pub find: String,
+    pub replace: String,
+}
+
+// Find and replace rules for the string values written to a destination, for example a hostname
+// that differs between environments. Every rule with a matching path is applied, in order.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct ValueTransformer {
+    rules: Vec<(PathFilter, Transform)>,
+}
+
+impl ValueTransformer {
+    pub fn new(transforms: &[Transform]) -> Result<ValueTransformer, ConfigError> {
+        let rules = transforms.iter()
+            .map(|transform| {
+                if transform.key.is_empty() {
+                    return Err(ConfigError::InvalidTransform(transform.key.clone(), "key is empty"));
+                }
+                if transform.find.is_empty() {
+                    return Err(ConfigError::InvalidTransform(transform.key.clone(), "find is empty"));
+                }
+                let include: Vec<String> = transform.path_pattern.iter().cloned().collect();
+                Ok((PathFilter::new(&include, &[])?, transform.clone()))
+            })
+            .collect::<Result<_, ConfigError>>()?;
+        Ok(ValueTransformer { rules })
+    }
+
+    // Path is the source secret path, for example "src/path/to/secret". Returns true if some
+    // values are changed.
+    pub fn apply(&self, path: &str, data: &mut Value) -> bool {
+        let Value::Object(map) = data else {
+            return false;
+        };
+        let mut changed = false;
+        for (filter, transform) in &self.rules {
+            if !filter.matches(path) {
+                continue;
+            }
+            if let Some(Value::String(value)) = map.get_mut(&transform.key) {
+                if value.contains(&transform.find) {
+                    *value = value.replace(&transform.find, &transform.replace);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::transform::{Transform, ValueTransformer};
+
+    fn transform(path_pattern: Option<&str>, key: &str, find: &str, replace: &str) -> Transform {
+        Transform {
+            path_pattern: path_pattern.map(String::from),
+            key: key.to_string(),
+            find: find.to_string(),
+            replace: replace.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let transformer = ValueTransformer::new(&[
+            transform(Some("^src/db/"), "url", "db.internal", "db.staging"),
+            transform(None, "url", "5432", "6432"),
+            transform(None, "port", "5432", "6432"),
+        ]).unwrap();
+        let mut data = json!({"url": "postgres://db.internal:5432/db.internal", "port": 5432, "user": "db.internal"});
+        assert!(transformer.apply("src/db/main", &mut data));
+        assert_eq!(data, json!({"url": "postgres://db.staging:6432/db.staging", "port": 5432, "user": "db.internal"}));
+
+        let mut data = json!({"url": "postgres://db.internal:5432/"});
+        assert!(transformer.apply("src/other", &mut data));
+        assert_eq!(data, json!({"url": "postgres://db.internal:6432/"}));
+
+        let mut data = json!({"url": "https://example.com/"});
+        assert!(!transformer.apply("src/db/main", &mut data));
+        assert_eq!(data, json!({"url": "https://example.com/"}));
+    }
+
+    #[test]
+    fn test_invalid_transform() {
+        let error = ValueTransformer::new(&[transform(None, "url", "", "db")]).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid transform of key \"url\": find is empty");
+        assert!(ValueTransformer::new(&[transform(Some("db-("), "url", "a", "b")]).unwrap_err().to_string().contains("db-("));
+    }
+}
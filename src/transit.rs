@@ -0,0 +1,354 @@
+// Transit keys, with secret_engine transit. The configuration of every key is recreated in the
+// destination, the key material with all versions is copied with backup and restore if the key is
+// exportable and allows plaintext backups. Transit keys are synced by full syncs only, requests to
+// transit secrets engines in the audit log are ignored.
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+
+use hashicorp_vault::client::{EndpointResponse, HttpVerb};
+use hashicorp_vault::client::error::{Error as VaultError, Result as VaultResult};
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::VaultSyncConfig;
+use crate::metrics::{Request, METRICS};
+use crate::report::ActionKind;
+use crate::sync::{send_op, SecretOp, SecretPath};
+use crate::vault::VaultClient;
+
+// Key as returned by <mount>/keys/<name>, without the key material
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct TransitKey {
+    // Also defines the allowed operations, like encryption or signing
+    #[serde(rename = "type")]
+    pub key_type: String,
+    #[serde(default)]
+    pub derived: bool,
+    #[serde(default)]
+    pub convergent_encryption: bool,
+    #[serde(default)]
+    pub exportable: bool,
+    #[serde(default)]
+    pub allow_plaintext_backup: bool,
+    #[serde(default)]
+    pub deletion_allowed: bool,
+    #[serde(default)]
+    pub min_decryption_version: u64,
+    #[serde(default)]
+    pub min_encryption_version: u64,
+    #[serde(default)]
+    pub latest_version: u64,
+    // Seconds, 0 if the key is not rotated automatically
+    #[serde(default)]
+    pub auto_rotate_period: u64,
+}
+
+impl TransitKey {
+    // Key material can be copied with backup and restore
+    fn is_portable(&self) -> bool {
+        self.exportable && self.allow_plaintext_backup
+    }
+}
+
+// Options of <mount>/keys/<name>/config
+#[derive(Serialize, Debug, PartialEq)]
+struct KeyConfig {
+    min_decryption_version: u64,
+    min_encryption_version: u64,
+    deletion_allowed: bool,
+    exportable: bool,
+    allow_plaintext_backup: bool,
+    auto_rotate_period: u64,
+}
+
+impl KeyConfig {
+    // Minimum versions cannot be greater than the latest version of the key they are written to.
+    // exportable and allow_plaintext_backup cannot be disabled once enabled.
+    fn new(key: &TransitKey, dst_key: Option<&TransitKey>, latest_version: u64) -> KeyConfig {
+        KeyConfig {
+            min_decryption_version: key.min_decryption_version.min(latest_version),
+            min_encryption_version: key.min_encryption_version.min(latest_version),
+            deletion_allowed: key.deletion_allowed,
+            exportable: key.exportable || dst_key.is_some_and(|dst_key| dst_key.exportable),
+            allow_plaintext_backup: key.allow_plaintext_backup || dst_key.is_some_and(|dst_key| dst_key.allow_plaintext_backup),
+            auto_rotate_period: key.auto_rotate_period,
+        }
+    }
+}
+
+// Lists the keys and sends them to the sync worker. Key names cannot have prefixes, so only the
+// filters apply. Returns false if some of the keys could not be listed, and the number of keys sent.
+pub fn full_sync(config: &VaultSyncConfig, src_client: &Arc<Mutex<VaultClient>>, tx: &mpsc::SyncSender<SecretOp>) -> (bool, u64) {
+    let mut listed = true;
+    let mut keys = 0;
+    for backend in config.src.synced_backends() {
+        // A copy of the shared client, so the live sync does not wait for the listing
+        let list_client = src_client.lock().unwrap().clone_without_data();
+        let names = match list_keys(&list_client, &backend) {
+            Ok(names) => names,
+            Err(error) => {
+                warn!(host = config.src.host.url.as_str(); "Failed to list transit keys in {} in {}: {}", &backend, &config.src.host.url, error);
+                listed = false;
+                continue;
+            }
+        };
+        for name in names {
+            if !config.src.filter.matches(&name) {
+                debug!("Transit key {}/{} is filtered out, skipping", &backend, &name);
+                continue;
+            }
+            keys += 1;
+            send_op(tx, SecretOp::Create(SecretPath { mount: backend.clone(), path: name }));
+        }
+    }
+    (listed, keys)
+}
+
+// Recreates the source key in the destination. Returns the action, None if the destination key
+// is up to date or the source key does not exist anymore.
+pub fn sync_key(
+    src_client: &Arc<Mutex<VaultClient>>,
+    dst_client: &Arc<Mutex<VaultClient>>,
+    src: &SecretPath,
+    dst: &SecretPath,
+    dry_run: bool,
+) -> VaultResult<Option<ActionKind>> {
+    let Some(key) = read_key(&src_client.lock().unwrap(), &src.mount, &src.path)? else {
+        return Ok(None);
+    };
+    let dst_key = {
+        let client = dst_client.lock().unwrap();
+        METRICS.time(Request::Read, || read_key(&client, &dst.mount, &dst.path))?
+    };
+    let action = if dst_key.is_some() { ActionKind::Update } else { ActionKind::Create };
+
+    // The backup has all versions and the configuration of the key
+    if key.is_portable() && dst_key.as_ref().is_none_or(|dst_key| dst_key.latest_version < key.latest_version) {
+        info!(secret = dst.path.as_str(); "Restoring transit key {} from a backup of version {}", &dst.path, key.latest_version);
+        if !dry_run {
+            let backup = backup_key(&src_client.lock().unwrap(), &src.mount, &src.path)?;
+            let client = dst_client.lock().unwrap();
+            METRICS.time(Request::Write, || restore_key(&client, &dst.mount, &dst.path, &backup, dst_key.is_some()))?;
+        }
+        return Ok(Some(action));
+    }
+
+    let latest_version = match &dst_key {
+        Some(dst_key) => dst_key.latest_version,
+        None => {
+            info!(secret = dst.path.as_str(); "Creating transit key {}", &dst.path);
+            if !key.is_portable() {
+                warn!(
+                    secret = dst.path.as_str();
+                    "Key material of transit key {} is not exportable, the destination key has new key material, data must be rewrapped separately",
+                    &dst.path,
+                );
+            }
+            if !dry_run {
+                let client = dst_client.lock().unwrap();
+                METRICS.time(Request::Write, || create_key(&client, &dst.mount, &dst.path, &key))?;
+            }
+            1
+        }
+    };
+    let config = KeyConfig::new(&key, dst_key.as_ref(), latest_version);
+    if let Some(dst_key) = &dst_key {
+        if KeyConfig::new(dst_key, None, latest_version) == config {
+            return Ok(None);
+        }
+        info!(secret = dst.path.as_str(); "Updating configuration of transit key {}", &dst.path);
+    }
+    if !dry_run {
+        let client = dst_client.lock().unwrap();
+        METRICS.time(Request::Write, || write_config(&client, &dst.mount, &dst.path, &config))?;
+    }
+    Ok(Some(action))
+}
+
+// Returns the names of the keys, a missing mount or no keys is not an error
+pub fn list_keys(client: &VaultClient, mount: &str) -> VaultResult<Vec<String>> {
+    let endpoint = format!("{}/keys", mount);
+    match client.call_endpoint::<Value>(HttpVerb::LIST, &endpoint, None, None) {
+        Ok(EndpointResponse::VaultResponse(response)) => Ok(response.data
+            .as_ref()
+            .and_then(|data| data.get("keys"))
+            .and_then(Value::as_array)
+            .map(|keys| keys.iter().filter_map(Value::as_str).map(String::from).collect())
+            .unwrap_or_default()),
+        Ok(EndpointResponse::Empty) => Ok(Vec::new()),
+        Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => Ok(Vec::new()),
+        Err(error) => Err(error),
+    }
+}
+
+// Returns None if the key does not exist
+fn read_key(client: &VaultClient, mount: &str, name: &str) -> VaultResult<Option<TransitKey>> {
+    let endpoint = format!("{}/keys/{}", mount, name);
+    match client.call_endpoint::<TransitKey>(HttpVerb::GET, &endpoint, None, None) {
+        Ok(EndpointResponse::VaultResponse(response)) => Ok(response.data),
+        Ok(EndpointResponse::Empty) => Ok(None),
+        Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// Options that can only be set when the key is created
+fn create_key(client: &VaultClient, mount: &str, name: &str, key: &TransitKey) -> VaultResult<()> {
+    let endpoint = format!("{}/keys/{}", mount, name);
+    let body = serde_json::json!({
+        "type": key.key_type,
+        "derived": key.derived,
+        "convergent_encryption": key.convergent_encryption,
+        "exportable": key.exportable,
+        "allow_plaintext_backup": key.allow_plaintext_backup,
+        "auto_rotate_period": key.auto_rotate_period,
+    }).to_string();
+    client.call_endpoint::<Value>(HttpVerb::POST, &endpoint, None, Some(&body))?;
+    Ok(())
+}
+
+fn write_config(client: &VaultClient, mount: &str, name: &str, config: &KeyConfig) -> VaultResult<()> {
+    let endpoint = format!("{}/keys/{}/config", mount, name);
+    let body = serde_json::to_string(config)?;
+    client.call_endpoint::<Value>(HttpVerb::POST, &endpoint, None, Some(&body))?;
+    Ok(())
+}
+
+fn backup_key(client: &VaultClient, mount: &str, name: &str) -> VaultResult<String> {
+    let endpoint = format!("{}/backup/{}", mount, name);
+    let backup = match client.call_endpoint::<Value>(HttpVerb::GET, &endpoint, None, None)? {
+        EndpointResponse::VaultResponse(response) => response.data
+            .as_ref()
+            .and_then(|data| data.get("backup"))
+            .and_then(Value::as_str)
+            .map(String::from),
+        EndpointResponse::Empty => None,
+    };
+    backup.ok_or_else(|| VaultError::Vault(format!("No backup of transit key {}", name)))
+}
+
+// force replaces the existing key
+fn restore_key(client: &VaultClient, mount: &str, name: &str, backup: &str, force: bool) -> VaultResult<()> {
+    let endpoint = format!("{}/restore/{}", mount, name);
+    let body = serde_json::json!({"backup": backup, "force": force}).to_string();
+    client.call_endpoint::<Value>(HttpVerb::POST, &endpoint, None, Some(&body))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use serde_json::Value;
+
+    use crate::config::{Backend, SecretEngine};
+    use crate::filter::PathFilter;
+    use crate::mock::{self, MockServer};
+    use crate::report::ActionKind;
+    use crate::sync::{SecretOp, SecretPath};
+    use crate::transit::{full_sync, sync_key};
+
+    const EXPORTABLE: &str = r#"{"request_id":"1","data":{"type":"aes256-gcm96","exportable":true,"allow_plaintext_backup":true,"min_decryption_version":1,"min_encryption_version":0,"latest_version":3}}"#;
+    const NOT_EXPORTABLE: &str = r#"{"request_id":"1","data":{"type":"ed25519","deletion_allowed":true,"min_decryption_version":2,"min_encryption_version":0,"latest_version":3,"auto_rotate_period":86400}}"#;
+
+    fn key(name: &str) -> SecretPath {
+        SecretPath { mount: "transit".to_string(), path: name.to_string() }
+    }
+
+    fn body(server: &MockServer, path: &str) -> Value {
+        let request = server.requests().into_iter().find(|r| r.method == "POST" && r.path == path).unwrap();
+        serde_json::from_str(&request.body).unwrap()
+    }
+
+    #[test]
+    fn test_full_sync() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/transit/keys") => (200, r#"{"request_id":"1","data":{"keys":["app","local"]}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = mock::config(&src.url, "http://127.0.0.1:8200/");
+        config.src.secret_engine = SecretEngine::Transit;
+        config.src.backend = Some(Backend::Backend("transit".to_string()));
+        config.src.filter = PathFilter::new(&[], &["local".to_string()]).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel(100);
+        assert_eq!(full_sync(&config, &mock::client(&src), &tx), (true, 1));
+        drop(tx);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![SecretOp::Create(key("app"))]);
+    }
+
+    #[test]
+    fn test_sync_key_restore() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/transit/keys/app") => (200, EXPORTABLE.to_string()),
+                ("GET", "/v1/transit/backup/app") => (200, r#"{"request_id":"1","data":{"backup":"c2VjcmV0"}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("POST", "/v1/transit/restore/app") => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+
+        assert_eq!(sync_key(&mock::client(&src), &mock::client(&dst), &key("app"), &key("app"), true).unwrap(), Some(ActionKind::Create));
+        assert_eq!(dst.count("POST", "/v1/transit/restore/app"), 0);
+
+        assert_eq!(sync_key(&mock::client(&src), &mock::client(&dst), &key("app"), &key("app"), false).unwrap(), Some(ActionKind::Create));
+        let body = body(&dst, "/v1/transit/restore/app");
+        assert_eq!(body["backup"], "c2VjcmV0");
+        assert_eq!(body["force"], false);
+    }
+
+    #[test]
+    fn test_sync_key_not_exportable() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/transit/keys/signing") => (200, NOT_EXPORTABLE.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("POST", "/v1/transit/keys/signing") | ("POST", "/v1/transit/keys/signing/config") => (204, "".to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+
+        assert_eq!(sync_key(&mock::client(&src), &mock::client(&dst), &key("signing"), &key("signing"), false).unwrap(), Some(ActionKind::Create));
+        let created = body(&dst, "/v1/transit/keys/signing");
+        assert_eq!(created["type"], "ed25519");
+        assert_eq!(created["exportable"], false);
+        // The new key has only one version
+        let config = body(&dst, "/v1/transit/keys/signing/config");
+        assert_eq!(config["min_decryption_version"], 1);
+        assert_eq!(config["deletion_allowed"], true);
+        assert_eq!(config["auto_rotate_period"], 86400);
+    }
+
+    #[test]
+    fn test_sync_key_unchanged() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/transit/keys/signing") => (200, NOT_EXPORTABLE.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let client = mock::client(&server);
+        assert_eq!(sync_key(&client, &client, &key("signing"), &key("signing"), false).unwrap(), None);
+        assert_eq!(server.count("POST", "/v1/transit/keys/signing/config"), 0);
+    }
+}
@@ -1,49 +1,686 @@
-use std::{thread, time};
+use std::{fs, thread, time};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::time::Duration;
 
 use hashicorp_vault::client as vault;
-use hashicorp_vault::client::{SecretsEngine, TokenData, VaultDuration};
-use hashicorp_vault::client::error::Result as VaultResult;
-use log::{info, warn};
+use hashicorp_vault::client::{EndpointResponse, HttpVerb, SecretsEngine, TokenData, VaultDuration};
+use hashicorp_vault::client::error::{Error as VaultError, Result as VaultResult};
+use reqwest::{Certificate, Identity, StatusCode};
+use reqwest::blocking::Client;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+use crate::binary;
 use crate::config::{EngineVersion, VaultAuthMethod, VaultHost};
+use crate::health::{self, HEALTH};
+use crate::metrics::METRICS;
 
 pub type VaultClient = hashicorp_vault::client::VaultClient<TokenData>;
 
+// Called by the token worker after the client is created again, see token_worker
+pub type OnReconnect = Arc<dyn Fn(&Arc<Mutex<VaultClient>>) + Send + Sync>;
+
+// The token worker checks the connection to Vault this often, and creates the client again after
+// MAX_CHECK_FAILURES failed checks in a row
+#[cfg(not(test))]
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+#[cfg(test)]
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_CHECK_FAILURES: u32 = 3;
+
+// Delays between the connection attempts on start, doubled after every attempt
+#[cfg(not(test))]
+const STARTUP_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const STARTUP_RETRY_BASE_DELAY: Duration = Duration::from_millis(10);
+const STARTUP_RETRY_MAX_DELAY: Duration = Duration::from_secs(15);
+
+// Clients for one Vault host, used by the sync threads. A checkout copies the shared client, which
+// has the token renewed by the token worker, with the next of pool_size HTTP clients in turn.
+// Every HTTP client has its own connections, the first one is the HTTP client of the shared
+// client.
+pub struct ClientPool {
+    shared: Arc<Mutex<VaultClient>>,
+    http_clients: Vec<Client>,
+    next: AtomicUsize,
+}
+
+impl ClientPool {
+    // If the HTTP clients cannot be created, for example a certificate file is removed, only the
+    // HTTP client of the shared client is used
+    pub fn new(host: &VaultHost, shared: Arc<Mutex<VaultClient>>, size: usize) -> ClientPool {
+        let http_clients = (1..size).map(|_| http_client(host)).collect::<VaultResult<Vec<_>>>().unwrap_or_else(|error| {
+            warn!("Failed to create the client pool for {}, using a single client: {}", &host.url, error);
+            Vec::new()
+        });
+        ClientPool { shared, http_clients, next: AtomicUsize::new(0) }
+    }
+
+    pub fn checkout(&self) -> Arc<Mutex<VaultClient>> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % (self.http_clients.len() + 1);
+        let shared = self.shared.lock().unwrap();
+        let client = match index {
+            0 => shared.clone_without_data(),
+            _ => shared.clone_with_http_client(self.http_clients[index - 1].clone()),
+        };
+        Arc::new(Mutex::new(client))
+    }
+}
+
+// Accessor of the client token, looked up when the client is created
+pub fn token_accessor(client: &VaultClient) -> Option<String> {
+    client.data.as_ref()?.data.as_ref()?.accessor.clone()
+}
+
 pub fn vault_client(host: &VaultHost, version: &EngineVersion) -> VaultResult<vault::VaultClient<TokenData>> {
+    let http_client = http_client(host)?;
     let mut result = match host.auth.as_ref().unwrap() {
         VaultAuthMethod::TokenAuth { token } => {
-            VaultClient::new(&host.url, token)
+            VaultClient::new_from_reqwest(&host.url, token, http_client)
+        },
+        // Resolved when the configuration is loaded, a host built otherwise has no token
+        VaultAuthMethod::TokenEnvAuth { token_env } => {
+            return Err(VaultError::Vault(format!("Environment variable {} of token_env is not resolved", token_env)));
+        },
+        VaultAuthMethod::TokenFileAuth { token_file } => {
+            // The file is read on every login, so the token can be rotated by rewriting the file
+            let token = read_token_file(token_file)?;
+            VaultClient::new_from_reqwest(&host.url, token, http_client)
+        },
+        VaultAuthMethod::WrappedTokenAuth { wrapped_token } => {
+            let token = unwrap_token(host, wrapped_token, &http_client)?;
+            VaultClient::new_from_reqwest(&host.url, token, http_client)
         },
         VaultAuthMethod::AppRoleAuth { role_id, secret_id} => {
-            let client = vault::VaultClient::new_app_role(
-                &host.url, role_id, Some(secret_id))?;
-            VaultClient::new(&host.url, client.token)
-        }
+            let payload = AppRoleLogin { role_id, secret_id };
+            let token = login(host, "approle", None, &payload, &http_client)?;
+            VaultClient::new_from_reqwest(&host.url, token, http_client)
+        },
+        VaultAuthMethod::KubernetesAuth { kubernetes } => {
+            // The projected service account token rotates, so it is read on every login
+            let jwt = fs::read_to_string(&kubernetes.jwt_path).map_err(|error| {
+                VaultError::Vault(format!(
+                    "Failed to read Kubernetes service account token {}: {}", &kubernetes.jwt_path, error))
+            })?;
+            let payload = KubernetesLogin { role: &kubernetes.role, jwt: jwt.trim() };
+            let token = login(host, "kubernetes", Some(&kubernetes.role), &payload, &http_client)?;
+            VaultClient::new_from_reqwest(&host.url, token, http_client)
+        },
+        VaultAuthMethod::JwtAuth { jwt } => {
+            // The JWT file is read on every login, so the identity provider can rotate it
+            let token = match (&jwt.jwt, &jwt.jwt_file) {
+                (Some(token), _) => token.clone(),
+                (None, Some(jwt_file)) => fs::read_to_string(jwt_file).map_err(|error| {
+                    VaultError::Vault(format!("Failed to read JWT file {}: {}", jwt_file, error))
+                })?,
+                // Checked when the configuration is loaded, a host built otherwise has no JWT
+                (None, None) => return Err(VaultError::Vault("jwt auth requires jwt or jwt_file".to_string())),
+            };
+            let payload = JwtLogin { role: &jwt.role, jwt: token.trim() };
+            let token = login(host, &jwt.mount, Some(&jwt.role), &payload, &http_client)?;
+            VaultClient::new_from_reqwest(&host.url, token, http_client)
+        },
     };
 
     if let Ok(client) = &mut result {
-        client.secrets_engine(
-            match version {
-                EngineVersion::V1 => SecretsEngine::KVV1,
-                EngineVersion::V2 => SecretsEngine::KVV2,
-            }
-        );
+        set_engine_version(client, version);
     }
 
     result
 }
 
+// Switches the paths of the KV requests of the client to the version
+pub fn set_engine_version(client: &mut VaultClient, version: &EngineVersion) {
+    client.secrets_engine(
+        match version {
+            EngineVersion::V1 => SecretsEngine::KVV1,
+            EngineVersion::V2 => SecretsEngine::KVV2,
+        }
+    );
+}
+
+// Connection errors, server errors and rate limiting may go away, other errors (like 403 or 404) do not.
+// A standby node rejects writes until it becomes active, which takes longer than the retries.
+pub fn is_transient(error: &VaultError) -> bool {
+    match error {
+        VaultError::Reqwest(error) => error.is_connect() || error.is_timeout() || error.is_request(),
+        VaultError::VaultResponse(_, response) if !is_standby(error) => {
+            response.status().is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS
+        },
+        _ => false,
+    }
+}
+
+// Errors of a standby node that does not forward the request to the active node
+const STANDBY_ERRORS: [&str; 2] = ["node not active", "performance standby"];
+
+pub fn is_standby(error: &VaultError) -> bool {
+    match error {
+        VaultError::VaultResponse(message, _) => STANDBY_ERRORS.iter().any(|standby| message.contains(standby)),
+        _ => false,
+    }
+}
+
+// Returns the address of the active node from sys/leader, None if the node is active itself or the
+// active node is not known. The endpoint does not require a token.
+pub fn leader_address(host: &VaultHost) -> VaultResult<Option<String>> {
+    let url = reqwest::Url::parse(&host.url)?.join("v1/sys/leader")?;
+    let response = http_client(host)?.get(url).send()?;
+    if !response.status().is_success() {
+        return Err(VaultError::Vault(format!("Failed to read sys/leader: {}", response.status())));
+    }
+    let body: Value = serde_json::from_reader(response)?;
+    if body["is_self"].as_bool() == Some(true) {
+        return Ok(None);
+    }
+    Ok(body["leader_address"].as_str().filter(|address| !address.is_empty()).map(String::from))
+}
+
+// Creates the client like vault_client, retrying transient errors until the timeout, for example
+// while Vault is starting or sealed. After the timeout, the last error is returned.
+pub fn vault_client_with_retry(host: &VaultHost, version: &EngineVersion, timeout: Duration) -> VaultResult<VaultClient> {
+    let deadline = time::Instant::now() + timeout;
+    let mut delay = STARTUP_RETRY_BASE_DELAY;
+    loop {
+        match vault_client(host, version) {
+            Err(error) if is_transient(&error) => {
+                let Some(remaining) = deadline.checked_duration_since(time::Instant::now()).filter(|remaining| !remaining.is_zero()) else {
+                    return Err(error);
+                };
+                let delay_now = delay.min(remaining);
+                warn!(host = host.url.as_str(); "Failed to connect to {}, retrying in {}ms: {}", &host.url, delay_now.as_millis(), error);
+                thread::sleep(delay_now);
+                delay = (delay * 2).min(STARTUP_RETRY_MAX_DELAY);
+            },
+            result => return result,
+        }
+    }
+}
+
+// Checks that secrets with the prefix can be listed, a missing prefix is not an error
+pub fn check_list(client: &mut VaultClient, backend: &str, prefix: &str) -> VaultResult<()> {
+    client.secret_backend(backend);
+    match client.list_secrets(prefix) {
+        Ok(_) => Ok(()),
+        Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+// Returns the capabilities of the token for every path from sys/capabilities-self, policies with
+// globs are applied by Vault. Paths missing in the response have no capabilities.
+pub fn capabilities(client: &VaultClient, paths: &[String]) -> VaultResult<HashMap<String, Vec<String>>> {
+    let body = serde_json::json!({ "paths": paths }).to_string();
+    let response = client.call_endpoint::<Value>(HttpVerb::POST, "sys/capabilities-self", None, Some(&body))?;
+    let mut result = HashMap::new();
+    let EndpointResponse::VaultResponse(response) = response else {
+        return Ok(result);
+    };
+    let Some(data) = response.data else {
+        return Ok(result);
+    };
+    for path in paths {
+        // Older Vault versions return only "capabilities" for a single path
+        let capabilities = data.get(path).or_else(|| data.get("capabilities").filter(|_| paths.len() == 1));
+        let capabilities = capabilities.and_then(Value::as_array)
+            .map(|capabilities| capabilities.iter().filter_map(Value::as_str).map(String::from).collect())
+            .unwrap_or_default();
+        result.insert(path.clone(), capabilities);
+    }
+    Ok(result)
+}
+
+// Returns the KV version of the secrets engine from sys/mounts, None if the backend is not mounted
+// or is not a KV secrets engine
+pub fn engine_version(client: &VaultClient, backend: &str) -> VaultResult<Option<EngineVersion>> {
+    let response = client.call_endpoint::<Value>(HttpVerb::GET, "sys/mounts", None, None)?;
+    let EndpointResponse::VaultResponse(response) = response else {
+        return Ok(None);
+    };
+    Ok(response.data.as_ref().and_then(|data| data.get(format!("{}/", backend))).and_then(kv_version))
+}
+
+// Same as engine_version, but from sys/internal/ui/mounts, which any token with access to the
+// mount can read. The Vault CLI uses it to find the version for the kv commands.
+pub fn ui_engine_version(client: &VaultClient, backend: &str) -> VaultResult<Option<EngineVersion>> {
+    let endpoint = format!("sys/internal/ui/mounts/{}", backend);
+    let response = client.call_endpoint::<Value>(HttpVerb::GET, &endpoint, None, None)?;
+    let EndpointResponse::VaultResponse(response) = response else {
+        return Ok(None);
+    };
+    Ok(response.data.as_ref().and_then(kv_version))
+}
+
+// None if the mount is not a KV secrets engine
+fn kv_version(mount: &Value) -> Option<EngineVersion> {
+    if mount.get("type").and_then(Value::as_str) != Some("kv") {
+        return None;
+    }
+    // KV v1 mounts may have no options at all
+    match mount.get("options").and_then(|options| options.get("version")).and_then(Value::as_str) {
+        Some("2") => Some(EngineVersion::V2),
+        _ => Some(EngineVersion::V1),
+    }
+}
+
+// Types of the secrets engines from sys/mounts by mount path without the trailing slash, for
+// example "secret" -> "kv"
+pub fn mount_types(client: &VaultClient) -> VaultResult<HashMap<String, String>> {
+    let response = client.call_endpoint::<Value>(HttpVerb::GET, "sys/mounts", None, None)?;
+    let EndpointResponse::VaultResponse(response) = response else {
+        return Ok(HashMap::new());
+    };
+    let Some(Value::Object(mounts)) = response.data else {
+        return Ok(HashMap::new());
+    };
+    Ok(mounts.iter()
+        .filter_map(|(path, mount)| Some((path.trim_end_matches('/').to_string(), mount.get("type")?.as_str()?.to_string())))
+        .collect())
+}
+
+// Metadata of a KV v2 secret from <mount>/metadata/<path>
+#[derive(Deserialize, Debug, Default)]
+pub struct SecretMetadata {
+    pub current_version: u64,
+    // Changes on every write of the secret or its metadata
+    #[serde(default)]
+    pub updated_time: String,
+    #[serde(default)]
+    pub versions: HashMap<String, VersionMetadata>,
+    #[serde(default)]
+    pub custom_metadata: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub max_versions: u64,
+    #[serde(default)]
+    pub delete_version_after: String,
+}
+
+// Metadata settings that can be written via <mount>/metadata/<path>
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MetadataSettings {
+    pub custom_metadata: HashMap<String, String>,
+    pub max_versions: u64,
+    pub delete_version_after: String,
+}
+
+impl From<&SecretMetadata> for MetadataSettings {
+    fn from(metadata: &SecretMetadata) -> Self {
+        MetadataSettings {
+            custom_metadata: metadata.custom_metadata.clone().unwrap_or_default(),
+            max_versions: metadata.max_versions,
+            delete_version_after: metadata.delete_version_after.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct VersionMetadata {
+    #[serde(default)]
+    pub destroyed: bool,
+}
+
+// Latest version of the secret data with a copy of the client, None if the secret does not exist
+// or its latest version is deleted
+pub fn read_secret(client: &Arc<Mutex<VaultClient>>, mount: &str, path: &str) -> VaultResult<Option<Value>> {
+    let mut client = client.lock().unwrap().clone_without_data();
+    client.secret_backend(mount);
+    match client.get_custom_secret(path) {
+        Ok(value) => Ok(Some(value)),
+        Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// Returns None if the secret does not exist
+pub fn read_metadata(client: &VaultClient, mount: &str, path: &str) -> VaultResult<Option<SecretMetadata>> {
+    let endpoint = format!("{}/metadata/{}", mount, path);
+    match client.call_endpoint::<SecretMetadata>(HttpVerb::GET, &endpoint, None, None) {
+        Ok(EndpointResponse::VaultResponse(response)) => Ok(response.data),
+        Ok(EndpointResponse::Empty) => Ok(None),
+        Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+pub fn write_metadata(client: &VaultClient, mount: &str, path: &str, settings: &MetadataSettings) -> VaultResult<()> {
+    let endpoint = format!("{}/metadata/{}", mount, path);
+    let body = serde_json::to_string(settings)?;
+    client.call_endpoint::<Value>(HttpVerb::POST, &endpoint, None, Some(&body))?;
+    Ok(())
+}
+
+// Removes all versions and metadata of the secret
+pub fn delete_metadata(client: &VaultClient, mount: &str, path: &str) -> VaultResult<()> {
+    let endpoint = format!("{}/metadata/{}", mount, path);
+    client.call_endpoint::<Value>(HttpVerb::DELETE, &endpoint, None, None)?;
+    Ok(())
+}
+
+pub fn secret_exists_v1(client: &VaultClient, mount: &str, path: &str) -> VaultResult<bool> {
+    let endpoint = format!("{}/{}", mount, path);
+    match client.call_endpoint::<Value>(HttpVerb::GET, &endpoint, None, None) {
+        Ok(_) => Ok(true),
+        Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+// Returns None if the version is deleted or destroyed
+pub fn read_version(client: &VaultClient, mount: &str, path: &str, version: u64) -> VaultResult<Option<Value>> {
+    let endpoint = format!("{}/data/{}?version={}", mount, path, version);
+    match client.call_endpoint::<Value>(HttpVerb::GET, &endpoint, None, None) {
+        Ok(EndpointResponse::VaultResponse(response)) => Ok(response.data.and_then(|mut data| data.get_mut("data").map(Value::take))),
+        Ok(EndpointResponse::Empty) => Ok(None),
+        Err(VaultError::VaultResponse(_, response)) if response.status() == StatusCode::NOT_FOUND => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// Writes a new version of the secret, check-and-set makes sure that the new version is
+// current_version + 1
+pub fn write_version(client: &VaultClient, mount: &str, path: &str, data: &Value, current_version: u64) -> VaultResult<()> {
+    let endpoint = format!("{}/data/{}", mount, path);
+    let body = serde_json::json!({"data": data, "options": {"cas": current_version}}).to_string();
+    client.call_endpoint::<Value>(HttpVerb::PUT, &endpoint, None, Some(&body))?;
+    Ok(())
+}
+
+// Reads the latest version of the secret like get_custom_secret, the values that are not valid
+// UTF-8 are base64-encoded, see binary
+pub fn read_secret_binary(client: &VaultClient, mount: &str, path: &str, version: &EngineVersion) -> VaultResult<Value> {
+    let endpoint = match version {
+        EngineVersion::V1 => format!("{}/{}", mount, path),
+        EngineVersion::V2 => format!("{}/data/{}", mount, path),
+    };
+    let mut response = binary::parse(&client.call_endpoint_bytes(HttpVerb::GET, &endpoint, None)?)?;
+    let mut data = match version {
+        EngineVersion::V1 => response["data"].take(),
+        EngineVersion::V2 => response["data"]["data"].take(),
+    };
+    if !data.is_object() {
+        return Err(VaultError::Vault(format!("No secret found in response for {}/{}", mount, path)));
+    }
+    binary::encode(&mut data).map_err(VaultError::Vault)?;
+    Ok(data)
+}
+
+// Writes the secret data from binary::decode, which is not valid UTF-8. A KV v2 secret that
+// requires check-and-set is written again with the current version.
+pub fn write_secret_binary(client: &VaultClient, mount: &str, path: &str, version: &EngineVersion, data: &[u8]) -> VaultResult<()> {
+    let write = |cas: Option<u64>| {
+        let (endpoint, body) = match (version, cas) {
+            (EngineVersion::V1, _) => (format!("{}/{}", mount, path), data.to_vec()),
+            (EngineVersion::V2, cas) => {
+                let mut body = br#"{"data":"#.to_vec();
+                body.extend_from_slice(data);
+                if let Some(cas) = cas {
+                    body.extend_from_slice(format!(r#","options":{{"cas":{}}}"#, cas).as_bytes());
+                }
+                body.push(b'}');
+                (format!("{}/data/{}", mount, path), body)
+            },
+        };
+        client.call_endpoint_bytes(HttpVerb::PUT, &endpoint, Some(body)).map(|_| ())
+    };
+    match write(None) {
+        Err(error) if *version == EngineVersion::V2 && is_cas_required(&error) => {
+            let current_version = read_metadata(client, mount, path)?.map_or(0, |metadata| metadata.current_version);
+            write(Some(current_version))
+        },
+        result => result,
+    }
+}
+
+// Errors of a KV v2 write without the cas option to a mount or secret with cas_required, and of a
+// write with a cas value that is not the current version
+const CAS_REQUIRED_ERROR: &str = "check-and-set parameter required";
+const CAS_MISMATCH_ERROR: &str = "check-and-set parameter did not match";
+
+// Writes with check-and-set are tried this often, the secret may be written in the meantime
+const CAS_ATTEMPTS: u32 = 3;
+
+pub fn is_cas_required(error: &VaultError) -> bool {
+    matches!(error, VaultError::VaultResponse(message, _) if message.contains(CAS_REQUIRED_ERROR))
+}
+
+fn is_cas_mismatch(error: &VaultError) -> bool {
+    matches!(error, VaultError::VaultResponse(message, _) if message.contains(CAS_MISMATCH_ERROR))
+}
+
+// Writes a new version of the secret with the current version from the metadata as the cas
+// value, 0 for a new secret. On a mismatch, the version is read again.
+pub fn write_secret_cas(client: &VaultClient, mount: &str, path: &str, data: &Value) -> VaultResult<()> {
+    let mut attempt = 1;
+    loop {
+        let current_version = read_metadata(client, mount, path)?.map_or(0, |metadata| metadata.current_version);
+        match write_version(client, mount, path, data, current_version) {
+            Err(error) if is_cas_mismatch(&error) && attempt < CAS_ATTEMPTS => {
+                debug!("Secret {}/{} is written in the meantime, writing again with the new version", mount, path);
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+// Soft deletes (delete) or permanently removes (destroy) versions of the secret
+pub fn delete_versions(client: &VaultClient, mount: &str, path: &str, versions: &[u64], destroy: bool) -> VaultResult<()> {
+    let (verb, endpoint) = if destroy {
+        (HttpVerb::PUT, format!("{}/destroy/{}", mount, path))
+    } else {
+        (HttpVerb::POST, format!("{}/delete/{}", mount, path))
+    };
+    let body = serde_json::json!({"versions": versions}).to_string();
+    client.call_endpoint::<Value>(verb, &endpoint, None, Some(&body))?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct AppRoleLogin<'a> {
+    role_id: &'a str,
+    secret_id: &'a str,
+}
+
+#[derive(Serialize)]
+struct KubernetesLogin<'a> {
+    role: &'a str,
+    jwt: &'a str,
+}
+
+#[derive(Serialize)]
+struct JwtLogin<'a> {
+    role: &'a str,
+    jwt: &'a str,
+}
+
+// Logs in via auth/<mount>/login, where mount is the default mount for the auth method or
+// auth_mount from the config, returns a new token. The role is reported in the login error.
+fn login<P: Serialize>(
+    host: &VaultHost,
+    default_mount: &str,
+    role: Option<&str>,
+    payload: &P,
+    http_client: &Client,
+) -> VaultResult<String> {
+    let mount = host.auth_mount.as_deref().unwrap_or(default_mount);
+    match vault::VaultClient::new_login_from_reqwest(&host.url, mount, payload, http_client.clone()) {
+        Ok(client) => Ok(client.token),
+        Err(error) => match role {
+            Some(role) => Err(VaultError::Vault(format!("Failed to login as role {} via auth/{}/login: {}", role, mount, error))),
+            None => Err(VaultError::Vault(format!("Failed to login via auth/{}/login: {}", mount, error))),
+        },
+    }
+}
+
+// Wrapped tokens and the tokens unwrapped from them. A wrapped token can be unwrapped only once, so
+// reconnects and reloads with the same wrapped token use the token unwrapped before.
+static UNWRAPPED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+fn unwrap_token(host: &VaultHost, wrapped_token: &str, http_client: &Client) -> VaultResult<String> {
+    if let Some((_, token)) = UNWRAPPED.lock().unwrap().iter().find(|(wrapped, _)| wrapped == wrapped_token) {
+        return Ok(token.clone());
+    }
+    let url = reqwest::Url::parse(&host.url)?.join("v1/sys/wrapping/unwrap")?;
+    let response = http_client.post(url).header("X-Vault-Token", wrapped_token).send()?;
+    let status = response.status();
+    let body: Value = serde_json::from_reader(response).unwrap_or_default();
+    if !status.is_success() {
+        let errors = body["errors"].as_array().map(|errors| {
+            errors.iter().filter_map(Value::as_str).collect::<Vec<&str>>().join(", ")
+        }).unwrap_or_default();
+        return Err(VaultError::Vault(format!(
+            "Failed to unwrap wrapped_token via sys/wrapping/unwrap ({}: {}), wrapped tokens can be unwrapped only once, \
+            it may have been unwrapped already or expired",
+            status, errors,
+        )));
+    }
+    let Some(token) = body["auth"]["client_token"].as_str() else {
+        return Err(VaultError::Vault("Wrapped response from sys/wrapping/unwrap has no client token".to_string()));
+    };
+    UNWRAPPED.lock().unwrap().push((wrapped_token.to_string(), token.to_string()));
+    Ok(token.to_string())
+}
+
+fn read_token_file(file_name: &str) -> VaultResult<String> {
+    let token = fs::read_to_string(file_name).map_err(|error| {
+        VaultError::Vault(format!("Failed to read token file {}: {}", file_name, error))
+    })?;
+    let token = token.trim_end();
+    if token.is_empty() {
+        return Err(VaultError::Vault(format!("Token file {} is empty", file_name)));
+    }
+    Ok(token.to_string())
+}
+
+// True if the auth method can request a new token by itself
+fn can_login(host: &VaultHost) -> bool {
+    matches!(
+        host.auth,
+        Some(VaultAuthMethod::AppRoleAuth { .. })
+        | Some(VaultAuthMethod::KubernetesAuth { .. })
+        | Some(VaultAuthMethod::JwtAuth { .. })
+        | Some(VaultAuthMethod::TokenFileAuth { .. })
+    )
+}
+
+// True if tokens are issued by a login to an auth method (as opposed to tokens created elsewhere)
+fn is_auth_login(host: &VaultHost) -> bool {
+    matches!(
+        host.auth,
+        Some(VaultAuthMethod::AppRoleAuth { .. })
+        | Some(VaultAuthMethod::KubernetesAuth { .. })
+        | Some(VaultAuthMethod::JwtAuth { .. })
+    )
+}
+
+// Revokes the token if vault-sync got it by a login, tokens from the configuration may be used
+// elsewhere, so they are kept. Returns false if the token is kept.
+pub fn revoke_token(host: &VaultHost, client: &VaultClient) -> VaultResult<bool> {
+    if !is_auth_login(host) {
+        return Ok(false);
+    }
+    client.call_endpoint::<Value>(HttpVerb::POST, "auth/token/revoke-self", None, None)?;
+    Ok(true)
+}
+
+// Requests a new token and replaces the token in the shared client
+fn relogin(host: &VaultHost, version: &EngineVersion, client: &Arc<Mutex<VaultClient>>) -> bool {
+    info!("Requesting a new token");
+    match vault_client(host, version) {
+        Ok(new_client) => {
+            let mut client = client.lock().unwrap();
+            client.token = new_client.token;
+            client.data = new_client.data;
+            true
+        },
+        Err(error) => {
+            warn!("Failed to request a new token: {}", error);
+            false
+        }
+    }
+}
+
+// Of the requests to Vault, unless timeout_secs is set
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Tokens are renewed after this fraction of the TTL, unless token_renew_fraction is set
+const DEFAULT_RENEW_FRACTION: f64 = 0.5;
+
+// A warning is logged if the token expires sooner
+const LOW_TTL: Duration = Duration::from_secs(5 * 60);
+
+// HTTP client with optional custom CA and TLS client certificate, system roots are always trusted
+fn http_client(host: &VaultHost) -> VaultResult<Client> {
+    // A hanging Vault fails the request, so it is retried instead of blocking the sync thread
+    let timeout = host.timeout_secs.map_or(DEFAULT_TIMEOUT, Duration::from_secs);
+    let mut builder = Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout);
+    if let Some(ca_cert) = &host.ca_cert {
+        let pem = read_pem(ca_cert, "CA certificate")?;
+        let cert = Certificate::from_pem(&pem).map_err(|error| {
+            VaultError::Vault(format!("Failed to parse CA certificate {}: {}", ca_cert, error))
+        })?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if let (Some(client_cert), Some(client_key)) = (&host.client_cert, &host.client_key) {
+        let cert = read_pem(client_cert, "client certificate")?;
+        let key = read_pem(client_key, "client key")?;
+        let identity = Identity::from_pkcs8_pem(&cert, &key).map_err(|error| {
+            VaultError::Vault(format!("Failed to parse client certificate {} or key {}: {}", client_cert, client_key, error))
+        })?;
+        builder = builder.identity(identity);
+    }
+    if host.tls_skip_verify {
+        warn!("TLS verification is disabled for {}", &host.url);
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder.build()?)
+}
+
+fn read_pem(file_name: &str, description: &str) -> VaultResult<Vec<u8>> {
+    fs::read(file_name).map_err(|error| {
+        VaultError::Vault(format!("Failed to read {} {}: {}", description, file_name, error))
+    })
+}
+
 // Worker to renew a Vault token lease, or to request a new token (for Vault AppRole auth method)
-pub fn token_worker(host: &VaultHost, version: &EngineVersion, client: Arc<Mutex<VaultClient>>) {
+// before it expires. token_expiry is updated every time the token is renewed or replaced. If Vault
+// is unreachable or does not accept the token (for example, after a restart of a dev server), the
+// client is created again, then on_reconnect is called. The worker exits when the stop flag is
+// set, for example when the client is replaced.
+pub fn token_worker(
+    host: &VaultHost,
+    version: &EngineVersion,
+    client: Arc<Mutex<VaultClient>>,
+    stop: Arc<AtomicBool>,
+    token_expiry: &AtomicU64,
+    on_reconnect: &OnReconnect,
+) {
     let mut token_age = time::Instant::now();
+    let renew_fraction = host.token_renew_fraction.unwrap_or(DEFAULT_RENEW_FRACTION);
+    let mut renewal = RenewalFailures::default();
     loop {
-        let info = {
-            let client = client.lock().unwrap();
-            TokenInfo::from_client(&client)
+        // The client data has the TTL when the client was created, renewals may grant less
+        let info = match lookup_token(&client) {
+            Ok(info) => {
+                token_expiry.store(health::token_expiry(info.ttl), Ordering::Relaxed);
+                info
+            },
+            Err(error) => {
+                warn!(host = host.url.as_str(); "Failed to look up token for {}: {}", &host.url, error);
+                TokenInfo::from_client(&client.lock().unwrap())
+            }
         };
         info!("Token: {:?}", &info);
+        if let Some(ttl) = info.ttl {
+            if ttl < LOW_TTL {
+                warn!(host = host.url.as_str(); "Token for {} expires in {}s", &host.url, ttl.as_secs());
+            }
+        }
 
         // Override token TTL and max TTL with optional values from config
         let mut plan = info.clone();
@@ -74,67 +711,181 @@ pub fn token_worker(host: &VaultHost, version: &EngineVersion, client: Arc<Mutex
         info!("Plan: {:?}", &plan);
 
         if !plan.renewable {
-            return;
-        } else {
-            if let Some(VaultAuthMethod::AppRoleAuth { role_id: _, secret_id: _ }) = &host.auth {
-                if plan.max_ttl.is_none() {
-                    warn!("Auth method is AppRole, but max_ttl is not set, using 32 days instead");
-                    plan.max_ttl = Some(time::Duration::from_secs(32 * 24 * 60 * 60));
-                }
-            }
-            if let Some(VaultAuthMethod::TokenAuth { token: _ }) = &host.auth {
-                if plan.max_ttl.is_some() {
-                    info!("Auth method is Token, but max_ttl is set, ignoring");
-                    plan.max_ttl = None;
+            // A token that never expires does not need any care, otherwise the only option is
+            // to request a new one before it expires
+            if !can_login(host) || plan.ttl.is_none() {
+                match wait(host, version, &client, &stop, None, on_reconnect) {
+                    Wait::Reconnected => {
+                        token_age = time::Instant::now();
+                        continue;
+                    },
+                    _ => {
+                        info!("Token worker for {} stopped", &host.url);
+                        return;
+                    },
                 }
             }
+            info!("Token is not renewable, a new token will be requested");
+        }
+        if is_auth_login(host) && plan.max_ttl.is_none() {
+            warn!("Auth method is AppRole, Kubernetes or JWT, but max_ttl is not set, using 32 days instead");
+            plan.max_ttl = Some(time::Duration::from_secs(32 * 24 * 60 * 60));
+        }
+        if !is_auth_login(host) && plan.max_ttl.is_some() {
+            info!("Auth method is Token, but max_ttl is set, ignoring");
+            plan.max_ttl = None;
         }
 
-        let duration = {
-            if plan.ttl.is_none() {
-                plan.max_ttl.unwrap()
-            } else if plan.max_ttl.is_none() {
-                plan.ttl.unwrap()
-            } else {
-                plan.ttl.unwrap().min(plan.max_ttl.unwrap())
-            }
+        let duration = match (plan.ttl, plan.max_ttl) {
+            (Some(ttl), Some(max_ttl)) => ttl.min(max_ttl),
+            (Some(ttl), None) => ttl,
+            (None, max_ttl) => max_ttl.unwrap(),
         };
-        let duration = time::Duration::from_secs(duration.as_secs() / 2);
+        let duration = duration.mul_f64(renew_fraction);
 
-        thread::sleep(duration);
+        match wait(host, version, &client, &stop, Some(duration), on_reconnect) {
+            Wait::Elapsed => {},
+            Wait::Reconnected => {
+                token_age = time::Instant::now();
+                continue;
+            },
+            Wait::Stopped => {
+                info!("Token worker for {} stopped", &host.url);
+                return;
+            },
+        }
+
+        if !plan.renewable {
+            if relogin(host, version, &client) {
+                token_age = time::Instant::now();
+            }
+            continue;
+        }
 
         if let Some(max_ttl) = plan.max_ttl {
-            let age = token_age.elapsed().as_secs();
-            let max_ttl = max_ttl.as_secs();
-            if age > max_ttl / 2 {
-                if let Some(VaultAuthMethod::AppRoleAuth { role_id: _, secret_id: _ }) = &host.auth {
-                    info!("Requesting a new token");
-                    match vault_client(&host, &version) {
-                        Ok(new_client) => {
-                            let mut client = client.lock().unwrap();
-                            client.token = new_client.token;
-                            client.data = new_client.data;
+            let age = token_age.elapsed();
+            if age > max_ttl.mul_f64(renew_fraction) && can_login(host) && relogin(host, version, &client) {
+                token_age = time::Instant::now();
+                continue;
+            }
+        }
+
+        if plan.ttl.is_some() {
+            info!("Renewing token");
+            match renew_token(&client) {
+                Ok(renewable) => {
+                    renewal.succeeded(host);
+                    // The token cannot be renewed again, for example it reached its max TTL
+                    if !renewable {
+                        info!(host = host.url.as_str(); "Token for {} is not renewable anymore", &host.url);
+                        if can_login(host) && relogin(host, version, &client) {
                             token_age = time::Instant::now();
-                            continue;
-                        },
-                        Err(error) => {
-                            warn!("Failed to request a new token: {}", error);
                         }
                     }
+                },
+                Err(error) => {
+                    warn!(host = host.url.as_str(); "Failed to renew token for {}: {}", &host.url, error);
+                    if can_login(host) && relogin(host, version, &client) {
+                        token_age = time::Instant::now();
+                        renewal.succeeded(host);
+                    } else {
+                        renewal.failed(host, time::Instant::now());
+                    }
                 }
             }
         }
+    }
+}
 
-        if let Some(_) = plan.ttl {
-            info!("Renewing token");
-            let result = {
-                let mut client = client.lock().unwrap();
-                client.renew()
-            };
-            if let Err(error) = result {
-                warn!("Failed to renew token: {}", error);
+// Failed renewals in a row of the token worker. After token_renew_grace, an error is logged and
+// vault-sync is not alive, so it can be alerted on before the token expires.
+#[derive(Default)]
+struct RenewalFailures {
+    since: Option<time::Instant>,
+}
+
+impl RenewalFailures {
+    fn failed(&mut self, host: &VaultHost, now: time::Instant) {
+        METRICS.token_renewal_failures.fetch_add(1, Ordering::Relaxed);
+        let since = *self.since.get_or_insert(now);
+        let Some(grace) = host.token_renew_grace else {
+            return;
+        };
+        let failing = now.duration_since(since);
+        if failing >= Duration::from_secs(grace) && HEALTH.set_renewal_failing(&host.url, true) {
+            error!(host = host.url.as_str(); "Token renewal for {} is failing for {}s, more than token_renew_grace", &host.url, failing.as_secs());
+        }
+    }
+
+    fn succeeded(&mut self, host: &VaultHost) {
+        self.since = None;
+        if HEALTH.set_renewal_failing(&host.url, false) {
+            info!(host = host.url.as_str(); "Token for {} is renewed again", &host.url);
+        }
+    }
+}
+
+enum Wait {
+    Elapsed,
+    Reconnected,
+    Stopped,
+}
+
+// Sleeps for the duration (or until stopped if there is no duration), checking the connection to
+// Vault every CHECK_INTERVAL. A new client replaces the shared one, so other workers use it too.
+fn wait(
+    host: &VaultHost,
+    version: &EngineVersion,
+    client: &Arc<Mutex<VaultClient>>,
+    stop: &AtomicBool,
+    duration: Option<Duration>,
+    on_reconnect: &OnReconnect,
+) -> Wait {
+    let deadline = duration.map(|duration| time::Instant::now() + duration);
+    let mut failures = 0;
+    loop {
+        let interval = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(time::Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => remaining.min(CHECK_INTERVAL),
+                _ => return Wait::Elapsed,
+            },
+            None => CHECK_INTERVAL,
+        };
+        thread::sleep(interval);
+        if stop.load(Ordering::Relaxed) {
+            return Wait::Stopped;
+        }
+
+        // After MAX_CHECK_FAILURES the client is considered broken, and only reconnects are tried
+        if failures < MAX_CHECK_FAILURES {
+            // Checks with a copy of the client, so other workers are not blocked by the lock
+            let check_client = client.lock().unwrap().clone_without_data();
+            match check_client.call_endpoint::<Value>(HttpVerb::GET, "auth/token/lookup-self", None, None) {
+                Ok(_) => {
+                    failures = 0;
+                    continue;
+                },
+                Err(error) => {
+                    failures += 1;
+                    warn!(host = host.url.as_str(); "Failed to check connection to {}: {}", &host.url, error);
+                    if failures < MAX_CHECK_FAILURES {
+                        continue;
+                    }
+                },
             }
         }
+        info!(host = host.url.as_str(); "Reconnecting to {}", &host.url);
+        match vault_client(host, version) {
+            Ok(new_client) => {
+                *client.lock().unwrap() = new_client;
+                info!(host = host.url.as_str(); "Reconnected to {}", &host.url);
+                on_reconnect(client);
+                return Wait::Reconnected;
+            },
+            Err(error) => {
+                warn!(host = host.url.as_str(); "Failed to reconnect to {}: {}", &host.url, error);
+            },
+        }
     }
 }
 
@@ -145,6 +896,32 @@ struct TokenInfo {
     max_ttl: Option<Duration>,
 }
 
+// Renews the token with a copy of the client, so other workers are not blocked by the lock while
+// Vault responds, then stores the new lease in the shared client. Returns false if the token cannot
+// be renewed again.
+fn renew_token(client: &Arc<Mutex<VaultClient>>) -> VaultResult<bool> {
+    let renew_client = client.lock().unwrap().clone_without_data();
+    let auth = match renew_client.call_endpoint::<Value>(HttpVerb::POST, "auth/token/renew-self", None, None)? {
+        EndpointResponse::VaultResponse(response) => response.auth,
+        EndpointResponse::Empty => None,
+    };
+    let renewable = auth.as_ref().is_none_or(|auth| auth.renewable);
+    if let Some(data) = client.lock().unwrap().data.as_mut() {
+        data.auth = auth;
+    }
+    Ok(renewable)
+}
+
+// Looks up the current TTL of the token, with a copy of the client so other workers are not
+// blocked by the lock
+fn lookup_token(client: &Arc<Mutex<VaultClient>>) -> VaultResult<TokenInfo> {
+    let lookup_client = client.lock().unwrap().clone_without_data();
+    match lookup_client.call_endpoint::<Value>(HttpVerb::GET, "auth/token/lookup-self", None, None)? {
+        EndpointResponse::VaultResponse(response) => Ok(TokenInfo::from_lookup(&response.data.unwrap_or_default())),
+        EndpointResponse::Empty => Ok(TokenInfo::new()),
+    }
+}
+
 impl TokenInfo {
     fn new() -> TokenInfo {
         // Defaults are for the root token, which is not renewable and has no TTL and max TTL
@@ -155,6 +932,15 @@ impl TokenInfo {
         }
     }
 
+    fn from_lookup(data: &Value) -> TokenInfo {
+        let seconds = |field: &str| data[field].as_u64().filter(|secs| *secs > 0).map(Duration::from_secs);
+        TokenInfo {
+            renewable: data["renewable"].as_bool().unwrap_or(false),
+            ttl: seconds("ttl"),
+            max_ttl: seconds("explicit_max_ttl"),
+        }
+    }
+
     fn from_client(client: &VaultClient) -> TokenInfo {
         let mut info = Self::new();
         if let Some(data) = &client.data {
@@ -175,3 +961,589 @@ impl TokenInfo {
         info
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::{thread, time};
+
+    use native_tls::{Identity, TlsAcceptor};
+
+    use crate::config::{EngineVersion, JwtAuthOptions, KubernetesAuthOptions, VaultAuthMethod, VaultHost};
+    use crate::health::HEALTH;
+    use crate::metrics::METRICS;
+    use crate::mock::{self, MockServer};
+    use crate::vault::{check_list, engine_version, mount_types, ui_engine_version, ClientPool, renew_token, revoke_token, token_worker, vault_client, vault_client_with_retry, OnReconnect, VaultClient};
+
+    const LOOKUP_SELF: &str = r#"{"request_id":"1","data":{"creation_time":0,"display_name":"root","id":"token","num_uses":0,"orphan":true,"path":"auth/token/root","policies":["root"],"ttl":0}}"#;
+
+    // Starts an HTTPS server with a self-signed certificate that answers every request with a
+    // token lookup response, returns the server URL
+    fn tls_server() -> String {
+        let cert = include_bytes!("../testdata/server.crt");
+        let key = include_bytes!("../testdata/server.key");
+        let acceptor = TlsAcceptor::new(Identity::from_pkcs8(cert, key).unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://127.0.0.1:{}/", listener.local_addr().unwrap().port());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Ok(mut stream) = acceptor.accept(stream) {
+                    let mut buf = [0; 4096];
+                    let _ = stream.read(&mut buf);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        LOOKUP_SELF.len(),
+                        LOOKUP_SELF,
+                    );
+                    let _ = stream.write_all(response.as_bytes());
+                }
+            }
+        });
+        url
+    }
+
+    fn host(url: &str) -> VaultHost {
+        VaultHost {
+            url: url.to_string(),
+            auth: Some(VaultAuthMethod::TokenAuth { token: "token".to_string() }),
+            ..Default::default()
+        }
+    }
+
+    fn no_reconnect() -> OnReconnect {
+        Arc::new(|_| {})
+    }
+
+    #[test]
+    fn test_vault_client_with_retry() {
+        // Sealed until the third lookup
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = lookups.clone();
+        let server = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") if counter.fetch_add(1, Ordering::Relaxed) < 2 => (503, r#"{"errors":["Vault is sealed"]}"#.to_string()),
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        assert!(vault_client_with_retry(&host(&server.url), &EngineVersion::V2, time::Duration::ZERO).is_err());
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+        assert!(vault_client_with_retry(&host(&server.url), &EngineVersion::V2, time::Duration::from_secs(10)).is_ok());
+        assert_eq!(lookups.load(Ordering::Relaxed), 3);
+
+        // Other errors are not retried
+        let server = MockServer::start(|_| (403, r#"{"errors":["permission denied"]}"#.to_string()));
+        let started = time::Instant::now();
+        assert!(vault_client_with_retry(&host(&server.url), &EngineVersion::V2, time::Duration::from_secs(10)).is_err());
+        assert_eq!(server.count("GET", "/v1/auth/token/lookup-self"), 1);
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+    }
+
+    fn app_role_host(url: &str) -> VaultHost {
+        VaultHost {
+            url: url.to_string(),
+            auth: Some(VaultAuthMethod::AppRoleAuth {
+                role_id: "role".to_string(),
+                secret_id: "secret".to_string(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_custom_ca_cert() {
+        let url = tls_server();
+        let mut host = host(&url);
+        assert!(vault_client(&host, &EngineVersion::V2).is_err());
+        host.ca_cert = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/server.crt").to_string());
+        assert!(vault_client(&host, &EngineVersion::V2).is_ok());
+    }
+
+    #[test]
+    fn test_tls_skip_verify() {
+        let url = tls_server();
+        let mut host = host(&url);
+        host.tls_skip_verify = true;
+        assert!(vault_client(&host, &EngineVersion::V2).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_ca_cert() {
+        let mut host = host("https://127.0.0.1:8200/");
+        host.ca_cert = Some("/nonexistent/ca.pem".to_string());
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("/nonexistent/ca.pem"));
+
+        host.ca_cert = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").to_string());
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("Failed to parse CA certificate"));
+    }
+
+    #[test]
+    fn test_app_role_login_then_renew() {
+        let logins = AtomicUsize::new(0);
+        let server = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/v1/auth/approle/login") => {
+                    let n = logins.fetch_add(1, Ordering::SeqCst) + 1;
+                    (200, mock::login(&format!("token-{}", n), true, 2))
+                },
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(true, 2)),
+                ("POST", "/v1/auth/token/renew-self") => (400, r#"{"errors":["lease is not renewable"]}"#.to_string()),
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let host = app_role_host(&server.url);
+        let client = vault_client(&host, &EngineVersion::V2).unwrap();
+        assert_eq!(client.token, "token-1");
+        assert!(server.requests()[0].body.contains(r#""role_id":"role""#));
+
+        // Token TTL is 2s, so the worker tries to renew it in 1s, then requests a new token
+        let client = Arc::new(Mutex::new(client));
+        let worker_client = client.clone();
+        thread::spawn(move || {
+            token_worker(&host, &EngineVersion::V2, worker_client, Arc::new(AtomicBool::new(false)), &AtomicU64::new(0), &no_reconnect());
+        });
+        for _ in 0..50 {
+            if client.lock().unwrap().token == "token-2" {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        assert_eq!(client.lock().unwrap().token, "token-2");
+        assert!(server.count("POST", "/v1/auth/token/renew-self") >= 1);
+    }
+
+    #[test]
+    fn test_token_worker_current_ttl() {
+        let lookups = AtomicUsize::new(0);
+        let server = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                // The client is created with a long TTL, but the token expires soon
+                ("GET", "/v1/auth/token/lookup-self") => {
+                    let ttl = if lookups.fetch_add(1, Ordering::SeqCst) == 0 { 3600 } else { 2 };
+                    (200, mock::lookup_self(true, ttl))
+                },
+                ("POST", "/v1/auth/token/renew-self") => (200, mock::login("token", true, 2)),
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let host = VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::TokenAuth { token: "token".to_string() }),
+            token_renew_fraction: Some(0.25),
+            ..Default::default()
+        };
+        let client = Arc::new(Mutex::new(vault_client(&host, &EngineVersion::V2).unwrap()));
+        let expiry = Arc::new(AtomicU64::new(0));
+        let worker_expiry = expiry.clone();
+        thread::spawn(move || {
+            token_worker(&host, &EngineVersion::V2, client, Arc::new(AtomicBool::new(false)), &worker_expiry, &no_reconnect());
+        });
+        for _ in 0..30 {
+            if server.count("POST", "/v1/auth/token/renew-self") > 0 {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        assert!(server.count("POST", "/v1/auth/token/renew-self") > 0);
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+        assert!(expiry.load(Ordering::Relaxed) <= now + 2);
+    }
+
+    #[test]
+    fn test_token_worker_renewal_failures() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(true, 1)),
+                ("POST", "/v1/auth/token/renew-self") => (500, r#"{"errors":["internal error"]}"#.to_string()),
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let host = VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::TokenAuth { token: "token".to_string() }),
+            token_renew_fraction: Some(0.1),
+            token_renew_grace: Some(0),
+            ..Default::default()
+        };
+        let client = Arc::new(Mutex::new(vault_client(&host, &EngineVersion::V2).unwrap()));
+        let failures = METRICS.token_renewal_failures.load(Ordering::Relaxed);
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let (host, stop) = (host.clone(), stop.clone());
+            thread::spawn(move || token_worker(&host, &EngineVersion::V2, client, stop, &AtomicU64::new(0), &no_reconnect()))
+        };
+        for _ in 0..30 {
+            if server.count("POST", "/v1/auth/token/renew-self") >= 2 && HEALTH.renewal_failing().contains(&server.url) {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        stop.store(true, Ordering::Relaxed);
+        worker.join().unwrap();
+        assert!(METRICS.token_renewal_failures.load(Ordering::Relaxed) >= failures + 2);
+        assert!(HEALTH.renewal_failing().contains(&server.url));
+        assert!(HEALTH.set_renewal_failing(&server.url, false));
+    }
+
+    #[test]
+    fn test_renew_token_without_lock() {
+        let shared: Arc<OnceLock<Arc<Mutex<VaultClient>>>> = Arc::new(OnceLock::new());
+        let locked = Arc::new(AtomicBool::new(false));
+        let (handler_shared, handler_locked) = (shared.clone(), locked.clone());
+        let server = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(true, 3600)),
+                // Other workers can use the shared client while the renewal waits for Vault
+                ("POST", "/v1/auth/token/renew-self") => {
+                    let client = handler_shared.get().unwrap();
+                    handler_locked.store(client.try_lock().is_err(), Ordering::SeqCst);
+                    (200, mock::login("token", false, 3600))
+                },
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let host = VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::TokenAuth { token: "token".to_string() }),
+            ..Default::default()
+        };
+        let client = shared.get_or_init(|| Arc::new(Mutex::new(vault_client(&host, &EngineVersion::V2).unwrap())));
+        assert!(!renew_token(client).unwrap());
+        assert!(!locked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_app_role_login_error() {
+        let server = MockServer::start(|_| (400, r#"{"errors":["invalid role"]}"#.to_string()));
+        let mut host = app_role_host(&server.url);
+        host.auth_mount = Some("custom-approle".to_string());
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("auth/custom-approle/login"));
+    }
+
+    #[test]
+    fn test_revoke_token() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/v1/auth/approle/login") => (200, mock::login("token", true, 60)),
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(true, 60)),
+                ("POST", "/v1/auth/token/revoke-self") => (204, "".to_string()),
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let login_host = app_role_host(&server.url);
+        let client = vault_client(&login_host, &EngineVersion::V2).unwrap();
+        assert!(revoke_token(&login_host, &client).unwrap());
+
+        // The token from the configuration is kept
+        let token_host = host(&server.url);
+        let client = vault_client(&token_host, &EngineVersion::V2).unwrap();
+        assert!(!revoke_token(&token_host, &client).unwrap());
+        assert_eq!(server.count("POST", "/v1/auth/token/revoke-self"), 1);
+    }
+
+    #[test]
+    fn test_kubernetes_login() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/v1/auth/kubernetes/login") => (200, mock::login("k8s-token", true, 60)),
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(true, 60)),
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let jwt_path = std::env::temp_dir().join(format!("vault-sync-test-jwt-{}", std::process::id()));
+        std::fs::write(&jwt_path, "service-account-jwt\n").unwrap();
+        let host = VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::KubernetesAuth {
+                kubernetes: KubernetesAuthOptions {
+                    role: "vault-sync".to_string(),
+                    jwt_path: jwt_path.to_str().unwrap().to_string(),
+                },
+            }),
+            ..Default::default()
+        };
+        let client = vault_client(&host, &EngineVersion::V2).unwrap();
+        assert_eq!(client.token, "k8s-token");
+        assert_eq!(server.requests()[0].body, r#"{"role":"vault-sync","jwt":"service-account-jwt"}"#);
+        std::fs::remove_file(&jwt_path).unwrap();
+
+        // Missing service account token at startup
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("Failed to read Kubernetes service account token"));
+
+    }
+
+    #[test]
+    fn test_wrapped_token() {
+        let unwraps = AtomicUsize::new(0);
+        let server = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/v1/sys/wrapping/unwrap") if unwraps.fetch_add(1, Ordering::SeqCst) == 0 => (200, mock::login("unwrapped-token", true, 60)),
+                ("POST", "/v1/sys/wrapping/unwrap") => (400, r#"{"errors":["wrapping token is not valid or does not exist"]}"#.to_string()),
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(true, 60)),
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let mut host = VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::WrappedTokenAuth { wrapped_token: "test-wrapping-token".to_string() }),
+            ..Default::default()
+        };
+        assert_eq!(vault_client(&host, &EngineVersion::V2).unwrap().token, "unwrapped-token");
+        // Reconnects use the unwrapped token
+        assert_eq!(vault_client(&host, &EngineVersion::V2).unwrap().token, "unwrapped-token");
+        assert_eq!(server.count("POST", "/v1/sys/wrapping/unwrap"), 1);
+
+        host.auth = Some(VaultAuthMethod::WrappedTokenAuth { wrapped_token: "test-used-wrapping-token".to_string() });
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err().to_string();
+        assert!(error.contains("wrapping token is not valid or does not exist"));
+        assert!(error.contains("may have been unwrapped already"));
+    }
+
+    #[test]
+    fn test_jwt_login() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/v1/auth/oidc/login") if request.body.contains(r#""jwt":"valid-jwt""#) => (200, mock::login("jwt-token", true, 60)),
+                ("POST", "/v1/auth/oidc/login") => (400, r#"{"errors":["error validating token: invalid signature"]}"#.to_string()),
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(true, 60)),
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let jwt_file = std::env::temp_dir().join(format!("vault-sync-test-oidc-jwt-{}", std::process::id()));
+        std::fs::write(&jwt_file, "valid-jwt\n").unwrap();
+        let mut host = VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::JwtAuth {
+                jwt: JwtAuthOptions {
+                    role: "vault-sync".to_string(),
+                    jwt: None,
+                    jwt_file: Some(jwt_file.to_str().unwrap().to_string()),
+                    mount: "oidc".to_string(),
+                },
+            }),
+            ..Default::default()
+        };
+        let client = vault_client(&host, &EngineVersion::V2).unwrap();
+        assert_eq!(client.token, "jwt-token");
+        assert_eq!(server.requests()[0].body, r#"{"role":"vault-sync","jwt":"valid-jwt"}"#);
+
+        // The file is read again on the next login
+        std::fs::write(&jwt_file, "expired-jwt").unwrap();
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("Failed to login as role vault-sync via auth/oidc/login"));
+        std::fs::remove_file(&jwt_file).unwrap();
+
+        if let Some(VaultAuthMethod::JwtAuth { jwt }) = &mut host.auth {
+            jwt.jwt = Some("valid-jwt".to_string());
+            jwt.jwt_file = None;
+        }
+        assert_eq!(vault_client(&host, &EngineVersion::V2).unwrap().token, "jwt-token");
+
+        if let Some(VaultAuthMethod::JwtAuth { jwt }) = &mut host.auth {
+            jwt.jwt = None;
+        }
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("jwt auth requires jwt or jwt_file"));
+    }
+
+    #[test]
+    fn test_token_file() {
+        let server = MockServer::start(|_| (200, mock::lookup_self(false, 60)));
+        let token_file = std::env::temp_dir().join(format!("vault-sync-test-token-{}", std::process::id()));
+        let host = VaultHost {
+            url: server.url.clone(),
+            auth: Some(VaultAuthMethod::TokenFileAuth {
+                token_file: token_file.to_str().unwrap().to_string(),
+            }),
+            ..Default::default()
+        };
+
+        std::fs::write(&token_file, "file-token \n").unwrap();
+        let client = vault_client(&host, &EngineVersion::V2).unwrap();
+        assert_eq!(client.token, "file-token");
+
+        std::fs::write(&token_file, "\n").unwrap();
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("is empty"));
+
+        std::fs::remove_file(&token_file).unwrap();
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("Failed to read token file"));
+    }
+
+    #[test]
+    fn test_token_env_not_resolved() {
+        let host = VaultHost {
+            url: "http://127.0.0.1:8200/".to_string(),
+            auth: Some(VaultAuthMethod::TokenEnvAuth { token_env: "VAULT_SYNC_TOKEN".to_string() }),
+            ..Default::default()
+        };
+        let error = vault_client(&host, &EngineVersion::V2).unwrap_err();
+        assert!(error.to_string().contains("VAULT_SYNC_TOKEN of token_env is not resolved"));
+    }
+
+    #[test]
+    fn test_client_pool() {
+        let server = MockServer::start(|request| match request.path.as_str() {
+            "/v1/auth/token/lookup-self" => (200, mock::lookup_self(false, 0)),
+            _ => (200, r#"{"request_id":"1","data":{"keys":["a"]}}"#.to_string()),
+        });
+        let shared = Arc::new(Mutex::new(vault_client(&host(&server.url), &EngineVersion::V2).unwrap()));
+        let pool = ClientPool::new(&host(&server.url), shared.clone(), 3);
+        assert_eq!(pool.http_clients.len(), 2);
+
+        // A renewed token of the shared client is used by the next checkouts
+        shared.lock().unwrap().token = "renewed".to_string();
+        for _ in 0..4 {
+            let client = pool.checkout();
+            let client = client.lock().unwrap();
+            assert_eq!(client.token, "renewed");
+            assert_eq!(client.list_secrets("").unwrap(), vec!["a"]);
+        }
+
+        // Falls back to the HTTP client of the shared client
+        let invalid = VaultHost { ca_cert: Some("/nonexistent/ca.crt".to_string()), ..host(&server.url) };
+        let pool = ClientPool::new(&invalid, shared, 3);
+        assert!(pool.http_clients.is_empty());
+        assert_eq!(pool.checkout().lock().unwrap().token, "renewed");
+    }
+
+    #[test]
+    fn test_check_list() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["foo"]}}"#.to_string()),
+                ("LIST", "/v1/secret/metadata/missing/") => (404, r#"{"errors":[]}"#.to_string()),
+                _ => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+            }
+        });
+        let mut client = vault_client(&host(&server.url), &EngineVersion::V2).unwrap();
+        assert!(check_list(&mut client, "secret", "src/").is_ok());
+        assert!(check_list(&mut client, "secret", "missing/").is_ok());
+        assert!(check_list(&mut client, "secret", "forbidden/").is_err());
+    }
+
+    #[test]
+    fn test_engine_version() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/sys/mounts") => (200, r#"{"request_id":"1","data":{
+                    "secret/":{"type":"kv","options":{"version":"2"}},
+                    "legacy/":{"type":"kv","options":null},
+                    "pki/":{"type":"pki","options":null}
+                }}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let client = vault_client(&host(&server.url), &EngineVersion::V2).unwrap();
+        assert_eq!(engine_version(&client, "secret").unwrap(), Some(EngineVersion::V2));
+        assert_eq!(engine_version(&client, "legacy").unwrap(), Some(EngineVersion::V1));
+        assert_eq!(engine_version(&client, "pki").unwrap(), None);
+        assert_eq!(engine_version(&client, "missing").unwrap(), None);
+        let types = mount_types(&client).unwrap();
+        assert_eq!(types["secret"], "kv");
+        assert_eq!(types["pki"], "pki");
+    }
+
+    #[test]
+    fn test_ui_engine_version() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/sys/mounts") => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                ("GET", "/v1/sys/internal/ui/mounts/secret") => (200, r#"{"request_id":"1","data":{
+                    "path":"secret/","type":"kv","options":{"version":"2"}
+                }}"#.to_string()),
+                ("GET", "/v1/sys/internal/ui/mounts/legacy") => (200, r#"{"request_id":"1","data":{
+                    "path":"legacy/","type":"kv","options":null
+                }}"#.to_string()),
+                _ => (403, r#"{"errors":["permission denied"]}"#.to_string()),
+            }
+        });
+        let client = vault_client(&host(&server.url), &EngineVersion::V2).unwrap();
+        assert!(engine_version(&client, "secret").is_err());
+        assert_eq!(ui_engine_version(&client, "secret").unwrap(), Some(EngineVersion::V2));
+        assert_eq!(ui_engine_version(&client, "legacy").unwrap(), Some(EngineVersion::V1));
+        assert!(ui_engine_version(&client, "forbidden").is_err());
+    }
+
+    #[test]
+    fn test_write_secret_v1_and_v2() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/foo") => (200, r#"{"request_id":"1","data":{"key":"v1"}}"#.to_string()),
+                ("GET", "/v1/secret/data/foo") => (200, r#"{"request_id":"1","data":{"data":{"key":"v2"},"metadata":{"version":3}}}"#.to_string()),
+                _ => (204, "".to_string()),
+            }
+        });
+        let secret = serde_json::json!({"key": "value"});
+
+        let mut client = vault_client(&host(&server.url), &EngineVersion::V1).unwrap();
+        client.secret_backend("secret");
+        assert_eq!(client.get_custom_secret::<_, serde_json::Value>("foo").unwrap(), serde_json::json!({"key": "v1"}));
+        client.set_custom_secret("foo", &secret).unwrap();
+
+        let mut client = vault_client(&host(&server.url), &EngineVersion::V2).unwrap();
+        client.secret_backend("secret");
+        assert_eq!(client.get_custom_secret::<_, serde_json::Value>("foo").unwrap(), serde_json::json!({"key": "v2"}));
+        client.set_custom_secret("foo", &secret).unwrap();
+
+        let writes: Vec<(String, String)> = server.requests().into_iter()
+            .filter(|r| r.method == "PUT")
+            .map(|r| (r.path, r.body))
+            .collect();
+        assert_eq!(writes, vec![
+            ("/v1/secret/foo".to_string(), r#"{"key":"value"}"#.to_string()),
+            ("/v1/secret/data/foo".to_string(), r#"{"data":{"key":"value"}}"#.to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let server = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["secret"]}}"#.to_string()),
+                _ => (404, "{}".to_string()),
+            }
+        });
+        let host = host(&server.url);
+        let client = Arc::new(Mutex::new(vault_client(&host, &EngineVersion::V2).unwrap()));
+        let reconnects = Arc::new(AtomicUsize::new(0));
+        let worker_client = client.clone();
+        let worker_reconnects = reconnects.clone();
+        let on_reconnect: OnReconnect = Arc::new(move |_: &Arc<Mutex<VaultClient>>| {
+            worker_reconnects.fetch_add(1, Ordering::SeqCst);
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let worker_stop = stop.clone();
+        thread::spawn(move || {
+            token_worker(&host, &EngineVersion::V2, worker_client, worker_stop, &AtomicU64::new(0), &on_reconnect);
+        });
+
+        server.stop();
+        assert!(check_list(&mut client.lock().unwrap(), "secret", "src/").is_err());
+        thread::sleep(time::Duration::from_millis(1000));
+        assert_eq!(reconnects.load(Ordering::SeqCst), 0);
+        server.restart();
+        for _ in 0..50 {
+            if reconnects.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(reconnects.load(Ordering::SeqCst), 1);
+        check_list(&mut client.lock().unwrap(), "secret", "src/").unwrap();
+    }
+}
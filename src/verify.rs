@@ -0,0 +1,203 @@
+// Verification that the destinations match the source, run with --verify after a sync. Both Vaults
+// are only listed and read. Every secret is compared by a checksum keyed for the run, so the report
+// can be kept as a record without the values, and the values cannot be guessed from it.
+
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+use crate::checksum::ChecksumKey;
+use crate::config::{get_prefixes, VaultSyncConfig};
+use crate::diff::expected_secret;
+use crate::sync::{list_all, resolve_dst_path, PathMap, SecretPath, SyncError};
+use crate::table;
+use crate::vault::{self, VaultClient};
+
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    // URL of the destination Vault
+    pub destination: String,
+    // Destination secret
+    pub mount: String,
+    pub path: String,
+    pub src_checksum: String,
+    // None if the secret is missing in the destination
+    pub dst_checksum: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Summary {
+    pub destination: String,
+    pub matched: u64,
+    pub mismatched: u64,
+    pub missing: u64,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Verification {
+    // In the order of the destinations
+    pub summaries: Vec<Summary>,
+    // Sorted by destination and path
+    pub mismatches: Vec<Mismatch>,
+    // False if some of the secrets could not be listed or read
+    pub complete: bool,
+}
+
+impl Verification {
+    pub fn matched(&self) -> u64 {
+        self.summaries.iter().map(|summary| summary.matched).sum()
+    }
+}
+
+// Checks every source secret that the full sync would write, filtered and without blacklisted
+// keys like in the sync. Extra destination secrets are not checked, see --diff.
+pub fn verify(config: &VaultSyncConfig, key: &ChecksumKey, src_client: &Arc<Mutex<VaultClient>>, dst_clients: &[Arc<Mutex<VaultClient>>]) -> Verification {
+    let mut verification = Verification { complete: true, ..Default::default() };
+    let src_prefixes = get_prefixes(&config.src.prefix);
+    let mut src_secrets = Vec::new();
+    for backend in config.src.synced_backends() {
+        for src_prefix in &src_prefixes {
+            verification.complete &= list_all(src_prefix, &backend, &config.src.host.url, src_client.clone(), |secret| {
+                if config.src.filter.matches(&secret) {
+                    src_secrets.push(SecretPath { mount: backend.clone(), path: secret });
+                }
+            });
+        }
+    }
+
+    for (dst, dst_client) in config.dst.iter().zip(dst_clients) {
+        let mut summary = Summary { destination: dst.host.url.clone(), ..Default::default() };
+        let paths = PathMap::new(&config.src, dst);
+        for src in &src_secrets {
+            if !dst.in_canary(&src.path) {
+                continue;
+            }
+            let checksums = resolve_dst_path(config, &paths, src_client, src).and_then(|dst_path| {
+                let Some(dst_path) = dst_path else {
+                    return Ok(None);
+                };
+                let Some(expected) = expected_secret(config, src_client, src, dst)? else {
+                    return Ok(None);
+                };
+                let dst_secret = SecretPath { mount: paths.mounts[&src.mount].clone(), path: dst_path };
+                let mut actual = vault::read_secret(dst_client, &dst_secret.mount, &dst_secret.path)
+                    .map_err(|error| SyncError::new(&dst_secret, &dst.host.url, error))?;
+                // The destination keeps its own values of the blacklisted keys
+                if let Some(actual) = &mut actual {
+                    config.src.blacklist.strip(&src.path, actual);
+                }
+                Ok(Some((dst_secret, key.checksum(&expected), actual.as_ref().map(|actual| key.checksum(actual)))))
+            });
+            let (dst_secret, src_checksum, dst_checksum) = match checksums {
+                Ok(Some(checksums)) => checksums,
+                // Skipped by the sync
+                Ok(None) => continue,
+                Err(error) => {
+                    warn!(secret = error.secret.path.as_str(), host = error.host.as_str(); "Failed to read secret {}", error);
+                    verification.complete = false;
+                    continue;
+                },
+            };
+            match &dst_checksum {
+                Some(dst_checksum) if dst_checksum == &src_checksum => summary.matched += 1,
+                Some(_) => summary.mismatched += 1,
+                None => summary.missing += 1,
+            }
+            if dst_checksum.as_ref() != Some(&src_checksum) {
+                verification.mismatches.push(Mismatch {
+                    destination: dst.host.url.clone(),
+                    mount: dst_secret.mount,
+                    path: dst_secret.path,
+                    src_checksum,
+                    dst_checksum,
+                });
+            }
+        }
+        verification.summaries.push(summary);
+    }
+    verification.mismatches.sort_by(|a, b| (&a.destination, &a.mount, &a.path).cmp(&(&b.destination, &b.mount, &b.path)));
+    verification
+}
+
+// Renders the mismatches with both checksums, then the summaries
+pub fn render(verification: &Verification) -> String {
+    let mut text = String::new();
+    if !verification.mismatches.is_empty() {
+        let mut lines = vec![["MISMATCH", "DESTINATION", "SECRET", "SOURCE", "DESTINATION"].map(String::from).to_vec()];
+        for mismatch in &verification.mismatches {
+            lines.push(vec![
+                if mismatch.dst_checksum.is_some() { "different" } else { "missing" }.to_string(),
+                mismatch.destination.clone(),
+                format!("{}/{}", &mismatch.mount, &mismatch.path),
+                mismatch.src_checksum.clone(),
+                mismatch.dst_checksum.clone().unwrap_or_else(|| "-".to_string()),
+            ]);
+        }
+        text.push_str(&table::render(&lines));
+        text.push('\n');
+    }
+    let mut lines = vec![["DESTINATION", "MATCHED", "MISMATCHED", "MISSING"].map(String::from).to_vec()];
+    for summary in &verification.summaries {
+        lines.push(vec![
+            summary.destination.clone(),
+            summary.matched.to_string(),
+            summary.mismatched.to_string(),
+            summary.missing.to_string(),
+        ]);
+    }
+    text.push_str(&table::render(&lines));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::checksum::ChecksumKey;
+    use crate::filter::{KeyBlacklist, KeyRule};
+    use crate::mock::{self, MockServer};
+    use crate::verify::{render, verify};
+
+    #[test]
+    fn test_verify() {
+        let src = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("LIST", "/v1/secret/metadata/src/") => (200, r#"{"request_id":"1","data":{"keys":["same","changed","missing","secret"]}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/same") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","b":"2"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/changed") => (200, r#"{"request_id":"1","data":{"data":{"a":"2"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/missing") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/src/secret") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","password":"src"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let dst = MockServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/v1/auth/token/lookup-self") => (200, mock::lookup_self(false, 0)),
+                ("GET", "/v1/secret/data/dst/same") => (200, r#"{"request_id":"1","data":{"data":{"b":"2","a":"1"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/changed") => (200, r#"{"request_id":"1","data":{"data":{"a":"1"}}}"#.to_string()),
+                ("GET", "/v1/secret/data/dst/secret") => (200, r#"{"request_id":"1","data":{"data":{"a":"1","password":"dst"}}}"#.to_string()),
+                _ => (404, r#"{"errors":[]}"#.to_string()),
+            }
+        });
+        let mut config = mock::config(&src.url, &dst.url);
+        config.src.blacklist = KeyBlacklist::new(&[KeyRule { path: None, keys: vec!["password".to_string()] }]).unwrap();
+
+        let key = ChecksumKey::from_secret("key");
+        let verification = verify(&config, &key, &mock::client(&src), &[mock::client(&dst)]);
+        assert!(verification.complete);
+        let summary = &verification.summaries[0];
+        assert_eq!((summary.matched, summary.mismatched, summary.missing), (2, 1, 1));
+        assert_eq!(verification.matched(), 2);
+        let paths: Vec<&str> = verification.mismatches.iter().map(|mismatch| mismatch.path.as_str()).collect();
+        assert_eq!(paths, ["dst/changed", "dst/missing"]);
+        assert_eq!(verification.mismatches[0].src_checksum, key.checksum(&json!({"a": "2"})));
+        assert_eq!(verification.mismatches[1].dst_checksum, None);
+        // Nothing is written
+        assert!(dst.requests().iter().all(|request| request.method == "GET"));
+
+        let text = render(&verification);
+        assert!(text.starts_with("MISMATCH"));
+        assert!(text.contains(&format!("missing    {}  secret/dst/missing", dst.url)));
+    }
+}
@@ -0,0 +1,214 @@
+// Notifications about sync events, POSTed as JSON to webhook_url. Notifications are delivered by
+// a separate thread, so a slow or failing receiver does not block the sync. If the receiver is too
+// slow, the notifications that do not fit in the queue are dropped.
+
+use std::sync::{mpsc, Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{thread, time};
+
+use log::{info, warn};
+use reqwest::blocking::Client;
+use serde::Serialize;
+
+use crate::config::{redact_url, VaultSyncConfig, WebhookEvent};
+use crate::health::unix_time;
+use crate::metrics::METRICS;
+
+// Attempts after the first one, with exponential backoff
+const RETRIES: u32 = 2;
+const RETRY_DELAY: time::Duration = time::Duration::from_secs(1);
+const TIMEOUT: time::Duration = time::Duration::from_secs(10);
+// Notifications waiting for the delivery, the memory does not grow while the receiver is down
+const CAPACITY: usize = 1000;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub event: WebhookEvent,
+    // Unix time in seconds
+    pub timestamp: u64,
+    // id of this vault-sync instance
+    pub id: String,
+    // Secret with the mount, for example "secret/path/to/secret"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    // URL of the destination Vault
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+pub struct Webhook {
+    id: String,
+    events: Vec<WebhookEvent>,
+    tx: mpsc::SyncSender<Notification>,
+    // Notifications that are not delivered or dropped yet
+    pending: Arc<AtomicUsize>,
+}
+
+static WEBHOOK: OnceLock<Webhook> = OnceLock::new();
+
+// Starts the delivery thread if webhook_url is set. Changes of the webhook options require restart.
+pub fn init(config: &VaultSyncConfig) {
+    if let Some(url) = &config.webhook_url {
+        info!("Sending {:?} events to webhook {}", &config.webhook_events, redact_url(url));
+        let _ = WEBHOOK.set(Webhook::start(url, &config.id, &config.webhook_events, RETRY_DELAY, CAPACITY));
+    }
+}
+
+pub fn notify(event: WebhookEvent, path: Option<String>, destination: Option<&str>, message: Option<String>) {
+    if let Some(webhook) = WEBHOOK.get() {
+        webhook.notify(event, path, destination, message);
+    }
+}
+
+// Waits for the queued notifications before the process exits
+pub fn flush(timeout: time::Duration) {
+    if let Some(webhook) = WEBHOOK.get() {
+        webhook.flush(timeout);
+    }
+}
+
+impl Webhook {
+    pub fn start(url: &str, id: &str, events: &[WebhookEvent], retry_delay: time::Duration, capacity: usize) -> Webhook {
+        let (tx, rx) = mpsc::sync_channel::<Notification>(capacity);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let url = url.to_string();
+        // For the log messages, the URL can have a token
+        let redacted = redact_url(&url);
+        let delivered = pending.clone();
+        thread::spawn(move || {
+            let client = Client::builder().timeout(TIMEOUT).build().unwrap_or_default();
+            for notification in rx {
+                deliver(&client, &url, &redacted, &notification, retry_delay);
+                delivered.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+        Webhook { id: id.to_string(), events: events.to_vec(), tx, pending }
+    }
+
+    pub fn notify(&self, event: WebhookEvent, path: Option<String>, destination: Option<&str>, message: Option<String>) {
+        if !self.events.contains(&event) {
+            return;
+        }
+        let notification = Notification {
+            event,
+            timestamp: unix_time(),
+            id: self.id.clone(),
+            path,
+            destination: destination.map(String::from),
+            message,
+        };
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        match self.tx.try_send(notification) {
+            Ok(()) => {},
+            Err(mpsc::TrySendError::Full(notification)) => {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+                METRICS.webhook_dropped.fetch_add(1, Ordering::Relaxed);
+                warn!("Webhook queue is full, dropping {:?} event", notification.event);
+            },
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+            },
+        }
+    }
+
+    pub fn flush(&self, timeout: time::Duration) {
+        let deadline = time::Instant::now() + timeout;
+        while self.pending.load(Ordering::Relaxed) > 0 && time::Instant::now() < deadline {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+}
+
+// Failed notifications are logged and dropped after the retries
+fn deliver(client: &Client, url: &str, redacted: &str, notification: &Notification, retry_delay: time::Duration) {
+    let body = serde_json::to_string(notification).unwrap();
+    let mut attempt = 0;
+    loop {
+        let error = match client.post(url).header("Content-Type", "application/json").body(body.clone()).send() {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => format!("status {}", response.status()),
+            Err(error) => error.without_url().to_string(),
+        };
+        if attempt == RETRIES {
+            warn!("Failed to send {:?} event to webhook {}: {}", notification.event, redacted, error);
+            return;
+        }
+        thread::sleep(retry_delay * 2u32.pow(attempt));
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time;
+
+    use serde_json::Value;
+
+    use crate::config::WebhookEvent;
+    use crate::metrics::METRICS;
+    use crate::mock::MockServer;
+    use crate::webhook::Webhook;
+
+    #[test]
+    fn test_webhook() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let server = MockServer::start(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                // The first delivery fails and is retried
+                ("POST", "/hook") if counter.fetch_add(1, Ordering::Relaxed) == 0 => (500, "".to_string()),
+                ("POST", "/hook") => (204, "".to_string()),
+                _ => (404, "".to_string()),
+            }
+        });
+        let url = format!("{}hook", server.url);
+        let webhook = Webhook::start(&url, "vault-sync", &[WebhookEvent::Delete], time::Duration::from_millis(10), 10);
+        webhook.notify(WebhookEvent::Error, None, None, Some("not sent".to_string()));
+        webhook.notify(WebhookEvent::Delete, Some("secret/dst/a".to_string()), Some("http://dst:8200/"), None);
+        webhook.flush(time::Duration::from_secs(10));
+
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        let request = server.requests().pop().unwrap();
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["event"], "delete");
+        assert_eq!(body["id"], "vault-sync");
+        assert_eq!(body["path"], "secret/dst/a");
+        assert_eq!(body["destination"], "http://dst:8200/");
+        assert!(body["timestamp"].as_u64().unwrap() > 0);
+        assert!(body.get("message").is_none());
+    }
+
+    #[test]
+    fn test_webhook_unreachable() {
+        let server = MockServer::start(|_| (503, "".to_string()));
+        let url = format!("{}hook", server.url);
+        let webhook = Webhook::start(&url, "vault-sync", &[WebhookEvent::Error], time::Duration::from_millis(10), 10);
+        webhook.notify(WebhookEvent::Error, None, None, Some("failed".to_string()));
+        webhook.flush(time::Duration::from_secs(10));
+        // The first attempt and the retries, then the notification is dropped
+        assert_eq!(server.count("POST", "/hook"), 3);
+    }
+
+    #[test]
+    fn test_webhook_queue_full() {
+        let server = MockServer::start(|_| {
+            std::thread::sleep(time::Duration::from_millis(200));
+            (204, "".to_string())
+        });
+        let url = format!("{}hook", server.url);
+        let webhook = Webhook::start(&url, "vault-sync", &[WebhookEvent::Error], time::Duration::from_millis(10), 1);
+        let dropped = METRICS.webhook_dropped.load(Ordering::Relaxed);
+        for _ in 0..5 {
+            webhook.notify(WebhookEvent::Error, None, None, Some("failed".to_string()));
+        }
+        webhook.flush(time::Duration::from_secs(10));
+        // One notification is delivered and one waits in the queue, the others are dropped
+        let delivered = server.count("POST", "/hook");
+        assert!(delivered <= 2);
+        assert_eq!(METRICS.webhook_dropped.load(Ordering::Relaxed) - dropped, 5 - delivered as u64);
+    }
+}
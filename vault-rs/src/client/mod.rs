@@ -28,7 +28,7 @@ pub mod error;
 /// Secrets engine.
 ///
 /// See https://developer.hashicorp.com/vault/api-docs/secret/kv.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum SecretsEngine {
     /// KV secrets engine, version 1.
     /// https://developer.hashicorp.com/vault/api-docs/secret/kv/kv-v1
@@ -783,19 +783,36 @@ impl VaultClient<()> {
         R: Into<String>,
         S: Into<String>,
     {
-        let host = host.try_into()?;
-        let client = Client::new();
         let secret_id = match secret_id {
             Some(s) => Some(s.into()),
             None => None,
         };
-        let payload = serde_json::to_string(&AppRolePayload {
+        let payload = AppRolePayload {
             role_id: role_id.into(),
             secret_id,
-        })?;
+        };
+        Self::new_login_from_reqwest(host, "approle", &payload, Client::new())
+    }
+
+    /// Construct a `VaultClient` via the auth method mounted at `auth/<mount>` (for example
+    /// `approle` or `kubernetes`) and reqwest::Client. The payload is sent as is to the login
+    /// endpoint of the auth method.
+    pub fn new_login_from_reqwest<U, P>(
+        host: U,
+        mount: &str,
+        payload: &P,
+        cli: Client,
+    ) -> Result<VaultClient<()>>
+    where
+        U: TryInto<Url, Err = Error>,
+        P: Serialize,
+    {
+        let host = host.try_into()?;
+        let client = cli;
+        let payload = serde_json::to_string(payload)?;
         let res = handle_reqwest_response(
             client
-                .post(host.join("/v1/auth/approle/login")?)
+                .post(host.join(&format!("/v1/auth/{}/login", mount))?)
                 .body(payload)
                 .send(),
         )?;
@@ -865,6 +882,27 @@ where
         self.secrets_engine = secrets_engine
     }
 
+    /// Returns a client with the same host, token, HTTP client, backend and secrets engine, but
+    /// without `data`
+    pub fn clone_without_data(&self) -> VaultClient<T> {
+        VaultClient {
+            host: self.host.clone(),
+            token: self.token.clone(),
+            client: self.client.clone(),
+            data: None,
+            secret_backend: self.secret_backend.clone(),
+            secrets_engine: self.secrets_engine.clone(),
+        }
+    }
+
+    /// Same as `clone_without_data`, but with another HTTP client, for example from a pool
+    pub fn clone_with_http_client(&self, client: Client) -> VaultClient<T> {
+        VaultClient {
+            client,
+            ..self.clone_without_data()
+        }
+    }
+
     /// Renew lease for `VaultClient`'s token and updates the
     /// `self.data.auth` based upon the response.  Corresponds to
     /// [`/auth/token/renew-self`][token].
@@ -1387,6 +1425,34 @@ where
         }
     }
 
+    /// Same as `call_endpoint`, but the request and response bodies are bytes, which do not have
+    /// to be valid UTF-8.
+    pub fn call_endpoint_bytes(
+        &self,
+        http_verb: HttpVerb,
+        endpoint: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let method = match http_verb {
+            HttpVerb::GET => Method::GET,
+            HttpVerb::POST => Method::POST,
+            HttpVerb::PUT => Method::PUT,
+            HttpVerb::DELETE => Method::DELETE,
+            HttpVerb::LIST => Method::from_str("LIST").expect("Failed to parse LIST to Method"),
+        };
+        let mut res = handle_reqwest_response(
+            self.client
+                .request(method, self.host.join(&format!("/v1/{}", endpoint))?)
+                .header("X-Vault-Token", self.token.to_string())
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.unwrap_or_default())
+                .send(),
+        )?;
+        let mut bytes = Vec::new();
+        let _ = res.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
     /// Accesses a given endpoint using the provided `wrap_ttl` and returns a single-use
     /// `wrapping_token` to access the response provided by the endpoint.
     pub fn get_wrapping_token_for_endpoint(